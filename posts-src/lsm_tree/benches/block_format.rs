@@ -0,0 +1,109 @@
+//! Compares SSTables with and without prefix compressed keys: prints the
+//! size of the data blocks for each, then times point lookups and a full
+//! scan against both.
+
+use std::path::PathBuf;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use lsm_tree::{LsmTree, Options};
+
+const TENANTS: u64 = 16;
+const USERS_PER_TENANT: u64 = 4096;
+
+/// Keys with the long shared prefixes the format is meant for
+fn key(tenant: u64, user: u64) -> Vec<u8> {
+    format!("tenant/{:04}/user/{:08}/profile", tenant, user).into_bytes()
+}
+
+/// A fully compacted tree holding every key, written with the given restart interval
+fn build_tree(restart_interval: usize) -> (LsmTree, PathBuf) {
+    let path = std::env::temp_dir().join(format!(
+        "lsm_bench_block_format_{}_{}",
+        std::process::id(),
+        restart_interval
+    ));
+    let _ = std::fs::remove_dir_all(&path);
+
+    let options = Options {
+        block_restart_interval: restart_interval,
+        ..Options::default()
+    };
+    let tree = LsmTree::open_with_options(&path, options).unwrap();
+    for tenant in 0..TENANTS {
+        for user in 0..USERS_PER_TENANT {
+            tree.put(key(tenant, user), user.to_le_bytes().to_vec())
+                .unwrap();
+        }
+    }
+    tree.flush().unwrap();
+    tree.compact_all().unwrap();
+
+    (tree, path)
+}
+
+fn block_format(c: &mut Criterion) {
+    // 1 stores every key in full, so it's the format without prefix compression
+    let trees: Vec<_> = [1, 16]
+        .into_iter()
+        .map(|restart_interval| (restart_interval, build_tree(restart_interval)))
+        .collect();
+
+    let full_size = trees[0].1 .0.stats().unwrap().uncompressed_bytes;
+    for (restart_interval, (tree, _)) in &trees {
+        let stats = tree.stats().unwrap();
+        println!(
+            "restart interval {:>2}: {} data blocks, {} bytes ({:.1}% of full keys)",
+            restart_interval,
+            stats.data_blocks,
+            stats.uncompressed_bytes,
+            100.0 * stats.uncompressed_bytes as f64 / full_size as f64
+        );
+    }
+
+    let mut group = c.benchmark_group("get");
+    for (restart_interval, (tree, _)) in &trees {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(restart_interval),
+            tree,
+            |b, tree| {
+                let mut i = 0u64;
+                b.iter(|| {
+                    // step through users in an order unrelated to the key order
+                    i = (i + 7919) % (TENANTS * USERS_PER_TENANT);
+                    black_box(tree.get(&key(i % TENANTS, i / TENANTS)).unwrap())
+                });
+            },
+        );
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("scan");
+    group.sample_size(10);
+    for (restart_interval, (tree, _)) in &trees {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(restart_interval),
+            tree,
+            |b, tree| {
+                b.iter(|| {
+                    let mut cursor = tree.cursor();
+                    cursor.seek_to_first().unwrap();
+                    let mut count = 0;
+                    while cursor.valid() {
+                        count += 1;
+                        cursor.next().unwrap();
+                    }
+                    black_box(count)
+                });
+            },
+        );
+    }
+    group.finish();
+
+    for (_, (tree, path)) in trees {
+        drop(tree);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+criterion_group!(benches, block_format);
+criterion_main!(benches);
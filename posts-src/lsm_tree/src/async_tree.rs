@@ -0,0 +1,439 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    ops::{Bound, RangeBounds},
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    pin::Pin,
+    sync::{mpsc, Arc, Mutex},
+    task::{Context, Poll},
+    thread::JoinHandle,
+};
+
+use anyhow::{anyhow, Result};
+use futures_core::Stream;
+use tokio::sync::oneshot;
+
+use crate::{Cursor, LsmTree, Options};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Dedicated threads running the tree's blocking calls, so they never tie
+/// up the async executor (or tokio's own blocking pool)
+#[derive(Debug)]
+struct BlockingPool {
+    // `None` once shut down
+    sender: Mutex<Option<mpsc::Sender<Job>>>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl BlockingPool {
+    fn new(threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..threads.max(1))
+            .map(|_| {
+                let receiver = receiver.clone();
+                std::thread::spawn(move || loop {
+                    // the lock is only held while waiting, not while running the job
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        // a panic only drops the job's result sender, which
+                        // its caller sees as an error
+                        Ok(job) => {
+                            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                        }
+                        // every sender is gone: shut down
+                        Err(_) => return,
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            sender: Mutex::new(Some(sender)),
+            threads: Mutex::new(threads),
+        }
+    }
+
+    /// Queue `job`, whose result arrives through the returned receiver.
+    /// If the receiver is dropped before a thread gets to the job, it's
+    /// skipped.
+    fn submit<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<oneshot::Receiver<T>> {
+        let (result_sender, result_receiver) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            if !result_sender.is_closed() {
+                let _ = result_sender.send(job());
+            }
+        });
+
+        self.sender
+            .lock()
+            .unwrap()
+            .as_ref()
+            .ok_or_else(shut_down)?
+            .send(job)
+            .map_err(|_| shut_down())?;
+        Ok(result_receiver)
+    }
+
+    /// Run `job` on the pool. Nothing is queued until the future is first
+    /// polled, and dropping it cancels the job unless it has already started.
+    async fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        self.submit(job)?.await.map_err(|_| panicked())?
+    }
+
+    /// Stop taking jobs, and hand back the threads to join once the ones
+    /// already queued are done
+    fn close(&self) -> Vec<JoinHandle<()>> {
+        self.sender.lock().unwrap().take();
+        std::mem::take(&mut *self.threads.lock().unwrap())
+    }
+}
+
+fn shut_down() -> anyhow::Error {
+    anyhow!("AsyncLsmTree has been shut down")
+}
+
+fn panicked() -> anyhow::Error {
+    anyhow!("AsyncLsmTree call panicked")
+}
+
+/// An `LsmTree` for async code (behind the `async` feature).
+///
+/// Every call runs on a small pool of dedicated threads, since the tree
+/// does blocking file I/O (and `put` waits on the WAL `sync_all`). Handles
+/// are cheap to clone and share the tree and the pool.
+///
+/// Dropping a returned future before it's first polled, or while its job
+/// is still queued, cancels the call. Once a job has started it runs to
+/// completion, so a cancelled write may still be applied.
+#[derive(Debug, Clone)]
+pub struct AsyncLsmTree {
+    tree: Arc<LsmTree>,
+    pool: Arc<BlockingPool>,
+}
+
+impl AsyncLsmTree {
+    pub const DEFAULT_POOL_THREADS: usize = 4;
+    /// Entries a `Scan` reads per trip to the pool
+    const SCAN_BATCH_SIZE: usize = 256;
+
+    /// Open (or create) a tree, see `LsmTree::open_with_options`
+    pub async fn open(path: impl Into<PathBuf>, options: Options) -> Result<Self> {
+        let path = path.into();
+        let pool = Arc::new(BlockingPool::new(Self::DEFAULT_POOL_THREADS));
+        let tree = pool
+            .run(move || LsmTree::open_with_options(&path, options))
+            .await?;
+        Ok(Self {
+            tree: Arc::new(tree),
+            pool,
+        })
+    }
+
+    /// Wrap an already opened tree, with `pool_threads` threads to run its calls
+    pub fn new(tree: LsmTree, pool_threads: usize) -> Self {
+        Self {
+            tree: Arc::new(tree),
+            pool: Arc::new(BlockingPool::new(pool_threads)),
+        }
+    }
+
+    /// The wrapped tree, for anything without an async version here
+    pub fn tree(&self) -> &Arc<LsmTree> {
+        &self.tree
+    }
+
+    pub async fn put(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let tree = self.tree.clone();
+        self.pool.run(move || tree.put(key, value)).await
+    }
+
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let tree = self.tree.clone();
+        let key = key.to_vec();
+        self.pool.run(move || tree.get(&key)).await
+    }
+
+    pub async fn delete(&self, key: Vec<u8>) -> Result<()> {
+        let tree = self.tree.clone();
+        self.pool.run(move || tree.delete(key)).await
+    }
+
+    /// Stream every key/value pair in `range`, in key order, as of this call
+    pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Scan {
+        Scan {
+            pool: self.pool.clone(),
+            start: Some(range.start_bound().cloned()),
+            end: range.end_bound().cloned(),
+            buffered: VecDeque::new(),
+            state: ScanState::Idle(Box::new(self.tree.cursor())),
+        }
+    }
+
+    /// See `LsmTree::flush`
+    pub async fn flush(&self) -> Result<()> {
+        let tree = self.tree.clone();
+        self.pool.run(move || tree.flush()).await
+    }
+
+    /// See `LsmTree::compact_all`
+    pub async fn compact(&self) -> Result<()> {
+        let tree = self.tree.clone();
+        self.pool.run(move || tree.compact_all()).await
+    }
+
+    /// Stop accepting calls (on every handle), wait for the ones already
+    /// queued to finish, and then drop the tree if this was the last handle
+    /// to it. `Scan`s still in progress end with an error.
+    pub async fn shutdown(self) -> Result<()> {
+        let threads = self.pool.close();
+        let (done_sender, done) = oneshot::channel();
+        // joining blocks, so it can't happen on the executor either
+        std::thread::spawn(move || {
+            for thread in threads {
+                let _ = thread.join();
+            }
+            drop(self.tree);
+            let _ = done_sender.send(());
+        });
+
+        done.await.map_err(|_| panicked())
+    }
+}
+
+enum ScanState {
+    // waiting to be polled for more
+    Idle(Box<Cursor>),
+    // a batch is being read on the pool
+    Reading(oneshot::Receiver<(Box<Cursor>, Result<ScanBatch>)>),
+    Done,
+}
+
+struct ScanBatch {
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    // whether the range has more after these
+    more: bool,
+}
+
+/// Stream returned by `AsyncLsmTree::scan`. It reads ahead a batch at a
+/// time on the pool, and dropping it stops the scan.
+pub struct Scan {
+    pool: Arc<BlockingPool>,
+    // taken by the first batch, which positions the cursor
+    start: Option<Bound<Vec<u8>>>,
+    end: Bound<Vec<u8>>,
+    buffered: VecDeque<(Vec<u8>, Vec<u8>)>,
+    state: ScanState,
+}
+
+impl Scan {
+    fn read_batch(
+        cursor: &mut Cursor,
+        start: Option<Bound<Vec<u8>>>,
+        end: &Bound<Vec<u8>>,
+    ) -> Result<ScanBatch> {
+        match start {
+            Some(Bound::Included(start)) => cursor.seek(&start)?,
+            Some(Bound::Excluded(start)) => {
+                cursor.seek(&start)?;
+                if cursor.key() == Some(start.as_slice()) {
+                    cursor.next()?;
+                }
+            }
+            Some(Bound::Unbounded) => cursor.seek_to_first()?,
+            None => {}
+        }
+
+        let mut entries = Vec::new();
+        while let (Some(key), Some(value)) = (cursor.key(), cursor.value()) {
+            let in_range = match end {
+                Bound::Included(end) => key <= end.as_slice(),
+                Bound::Excluded(end) => key < end.as_slice(),
+                Bound::Unbounded => true,
+            };
+            if !in_range {
+                return Ok(ScanBatch {
+                    entries,
+                    more: false,
+                });
+            }
+            if entries.len() == AsyncLsmTree::SCAN_BATCH_SIZE {
+                return Ok(ScanBatch {
+                    entries,
+                    more: true,
+                });
+            }
+            entries.push((key.to_vec(), value.to_vec()));
+            cursor.next()?;
+        }
+        Ok(ScanBatch {
+            entries,
+            more: false,
+        })
+    }
+}
+
+impl Stream for Scan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(entry) = this.buffered.pop_front() {
+                return Poll::Ready(Some(Ok(entry)));
+            }
+
+            // anything that doesn't put a state back ends the stream
+            match std::mem::replace(&mut this.state, ScanState::Done) {
+                ScanState::Done => return Poll::Ready(None),
+                ScanState::Idle(mut cursor) => {
+                    let start = this.start.take();
+                    let end = this.end.clone();
+                    let job = move || {
+                        let batch = Self::read_batch(&mut cursor, start, &end);
+                        (cursor, batch)
+                    };
+                    match this.pool.submit(job) {
+                        Ok(receiver) => this.state = ScanState::Reading(receiver),
+                        Err(e) => return Poll::Ready(Some(Err(e))),
+                    }
+                }
+                ScanState::Reading(mut receiver) => match Pin::new(&mut receiver).poll(cx) {
+                    Poll::Pending => {
+                        this.state = ScanState::Reading(receiver);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready(Err(_)) => return Poll::Ready(Some(Err(panicked()))),
+                    Poll::Ready(Ok((_, Err(e)))) => return Poll::Ready(Some(Err(e))),
+                    Poll::Ready(Ok((cursor, Ok(batch)))) => {
+                        this.buffered.extend(batch.entries);
+                        if batch.more {
+                            this.state = ScanState::Idle(cursor);
+                        }
+                    }
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        task::{Wake, Waker},
+        thread::Thread,
+    };
+
+    use super::*;
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Just enough of an executor to drive one future on the test thread
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            std::thread::park();
+        }
+    }
+
+    async fn collect(mut scan: Scan) -> Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+        while let Some(entry) = std::future::poll_fn(|cx| Pin::new(&mut scan).poll_next(cx)).await {
+            keys.push(entry?.0);
+        }
+        Ok(keys)
+    }
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key{:05}", i).into_bytes()
+    }
+
+    #[test]
+    fn calls_and_scans_run_on_the_pool() {
+        let dir = tempfile::tempdir().unwrap();
+        block_on(async {
+            let tree = AsyncLsmTree::open(dir.path(), Options::default())
+                .await
+                .unwrap();
+            for i in 0..1000 {
+                tree.put(key(i), vec![1; 40]).await.unwrap();
+            }
+            tree.delete(key(10)).await.unwrap();
+            tree.flush().await.unwrap();
+            tree.compact().await.unwrap();
+            assert_eq!(tree.get(&key(11)).await.unwrap(), Some(vec![1; 40]));
+            assert_eq!(tree.get(&key(10)).await.unwrap(), None);
+
+            // several batches' worth
+            let keys = collect(tree.scan(key(5)..key(900))).await.unwrap();
+            let expected: Vec<_> = (5..900).filter(|&i| i != 10).map(key).collect();
+            assert_eq!(keys, expected);
+            assert_eq!(collect(tree.scan(..)).await.unwrap().len(), 999);
+            let keys = collect(tree.scan((Bound::Excluded(key(5)), Bound::Included(key(7)))))
+                .await
+                .unwrap();
+            assert_eq!(keys, [key(6), key(7)]);
+            assert!(collect(tree.scan(key(7)..key(7))).await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn unpolled_calls_are_cancelled_and_shutdown_ends_the_rest() {
+        let dir = tempfile::tempdir().unwrap();
+        block_on(async {
+            let tree = AsyncLsmTree::open(dir.path(), Options::default())
+                .await
+                .unwrap();
+            tree.put(b"key".to_vec(), b"value".to_vec()).await.unwrap();
+            drop(tree.put(b"never".to_vec(), Vec::new()));
+
+            let pending = tree.scan(..);
+            let other = tree.clone();
+            tree.shutdown().await.unwrap();
+            assert!(other.get(b"key").await.is_err());
+            assert!(collect(pending).await.is_err());
+        });
+
+        let tree = LsmTree::open(dir.path()).unwrap();
+        assert_eq!(tree.get(b"never").unwrap(), None);
+        assert_eq!(tree.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn panicking_jobs_fail_without_taking_down_the_pool() {
+        let pool = BlockingPool::new(1);
+        let ran = Arc::new(AtomicUsize::new(0));
+        block_on(async {
+            let result: Result<()> = pool.run(|| panic!("job panicked")).await;
+            assert!(result.is_err());
+            let counter = ran.clone();
+            pool.run(move || Ok(counter.fetch_add(1, Ordering::Relaxed)))
+                .await
+                .unwrap();
+        });
+        assert_eq!(ran.load(Ordering::Relaxed), 1);
+        for thread in pool.close() {
+            thread.join().unwrap();
+        }
+    }
+}
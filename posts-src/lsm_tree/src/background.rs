@@ -0,0 +1,171 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::{compaction::CompactionInputs, TreeInner};
+
+/// What a background worker thread does, there's one thread for each
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Job {
+    Flush,
+    Compact,
+}
+
+impl Job {
+    pub(crate) const ALL: [Job; 2] = [Job::Flush, Job::Compact];
+
+    pub(crate) fn thread_name(&self) -> &'static str {
+        match self {
+            Job::Flush => "lsm-flush",
+            Job::Compact => "lsm-compaction",
+        }
+    }
+}
+
+/// Body of a background worker thread, which either flushes immutable
+/// memtables (oldest first) or compacts once level 0 has piled up (into
+/// the level 1 tables sharing keys with it).
+/// Flushes get a thread of their own so they never wait behind a
+/// compaction, which can take long (all the more so rate limited):
+/// immutable memtables are what stops writes soonest.
+///
+/// Stalled writers and `LsmTree::flush` are woken after every job. The
+/// first failure is recorded and ends both workers, after which writes
+/// error out instead of waiting on them forever.
+pub(crate) fn run(inner: Arc<TreeInner>, job: Job) {
+    loop {
+        {
+            let mut background = inner.background.lock().unwrap();
+            loop {
+                if background.shutdown || background.error.is_some() {
+                    return;
+                }
+                if has_work(&inner, job) {
+                    break;
+                }
+                background = inner.background_cv.wait(background).unwrap();
+            }
+        }
+
+        let result = match job {
+            Job::Flush => inner.flush_oldest_immutable(),
+            Job::Compact => compact_level0(&inner),
+        };
+
+        let mut background = inner.background.lock().unwrap();
+        if let Err(e) = result {
+            background.error.get_or_insert(format!("{:#}", e));
+        }
+        inner.background_cv.notify_all();
+    }
+}
+
+fn has_work(inner: &TreeInner, job: Job) -> bool {
+    let state = inner.state.read().unwrap();
+    match job {
+        Job::Flush => !state.immutables.is_empty(),
+        Job::Compact => state.l0_tables().count() >= inner.options.l0_compaction_trigger,
+    }
+}
+
+fn compact_level0(inner: &TreeInner) -> Result<()> {
+    let _compaction = inner.compaction_lock.lock().unwrap();
+    // a manual compaction may have beaten us to it
+    if has_work(inner, Job::Compact) {
+        inner.compact_locked(CompactionInputs::Level0)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::{LsmTree, Options, RateLimiter};
+
+    #[test]
+    fn flushes_go_ahead_of_a_throttled_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            l0_compaction_trigger: 100,
+            l0_slowdown_writes_trigger: 100,
+            l0_stop_writes_trigger: 100,
+            ..Options::default()
+        };
+        let tree = LsmTree::open_with_options(dir.path(), options).unwrap();
+        for table in 0..8 {
+            for i in 0..500 {
+                let key = format!("{}-{:04}", table, i).into_bytes();
+                tree.put(key, vec![table as u8; 200]).unwrap();
+            }
+            tree.flush().unwrap();
+        }
+        drop(tree);
+
+        // reopened with the default trigger, level 0 gets compacted right
+        // away, at ~8 seconds for the ~1 MB there is
+        let rate_limiter = std::sync::Arc::new(RateLimiter::new(128 * 1024));
+        let options = Options {
+            rate_limiter: Some(rate_limiter.clone()),
+            ..Options::default()
+        };
+        let tree = LsmTree::open_with_options(dir.path(), options).unwrap();
+        let started = Instant::now();
+        while tree.stats().unwrap().compaction.is_none() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        tree.put(b"new".to_vec(), b"value".to_vec()).unwrap();
+        tree.flush().unwrap();
+        assert!(tree.stats().unwrap().compaction.is_some());
+        assert!(started.elapsed() < Duration::from_secs(4));
+
+        rate_limiter.set_bytes_per_second(0);
+        assert_eq!(tree.get(b"new").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(tree.get(b"7-0499").unwrap(), Some(vec![7; 200]));
+    }
+
+    #[test]
+    fn level0_is_merged_into_the_level1_tables_it_overlaps() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            target_file_size: 16 * 1024,
+            ..Options::default()
+        };
+        let tree = LsmTree::open_with_options(dir.path(), options).unwrap();
+        let key = |i: u32| format!("key{:05}", i).into_bytes();
+        for i in 0..1000 {
+            tree.put(key(i), vec![1; 100]).unwrap();
+        }
+        tree.flush().unwrap();
+        tree.compact_all().unwrap();
+        let level1 = tree.sstables();
+        assert!(level1.len() >= 6);
+
+        // enough flushes to set the worker off, all within the second table
+        let first = &level1[1].properties.smallest_key;
+        let first: u32 = std::str::from_utf8(&first[3..]).unwrap().parse().unwrap();
+        for table in 0..4 {
+            tree.put(key(first + table), vec![2; 100]).unwrap();
+            tree.flush().unwrap();
+        }
+        let started = Instant::now();
+        while tree.sstables().iter().any(|table| table.level == 0) {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let ids = |tables: &[crate::SSTableInfo]| -> Vec<u32> {
+            tables.iter().map(|table| table.id).collect()
+        };
+        let after = tree.sstables();
+        assert_eq!(after.len(), level1.len());
+        let mut untouched = ids(&level1);
+        untouched.remove(1);
+        assert!(untouched.iter().all(|id| ids(&after).contains(id)));
+        assert!(!ids(&after).contains(&level1[1].id));
+        assert_eq!(tree.get(&key(first + 3)).unwrap(), Some(vec![2; 100]));
+        assert_eq!(tree.get(&key(first + 4)).unwrap(), Some(vec![1; 100]));
+    }
+}
@@ -0,0 +1,1204 @@
+//! Serves an `LsmTree` over HTTP/1.1, one thread per connection:
+//!
+//! - `GET`, `PUT` and `DELETE /kv/{key}`: the value is the raw request or
+//!   response body
+//! - `GET /scan?prefix=&start=&end=&limit=`: entries in key order, with
+//!   `start` inclusive and `end` exclusive
+//! - `POST /batch`: a JSON array of `{"op": "put", "key", "value"}`,
+//!   `{"op": "delete", "key"}` and `{"op": "delete_range", "start", "end"}`,
+//!   written atomically
+//! - `POST /admin/flush`, `POST /admin/compact` and `GET /stats`
+//!
+//! Keys in the path and query are percent-encoded bytes. Keys and values
+//! inside JSON are base64 (standard alphabet), so either can be any bytes.
+//!
+//! Usage: `http_server <data dir> [listen address]`. The address defaults
+//! to `127.0.0.1:8080`, and once listening the actual one is printed (handy
+//! with port 0).
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use lsm_tree::{LsmError, LsmTree, Options, Stats, WriteBatch};
+
+const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:8080";
+// the request line and each header
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+const MAX_HEADERS: usize = 100;
+// room in a `/batch` body for the keys and JSON around its values
+const MAX_BATCH_OVERHEAD: usize = 1024 * 1024;
+const DEFAULT_SCAN_LIMIT: usize = 100;
+const MAX_SCAN_LIMIT: usize = 10_000;
+// nesting allowed in a JSON body
+const MAX_JSON_DEPTH: usize = 32;
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let Some(path) = args.next() else {
+        bail!("Usage: http_server <data dir> [listen address]");
+    };
+    let address = args
+        .next()
+        .unwrap_or_else(|| DEFAULT_LISTEN_ADDRESS.to_string());
+
+    let tree = LsmTree::open(Path::new(&path))?;
+    let listener =
+        TcpListener::bind(&address).with_context(|| format!("Failed to listen on {}", address))?;
+    println!("Listening on {}", listener.local_addr()?);
+    run(listener, tree)
+}
+
+/// Serve `tree` to every client `listener` accepts, each on its own thread
+fn run(listener: TcpListener, tree: LsmTree) -> Result<()> {
+    let tree = Arc::new(tree);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let tree = tree.clone();
+        std::thread::spawn(move || {
+            let peer = stream.peer_addr().ok();
+            if let Err(e) = serve(&tree, stream) {
+                eprintln!("Connection from {:?} failed: {:#}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Largest body a request to `path` may have. A value is the whole body
+/// of a `/kv/` PUT, so that's as large as the tree takes one. A batch can
+/// have one that large too, base64 encoded, and bigger batches have to be
+/// split up. Nothing else reads its body.
+fn max_body_length(options: &Options, path: &str) -> usize {
+    let max_value_size = options.max_value_size();
+    if path.starts_with("/kv/") {
+        max_value_size
+    } else if path == "/batch" {
+        max_value_size.div_ceil(3) * 4 + MAX_BATCH_OVERHEAD
+    } else {
+        0
+    }
+}
+
+struct Request {
+    method: String,
+    // still percent-encoded
+    path: String,
+    query: Vec<(String, Vec<u8>)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn query(&self, name: &str) -> Option<&[u8]> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_slice())
+    }
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+    // for a 405, the methods the path does take
+    allow: Option<&'static str>,
+}
+
+impl Response {
+    fn no_content() -> Self {
+        Self {
+            status: 204,
+            content_type: "",
+            body: Vec::new(),
+            allow: None,
+        }
+    }
+
+    fn bytes(body: Vec<u8>) -> Self {
+        Self {
+            status: 200,
+            content_type: "application/octet-stream",
+            body,
+            allow: None,
+        }
+    }
+
+    fn json(status: u16, body: String) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.into_bytes(),
+            allow: None,
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self::json(status, format!("{{\"error\":{}}}", json_string(message)))
+    }
+
+    fn write(&self, out: &mut impl Write, keep_alive: bool) -> io::Result<()> {
+        write!(out, "HTTP/1.1 {} {}\r\n", self.status, reason(self.status))?;
+        if self.status != 204 {
+            write!(out, "Content-Length: {}\r\n", self.body.len())?;
+            write!(out, "Content-Type: {}\r\n", self.content_type)?;
+        }
+        if let Some(allow) = self.allow {
+            write!(out, "Allow: {}\r\n", allow)?;
+        }
+        if !keep_alive {
+            out.write_all(b"Connection: close\r\n")?;
+        }
+        out.write_all(b"\r\n")?;
+        out.write_all(&self.body)
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        _ => "",
+    }
+}
+
+/// Why a request failed: either the client's fault, with the status to
+/// answer with, or the tree's
+enum HttpError {
+    Status(u16, String),
+    Internal(anyhow::Error),
+}
+
+impl From<anyhow::Error> for HttpError {
+    /// Writes the tree turned down are the client's fault
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast_ref::<LsmError>() {
+            Some(LsmError::KeyTooLarge(_) | LsmError::ValueTooLarge(_)) => {
+                HttpError::Status(413, e.to_string())
+            }
+            Some(LsmError::InvalidWrite(_)) => bad_request(e.to_string()),
+            _ => HttpError::Internal(e),
+        }
+    }
+}
+
+fn bad_request(message: impl Into<String>) -> HttpError {
+    HttpError::Status(400, message.into())
+}
+
+fn serve(tree: &LsmTree, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let (request, keep_alive) = match read_request(&mut reader, &mut writer, tree.options()) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(HttpError::Status(status, message)) => {
+                // the stream can't be trusted past a malformed request
+                Response::error(status, &message).write(&mut writer, false)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(HttpError::Internal(e)) => return Err(e),
+        };
+
+        let response = match route(tree, &request) {
+            Ok(response) => response,
+            Err(HttpError::Status(status, message)) => Response::error(status, &message),
+            Err(HttpError::Internal(e)) => Response::error(500, &format!("{:#}", e)),
+        };
+        response.write(&mut writer, keep_alive)?;
+        // pipelined requests get their responses in one go
+        if !keep_alive || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// The next request, and whether the connection stays open after it.
+/// `None` once the client hangs up between requests.
+fn read_request(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    options: &Options,
+) -> Result<Option<(Request, bool)>, HttpError> {
+    let Some(request_line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(bad_request("malformed request line"));
+    };
+    let mut keep_alive = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Err(HttpError::Status(501, "unsupported HTTP version".into())),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let max_body_length = max_body_length(options, path);
+
+    let mut content_length = None;
+    let mut expect_continue = false;
+    let mut headers = 0;
+    loop {
+        let line =
+            read_line(reader)?.ok_or_else(|| bad_request("connection closed mid-request"))?;
+        if line.is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return Err(HttpError::Status(431, "too many headers".into()));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| bad_request("malformed header"))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                let length: usize = value
+                    .parse()
+                    .map_err(|_| bad_request("invalid Content-Length"))?;
+                if length > max_body_length {
+                    return Err(HttpError::Status(413, "body too large".into()));
+                }
+                content_length = Some(length);
+            }
+            "transfer-encoding" => {
+                return Err(HttpError::Status(
+                    411,
+                    "chunked bodies aren't supported, send a Content-Length".into(),
+                ));
+            }
+            "connection" => {
+                let value = value.to_ascii_lowercase();
+                if value.contains("close") {
+                    keep_alive = false;
+                } else if value.contains("keep-alive") {
+                    keep_alive = true;
+                }
+            }
+            "expect" => expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            _ => {}
+        }
+    }
+
+    let mut body = vec![0u8; content_length.unwrap_or(0)];
+    if !body.is_empty() {
+        // clients sending large bodies may wait for this before sending them
+        if expect_continue {
+            writer
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .and_then(|()| writer.flush())
+                .context("Failed to send 100 Continue")?;
+        }
+        reader
+            .read_exact(&mut body)
+            .map_err(|_| bad_request("connection closed mid-body"))?;
+    }
+
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let name = String::from_utf8(percent_decode(name, true)?)
+                .map_err(|_| bad_request("query parameter names must be UTF-8"))?;
+            Ok((name, percent_decode(value, true)?))
+        })
+        .collect::<Result<_, HttpError>>()?;
+
+    let request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        body,
+    };
+    Ok(Some((request, keep_alive)))
+}
+
+/// One line without its line ending, `None` at EOF
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>, HttpError> {
+    let mut line = Vec::new();
+    let read = reader
+        .take(MAX_LINE_LENGTH as u64)
+        .read_until(b'\n', &mut line)
+        .context("Failed to read from the connection")?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(HttpError::Status(431, "line too long or cut off".into()));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| bad_request("request line and headers must be UTF-8"))
+}
+
+fn route(tree: &LsmTree, request: &Request) -> Result<Response, HttpError> {
+    let method = request.method.as_str();
+    let not_allowed = |allow: &'static str| -> Result<Response, HttpError> {
+        Ok(Response {
+            allow: Some(allow),
+            ..Response::error(405, "method not allowed")
+        })
+    };
+
+    if let Some(key) = request.path.strip_prefix("/kv/") {
+        let key = percent_decode(key, false)?;
+        if key.is_empty() {
+            return Err(bad_request("empty key"));
+        }
+        return match method {
+            "GET" => match tree.get(&key)? {
+                Some(value) => Ok(Response::bytes(value)),
+                None => Err(HttpError::Status(404, "key not found".into())),
+            },
+            "PUT" => {
+                tree.put(key, request.body.clone())?;
+                Ok(Response::no_content())
+            }
+            "DELETE" => {
+                tree.delete(key)?;
+                Ok(Response::no_content())
+            }
+            _ => not_allowed("GET, PUT, DELETE"),
+        };
+    }
+
+    match (request.path.as_str(), method) {
+        ("/scan", "GET") => scan(tree, request),
+        ("/batch", "POST") => {
+            tree.write(parse_batch(&request.body)?)?;
+            Ok(Response::no_content())
+        }
+        ("/admin/flush", "POST") => {
+            tree.flush()?;
+            Ok(Response::no_content())
+        }
+        ("/admin/compact", "POST") => {
+            tree.compact_all()?;
+            Ok(Response::no_content())
+        }
+        ("/stats", "GET") => Ok(Response::json(200, stats_json(&tree.stats()?))),
+        ("/scan" | "/stats", _) => not_allowed("GET"),
+        ("/batch" | "/admin/flush" | "/admin/compact", _) => not_allowed("POST"),
+        _ => Err(HttpError::Status(404, "no such endpoint".into())),
+    }
+}
+
+/// `{"entries": [{"key", "value"}, ...], "next": key or null}`, where `next`
+/// is the `start` to pass to carry on if `limit` cut the scan short
+fn scan(tree: &LsmTree, request: &Request) -> Result<Response, HttpError> {
+    let limit = match request.query("limit") {
+        Some(limit) => std::str::from_utf8(limit)
+            .ok()
+            .and_then(|limit| limit.parse().ok())
+            .filter(|limit| (1..=MAX_SCAN_LIMIT).contains(limit))
+            .ok_or_else(|| bad_request(format!("limit must be 1 to {}", MAX_SCAN_LIMIT)))?,
+        None => DEFAULT_SCAN_LIMIT,
+    };
+
+    // the prefix is just another range, narrowed further by start and end
+    let prefix = request.query("prefix").unwrap_or_default();
+    let mut start = prefix.to_vec();
+    let mut end = prefix_end(prefix);
+    if let Some(scan_start) = request.query("start") {
+        start = start.max(scan_start.to_vec());
+    }
+    if let Some(scan_end) = request.query("end") {
+        end = Some(match end {
+            Some(end) => end.min(scan_end.to_vec()),
+            None => scan_end.to_vec(),
+        });
+    }
+
+    let mut cursor = tree.cursor();
+    cursor.seek(&start)?;
+    let mut body = String::from("{\"entries\":[");
+    let mut count = 0;
+    let mut next = None;
+    while let (Some(key), Some(value)) = (cursor.key(), cursor.value()) {
+        if end.as_ref().is_some_and(|end| key >= end.as_slice()) {
+            break;
+        }
+        if count == limit {
+            next = Some(key.to_vec());
+            break;
+        }
+        if count > 0 {
+            body.push(',');
+        }
+        let _ = write!(
+            body,
+            "{{\"key\":\"{}\",\"value\":\"{}\"}}",
+            base64_encode(key),
+            base64_encode(value)
+        );
+        count += 1;
+        cursor.next()?;
+    }
+    match next {
+        Some(next) => {
+            let _ = write!(body, "],\"next\":\"{}\"}}", base64_encode(&next));
+        }
+        None => body.push_str("],\"next\":null}"),
+    }
+    Ok(Response::json(200, body))
+}
+
+/// The first key past every key starting with `prefix`, `None` if there
+/// isn't one (the prefix is empty or all `0xff`)
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+fn parse_batch(body: &[u8]) -> Result<WriteBatch, HttpError> {
+    let json = Json::parse(body).map_err(|e| bad_request(format!("invalid JSON: {:#}", e)))?;
+    let Json::Array(ops) = json else {
+        return Err(bad_request("the batch must be a JSON array of ops"));
+    };
+
+    let mut batch = WriteBatch::new();
+    for (i, op) in ops.iter().enumerate() {
+        let field = |name: &str| -> Result<Vec<u8>, HttpError> {
+            match op.get(name) {
+                Some(Json::String(encoded)) => base64_decode(encoded).map_err(|e| {
+                    bad_request(format!("op {}: \"{}\" isn't base64: {:#}", i, name, e))
+                }),
+                _ => Err(bad_request(format!(
+                    "op {}: expected a string \"{}\"",
+                    i, name
+                ))),
+            }
+        };
+        match op.get("op") {
+            Some(Json::String(kind)) if kind == "put" => batch.put(field("key")?, field("value")?),
+            Some(Json::String(kind)) if kind == "delete" => batch.delete(field("key")?),
+            Some(Json::String(kind)) if kind == "delete_range" => {
+                batch.delete_range(field("start")?, field("end")?)
+            }
+            _ => {
+                return Err(bad_request(format!(
+                    "op {}: \"op\" must be \"put\", \"delete\" or \"delete_range\"",
+                    i
+                )))
+            }
+        }
+    }
+    Ok(batch)
+}
+
+fn stats_json(stats: &Stats) -> String {
+    let compaction = match &stats.compaction {
+        Some(progress) => format!(
+            "{{\"input_tables\":{},\"input_bytes\":{},\"merged_tables\":{},\"merged_bytes\":{}}}",
+            progress.input_tables,
+            progress.input_bytes,
+            progress.merged_tables,
+            progress.merged_bytes
+        ),
+        None => "null".to_string(),
+    };
+    let fields = [
+        ("sstables", stats.sstables.to_string()),
+        ("l0_sstables", stats.l0_sstables.to_string()),
+        ("immutable_memtables", stats.immutable_memtables.to_string()),
+        (
+            "pending_compaction_bytes",
+            stats.pending_compaction_bytes.to_string(),
+        ),
+        (
+            "write_stall",
+            json_string(&format!("{:?}", stats.write_stall).to_lowercase()),
+        ),
+        ("stall_time_ms", stats.stall_time.as_millis().to_string()),
+        ("delayed_writes", stats.delayed_writes.to_string()),
+        ("stopped_writes", stats.stopped_writes.to_string()),
+        ("data_blocks", stats.data_blocks.to_string()),
+        ("uncompressed_bytes", stats.uncompressed_bytes.to_string()),
+        ("compressed_bytes", stats.compressed_bytes.to_string()),
+        ("compression_ratio", stats.compression_ratio().to_string()),
+        (
+            "block_cache",
+            format!(
+                "{{\"hits\":{},\"misses\":{}}}",
+                stats.block_cache.hits, stats.block_cache.misses
+            ),
+        ),
+        (
+            "table_cache",
+            format!(
+                "{{\"hits\":{},\"misses\":{}}}",
+                stats.table_cache.hits, stats.table_cache.misses
+            ),
+        ),
+        ("compaction", compaction),
+    ];
+
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(name, value)| format!("\"{}\":{}", name, value))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+/// Percent-decode part of a URL. In a query string `+` is a space too.
+fn percent_decode(encoded: &str, query: bool) -> Result<Vec<u8>, HttpError> {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let byte = bytes
+                    .get(i + 1..i + 3)
+                    // `from_str_radix` alone would take a sign, as in "%+f"
+                    .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| bad_request("invalid percent-encoding"))?;
+                decoded.push(byte);
+                i += 3;
+            }
+            b'+' if query => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Ok(decoded)
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | (byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(group >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Padding is optional
+fn base64_decode(encoded: &str) -> Result<Vec<u8>> {
+    let encoded = encoded.trim_end_matches('=').as_bytes();
+    if encoded.len() % 4 == 1 {
+        bail!("wrong length");
+    }
+
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    for chunk in encoded.chunks(4) {
+        let mut group = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let digit = BASE64_ALPHABET
+                .iter()
+                .position(|&a| a == c)
+                .with_context(|| format!("unexpected character '{}'", c as char))?;
+            group |= (digit as u32) << (18 - 6 * i);
+        }
+        // n characters carry n - 1 whole bytes
+        for i in 0..chunk.len() - 1 {
+            decoded.push((group >> (16 - 8 * i)) as u8);
+        }
+    }
+    Ok(decoded)
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Just enough JSON to read a batch
+#[derive(Debug)]
+enum Json {
+    // null, a boolean or a number, none of which a batch uses
+    Scalar,
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(input: &[u8]) -> Result<Json> {
+        let mut parser = JsonParser { input, position: 0 };
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+        if parser.position < input.len() {
+            bail!("trailing characters at {}", parser.position);
+        }
+        Ok(value)
+    }
+
+    /// A field of an object
+    fn get(&self, name: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
+struct JsonParser<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+
+impl JsonParser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self
+            .input
+            .get(self.position)
+            .is_some_and(|c| matches!(c, b' ' | b'\t' | b'\n' | b'\r'))
+        {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.input.get(self.position).copied()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<()> {
+        if self.peek() != Some(c) {
+            bail!("expected '{}' at {}", c as char, self.position);
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, literal: &[u8]) -> Result<Json> {
+        if !self.input[self.position..].starts_with(literal) {
+            bail!("unexpected token at {}", self.position);
+        }
+        self.position += literal.len();
+        Ok(Json::Scalar)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json> {
+        if depth > MAX_JSON_DEPTH {
+            bail!("nested too deeply");
+        }
+        match self.peek().context("unexpected end of input")? {
+            b'n' => self.literal(b"null"),
+            b't' => self.literal(b"true"),
+            b'f' => self.literal(b"false"),
+            b'"' => Ok(Json::String(self.string()?)),
+            b'[' => {
+                self.position += 1;
+                let mut items = Vec::new();
+                if self.peek() == Some(b']') {
+                    self.position += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value(depth + 1)?);
+                    match self.peek() {
+                        Some(b',') => self.position += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b']')?;
+                Ok(Json::Array(items))
+            }
+            b'{' => {
+                self.position += 1;
+                let mut fields = Vec::new();
+                if self.peek() == Some(b'}') {
+                    self.position += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    if self.peek() != Some(b'"') {
+                        bail!("expected a field name at {}", self.position);
+                    }
+                    let name = self.string()?;
+                    self.expect(b':')?;
+                    fields.push((name, self.value(depth + 1)?));
+                    match self.peek() {
+                        Some(b',') => self.position += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b'}')?;
+                Ok(Json::Object(fields))
+            }
+            _ => self.number(),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json> {
+        let start = self.position;
+        while self
+            .input
+            .get(self.position)
+            .is_some_and(|c| matches!(c, b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9'))
+        {
+            self.position += 1;
+        }
+        std::str::from_utf8(&self.input[start..self.position])
+            .ok()
+            .and_then(|number| number.parse::<f64>().ok())
+            .map(|_| Json::Scalar)
+            .with_context(|| format!("unexpected token at {}", start))
+    }
+
+    /// A string, starting at its opening quote
+    fn string(&mut self) -> Result<String> {
+        self.position += 1;
+        let mut string = Vec::new();
+        loop {
+            let c = *self
+                .input
+                .get(self.position)
+                .context("unterminated string")?;
+            self.position += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escape = *self
+                        .input
+                        .get(self.position)
+                        .context("unterminated string")?;
+                    self.position += 1;
+                    let decoded = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => bail!("invalid escape at {}", self.position - 1),
+                    };
+                    let mut buffer = [0; 4];
+                    string.extend_from_slice(decoded.encode_utf8(&mut buffer).as_bytes());
+                }
+                c if c < 0x20 => bail!("control character in string at {}", self.position - 1),
+                c => string.push(c),
+            }
+        }
+        String::from_utf8(string).context("string isn't UTF-8")
+    }
+
+    /// The character of a `\u` escape (just past the `u`), which may be
+    /// the first half of a surrogate pair
+    fn unicode_escape(&mut self) -> Result<char> {
+        let high = self.hex4()?;
+        if !(0xd800..0xdc00).contains(&high) {
+            return char::from_u32(high).context("invalid \\u escape");
+        }
+        if !self.input[self.position..].starts_with(b"\\u") {
+            bail!("unpaired surrogate at {}", self.position);
+        }
+        self.position += 2;
+        let low = self.hex4()?;
+        if !(0xdc00..0xe000).contains(&low) {
+            bail!("unpaired surrogate at {}", self.position);
+        }
+        char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00))
+            .context("invalid \\u escape")
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let digits = self
+            .input
+            .get(self.position..self.position + 4)
+            // as in `percent_decode`, keeping out signs
+            .filter(|digits| digits.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .with_context(|| format!("invalid \\u escape at {}", self.position))?;
+        self.position += 4;
+        Ok(digits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+    struct Client {
+        address: SocketAddr,
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        /// Start a server for a tree in `dir` and connect to it
+        fn start(dir: &Path, options: Options) -> Self {
+            let tree = LsmTree::open_with_options(dir, options).unwrap();
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            std::thread::spawn(move || run(listener, tree));
+            Self::connect(address)
+        }
+
+        fn connect(address: SocketAddr) -> Self {
+            let writer = TcpStream::connect(address).unwrap();
+            Self {
+                address,
+                reader: BufReader::new(writer.try_clone().unwrap()),
+                writer,
+            }
+        }
+
+        fn request(&mut self, method: &str, target: &str, body: &[u8]) -> (u16, Vec<u8>) {
+            let head = format!(
+                "{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+                method,
+                target,
+                body.len()
+            );
+            self.writer.write_all(head.as_bytes()).unwrap();
+            self.writer.write_all(body).unwrap();
+            self.read_response()
+        }
+
+        /// Status and body, reconnecting if the server closed the connection
+        fn read_response(&mut self) -> (u16, Vec<u8>) {
+            let mut status_line = String::new();
+            self.reader.read_line(&mut status_line).unwrap();
+            let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+            let mut content_length = 0;
+            let mut close = false;
+            loop {
+                let mut header = String::new();
+                self.reader.read_line(&mut header).unwrap();
+                let header = header.trim_end().to_ascii_lowercase();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some(length) = header.strip_prefix("content-length: ") {
+                    content_length = length.parse().unwrap();
+                }
+                close |= header == "connection: close";
+            }
+            let mut body = vec![0; content_length];
+            self.reader.read_exact(&mut body).unwrap();
+            if close {
+                *self = Self::connect(self.address);
+            }
+            (status, body)
+        }
+
+        fn get(&mut self, target: &str) -> (u16, Vec<u8>) {
+            self.request("GET", target, b"")
+        }
+
+        /// A `/scan`'s entries and `next`
+        fn scan(&mut self, query: &str) -> (Entries, Option<Vec<u8>>) {
+            let (status, body) = self.get(&format!("/scan?{}", query));
+            assert_eq!(status, 200, "{}", String::from_utf8_lossy(&body));
+            let json = Json::parse(&body).unwrap();
+            let decode = |value: Option<&Json>| match value {
+                Some(Json::String(encoded)) => base64_decode(encoded).unwrap(),
+                other => panic!("expected a string, got {:?}", other),
+            };
+            let Some(Json::Array(entries)) = json.get("entries") else {
+                panic!("no entries in {:?}", json);
+            };
+            let entries = entries
+                .iter()
+                .map(|entry| (decode(entry.get("key")), decode(entry.get("value"))))
+                .collect();
+            let next = match json.get("next") {
+                Some(Json::Scalar) => None,
+                next => Some(decode(next)),
+            };
+            (entries, next)
+        }
+    }
+
+    fn put_op(key: &[u8], value: &[u8]) -> String {
+        format!(
+            "{{\"op\":\"put\",\"key\":\"{}\",\"value\":\"{}\"}}",
+            base64_encode(key),
+            base64_encode(value)
+        )
+    }
+
+    #[test]
+    fn keys_and_values_are_any_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = Client::start(dir.path(), Options::default());
+
+        let value = [0, 255, b'\r', b'\n', 128];
+        assert_eq!(client.request("PUT", "/kv/%00%FF%2fkey", &value).0, 204);
+        assert_eq!(client.get("/kv/%00%ff%2Fkey"), (200, value.to_vec()));
+        // `+` is only a space in the query string
+        assert_eq!(client.request("PUT", "/kv/a+b", b"plus").0, 204);
+        assert_eq!(client.get("/kv/a%2Bb"), (200, b"plus".to_vec()));
+        assert_eq!(client.get("/kv/a%20b").0, 404);
+        assert_eq!(client.request("PUT", "/kv/empty", b"").0, 204);
+        assert_eq!(client.get("/kv/empty"), (200, Vec::new()));
+
+        assert_eq!(client.request("DELETE", "/kv/a+b", b"").0, 204);
+        assert_eq!(client.get("/kv/a+b").0, 404);
+        assert_eq!(client.get("/kv/").0, 400);
+        assert_eq!(client.request("POST", "/kv/key", b"").0, 405);
+        assert_eq!(client.get("/nowhere").0, 404);
+
+        let (entries, next) = client.scan("");
+        assert_eq!(next, None);
+        assert_eq!(
+            entries,
+            [
+                (b"\0\xff/key".to_vec(), value.to_vec()),
+                (b"empty".to_vec(), Vec::new())
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_percent_encoding_is_rejected() {
+        for encoded in ["%+f", "%-1", "%4", "%zz", "%%41", "%4\u{e9}"] {
+            assert!(percent_decode(encoded, false).is_err(), "{}", encoded);
+        }
+        assert_eq!(
+            percent_decode("a+%2B%41", true).ok(),
+            Some(b"a +A".to_vec())
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = Client::start(dir.path(), Options::default());
+        assert_eq!(client.request("PUT", "/kv/%+f", b"value").0, 400);
+        assert_eq!(client.get("/scan?prefix=%+f").0, 400);
+        assert!(client.scan("").0.is_empty());
+    }
+
+    #[test]
+    fn scans_take_ranges_prefixes_and_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = Client::start(dir.path(), Options::default());
+        let ops: Vec<_> = ["a1", "a2", "a3", "b1", "b2", "c1"]
+            .iter()
+            .map(|key| put_op(key.as_bytes(), key.to_uppercase().as_bytes()))
+            .collect();
+        let batch = format!("[{}]", ops.join(","));
+        assert_eq!(client.request("POST", "/batch", batch.as_bytes()).0, 204);
+
+        let keys = |entries: Entries| -> Vec<String> {
+            entries
+                .into_iter()
+                .map(|(key, _)| String::from_utf8(key).unwrap())
+                .collect()
+        };
+        let (entries, next) = client.scan("prefix=b");
+        assert_eq!(entries[0], (b"b1".to_vec(), b"B1".to_vec()));
+        assert_eq!(
+            (keys(entries), next),
+            (vec!["b1".into(), "b2".into()], None)
+        );
+        let (entries, next) = client.scan("start=a2&end=c1");
+        assert_eq!(keys(entries), ["a2", "a3", "b1", "b2"]);
+        assert_eq!(next, None);
+        let (entries, next) = client.scan("prefix=a&start=a2&end=z");
+        assert_eq!(keys(entries), ["a2", "a3"]);
+        assert_eq!(next, None);
+
+        // paging through with `next`
+        let (entries, next) = client.scan("limit=4");
+        assert_eq!(keys(entries), ["a1", "a2", "a3", "b1"]);
+        assert_eq!(next.as_deref(), Some(&b"b2"[..]));
+        let (entries, next) = client.scan("limit=4&start=b2");
+        assert_eq!(keys(entries), ["b2", "c1"]);
+        assert_eq!(next, None);
+        let (entries, next) = client.scan("limit=2&prefix=a");
+        assert_eq!(keys(entries), ["a1", "a2"]);
+        assert_eq!(next.as_deref(), Some(&b"a3"[..]));
+
+        assert_eq!(client.get("/scan?limit=0").0, 400);
+        assert_eq!(client.get("/scan?limit=10001").0, 400);
+        assert_eq!(client.get("/scan?limit=-1").0, 400);
+        assert_eq!(client.get("/scan?limit=10000").0, 200);
+        assert_eq!(client.request("POST", "/scan", b"").0, 405);
+    }
+
+    #[test]
+    fn batches_are_atomic_and_validated() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = Client::start(dir.path(), Options::default());
+        let batch = format!(
+            "[{}, {}, {}, {{\"op\":\"delete\",\"key\":\"{}\"}}, \
+             {{\"op\":\"delete_range\",\"start\":\"{}\",\"end\":\"{}\"}}]",
+            put_op(b"a", b"1"),
+            put_op(b"b", b"2"),
+            put_op(b"c", b"3"),
+            base64_encode(b"a"),
+            base64_encode(b"b"),
+            base64_encode(b"c")
+        );
+        assert_eq!(client.request("POST", "/batch", batch.as_bytes()).0, 204);
+        let (entries, _) = client.scan("");
+        assert_eq!(entries, [(b"c".to_vec(), b"3".to_vec())]);
+
+        let valid = put_op(b"new", b"value");
+        for malformed in [
+            "".to_string(),
+            "not json".into(),
+            format!("{} trailing", valid),
+            format!("[{}", valid),
+            valid.clone(),
+            format!("[{}, {{\"op\":\"merge\",\"key\":\"YQ==\"}}]", valid),
+            format!("[{}, {{\"op\":\"put\",\"key\":\"YQ==\"}}]", valid),
+            format!("[{}, {{\"op\":\"delete\",\"key\":\"Y\"}}]", valid),
+            format!("[{}, {{\"op\":\"delete\",\"key\":\"Y*==\"}}]", valid),
+            format!("[{}, {{\"op\":\"delete\",\"key\":1}}]", valid),
+            format!("[{}, {{\"op\":\"d\\u+065lete\",\"key\":\"YQ==\"}}]", valid),
+            format!("[{}, {{\"op\":\"delete\",\"key\":\"\\ud800\"}}]", valid),
+            format!("{}{}", "[".repeat(100), "]".repeat(100)),
+        ] {
+            let (status, body) = client.request("POST", "/batch", malformed.as_bytes());
+            assert_eq!(
+                status,
+                400,
+                "{}: {}",
+                malformed,
+                String::from_utf8_lossy(&body)
+            );
+        }
+        // the tree's own checks fail the whole batch too
+        let batch = format!(
+            "[{}, {{\"op\":\"delete_range\",\"start\":\"{}\",\"end\":\"{}\"}}]",
+            valid,
+            base64_encode(b"z"),
+            base64_encode(b"a")
+        );
+        assert_eq!(client.request("POST", "/batch", batch.as_bytes()).0, 400);
+        let batch = format!("[{}, {}]", valid, put_op(&[b'k'; 65 * 1024], b"value"));
+        assert_eq!(client.request("POST", "/batch", batch.as_bytes()).0, 413);
+        assert_eq!(client.get("/kv/new").0, 404);
+        assert_eq!(client.get("/batch").0, 405);
+    }
+
+    #[test]
+    fn bodies_are_limited_by_what_the_tree_takes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = Client::start(dir.path(), Options::default());
+        let value = vec![b'v'; 64 * 1024];
+        assert_eq!(client.request("PUT", "/kv/max", &value).0, 204);
+        // turned away from the headers alone, so the body is never sent
+        client
+            .writer
+            .write_all(b"PUT /kv/key HTTP/1.1\r\nContent-Length: 65537\r\n\r\n")
+            .unwrap();
+        assert_eq!(client.read_response().0, 413);
+        assert_eq!(client.request("POST", "/admin/flush", b"{}").0, 413);
+        drop(client);
+
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            blob_threshold: Some(1024),
+            ..Options::default()
+        };
+        let mut client = Client::start(dir.path(), options);
+        let value = vec![b'v'; 1024 * 1024];
+        assert_eq!(client.request("PUT", "/kv/big", &value).0, 204);
+        assert_eq!(client.get("/kv/big"), (200, value.clone()));
+        let batch = format!("[{}]", put_op(b"batched", &value));
+        assert_eq!(client.request("POST", "/batch", batch.as_bytes()).0, 204);
+        assert_eq!(client.get("/kv/batched"), (200, value));
+        client
+            .writer
+            .write_all(b"POST /batch HTTP/1.1\r\nContent-Length: 100000000\r\n\r\n")
+            .unwrap();
+        assert_eq!(client.read_response().0, 413);
+    }
+
+    #[test]
+    fn admin_endpoints_and_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = Client::start(dir.path(), Options::default());
+        client.request("PUT", "/kv/key", b"value");
+        assert_eq!(client.request("POST", "/admin/flush", b"").0, 204);
+        client.request("PUT", "/kv/other", b"value");
+        assert_eq!(client.request("POST", "/admin/flush", b"").0, 204);
+        assert_eq!(client.request("POST", "/admin/compact", b"").0, 204);
+        assert_eq!(client.get("/admin/compact").0, 405);
+
+        let (status, body) = client.get("/stats");
+        assert_eq!(status, 200);
+        let stats = Json::parse(&body).unwrap();
+        assert!(matches!(stats.get("write_stall"), Some(Json::String(stall)) if stall == "none"));
+        assert!(matches!(stats.get("block_cache"), Some(Json::Object(_))));
+        assert!(String::from_utf8(body).unwrap().contains("\"sstables\":1,"));
+        assert_eq!(client.get("/kv/key"), (200, b"value".to_vec()));
+    }
+}
@@ -0,0 +1,94 @@
+//! Point-in-time recovery: rebuild a tree from a checkpoint and the WAL
+//! segments written after it, up to a given write or time.
+//!
+//! ```text
+//! lsm_restore <checkpoint dir> <new tree dir> --sequence <n> [WAL segments or dirs...]
+//! lsm_restore <checkpoint dir> <new tree dir> --time <Unix seconds> [...]
+//! lsm_restore <checkpoint dir> <new tree dir> --ago <duration, e.g. 10m> [...]
+//! ```
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use lsm_tree::{restore_point_in_time, Options, RecoveryTarget};
+
+const USAGE: &str = "Usage: lsm_restore <checkpoint dir> <new tree dir> \
+    (--sequence <n> | --time <Unix seconds> | --ago <duration>) [WAL segments or dirs...]";
+
+fn main() -> Result<()> {
+    let mut positional = Vec::new();
+    let mut target = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let parse: fn(&str) -> Result<RecoveryTarget> = match arg.as_str() {
+            "--sequence" => |value| {
+                let sequence = value.parse().context("Invalid sequence")?;
+                Ok(RecoveryTarget::Sequence(sequence))
+            },
+            "--time" => |value| {
+                let seconds: f64 = value.parse().context("Invalid Unix time")?;
+                let since_epoch =
+                    Duration::try_from_secs_f64(seconds).context("Invalid Unix time")?;
+                Ok(RecoveryTarget::Time(UNIX_EPOCH + since_epoch))
+            },
+            "--ago" => |value| {
+                let ago = parse_duration(value)?;
+                let time = SystemTime::now()
+                    .checked_sub(ago)
+                    .ok_or_else(|| anyhow!("{} ago is out of range", value))?;
+                Ok(RecoveryTarget::Time(time))
+            },
+            _ => {
+                positional.push(PathBuf::from(arg));
+                continue;
+            }
+        };
+        if target.is_some() {
+            bail!("Only one target can be given\n{}", USAGE);
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| anyhow!("{} needs a value\n{}", arg, USAGE))?;
+        target = Some(parse(&value)?);
+    }
+
+    let (Some(target), [checkpoint, path, wal_segments @ ..]) = (target, positional.as_slice())
+    else {
+        bail!(USAGE);
+    };
+    let report = restore_point_in_time(
+        checkpoint,
+        wal_segments,
+        Path::new(path),
+        target,
+        Options::default(),
+    )?;
+    println!("{}", report);
+
+    Ok(())
+}
+
+/// A number of seconds, minutes, hours or days such as `90s`, `10m` or `2h`
+fn parse_duration(value: &str) -> Result<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .with_context(|| format!("Invalid duration {:?}", value))?;
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("Invalid duration unit {:?}, expected s, m, h or d", unit),
+    };
+    amount
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(|| anyhow!("Duration {:?} is too long", value))
+}
@@ -0,0 +1,788 @@
+//! Serves an `LsmTree` over the Redis protocol (RESP2), so any Redis client
+//! can use it. Supports GET, SET, DEL, EXISTS, MGET, MSET, SCAN, INCRBY,
+//! FLUSHDB, INFO and PING, one thread per connection.
+//!
+//! Usage: `resp_server <data dir> [listen address]`. The address defaults
+//! to `127.0.0.1:6380`, and once listening the actual one is printed (handy
+//! with port 0).
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use lsm_tree::{LsmError, LsmTree, WriteBatch};
+
+const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:6380";
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+// headers and inline commands
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+const DEFAULT_SCAN_COUNT: usize = 10;
+// `SCAN` cursors kept for clients to come back with, oldest dropped first
+const MAX_SCAN_CURSORS: usize = 1024;
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let Some(path) = args.next() else {
+        bail!("Usage: resp_server <data dir> [listen address]");
+    };
+    let address = args
+        .next()
+        .unwrap_or_else(|| DEFAULT_LISTEN_ADDRESS.to_string());
+
+    let tree = LsmTree::open(Path::new(&path))?;
+    let listener =
+        TcpListener::bind(&address).with_context(|| format!("Failed to listen on {}", address))?;
+    println!("Listening on {}", listener.local_addr()?);
+    run(listener, tree)
+}
+
+/// Serve `tree` to every client `listener` accepts, each on its own thread
+fn run(listener: TcpListener, tree: LsmTree) -> Result<()> {
+    let server = Arc::new(Server {
+        // keys are never longer than values can be, so this bounds every
+        // argument a command can usefully have
+        max_bulk_length: tree.options().max_value_size(),
+        tree,
+        address: listener.local_addr()?,
+        started: Instant::now(),
+        scan_cursors: Mutex::new(ScanCursors::default()),
+        connections: AtomicU64::new(0),
+        commands: AtomicU64::new(0),
+    });
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let server = server.clone();
+        std::thread::spawn(move || {
+            let peer = stream.peer_addr().ok();
+            if let Err(e) = server.serve(stream) {
+                eprintln!("Connection from {:?} failed: {:#}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Reply::Simple(message) => write!(out, "+{}\r\n", message),
+            // a line break would end the reply early
+            Reply::Error(message) => write!(out, "-{}\r\n", message.replace(['\r', '\n'], " ")),
+            Reply::Integer(n) => write!(out, ":{}\r\n", n),
+            Reply::Bulk(None) => out.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(out, "${}\r\n", bytes.len())?;
+                out.write_all(bytes)?;
+                out.write_all(b"\r\n")
+            }
+            Reply::Array(items) => {
+                write!(out, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write(out))
+            }
+        }
+    }
+}
+
+/// Where each outstanding `SCAN` cursor picks up again. Redis clients
+/// expect cursors to be numbers, so the key to resume from stays here.
+#[derive(Default)]
+struct ScanCursors {
+    next_id: u64,
+    resume_keys: BTreeMap<u64, Vec<u8>>,
+}
+
+struct Server {
+    tree: LsmTree,
+    max_bulk_length: usize,
+    address: SocketAddr,
+    started: Instant,
+    scan_cursors: Mutex<ScanCursors>,
+    connections: AtomicU64,
+    commands: AtomicU64,
+}
+
+impl Server {
+    fn serve(&self, stream: TcpStream) -> Result<()> {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+
+        loop {
+            let args = match read_command(&mut reader, self.max_bulk_length) {
+                Ok(Some(args)) => args,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    // the stream can't be trusted past a malformed command
+                    Reply::Error(format!("ERR Protocol error: {:#}", e)).write(&mut writer)?;
+                    writer.flush()?;
+                    return Err(e);
+                }
+            };
+            if args.is_empty() {
+                continue;
+            }
+
+            self.commands.fetch_add(1, Ordering::Relaxed);
+            let reply = self
+                .execute(&args)
+                .unwrap_or_else(|e| Reply::Error(format!("ERR {:#}", e)));
+            reply.write(&mut writer)?;
+            // pipelined commands get their replies in one go
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+        }
+    }
+
+    fn execute(&self, args: &[Vec<u8>]) -> Result<Reply> {
+        let (command, args) = args.split_first().context("empty command")?;
+        let name = String::from_utf8_lossy(command).to_ascii_uppercase();
+        let check_arity = |ok: bool| {
+            if ok {
+                Ok(())
+            } else {
+                Err(anyhow!(
+                    "wrong number of arguments for '{}' command",
+                    name.to_ascii_lowercase()
+                ))
+            }
+        };
+
+        match name.as_str() {
+            "PING" => {
+                check_arity(args.len() <= 1)?;
+                Ok(match args.first() {
+                    Some(message) => Reply::Bulk(Some(message.clone())),
+                    None => Reply::Simple("PONG"),
+                })
+            }
+            "GET" => {
+                check_arity(args.len() == 1)?;
+                Ok(Reply::Bulk(self.tree.get(&args[0])?))
+            }
+            "SET" => {
+                check_arity(args.len() == 2)?;
+                self.tree.put(args[0].clone(), args[1].clone())?;
+                Ok(Reply::Simple("OK"))
+            }
+            "MSET" => {
+                check_arity(!args.is_empty() && args.len().is_multiple_of(2))?;
+                let mut batch = WriteBatch::new();
+                for pair in args.chunks_exact(2) {
+                    batch.put(pair[0].clone(), pair[1].clone());
+                }
+                self.tree.write(batch)?;
+                Ok(Reply::Simple("OK"))
+            }
+            "MGET" => {
+                check_arity(!args.is_empty())?;
+                let values = self.tree.multi_get(args)?;
+                Ok(Reply::Array(values.into_iter().map(Reply::Bulk).collect()))
+            }
+            "EXISTS" => {
+                check_arity(!args.is_empty())?;
+                // repeated keys count every time, as in Redis
+                let values = self.tree.multi_get(args)?;
+                Ok(Reply::Integer(
+                    values.iter().filter(|value| value.is_some()).count() as i64,
+                ))
+            }
+            "DEL" => {
+                check_arity(!args.is_empty())?;
+                let keys: Vec<&Vec<u8>> =
+                    args.iter().collect::<BTreeSet<_>>().into_iter().collect();
+                let existing = self.tree.multi_get(&keys)?;
+                let mut batch = WriteBatch::new();
+                for key in keys {
+                    batch.delete(key.clone());
+                }
+                self.tree.write(batch)?;
+                Ok(Reply::Integer(
+                    existing.iter().filter(|value| value.is_some()).count() as i64,
+                ))
+            }
+            "INCRBY" => {
+                check_arity(args.len() == 2)?;
+                Ok(Reply::Integer(
+                    self.increment(&args[0], parse_integer(&args[1])?)?,
+                ))
+            }
+            "SCAN" => {
+                check_arity(!args.is_empty())?;
+                self.scan(args)
+            }
+            "FLUSHDB" => {
+                // `ASYNC` and `SYNC` make no difference here
+                check_arity(args.len() <= 1)?;
+                self.flush_db()?;
+                Ok(Reply::Simple("OK"))
+            }
+            "INFO" => {
+                // every section is always returned
+                check_arity(args.len() <= 1)?;
+                Ok(Reply::Bulk(Some(self.info()?.into_bytes())))
+            }
+            _ => Ok(Reply::Error(format!(
+                "ERR unknown command '{}'",
+                String::from_utf8_lossy(command)
+            ))),
+        }
+    }
+
+    /// Read-modify-write in an optimistic transaction, retried until no
+    /// other write to the key gets in between
+    fn increment(&self, key: &[u8], delta: i64) -> Result<i64> {
+        loop {
+            let mut transaction = self.tree.begin_transaction();
+            let current = match transaction.get(key)? {
+                Some(value) => parse_integer(&value)?,
+                None => 0,
+            };
+            let value = current
+                .checked_add(delta)
+                .context("increment or decrement would overflow")?;
+            transaction.put(key.to_vec(), value.to_string().into_bytes());
+
+            match transaction.commit() {
+                Ok(()) => return Ok(value),
+                Err(e) if matches!(e.downcast_ref(), Some(LsmError::TransactionConflict(_))) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// `SCAN cursor [MATCH pattern] [COUNT count]`, where `COUNT` is the
+    /// number of keys looked at (matching or not)
+    fn scan(&self, args: &[Vec<u8>]) -> Result<Reply> {
+        let cursor_id: u64 = std::str::from_utf8(&args[0])
+            .ok()
+            .and_then(|cursor| cursor.parse().ok())
+            .context("invalid cursor")?;
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        for option in args[1..].chunks(2) {
+            let [name, value] = option else {
+                bail!("syntax error");
+            };
+            match name.to_ascii_uppercase().as_slice() {
+                b"MATCH" => pattern = Some(value.as_slice()),
+                b"COUNT" => {
+                    count = usize::try_from(parse_integer(value)?)
+                        .ok()
+                        .filter(|&count| count > 0)
+                        .context("syntax error")?;
+                }
+                _ => bail!("syntax error"),
+            }
+        }
+
+        let mut cursor = self.tree.cursor();
+        if cursor_id == 0 {
+            cursor.seek_to_first()?;
+        } else {
+            let resume_key = self
+                .scan_cursors
+                .lock()
+                .unwrap()
+                .resume_keys
+                .remove(&cursor_id)
+                .context("invalid cursor")?;
+            cursor.seek(&resume_key)?;
+        }
+
+        let mut keys = Vec::new();
+        for _ in 0..count {
+            let Some(key) = cursor.key() else {
+                break;
+            };
+            if pattern.is_none_or(|pattern| glob_match(pattern, key)) {
+                keys.push(Reply::Bulk(Some(key.to_vec())));
+            }
+            cursor.next()?;
+        }
+
+        let next_id = match cursor.key() {
+            Some(key) => {
+                let mut scan_cursors = self.scan_cursors.lock().unwrap();
+                scan_cursors.next_id += 1;
+                let id = scan_cursors.next_id;
+                scan_cursors.resume_keys.insert(id, key.to_vec());
+                if scan_cursors.resume_keys.len() > MAX_SCAN_CURSORS {
+                    scan_cursors.resume_keys.pop_first();
+                }
+                id
+            }
+            None => 0,
+        };
+
+        Ok(Reply::Array(vec![
+            Reply::Bulk(Some(next_id.to_string().into_bytes())),
+            Reply::Array(keys),
+        ]))
+    }
+
+    /// Delete everything there is right now with a single range tombstone
+    fn flush_db(&self) -> Result<()> {
+        let mut cursor = self.tree.cursor();
+        cursor.seek_to_first()?;
+        let Some(first) = cursor.key().map(|key| key.to_vec()) else {
+            return Ok(());
+        };
+        cursor.seek_to_last()?;
+        let last = cursor.key().map(|key| key.to_vec()).unwrap_or_default();
+
+        // the tombstone's end is exclusive, so the last key goes on its own
+        let mut batch = WriteBatch::new();
+        batch.delete_range(first, last.clone());
+        batch.delete(last);
+        self.tree.write(batch)
+    }
+
+    fn info(&self) -> Result<String> {
+        let stats = self.tree.stats()?;
+        let sections = [
+            (
+                "Server",
+                vec![
+                    ("lsm_tree_version", env!("CARGO_PKG_VERSION").to_string()),
+                    ("tcp_port", self.address.port().to_string()),
+                    (
+                        "uptime_in_seconds",
+                        self.started.elapsed().as_secs().to_string(),
+                    ),
+                ],
+            ),
+            (
+                "Stats",
+                vec![
+                    (
+                        "total_connections_received",
+                        self.connections.load(Ordering::Relaxed).to_string(),
+                    ),
+                    (
+                        "total_commands_processed",
+                        self.commands.load(Ordering::Relaxed).to_string(),
+                    ),
+                ],
+            ),
+            (
+                "Storage",
+                vec![
+                    ("sstables", stats.sstables.to_string()),
+                    ("l0_sstables", stats.l0_sstables.to_string()),
+                    ("immutable_memtables", stats.immutable_memtables.to_string()),
+                    (
+                        "pending_compaction_bytes",
+                        stats.pending_compaction_bytes.to_string(),
+                    ),
+                    (
+                        "write_stall",
+                        format!("{:?}", stats.write_stall).to_lowercase(),
+                    ),
+                    ("stall_time_ms", stats.stall_time.as_millis().to_string()),
+                    ("data_blocks", stats.data_blocks.to_string()),
+                    ("uncompressed_bytes", stats.uncompressed_bytes.to_string()),
+                    ("compressed_bytes", stats.compressed_bytes.to_string()),
+                    ("block_cache_hits", stats.block_cache.hits.to_string()),
+                    ("block_cache_misses", stats.block_cache.misses.to_string()),
+                    ("table_cache_hits", stats.table_cache.hits.to_string()),
+                    ("table_cache_misses", stats.table_cache.misses.to_string()),
+                    (
+                        "compaction_in_progress",
+                        (stats.compaction.is_some() as u8).to_string(),
+                    ),
+                ],
+            ),
+        ];
+
+        let mut info = String::new();
+        for (section, fields) in sections {
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            let _ = write!(info, "# {}\r\n", section);
+            for (name, value) in fields {
+                let _ = write!(info, "{}:{}\r\n", name, value);
+            }
+        }
+        Ok(info)
+    }
+}
+
+/// The next command's arguments, `None` once the client hangs up.
+/// Takes both RESP arrays of bulk strings (what clients send) and inline
+/// commands (what someone typing into telnet sends). Bulk strings longer
+/// than `max_bulk_length` are a protocol error.
+fn read_command(reader: &mut impl BufRead, max_bulk_length: usize) -> Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+
+    let Some(count) = line.strip_prefix(b"*") else {
+        return Ok(Some(
+            line.split(|byte| byte.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| arg.to_vec())
+                .collect(),
+        ));
+    };
+
+    let count = parse_length(count, MAX_ARGUMENTS)?;
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let line = read_line(reader)?.context("connection closed mid-command")?;
+        let Some(length) = line.strip_prefix(b"$") else {
+            bail!("expected '$', got '{}'", String::from_utf8_lossy(&line));
+        };
+        let length = parse_length(length, max_bulk_length)?;
+
+        let mut arg = vec![0u8; length + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            bail!("bulk string not terminated by CRLF");
+        }
+        arg.truncate(length);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// One line without its line ending, `None` at EOF
+fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let read = reader
+        .take(MAX_LINE_LENGTH as u64)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        bail!("line too long or cut off");
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_length(digits: &[u8], max: usize) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .filter(|&length| length <= max)
+        .with_context(|| format!("invalid length '{}'", String::from_utf8_lossy(digits)))
+}
+
+fn parse_integer(digits: &[u8]) -> Result<i64> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .context("value is not an integer or out of range")
+}
+
+/// Redis style glob: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // pattern position just past the last `*`, and how much text it has taken
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            star = Some((p + 1, t));
+            p += 1;
+            continue;
+        }
+        if let Some(next) = match_one(pattern, p, text[t]) {
+            p = next;
+            t += 1;
+            continue;
+        }
+        // let the last `*` take one more byte and try again from there
+        let Some((star_p, star_t)) = star else {
+            return false;
+        };
+        star = Some((star_p, star_t + 1));
+        p = star_p;
+        t = star_t + 1;
+    }
+    pattern[p..].iter().all(|&byte| byte == b'*')
+}
+
+/// Whether the pattern element at `p` (anything but `*`) matches `byte`,
+/// and if so where the next element starts
+fn match_one(pattern: &[u8], p: usize, byte: u8) -> Option<usize> {
+    match *pattern.get(p)? {
+        b'?' => Some(p + 1),
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == byte).then_some(p + 2),
+        b'[' => {
+            let mut i = p + 1;
+            let negate = pattern.get(i) == Some(&b'^');
+            if negate {
+                i += 1;
+            }
+            let mut matched = false;
+            loop {
+                match pattern.get(i) {
+                    // unterminated, so it was a plain `[` after all
+                    None => return (byte == b'[').then_some(p + 1),
+                    Some(b']') => break,
+                    Some(b'\\') if i + 1 < pattern.len() => {
+                        matched |= pattern[i + 1] == byte;
+                        i += 2;
+                    }
+                    Some(&low) if pattern.get(i + 1) == Some(&b'-') && i + 2 < pattern.len() => {
+                        let high = pattern[i + 2];
+                        matched |= low.min(high) <= byte && byte <= low.max(high);
+                        i += 3;
+                    }
+                    Some(&other) => {
+                        matched |= other == byte;
+                        i += 1;
+                    }
+                }
+            }
+            (matched != negate).then_some(i + 1)
+        }
+        other => (other == byte).then_some(p + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lsm_tree::Options;
+
+    use super::*;
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        /// Start a server for a tree in `dir` and connect to it
+        fn start(dir: &Path, options: Options) -> Self {
+            let tree = LsmTree::open_with_options(dir, options).unwrap();
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            std::thread::spawn(move || run(listener, tree));
+            Self::connect(address)
+        }
+
+        fn connect(address: SocketAddr) -> Self {
+            let writer = TcpStream::connect(address).unwrap();
+            Self {
+                reader: BufReader::new(writer.try_clone().unwrap()),
+                writer,
+            }
+        }
+
+        /// Send a command as an array of bulk strings, and return the raw reply
+        fn command(&mut self, args: &[&[u8]]) -> Vec<u8> {
+            let mut command = format!("*{}\r\n", args.len()).into_bytes();
+            for arg in args {
+                command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+                command.extend_from_slice(arg);
+                command.extend_from_slice(b"\r\n");
+            }
+            self.send(&command)
+        }
+
+        fn send(&mut self, bytes: &[u8]) -> Vec<u8> {
+            self.writer.write_all(bytes).unwrap();
+            self.read_reply()
+        }
+
+        /// One whole reply, arrays included, as it came over the wire
+        fn read_reply(&mut self) -> Vec<u8> {
+            let mut reply = Vec::new();
+            self.reader.read_until(b'\n', &mut reply).unwrap();
+            let length = || {
+                std::str::from_utf8(&reply[1..reply.len() - 2])
+                    .unwrap()
+                    .parse::<i64>()
+                    .unwrap()
+            };
+            match reply[0] {
+                b'$' if length() >= 0 => {
+                    let mut bulk = vec![0; length() as usize + 2];
+                    self.reader.read_exact(&mut bulk).unwrap();
+                    reply.extend_from_slice(&bulk);
+                }
+                b'*' => {
+                    for _ in 0..length() {
+                        let item = self.read_reply();
+                        reply.extend_from_slice(&item);
+                    }
+                }
+                _ => {}
+            }
+            reply
+        }
+
+        fn closed(&mut self) -> bool {
+            let mut rest = Vec::new();
+            self.reader.read_to_end(&mut rest).is_ok() && rest.is_empty()
+        }
+    }
+
+    #[test]
+    fn commands_read_and_write_the_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = Client::start(dir.path(), Options::default());
+
+        assert_eq!(client.command(&[b"PING"]), b"+PONG\r\n");
+        assert_eq!(client.command(&[b"SET", b"key", b"a\r\nb"]), b"+OK\r\n");
+        assert_eq!(client.command(&[b"get", b"key"]), b"$4\r\na\r\nb\r\n");
+        assert_eq!(client.command(&[b"GET", b"missing"]), b"$-1\r\n");
+        assert_eq!(
+            client.command(&[b"MSET", b"a", b"1", b"b", b"2", b"c", b""]),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            client.command(&[b"MGET", b"a", b"missing", b"c"]),
+            b"*3\r\n$1\r\n1\r\n$-1\r\n$0\r\n\r\n"
+        );
+        assert_eq!(
+            client.command(&[b"EXISTS", b"a", b"a", b"missing"]),
+            b":2\r\n"
+        );
+        assert_eq!(client.command(&[b"DEL", b"a", b"a", b"missing"]), b":1\r\n");
+        assert_eq!(client.command(&[b"EXISTS", b"a"]), b":0\r\n");
+
+        assert_eq!(client.command(&[b"INCRBY", b"counter", b"5"]), b":5\r\n");
+        assert_eq!(client.command(&[b"INCRBY", b"counter", b"-7"]), b":-2\r\n");
+        assert_eq!(client.command(&[b"GET", b"counter"]), b"$2\r\n-2\r\n");
+
+        // inline commands, as typed into telnet
+        assert_eq!(client.send(b"GET b\r\n"), b"$1\r\n2\r\n");
+
+        let info = String::from_utf8(client.command(&[b"INFO"])).unwrap();
+        assert!(info.contains("# Server\r\n") && info.contains("# Storage\r\n"));
+        assert!(info.contains("total_connections_received:1\r\n"));
+
+        assert_eq!(client.command(&[b"FLUSHDB"]), b"+OK\r\n");
+        assert_eq!(
+            client.command(&[b"EXISTS", b"b", b"c", b"counter", b"key"]),
+            b":0\r\n"
+        );
+        assert_eq!(client.command(&[b"SCAN", b"0"]), b"*2\r\n$1\r\n0\r\n*0\r\n");
+    }
+
+    #[test]
+    fn scan_cursors_pick_up_where_they_left_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = Client::start(dir.path(), Options::default());
+        client.command(&[
+            b"MSET", b"a1", b"", b"a2", b"", b"b1", b"", b"b2", b"", b"c1", b"",
+        ]);
+
+        assert_eq!(
+            client.command(&[b"SCAN", b"0", b"COUNT", b"3"]),
+            b"*2\r\n$1\r\n1\r\n*3\r\n$2\r\na1\r\n$2\r\na2\r\n$2\r\nb1\r\n"
+        );
+        assert_eq!(
+            client.command(&[b"SCAN", b"1", b"COUNT", b"3"]),
+            b"*2\r\n$1\r\n0\r\n*2\r\n$2\r\nb2\r\n$2\r\nc1\r\n"
+        );
+        // cursors are good for one use
+        assert!(client
+            .command(&[b"SCAN", b"1"])
+            .starts_with(b"-ERR invalid cursor"));
+        assert_eq!(
+            client.command(&[b"SCAN", b"0", b"MATCH", b"*1", b"COUNT", b"100"]),
+            b"*2\r\n$1\r\n0\r\n*3\r\n$2\r\na1\r\n$2\r\nb1\r\n$2\r\nc1\r\n"
+        );
+        assert!(client
+            .command(&[b"SCAN", b"0", b"COUNT", b"0"])
+            .starts_with(b"-ERR"));
+    }
+
+    #[test]
+    fn command_errors_keep_the_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = Client::start(dir.path(), Options::default());
+        client.command(&[b"SET", b"text", b"abc"]);
+
+        assert_eq!(
+            client.command(&[b"INCRBY", b"text", b"1"]),
+            b"-ERR value is not an integer or out of range\r\n"
+        );
+        assert_eq!(
+            client.command(&[b"INCRBY", b"counter", b"one"]),
+            b"-ERR value is not an integer or out of range\r\n"
+        );
+        assert_eq!(
+            client.command(&[b"NOPE", b"key"]),
+            b"-ERR unknown command 'NOPE'\r\n"
+        );
+        assert_eq!(
+            client.command(&[b"GET"]),
+            b"-ERR wrong number of arguments for 'get' command\r\n"
+        );
+        assert_eq!(client.command(&[b"GET", b"text"]), b"$3\r\nabc\r\n");
+    }
+
+    #[test]
+    fn protocol_errors_close_the_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = Client::start(dir.path(), Options::default());
+        let address = client.writer.peer_addr().unwrap();
+
+        for malformed in [
+            &b"*1\r\n$abc\r\n"[..],
+            b"*1\r\n$-1\r\n",
+            b"*1\r\n:1\r\n",
+            b"*1\r\n$3\r\nGETxx",
+            // longer than any value the tree takes
+            b"*2\r\n$3\r\nGET\r\n$65537\r\n",
+        ] {
+            let reply = client.send(malformed);
+            assert!(reply.starts_with(b"-ERR Protocol error: "), "{:?}", reply);
+            assert!(client.closed());
+            client = Client::connect(address);
+        }
+    }
+
+    #[test]
+    fn bulk_strings_can_be_as_long_as_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            blob_threshold: Some(1024),
+            ..Options::default()
+        };
+        let mut client = Client::start(dir.path(), options);
+        let value = vec![b'v'; 1024 * 1024];
+        assert_eq!(client.command(&[b"SET", b"big", &value]), b"+OK\r\n");
+        // keys are still limited to 64 KB, by the tree
+        let reply = client.command(&[b"SET", &[b'k'; 100 * 1024], b"value"]);
+        assert!(reply.starts_with(b"-ERR Key is too large"));
+        let reply = client.command(&[b"GET", b"big"]);
+        assert_eq!(
+            &reply[reply.len() - value.len() - 2..][..value.len()],
+            &value[..]
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{encode_entry, LsmTree};
+
+/// Where a separated value lives: `length` bytes at `offset` in blob file `file_id`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobPointer {
+    pub file_id: u32,
+    pub offset: u64,
+    pub length: u32,
+}
+
+impl BlobPointer {
+    pub const ENCODED_SIZE: usize = 4 + 8 + 4;
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.file_id.to_le_bytes());
+        buf.extend_from_slice(&self.offset.to_le_bytes());
+        buf.extend_from_slice(&self.length.to_le_bytes());
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != Self::ENCODED_SIZE {
+            bail!("Invalid blob pointer length {}", bytes.len());
+        }
+        Ok(Self {
+            file_id: u32::from_le_bytes(bytes[0..4].try_into()?),
+            offset: u64::from_le_bytes(bytes[4..12].try_into()?),
+            length: u32::from_le_bytes(bytes[12..16].try_into()?),
+        })
+    }
+}
+
+/// The set of append-only blob files (`<path>/blob_{id}.blob`) holding
+/// large values that were separated out of SSTables.
+///
+/// Each file uses the usual `<u32 key length><u32 value length><key><value>`
+/// record format, keeping the key next to the value so a file can be
+/// understood on its own. Files are never modified once written, space is
+/// reclaimed by compaction (see `LsmTree::compact_all`).
+///
+/// Shared between the tree, its cursors and the background workers, so
+/// every method takes `&self`.
+#[derive(Debug)]
+pub struct BlobStore {
+    path: PathBuf,
+    // file id -> size on disk, for files referenced by a live SSTable
+    files: Mutex<BTreeMap<u32, u64>>,
+    next_file_id: AtomicU32,
+}
+
+impl BlobStore {
+    const FILE_NAME_PREFIX: &'static str = "blob_";
+    const FILE_EXT: &'static str = ".blob";
+
+    /// Load the existing blob files in the tree directory
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut files = BTreeMap::new();
+        for dir_entry_result in std::fs::read_dir(path)? {
+            let dir_entry = dir_entry_result?;
+            let file_id_opt = dir_entry
+                .file_name()
+                .to_str()
+                .and_then(|s| s.strip_prefix(Self::FILE_NAME_PREFIX))
+                .and_then(|s| s.strip_suffix(Self::FILE_EXT))
+                .and_then(|s| s.parse::<u32>().ok());
+
+            if let Some(file_id) = file_id_opt {
+                files.insert(file_id, dir_entry.metadata()?.len());
+            }
+        }
+
+        let next_file_id = files.keys().next_back().map_or(0, |id| id + 1);
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            files: Mutex::new(files),
+            next_file_id: AtomicU32::new(next_file_id),
+        })
+    }
+
+    pub(crate) fn file_path(&self, file_id: u32) -> PathBuf {
+        self.path.join(format!(
+            "{}{}{}",
+            Self::FILE_NAME_PREFIX,
+            file_id,
+            Self::FILE_EXT
+        ))
+    }
+
+    /// Size on disk of every blob file, keyed by file id
+    pub fn files(&self) -> BTreeMap<u32, u64> {
+        self.files.lock().unwrap().clone()
+    }
+
+    /// Read a separated value back
+    pub fn read(&self, pointer: &BlobPointer) -> Result<Vec<u8>> {
+        let mut file = File::open(self.file_path(pointer.file_id))
+            .with_context(|| format!("Failed to open blob file {}", pointer.file_id))?;
+        file.seek(SeekFrom::Start(pointer.offset))?;
+
+        let mut value = vec![0u8; pointer.length as usize];
+        file.read_exact(&mut value)
+            .context("Failed to read value from blob file")?;
+
+        Ok(value)
+    }
+
+    /// Start a new blob file, returned writer must be `finish`ed
+    pub fn create_writer(&self) -> Result<BlobWriter> {
+        let file_id = self.next_file_id.fetch_add(1, Ordering::Relaxed);
+
+        Ok(BlobWriter {
+            file_id,
+            writer: BufWriter::new(File::create(self.file_path(file_id))?),
+            offset: 0,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Sync a finished writer's file, returning its id and size so it can
+    /// be `register`ed along with the SSTable pointing into it.
+    /// Files that ended up empty are removed instead.
+    pub fn finish_writer(&self, mut blob_writer: BlobWriter) -> Result<Option<(u32, u64)>> {
+        blob_writer.writer.flush()?;
+        if blob_writer.offset == 0 {
+            drop(blob_writer.writer);
+            std::fs::remove_file(self.file_path(blob_writer.file_id))?;
+            return Ok(None);
+        }
+
+        blob_writer.writer.get_ref().sync_all()?;
+
+        Ok(Some((blob_writer.file_id, blob_writer.offset)))
+    }
+
+    /// Start tracking a finished file for garbage collection
+    pub fn register(&self, file_id: u32, file_size: u64) {
+        self.files.lock().unwrap().insert(file_id, file_size);
+    }
+
+    /// Stop tracking a file that compaction found to be garbage.
+    /// The file itself is deleted once no reader can still reach it.
+    pub fn forget(&self, file_id: u32) {
+        self.files.lock().unwrap().remove(&file_id);
+    }
+}
+
+/// Appends values to a single new blob file
+#[derive(Debug)]
+pub struct BlobWriter {
+    file_id: u32,
+    writer: BufWriter<File>,
+    offset: u64,
+    buf: Vec<u8>,
+}
+
+impl BlobWriter {
+    /// Append a value and return where it landed
+    pub fn append(&mut self, key: &[u8], value: &[u8]) -> Result<BlobPointer> {
+        if value.len() > LsmTree::MAX_BLOB_VALUE_SIZE {
+            bail!("Value too large for a blob file (length={})", value.len());
+        }
+
+        self.buf.clear();
+        encode_entry(&mut self.buf, key, value);
+        self.writer.write_all(&self.buf)?;
+
+        let pointer = BlobPointer {
+            file_id: self.file_id,
+            // value bytes sit right after the header and key
+            offset: self.offset + 8 + key.len() as u64,
+            length: value.len() as u32,
+        };
+        self.offset += self.buf.len() as u64;
+
+        Ok(pointer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Options;
+
+    fn blob_files(path: &Path) -> usize {
+        std::fs::read_dir(path)
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .ends_with(".blob")
+            })
+            .count()
+    }
+
+    #[test]
+    fn pointer_round_trips() {
+        let pointer = BlobPointer {
+            file_id: 7,
+            offset: 1 << 40,
+            length: 123_456,
+        };
+        let mut buf = Vec::new();
+        pointer.encode(&mut buf);
+        assert_eq!(buf.len(), BlobPointer::ENCODED_SIZE);
+        assert_eq!(BlobPointer::decode(&buf).unwrap(), pointer);
+        assert!(BlobPointer::decode(&buf[1..]).is_err());
+    }
+
+    #[test]
+    fn large_values_are_separated_and_collected() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            blob_threshold: Some(1024),
+            ..Options::default()
+        };
+        let tree = LsmTree::open_with_options(dir.path(), options.clone()).unwrap();
+        let small = b"small".to_vec();
+        let large = |fill: u8| vec![fill; 200 * 1024];
+        tree.put(b"a".to_vec(), large(1)).unwrap();
+        tree.put(b"b".to_vec(), small.clone()).unwrap();
+        tree.put(b"c".to_vec(), large(2)).unwrap();
+        tree.flush().unwrap();
+        assert!(blob_files(dir.path()) > 0);
+        assert_eq!(tree.get(b"a").unwrap(), Some(large(1)));
+
+        let mut cursor = tree.cursor();
+        cursor.seek(b"c").unwrap();
+        assert_eq!(cursor.value(), Some(large(2).as_slice()));
+        drop(cursor);
+
+        // with every large value overwritten, compaction can drop their files
+        let before = blob_files(dir.path());
+        tree.put(b"a".to_vec(), small.clone()).unwrap();
+        tree.delete(b"c".to_vec()).unwrap();
+        tree.flush().unwrap();
+        tree.compact_all().unwrap();
+        assert!(blob_files(dir.path()) < before);
+        assert_eq!(tree.get(b"a").unwrap(), Some(small.clone()));
+        assert_eq!(tree.get(b"c").unwrap(), None);
+
+        tree.put(b"d".to_vec(), large(3)).unwrap();
+        tree.flush().unwrap();
+        drop(tree);
+        let tree = LsmTree::open_with_options(dir.path(), options).unwrap();
+        assert_eq!(tree.get(b"b").unwrap(), Some(small));
+        assert_eq!(tree.get(b"d").unwrap(), Some(large(3)));
+    }
+
+    #[test]
+    fn large_values_need_a_blob_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        let too_large = vec![0; LsmTree::MAX_ENTRY_SIZE + 1];
+        assert!(tree.put(b"key".to_vec(), too_large).is_err());
+        assert_eq!(tree.get(b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn gc_ratios_are_validated() {
+        let dir = tempfile::tempdir().unwrap();
+        for blob_gc_ratio in [0.0, -0.5, 1.5, f64::NAN] {
+            let options = Options {
+                blob_gc_ratio,
+                ..Options::default()
+            };
+            assert!(LsmTree::open_with_options(dir.path(), options).is_err());
+        }
+        let options = Options {
+            blob_gc_ratio: 1.0,
+            ..Options::default()
+        };
+        LsmTree::open_with_options(dir.path(), options).unwrap();
+    }
+}
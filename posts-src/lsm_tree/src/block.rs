@@ -0,0 +1,391 @@
+use anyhow::{bail, Result};
+
+use crate::StoredValue;
+
+/// A decompressed SSTable data block.
+///
+/// Keys are prefix compressed against the key before them, each entry being
+/// `<varint shared><varint unshared><varint value length><unshared key bytes><value>`.
+/// Every `restart_interval` entries the full key is stored again (`shared`
+/// is 0), and the offsets of those restart points are listed at the end of
+/// the block as `<u32 offset>...<u32 count>`. Lookups binary search the
+/// restart points and then only decode the entries of one interval.
+#[derive(Debug, Clone, Default)]
+pub struct Block {
+    // entries only, without the restart trailer
+    data: Vec<u8>,
+    restarts: Vec<u32>,
+}
+
+impl Block {
+    /// Parse a decompressed block, checking the restart trailer
+    pub(crate) fn new(mut data: Vec<u8>) -> Result<Self> {
+        let Some(count_offset) = data.len().checked_sub(4) else {
+            bail!("Block too short for its restart count");
+        };
+        let count = u32::from_le_bytes(data[count_offset..].try_into()?) as usize;
+        let Some(restarts_offset) = count
+            .checked_mul(4)
+            .and_then(|length| count_offset.checked_sub(length))
+        else {
+            bail!("Block too short for {} restart points", count);
+        };
+
+        let restarts: Vec<u32> = data[restarts_offset..count_offset]
+            .chunks_exact(4)
+            .map(|offset| u32::from_le_bytes(offset.try_into().unwrap()))
+            .collect();
+        if restarts
+            .iter()
+            .any(|&offset| offset as usize >= restarts_offset)
+        {
+            bail!("Block restart point out of range");
+        }
+
+        data.truncate(restarts_offset);
+        Ok(Self { data, restarts })
+    }
+
+    /// Bytes held in memory, for the block cache
+    pub(crate) fn charge(&self) -> usize {
+        self.data.len() + 4 * self.restarts.len()
+    }
+
+    fn iter_from(&self, restart_idx: usize) -> BlockIter<'_> {
+        BlockIter {
+            data: &self.data,
+            offset: self.restarts.get(restart_idx).map_or(0, |&o| o as usize),
+            key: Vec::new(),
+        }
+    }
+
+    /// Every entry in order
+    pub(crate) fn iter(&self) -> BlockIter<'_> {
+        self.iter_from(0)
+    }
+
+    /// Decode every entry up front, for cursors that move back and forth
+    pub(crate) fn decode(&self) -> Result<Vec<(Vec<u8>, StoredValue)>> {
+        self.iter().collect()
+    }
+
+    /// Find a single key: the last restart point at or before it, then a
+    /// scan through that interval
+    pub(crate) fn get(&self, target_key: &[u8]) -> Result<Option<StoredValue>> {
+        let mut low = 0;
+        let mut high = self.restarts.len();
+        // restart points with a key `<= target_key` are all before `low`
+        while low < high {
+            let mid = (low + high) / 2;
+            let (key, _, _) = read_entry(&self.data, self.restarts[mid] as usize, &[])?;
+            if key.as_slice() <= target_key {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        if low == 0 {
+            return Ok(None);
+        }
+
+        for entry in self.iter_from(low - 1) {
+            let (key, value) = entry?;
+            match key.as_slice().cmp(target_key) {
+                std::cmp::Ordering::Less => continue,
+                std::cmp::Ordering::Equal => return Ok(Some(value)),
+                std::cmp::Ordering::Greater => break,
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Walks a block's entries from a restart point onwards
+pub(crate) struct BlockIter<'a> {
+    data: &'a [u8],
+    offset: usize,
+    key: Vec<u8>,
+}
+
+impl Iterator for BlockIter<'_> {
+    type Item = Result<(Vec<u8>, StoredValue)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.data.len() {
+            return None;
+        }
+        let result =
+            read_entry(self.data, self.offset, &self.key).and_then(|(key, value, next)| {
+                let value = StoredValue::decode(value)?;
+                self.offset = next;
+                self.key.clone_from(&key);
+                Ok((key, value))
+            });
+        if result.is_err() {
+            // don't keep failing on the same entry
+            self.offset = self.data.len();
+        }
+        Some(result)
+    }
+}
+
+/// Decode the entry at `offset` given the key before it, returning its key,
+/// its encoded value and the offset of the next entry
+fn read_entry<'a>(
+    data: &'a [u8],
+    offset: usize,
+    previous_key: &[u8],
+) -> Result<(Vec<u8>, &'a [u8], usize)> {
+    let mut pos = offset;
+    let shared = read_varint(data, &mut pos)? as usize;
+    let unshared = read_varint(data, &mut pos)? as usize;
+    let value_length = read_varint(data, &mut pos)? as usize;
+    if shared > previous_key.len() || value_length > StoredValue::MAX_ENCODED_SIZE {
+        bail!("Corrupt block entry");
+    }
+    let Some(value_start) = pos.checked_add(unshared) else {
+        bail!("Corrupt block entry");
+    };
+    let Some(unshared_key) = data.get(pos..value_start) else {
+        bail!("Truncated block entry");
+    };
+    let next = value_start + value_length;
+    let Some(value) = data.get(value_start..next) else {
+        bail!("Truncated block entry");
+    };
+
+    let mut key = Vec::with_capacity(shared + unshared);
+    key.extend_from_slice(&previous_key[..shared]);
+    key.extend_from_slice(unshared_key);
+    Ok((key, value, next))
+}
+
+/// Builds a `Block` one sorted entry at a time
+#[derive(Debug)]
+pub(crate) struct BlockBuilder {
+    buf: Vec<u8>,
+    restarts: Vec<u32>,
+    restart_interval: usize,
+    // entries added since the last restart point
+    counter: usize,
+    last_key: Vec<u8>,
+}
+
+impl BlockBuilder {
+    pub(crate) fn new(restart_interval: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            restarts: Vec::new(),
+            restart_interval: restart_interval.max(1),
+            counter: 0,
+            last_key: Vec::new(),
+        }
+    }
+
+    pub(crate) fn add(&mut self, key: &[u8], value: &[u8]) {
+        let shared = if self.counter == self.restart_interval || self.buf.is_empty() {
+            self.counter = 0;
+            self.restarts.push(self.buf.len() as u32);
+            0
+        } else {
+            self.last_key
+                .iter()
+                .zip(key)
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        self.counter += 1;
+
+        write_varint(&mut self.buf, shared as u32);
+        write_varint(&mut self.buf, (key.len() - shared) as u32);
+        write_varint(&mut self.buf, value.len() as u32);
+        self.buf.extend_from_slice(&key[shared..]);
+        self.buf.extend_from_slice(value);
+
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Size of the block if it were finished now
+    pub(crate) fn size(&self) -> usize {
+        self.buf.len() + 4 * (self.restarts.len() + 1)
+    }
+
+    pub(crate) fn last_key(&self) -> &[u8] {
+        &self.last_key
+    }
+
+    /// Append the restart trailer and return the finished block. The
+    /// builder must be `reset` before it's used again.
+    pub(crate) fn finish(&mut self) -> &[u8] {
+        for offset in &self.restarts {
+            self.buf.extend_from_slice(&offset.to_le_bytes());
+        }
+        self.buf
+            .extend_from_slice(&(self.restarts.len() as u32).to_le_bytes());
+        &self.buf
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.buf.clear();
+        self.restarts.clear();
+        self.counter = 0;
+        self.last_key.clear();
+    }
+}
+
+/// LEB128, 7 bits at a time with the high bit set on all but the last byte
+fn write_varint(buf: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let Some(&byte) = data.get(*pos) else {
+            bail!("Truncated varint");
+        };
+        *pos += 1;
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("Varint too long")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LsmTree, Options};
+
+    fn build(entries: &[(Vec<u8>, StoredValue)], restart_interval: usize) -> Block {
+        let mut builder = BlockBuilder::new(restart_interval);
+        let mut value = Vec::new();
+        for (key, stored) in entries {
+            value.clear();
+            stored.encode(&mut value);
+            builder.add(key, &value);
+        }
+        Block::new(builder.finish().to_vec()).unwrap()
+    }
+
+    fn entries() -> Vec<(Vec<u8>, StoredValue)> {
+        (0..200)
+            .map(|i| {
+                let key = format!("tenant/{}/user/{:04}", i / 50, i * 3).into_bytes();
+                let value = match i % 7 {
+                    0 => StoredValue::Tombstone,
+                    _ => StoredValue::Inline(format!("value {}", i).into_bytes()),
+                };
+                (key, value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_at_any_restart_interval() {
+        let entries = entries();
+        for restart_interval in [1, 2, 16, 1000] {
+            let block = build(&entries, restart_interval);
+            assert_eq!(
+                block.restarts.len(),
+                entries.len().div_ceil(restart_interval)
+            );
+            assert_eq!(block.decode().unwrap(), entries);
+
+            for (key, value) in &entries {
+                assert_eq!(block.get(key).unwrap().as_ref(), Some(value));
+                // keys all have the same length, so neither of these is in the block
+                let mut after = key.clone();
+                after.push(0);
+                assert_eq!(block.get(&after).unwrap(), None);
+                assert_eq!(block.get(&key[..key.len() - 1]).unwrap(), None);
+            }
+            assert_eq!(block.get(b"").unwrap(), None);
+            assert_eq!(block.get(b"tenant/9").unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn shared_prefixes_are_stored_once() {
+        let entries = entries();
+        let full = build(&entries, 1).charge();
+        let compressed = build(&entries, 16).charge();
+        assert!(compressed * 2 < full, "{} vs {}", compressed, full);
+    }
+
+    #[test]
+    fn corrupt_blocks_are_rejected() {
+        let entries = entries();
+        let mut builder = BlockBuilder::new(16);
+        for (key, _) in &entries {
+            builder.add(key, &[]);
+        }
+        let data = builder.finish().to_vec();
+
+        assert!(Block::new(Vec::new()).is_err());
+        assert!(Block::new(vec![0xff; 4]).is_err());
+        // a restart point past the entries
+        let mut corrupt = data.clone();
+        let first_restart = corrupt.len() - 4 * (entries.len().div_ceil(16) + 1);
+        corrupt[first_restart..][..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Block::new(corrupt).is_err());
+        // the last entry cut short, with the trailer intact
+        let mut truncated = data[..first_restart - 3].to_vec();
+        truncated.extend_from_slice(&data[first_restart..]);
+        let block = Block::new(truncated).unwrap();
+        assert!(block.decode().is_err());
+        assert!(block.get(&entries.last().unwrap().0).is_err());
+    }
+
+    #[test]
+    fn trees_read_back_at_any_restart_interval() {
+        for block_restart_interval in [1, 3, 1000] {
+            let dir = tempfile::tempdir().unwrap();
+            let options = Options {
+                block_restart_interval,
+                ..Options::default()
+            };
+            let tree = LsmTree::open_with_options(dir.path(), options).unwrap();
+            for (key, value) in entries() {
+                match value {
+                    StoredValue::Inline(value) => tree.put(key, value).unwrap(),
+                    _ => tree.delete(key).unwrap(),
+                }
+            }
+            tree.flush().unwrap();
+            for (key, value) in entries() {
+                let expected = match value {
+                    StoredValue::Inline(value) => Some(value),
+                    _ => None,
+                };
+                assert_eq!(tree.get(&key).unwrap(), expected);
+            }
+            let mut cursor = tree.cursor();
+            cursor.seek(b"tenant/2").unwrap();
+            assert_eq!(cursor.key(), Some(&b"tenant/2/user/0300"[..]));
+        }
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, u32::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+            let mut pos = 0;
+            assert_eq!(read_varint(&buf, &mut pos).unwrap(), value);
+            assert_eq!(pos, buf.len());
+            assert!(read_varint(&buf[..buf.len() - 1], &mut 0).is_err());
+        }
+        assert!(read_varint(&[0xff; 6], &mut 0).is_err());
+    }
+}
@@ -0,0 +1,379 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::Result;
+
+use crate::{Block, TableReader};
+
+/// Hit and miss counters of a cache, since it was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Debug)]
+struct LruEntry<V> {
+    value: Arc<V>,
+    charge: usize,
+    tick: u64,
+}
+
+/// A single LRU shard. Recency is tracked with an increasing tick per
+/// access, and `order` maps ticks back to keys so the least recently
+/// used entry is always the first one.
+#[derive(Debug)]
+struct LruShard<K, V> {
+    map: HashMap<K, LruEntry<V>>,
+    order: BTreeMap<u64, K>,
+    usage: usize,
+    capacity: usize,
+    tick: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> LruShard<K, V> {
+    fn get(&mut self, key: &K) -> Option<Arc<V>> {
+        let entry = self.map.get_mut(key)?;
+        self.order.remove(&entry.tick);
+        self.tick += 1;
+        entry.tick = self.tick;
+        self.order.insert(self.tick, key.clone());
+        Some(entry.value.clone())
+    }
+
+    fn insert(&mut self, key: K, value: Arc<V>, charge: usize) {
+        self.remove(&key);
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.map.insert(
+            key,
+            LruEntry {
+                value,
+                charge,
+                tick: self.tick,
+            },
+        );
+        self.usage += charge;
+
+        // always keep the newest entry, even if it's bigger than the shard
+        while self.usage > self.capacity && self.map.len() > 1 {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.map.remove(&oldest) {
+                self.usage -= entry.charge;
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(entry) = self.map.remove(key) {
+            self.order.remove(&entry.tick);
+            self.usage -= entry.charge;
+        }
+    }
+}
+
+/// A capacity bounded LRU, split into independently locked shards so
+/// concurrent readers rarely contend. Values are handed out as `Arc`s,
+/// so an evicted entry stays alive for anyone still using it.
+#[derive(Debug)]
+struct ShardedLru<K, V> {
+    shards: Vec<Mutex<LruShard<K, V>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<K: Hash + Eq + Clone, V> ShardedLru<K, V> {
+    fn new(capacity: usize, shard_count: usize) -> Self {
+        let shard_count = shard_count.max(1);
+        let shards = (0..shard_count)
+            .map(|_| {
+                Mutex::new(LruShard {
+                    map: HashMap::new(),
+                    order: BTreeMap::new(),
+                    usage: 0,
+                    capacity: capacity.div_ceil(shard_count),
+                    tick: 0,
+                })
+            })
+            .collect();
+
+        Self {
+            shards,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<LruShard<K, V>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// Look up `key`, calling `load` to fill the entry on a miss.
+    /// The shard isn't locked while loading, so two threads missing on the
+    /// same key may both load it, and the last one to finish wins.
+    fn get_or_load(&self, key: &K, load: impl FnOnce() -> Result<(V, usize)>) -> Result<Arc<V>> {
+        let cached = self.shard(key).lock().unwrap().get(key);
+        if let Some(value) = cached {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(value);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let (value, charge) = load()?;
+        let value = Arc::new(value);
+        self.shard(key)
+            .lock()
+            .unwrap()
+            .insert(key.clone(), value.clone(), charge);
+
+        Ok(value)
+    }
+
+    fn insert(&self, key: K, value: Arc<V>, charge: usize) {
+        self.shard(&key).lock().unwrap().insert(key, value, charge);
+    }
+
+    fn remove(&self, key: &K) {
+        self.shard(key).lock().unwrap().remove(key);
+    }
+
+    fn usage(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().usage).sum()
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Decoded SSTable data blocks, bounded by their uncompressed size in bytes.
+///
+/// Wrap it in an `Arc` and hand it to several trees through
+/// `Options::block_cache` to share one memory budget between them.
+#[derive(Debug)]
+pub struct BlockCache {
+    lru: ShardedLru<(u64, u64), Block>,
+}
+
+impl BlockCache {
+    const SHARD_COUNT: usize = 16;
+
+    pub fn new(capacity_bytes: usize) -> Self {
+        Self {
+            lru: ShardedLru::new(capacity_bytes, Self::SHARD_COUNT),
+        }
+    }
+
+    /// Blocks are keyed by their table's process-unique cache id and the
+    /// block's offset in the file
+    pub(crate) fn get_or_load(
+        &self,
+        cache_id: u64,
+        offset: u64,
+        load: impl FnOnce() -> Result<(Block, usize)>,
+    ) -> Result<Arc<Block>> {
+        self.lru.get_or_load(&(cache_id, offset), load)
+    }
+
+    /// Bytes currently held
+    pub fn usage(&self) -> usize {
+        self.lru.usage()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.lru.stats()
+    }
+}
+
+/// Open SSTable readers (file handle plus parsed index), bounded by the
+/// number of tables kept open at once.
+///
+/// Like `BlockCache` it can be shared between trees via `Options::table_cache`.
+#[derive(Debug)]
+pub struct TableCache {
+    lru: ShardedLru<PathBuf, TableReader>,
+}
+
+impl TableCache {
+    // a single shard keeps the open table limit exact, and lookups are cheap
+    const SHARD_COUNT: usize = 1;
+
+    pub fn new(max_open_tables: usize) -> Self {
+        Self {
+            lru: ShardedLru::new(max_open_tables, Self::SHARD_COUNT),
+        }
+    }
+
+    /// Open `path` if it isn't already, with blocks cached under `cache_id`
+    pub(crate) fn get_or_open(&self, path: &Path, cache_id: u64) -> Result<Arc<TableReader>> {
+        self.lru.get_or_load(&path.to_path_buf(), || {
+            Ok((TableReader::open(path, cache_id)?, 1))
+        })
+    }
+
+    /// Seed the cache with a reader for a table that was just written
+    pub(crate) fn insert(&self, path: &Path, reader: TableReader) {
+        self.lru.insert(path.to_path_buf(), Arc::new(reader), 1);
+    }
+
+    /// Drop the reader for a table that was deleted
+    pub(crate) fn evict(&self, path: &Path) {
+        self.lru.remove(&path.to_path_buf());
+    }
+
+    /// Number of tables currently open
+    pub fn usage(&self) -> usize {
+        self.lru.usage()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.lru.stats()
+    }
+}
+
+/// The caches a tree reads its SSTables through
+#[derive(Debug, Clone)]
+pub struct Caches {
+    pub block: Arc<BlockCache>,
+    pub table: Arc<TableCache>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LsmTree, Options};
+
+    #[test]
+    fn least_recently_used_goes_first() {
+        let lru: ShardedLru<u32, &str> = ShardedLru::new(3, 1);
+        for key in 1..=3 {
+            lru.insert(key, Arc::new("value"), 1);
+        }
+        // 1 is now the most recently used
+        let loaded = lru.get_or_load(&1, || unreachable!()).unwrap();
+        assert_eq!(*loaded, "value");
+        lru.insert(4, Arc::new("value"), 1);
+
+        let mut shard = lru.shards[0].lock().unwrap();
+        assert!(shard.get(&2).is_none());
+        for key in [1, 3, 4] {
+            assert!(shard.get(&key).is_some());
+        }
+        assert_eq!(shard.usage, 3);
+    }
+
+    #[test]
+    fn charges_bound_usage() {
+        let lru: ShardedLru<u32, ()> = ShardedLru::new(100, 1);
+        lru.insert(1, Arc::new(()), 60);
+        lru.insert(2, Arc::new(()), 60);
+        assert_eq!(lru.usage(), 60);
+        // an entry bigger than the whole cache is still kept, on its own
+        lru.insert(3, Arc::new(()), 500);
+        assert_eq!(lru.usage(), 500);
+        lru.remove(&3);
+        assert_eq!(lru.usage(), 0);
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let lru: ShardedLru<u32, u32> = ShardedLru::new(10, 4);
+        let mut loads = 0;
+        for _ in 0..3 {
+            let value = lru
+                .get_or_load(&7, || {
+                    loads += 1;
+                    Ok((49, 1))
+                })
+                .unwrap();
+            assert_eq!(*value, 49);
+        }
+        assert_eq!(loads, 1);
+        assert_eq!(lru.stats(), CacheStats { hits: 2, misses: 1 });
+        assert!(lru.get_or_load(&8, || anyhow::bail!("no")).is_err());
+    }
+
+    #[test]
+    fn trees_can_share_caches() {
+        let dir = tempfile::tempdir().unwrap();
+        let block_cache = Arc::new(BlockCache::new(64 * 1024));
+        let table_cache = Arc::new(TableCache::new(2));
+        let options = Options {
+            block_cache: Some(block_cache.clone()),
+            table_cache: Some(table_cache.clone()),
+            ..Options::default()
+        };
+        let trees: Vec<LsmTree> = ["a", "b"]
+            .iter()
+            .map(|name| LsmTree::open_with_options(&dir.path().join(name), options.clone()))
+            .collect::<Result<_>>()
+            .unwrap();
+
+        for (fill, tree) in trees.iter().enumerate() {
+            for i in 0..3000 {
+                let key = format!("key{:05}", i).into_bytes();
+                tree.put(key, vec![fill as u8; 100]).unwrap();
+            }
+            tree.flush().unwrap();
+        }
+        for _ in 0..2 {
+            for (fill, tree) in trees.iter().enumerate() {
+                for i in (0..3000).step_by(7) {
+                    let key = format!("key{:05}", i).into_bytes();
+                    assert_eq!(tree.get(&key).unwrap(), Some(vec![fill as u8; 100]));
+                }
+            }
+        }
+
+        assert!(table_cache.usage() <= 2);
+        assert!(block_cache.usage() <= 64 * 1024 + 16 * LsmTree::MAX_ENTRY_SIZE);
+        let stats = trees[0].stats().unwrap();
+        assert!(stats.block_cache.hits > 0);
+        assert!(stats.block_cache.misses > 0);
+        assert_eq!(stats.block_cache, block_cache.stats());
+    }
+
+    #[test]
+    fn blocks_outlive_their_table_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let block_cache = Arc::new(BlockCache::new(1024 * 1024));
+        let options = Options {
+            block_cache: Some(block_cache.clone()),
+            table_cache: Some(Arc::new(TableCache::new(1))),
+            ..Options::default()
+        };
+        let tree = LsmTree::open_with_options(dir.path(), options).unwrap();
+        for key in ["a", "b"] {
+            tree.put(key.as_bytes().to_vec(), b"value".to_vec())
+                .unwrap();
+            tree.flush().unwrap();
+        }
+
+        // each read closes the other table, but its block stays cached
+        tree.get(b"a").unwrap();
+        tree.get(b"b").unwrap();
+        let before = block_cache.stats();
+        for _ in 0..3 {
+            assert_eq!(tree.get(b"a").unwrap(), Some(b"value".to_vec()));
+            assert_eq!(tree.get(b"b").unwrap(), Some(b"value".to_vec()));
+        }
+        let after = block_cache.stats();
+        assert_eq!(after.misses, before.misses);
+        assert_eq!(after.hits, before.hits + 6);
+    }
+}
@@ -0,0 +1,398 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+
+use crate::{SSTable, StoredValue};
+
+/// Which SSTables a compaction should merge
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum CompactionInputs<'a> {
+    All,
+    /// Every level 0 table, and the level 1 tables sharing keys with them
+    Level0,
+    /// Tables that may hold keys in `[start, end)`
+    Range(&'a [u8], &'a [u8]),
+    /// Tables with these ids
+    Files(&'a [u32]),
+}
+
+/// How far along a running compaction is, see `Stats::compaction`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionProgress {
+    pub input_tables: usize,
+    pub input_bytes: u64,
+    /// Input tables merged so far
+    pub merged_tables: usize,
+    /// Size of the input tables merged so far
+    pub merged_bytes: u64,
+}
+
+/// Inputs picked out of a compaction's snapshot of the tree's tables
+#[derive(Debug)]
+pub(crate) struct PickedInputs {
+    /// Parallel to the snapshot (so also oldest first)
+    pub(crate) selected: Vec<bool>,
+    /// No table outside the inputs is older than them and shares any keys
+    /// with them, so tombstones have nothing left to hide
+    pub(crate) bottommost: bool,
+}
+
+/// Closed key interval, possibly wider than the keys actually stored
+type KeySpan = (Vec<u8>, Vec<u8>);
+
+fn overlaps(a: &KeySpan, b: &KeySpan) -> bool {
+    a.0 <= b.1 && b.0 <= a.1
+}
+
+fn extend(span: &mut KeySpan, other: &KeySpan) {
+    if other.0 < span.0 {
+        span.0.clone_from(&other.0);
+    }
+    if other.1 > span.1 {
+        span.1.clone_from(&other.1);
+    }
+}
+
+fn union(span: &mut Option<KeySpan>, other: &KeySpan) {
+    match span {
+        Some(span) => extend(span, other),
+        None => *span = Some(other.clone()),
+    }
+}
+
+/// Pick the inputs for `inputs` out of `tables` (every table, oldest first).
+///
+/// Tables may overlap, and which of them wins on a key is decided by age
+/// alone, so the output has to take the place of the newest input. Any
+/// newer table sharing keys with the inputs is therefore pulled in as well:
+/// otherwise the older values merged into the output would start shadowing
+/// it. Returns `None` if there is nothing to compact.
+pub(crate) fn pick_inputs(
+    tables: &[Arc<SSTable>],
+    inputs: CompactionInputs,
+) -> Result<Option<PickedInputs>> {
+    if let CompactionInputs::All = inputs {
+        return Ok((!tables.is_empty()).then(|| PickedInputs {
+            selected: vec![true; tables.len()],
+            bottommost: true,
+        }));
+    }
+
+    let spans: Vec<_> = tables.iter().map(|table| table.key_span()).collect();
+
+    let mut selected = vec![false; tables.len()];
+    let mut span = None;
+    match inputs {
+        CompactionInputs::All => unreachable!("handled above"),
+        CompactionInputs::Level0 => {
+            for (i, table) in tables.iter().enumerate() {
+                if table.level() == 0 {
+                    selected[i] = true;
+                    if let Some(table_span) = &spans[i] {
+                        union(&mut span, table_span);
+                    }
+                }
+            }
+            let Some(level0_span) = span.clone() else {
+                return Ok(None);
+            };
+            for (i, table) in tables.iter().enumerate() {
+                if table.level() == 1
+                    && spans[i].as_ref().is_some_and(|s| overlaps(s, &level0_span))
+                {
+                    selected[i] = true;
+                    union(&mut span, spans[i].as_ref().unwrap());
+                }
+            }
+        }
+        CompactionInputs::Range(start, end) => {
+            if start >= end {
+                return Ok(None);
+            }
+            // `end` is exclusive, but treating it as inclusive only ever
+            // picks one table too many
+            let range = (start.to_vec(), end.to_vec());
+            for (i, table_span) in spans.iter().enumerate() {
+                if let Some(table_span) = table_span.as_ref().filter(|s| overlaps(s, &range)) {
+                    selected[i] = true;
+                    union(&mut span, table_span);
+                }
+            }
+        }
+        CompactionInputs::Files(ids) => {
+            for &id in ids {
+                let Some(i) = tables.iter().position(|table| table.id() == id) else {
+                    bail!("No SSTable with id {}", id);
+                };
+                selected[i] = true;
+                if let Some(table_span) = &spans[i] {
+                    union(&mut span, table_span);
+                }
+            }
+        }
+    }
+
+    let Some(first) = selected.iter().position(|&selected| selected) else {
+        return Ok(None);
+    };
+    if let Some(span) = &mut span {
+        for i in first..tables.len() {
+            if let Some(table_span) = spans[i].as_ref().filter(|s| overlaps(s, span)) {
+                if !selected[i] {
+                    selected[i] = true;
+                    extend(span, table_span);
+                }
+            }
+        }
+    }
+
+    let last = selected
+        .iter()
+        .rposition(|&selected| selected)
+        .unwrap_or(first);
+    let bottommost = match &span {
+        Some(span) => (0..last).all(|i| {
+            selected[i]
+                || spans[i]
+                    .as_ref()
+                    .is_none_or(|table_span| !overlaps(table_span, span))
+        }),
+        None => true,
+    };
+
+    Ok(Some(PickedInputs {
+        selected,
+        bottommost,
+    }))
+}
+
+type Entries<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, StoredValue)>> + 'a>;
+
+/// The entries of a compaction's input tables (oldest first), merged in key
+/// order a block at a time: only the newest version of each key, and none
+/// that a newer table's range tombstone hides
+pub(crate) struct MergedEntries<'a> {
+    tables: &'a [Arc<SSTable>],
+    entries: Vec<Entries<'a>>,
+    // the next entry of each table, `None` once it has run out
+    heads: Vec<Option<(Vec<u8>, StoredValue)>>,
+    started: bool,
+}
+
+impl<'a> MergedEntries<'a> {
+    pub(crate) fn new(tables: &'a [Arc<SSTable>]) -> Result<Self> {
+        let mut entries = Vec::with_capacity(tables.len());
+        for table in tables {
+            entries.push(Box::new(table.iter()?) as Entries);
+        }
+        Ok(Self {
+            tables,
+            entries,
+            heads: Vec::new(),
+            started: false,
+        })
+    }
+
+    /// Tables with nothing left to merge
+    pub(crate) fn finished_tables(&self) -> impl Iterator<Item = &Arc<SSTable>> {
+        self.tables
+            .iter()
+            .zip(&self.heads)
+            .filter(|(_, head)| self.started && head.is_none())
+            .map(|(table, _)| table)
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(Vec<u8>, StoredValue)>> {
+        if !self.started {
+            for entries in &mut self.entries {
+                self.heads.push(entries.next().transpose()?);
+            }
+            self.started = true;
+        }
+
+        loop {
+            // on equal keys the newest table's entry wins
+            let mut newest: Option<usize> = None;
+            for (i, head) in self.heads.iter().enumerate() {
+                let Some((key, _)) = head else {
+                    continue;
+                };
+                let is_smaller = match newest {
+                    Some(j) => key <= &self.heads[j].as_ref().unwrap().0,
+                    None => true,
+                };
+                if is_smaller {
+                    newest = Some(i);
+                }
+            }
+            let Some(newest) = newest else {
+                return Ok(None);
+            };
+
+            let (key, value) = self.heads[newest].take().unwrap();
+            self.heads[newest] = self.entries[newest].next().transpose()?;
+            for i in 0..newest {
+                if self.heads[i]
+                    .as_ref()
+                    .is_some_and(|(other, _)| *other == key)
+                {
+                    self.heads[i] = self.entries[i].next().transpose()?;
+                }
+            }
+
+            // a table's range tombstones hide what older tables had, but
+            // not its own entries
+            let hidden = self.tables[newest + 1..]
+                .iter()
+                .any(|table| table.covering_range_tombstone(&key).is_some());
+            if !hidden {
+                return Ok(Some((key, value)));
+            }
+        }
+    }
+}
+
+impl Iterator for MergedEntries<'_> {
+    type Item = Result<(Vec<u8>, StoredValue)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{LsmTree, Options};
+
+    /// Without the compaction worker, which would otherwise race the
+    /// compactions under test once a fourth table is flushed
+    fn open(path: &Path) -> LsmTree {
+        let options = Options {
+            l0_compaction_trigger: 100,
+            l0_slowdown_writes_trigger: 100,
+            l0_stop_writes_trigger: 100,
+            ..Options::default()
+        };
+        LsmTree::open_with_options(path, options).unwrap()
+    }
+
+    /// Flush one table holding `keys`, with values saying which table it is
+    fn flush_table(tree: &LsmTree, keys: &[&str], table: &str) -> u32 {
+        for key in keys {
+            tree.put(key.as_bytes().to_vec(), table.as_bytes().to_vec())
+                .unwrap();
+        }
+        tree.flush().unwrap();
+        tree.sstables().last().unwrap().id
+    }
+
+    fn ids(tree: &LsmTree) -> Vec<u32> {
+        tree.sstables().iter().map(|table| table.id).collect()
+    }
+
+    #[test]
+    fn ranges_only_compact_overlapping_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = open(dir.path());
+        let a = flush_table(&tree, &["a1", "a2"], "a");
+        let m1 = flush_table(&tree, &["m1", "m2"], "m1");
+        let m2 = flush_table(&tree, &["m2", "m3"], "m2");
+        let z = flush_table(&tree, &["z1", "z2"], "z");
+
+        tree.compact_range(b"m", b"n").unwrap();
+        let after = ids(&tree);
+        assert_eq!(after.len(), 3);
+        // the output goes in as the newest table, fine since none of
+        // those it jumps ahead of share keys with it
+        assert_eq!((after[0], after[1]), (a, z));
+        assert!(![m1, m2].contains(&after[2]));
+        assert_eq!(tree.sstables()[2].properties.entries, 3);
+        assert_eq!(tree.get(b"m2").unwrap(), Some(b"m2".to_vec()));
+
+        // nothing in the range, nothing to do
+        tree.compact_range(b"b", b"l").unwrap();
+        tree.compact_range(b"n", b"m").unwrap();
+        assert_eq!(ids(&tree), after);
+    }
+
+    #[test]
+    fn newer_overlapping_tables_are_merged_along() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = open(dir.path());
+        let first = flush_table(&tree, &["a", "c"], "first");
+        flush_table(&tree, &["b", "d"], "second");
+        let unrelated = flush_table(&tree, &["x", "y"], "third");
+        flush_table(&tree, &["c"], "fourth");
+
+        // `first` alone would shadow "c" from the fourth table once compacted
+        tree.compact_files(&[first]).unwrap();
+        let after = ids(&tree);
+        assert_eq!(after.len(), 2);
+        assert!(after.contains(&unrelated));
+        assert_eq!(tree.get(b"a").unwrap(), Some(b"first".to_vec()));
+        assert_eq!(tree.get(b"b").unwrap(), Some(b"second".to_vec()));
+        assert_eq!(tree.get(b"c").unwrap(), Some(b"fourth".to_vec()));
+
+        assert!(tree.compact_files(&[12345]).is_err());
+    }
+
+    #[test]
+    fn tombstones_stay_while_older_tables_are_left_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = open(dir.path());
+        let old = flush_table(&tree, &["k", "l"], "old");
+        tree.delete(b"k".to_vec()).unwrap();
+        tree.delete_range(b"l".to_vec(), b"m".to_vec()).unwrap();
+        let deletes = flush_table(&tree, &[], "");
+
+        tree.compact_files(&[deletes]).unwrap();
+        let properties = &tree.sstables()[1].properties;
+        assert_eq!((properties.tombstones, properties.range_tombstones), (1, 1));
+        assert_eq!(tree.get(b"k").unwrap(), None);
+        assert_eq!(tree.get(b"l").unwrap(), None);
+
+        // with the older table in, there's nothing left to hide
+        let newest = tree.sstables()[1].id;
+        tree.compact_files(&[old, newest]).unwrap();
+        let properties = &tree.sstables()[0].properties;
+        assert_eq!((properties.entries, properties.range_tombstones), (0, 0));
+        assert_eq!(tree.get(b"k").unwrap(), None);
+    }
+
+    #[test]
+    fn outputs_are_split_unless_range_tombstones_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            target_file_size: 16 * 1024,
+            l0_compaction_trigger: 100,
+            l0_slowdown_writes_trigger: 100,
+            l0_stop_writes_trigger: 100,
+            ..Options::default()
+        };
+        let tree = LsmTree::open_with_options(dir.path(), options).unwrap();
+        let key = |i: u32| format!("key{:05}", i).into_bytes();
+        for i in 0..1000 {
+            tree.put(key(i), vec![1; 100]).unwrap();
+        }
+        tree.flush().unwrap();
+        tree.delete_range(key(10), key(20)).unwrap();
+        let deletes = flush_table(&tree, &[], "");
+
+        // the range tombstone has older keys to hide
+        tree.compact_files(&[deletes]).unwrap();
+        assert_eq!(tree.sstables().len(), 2);
+
+        tree.compact_all().unwrap();
+        let tables = tree.sstables();
+        assert!(tables.len() >= 6, "{}", tables.len());
+        for pair in tables.windows(2) {
+            assert!(pair[0].properties.largest_key < pair[1].properties.smallest_key);
+        }
+        let entries: u64 = tables.iter().map(|table| table.properties.entries).sum();
+        assert_eq!(entries, 990);
+        assert_eq!(tree.get(&key(15)).unwrap(), None);
+        assert_eq!(tree.get(&key(999)).unwrap(), Some(vec![1; 100]));
+    }
+}
@@ -0,0 +1,171 @@
+use std::fmt;
+
+/// What a `CompactionFilter` wants done with an entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    Keep,
+    /// Drop the entry, as if it had been deleted
+    Remove,
+    /// Keep the key, but with this value instead
+    ChangeValue(Vec<u8>),
+}
+
+/// What the compaction calling a filter is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionFilterContext {
+    /// Level of the table the entry is being written to
+    pub output_level: u8,
+    /// Whether every SSTable in the tree is an input, so this is the
+    /// oldest version of the key anywhere and removing it can't uncover
+    /// an older value
+    pub is_full_compaction: bool,
+}
+
+/// Hook into compaction to drop or rewrite entries, e.g. to expire
+/// application-level TTLs without a separate sweep.
+///
+/// It is called once per live key in the compaction output, with the
+/// newest value (separated values are read back from their blob file
+/// first, so filtering a blob heavy tree is much more I/O). Deleted keys
+/// and memtable contents aren't seen: a value only reaches the filter once
+/// it has been flushed and is being compacted. Set it through
+/// `Options::compaction_filter`.
+pub trait CompactionFilter: Send + Sync + fmt::Debug {
+    fn filter(&self, context: &CompactionFilterContext, key: &[u8], value: &[u8])
+        -> FilterDecision;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{LsmTree, Options};
+
+    /// Removes `expired*`, upper-cases `upper*` and blows `big*` up to 100 KB
+    #[derive(Debug, Default)]
+    struct TestFilter {
+        calls: Mutex<Vec<(CompactionFilterContext, Vec<u8>)>>,
+    }
+
+    impl CompactionFilter for TestFilter {
+        fn filter(
+            &self,
+            context: &CompactionFilterContext,
+            key: &[u8],
+            value: &[u8],
+        ) -> FilterDecision {
+            self.calls.lock().unwrap().push((*context, key.to_vec()));
+            if key.starts_with(b"expired") {
+                FilterDecision::Remove
+            } else if key.starts_with(b"upper") {
+                FilterDecision::ChangeValue(value.to_ascii_uppercase())
+            } else if key.starts_with(b"big") {
+                FilterDecision::ChangeValue(vec![b'x'; 100 * 1024])
+            } else {
+                FilterDecision::Keep
+            }
+        }
+    }
+
+    fn open(path: &std::path::Path, blob_threshold: Option<usize>) -> (LsmTree, Arc<TestFilter>) {
+        let filter = Arc::new(TestFilter::default());
+        let options = Options {
+            compaction_filter: Some(filter.clone()),
+            blob_threshold,
+            ..Options::default()
+        };
+        (LsmTree::open_with_options(path, options).unwrap(), filter)
+    }
+
+    fn put(tree: &LsmTree, key: &str, value: &str) {
+        tree.put(key.as_bytes().to_vec(), value.as_bytes().to_vec())
+            .unwrap();
+    }
+
+    fn get(tree: &LsmTree, key: &str) -> Option<Vec<u8>> {
+        tree.get(key.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn decisions_apply_once_compacted() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tree, filter) = open(dir.path(), None);
+        put(&tree, "expired-1", "value");
+        put(&tree, "upper-1", "value");
+        put(&tree, "kept-1", "value");
+        put(&tree, "deleted", "value");
+        tree.flush().unwrap();
+        tree.delete(b"deleted".to_vec()).unwrap();
+        tree.flush().unwrap();
+        put(&tree, "expired-2", "unflushed");
+
+        // flushes leave values alone
+        assert!(filter.calls.lock().unwrap().is_empty());
+        assert_eq!(get(&tree, "expired-1"), Some(b"value".to_vec()));
+
+        tree.compact_all().unwrap();
+        assert_eq!(get(&tree, "expired-1"), None);
+        assert_eq!(get(&tree, "upper-1"), Some(b"VALUE".to_vec()));
+        assert_eq!(get(&tree, "kept-1"), Some(b"value".to_vec()));
+        assert_eq!(get(&tree, "deleted"), None);
+        // memtable contents aren't compacted
+        assert_eq!(get(&tree, "expired-2"), Some(b"unflushed".to_vec()));
+
+        let calls = filter.calls.lock().unwrap();
+        let keys: Vec<_> = calls.iter().map(|(_, key)| key.as_slice()).collect();
+        assert_eq!(keys, [&b"expired-1"[..], b"kept-1", b"upper-1"]);
+        let context = CompactionFilterContext {
+            output_level: 1,
+            is_full_compaction: true,
+        };
+        assert!(calls.iter().all(|(call, _)| *call == context));
+        drop(calls);
+
+        drop(tree);
+        let (tree, _) = open(dir.path(), None);
+        assert_eq!(get(&tree, "expired-1"), None);
+        assert_eq!(get(&tree, "upper-1"), Some(b"VALUE".to_vec()));
+    }
+
+    #[test]
+    fn removing_from_some_tables_hides_older_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tree, filter) = open(dir.path(), None);
+        put(&tree, "expired", "old");
+        tree.flush().unwrap();
+        put(&tree, "expired", "new");
+        tree.flush().unwrap();
+
+        let newest = tree.sstables().last().unwrap().id;
+        tree.compact_files(&[newest]).unwrap();
+        let (context, _) = filter.calls.lock().unwrap()[0];
+        assert!(!context.is_full_compaction);
+        // removed like a delete, the older value doesn't resurface
+        assert_eq!(get(&tree, "expired"), None);
+        assert_eq!(tree.sstables()[1].properties.tombstones, 1);
+
+        tree.compact_all().unwrap();
+        assert_eq!(get(&tree, "expired"), None);
+        assert_eq!(tree.sstables()[0].properties.entries, 0);
+    }
+
+    #[test]
+    fn large_changed_values_go_to_blob_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tree, _) = open(dir.path(), None);
+        put(&tree, "big", "small");
+        tree.flush().unwrap();
+        // too big to inline without blob files
+        assert!(tree.compact_all().is_err());
+        assert_eq!(get(&tree, "big"), Some(b"small".to_vec()));
+        drop(tree);
+
+        let (tree, _) = open(dir.path(), Some(1024));
+        tree.compact_all().unwrap();
+        assert_eq!(get(&tree, "big"), Some(vec![b'x'; 100 * 1024]));
+        drop(tree);
+        let (tree, _) = open(dir.path(), Some(1024));
+        assert_eq!(get(&tree, "big"), Some(vec![b'x'; 100 * 1024]));
+    }
+}
@@ -0,0 +1,166 @@
+use anyhow::{bail, Result};
+
+/// Codec used for SSTable data blocks.
+///
+/// Every block starts with a `<u8 codec id><u32 uncompressed length>` header,
+/// so tables written with different codecs (or by levels configured
+/// differently) can always be read back, as long as the codec's cargo
+/// feature is enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    /// LZ4 block format (requires the `lz4` feature)
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// Zstandard at the given compression level (requires the `zstd` feature)
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+impl Compression {
+    const NONE_ID: u8 = 0;
+    const LZ4_ID: u8 = 1;
+    const ZSTD_ID: u8 = 2;
+    pub const HEADER_SIZE: usize = 1 + 4;
+
+    /// Append `raw` to `buf` as a block (header plus payload).
+    /// Falls back to storing the block uncompressed if the codec doesn't
+    /// actually make it smaller.
+    pub fn compress_block(&self, raw: &[u8], buf: &mut Vec<u8>) -> Result<()> {
+        let compressed: Option<(u8, Vec<u8>)> = match self {
+            Compression::None => None,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some((Self::LZ4_ID, lz4_flex::block::compress(raw))),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => Some((Self::ZSTD_ID, zstd::bulk::compress(raw, *level)?)),
+        };
+
+        let (codec_id, payload) = match &compressed {
+            Some((codec_id, payload)) if payload.len() < raw.len() => {
+                (*codec_id, payload.as_slice())
+            }
+            _ => (Self::NONE_ID, raw),
+        };
+
+        buf.push(codec_id);
+        buf.extend_from_slice(&(raw.len() as u32).to_le_bytes());
+        buf.extend_from_slice(payload);
+
+        Ok(())
+    }
+
+    /// Decode a block written by `compress_block` back into its raw bytes
+    pub fn decompress_block(block: &[u8]) -> Result<Vec<u8>> {
+        if block.len() < Self::HEADER_SIZE {
+            bail!("Corrupt block: missing header");
+        }
+        let codec_id = block[0];
+        let raw_length = u32::from_le_bytes(block[1..5].try_into()?) as usize;
+        let payload = &block[Self::HEADER_SIZE..];
+
+        let raw = match codec_id {
+            Self::NONE_ID => payload.to_vec(),
+            #[cfg(feature = "lz4")]
+            Self::LZ4_ID => lz4_flex::block::decompress(payload, raw_length)?,
+            #[cfg(feature = "zstd")]
+            Self::ZSTD_ID => zstd::bulk::decompress(payload, raw_length)?,
+            #[cfg(not(feature = "lz4"))]
+            Self::LZ4_ID => bail!("Block is lz4 compressed, but the `lz4` feature is disabled"),
+            #[cfg(not(feature = "zstd"))]
+            Self::ZSTD_ID => bail!("Block is zstd compressed, but the `zstd` feature is disabled"),
+            other => bail!("Corrupt block: unknown codec id {}", other),
+        };
+
+        if raw.len() != raw_length {
+            bail!(
+                "Corrupt block: expected {} bytes after decompression, got {}",
+                raw_length,
+                raw.len()
+            );
+        }
+
+        Ok(raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LsmTree, Options};
+
+    fn codecs() -> Vec<Compression> {
+        vec![
+            Compression::None,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(3),
+        ]
+    }
+
+    #[test]
+    fn blocks_round_trip() {
+        let compressible = b"the same few words, ".repeat(200);
+        let mut state = 1u64;
+        let incompressible: Vec<u8> = (0..4096)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (state >> 56) as u8
+            })
+            .collect();
+
+        for codec in codecs() {
+            for raw in [&compressible, &incompressible, &Vec::new()] {
+                let mut block = Vec::new();
+                codec.compress_block(raw, &mut block).unwrap();
+                // never bigger than storing it as is
+                assert!(block.len() <= Compression::HEADER_SIZE + raw.len());
+                assert_eq!(&Compression::decompress_block(&block).unwrap(), raw);
+            }
+        }
+    }
+
+    #[test]
+    fn corrupt_blocks_are_rejected() {
+        assert!(Compression::decompress_block(&[0, 1]).is_err());
+        // unknown codec id
+        assert!(Compression::decompress_block(&[9, 0, 0, 0, 0]).is_err());
+        // fewer bytes than the header promises
+        let mut block = Vec::new();
+        Compression::None
+            .compress_block(b"hello", &mut block)
+            .unwrap();
+        block.pop();
+        assert!(Compression::decompress_block(&block).is_err());
+    }
+
+    #[test]
+    fn levels_use_their_own_codec() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            compression_per_level: codecs().into_iter().rev().collect(),
+            ..Options::default()
+        };
+        let tree = LsmTree::open_with_options(dir.path(), options).unwrap();
+        let value = |i: usize| format!("{{\"id\":{},\"name\":\"user number {}\"}}", i, i);
+        for i in 0..2000 {
+            let key = format!("user/{:05}", i).into_bytes();
+            tree.put(key, value(i).into_bytes()).unwrap();
+        }
+        tree.flush().unwrap();
+        tree.compact_all().unwrap();
+        let stats = tree.stats().unwrap();
+        if codecs().len() > 1 {
+            assert!(stats.compression_ratio() > 1.0);
+        }
+        drop(tree);
+
+        // codecs are recorded per block, so any options can read them back
+        let tree = LsmTree::open(dir.path()).unwrap();
+        for i in (0..2000).step_by(97) {
+            let key = format!("user/{:05}", i).into_bytes();
+            assert_eq!(tree.get(&key).unwrap(), Some(value(i).into_bytes()));
+        }
+    }
+}
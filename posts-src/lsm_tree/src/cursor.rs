@@ -0,0 +1,651 @@
+use std::{ops::Bound, sync::Arc};
+
+use anyhow::Result;
+
+use crate::{range_tombstone, BlobStore, Memtable, RangeTombstone, ReadView, SSTable, StoredValue};
+
+/// A positioned reader over one sorted source (a memtable or a single SSTable)
+trait SourceCursor {
+    /// Position at the first entry with a key `>= key`
+    fn seek(&mut self, key: &[u8]) -> Result<()>;
+    /// Position at the last entry with a key `<= key`
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()>;
+    fn seek_to_first(&mut self) -> Result<()>;
+    fn seek_to_last(&mut self) -> Result<()>;
+    fn next(&mut self) -> Result<()>;
+    fn prev(&mut self) -> Result<()>;
+    /// `None` once the cursor has run off either end
+    fn key(&self) -> Option<&[u8]>;
+    /// Value under the cursor (`None` if deleted), only called while `key()` is `Some`
+    fn value(&self) -> Result<Option<Vec<u8>>>;
+    /// A range tombstone of this source hiding `key` in older sources
+    fn covering_range_tombstone(&self, key: &[u8]) -> Option<&RangeTombstone>;
+}
+
+/// The entry under the cursor is copied out, so nothing is borrowed from
+/// the (shared, possibly frozen) memtable
+struct MemtableCursor {
+    memtable: Arc<Memtable>,
+    // the view's sequence, newer writes are skipped
+    sequence: u64,
+    // the ones visible at `sequence`, taken up front
+    range_tombstones: Vec<RangeTombstone>,
+    current: Option<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl SourceCursor for MemtableCursor {
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.current = self.memtable.seek(Bound::Included(key), self.sequence);
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        self.current = self
+            .memtable
+            .seek_for_prev(Bound::Included(key), self.sequence);
+        Ok(())
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.current = self.memtable.seek(Bound::Unbounded, self.sequence);
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.current = self.memtable.seek_for_prev(Bound::Unbounded, self.sequence);
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if let Some((key, _)) = self.current.take() {
+            self.current = self
+                .memtable
+                .seek(Bound::Excluded(key.as_slice()), self.sequence);
+        }
+        Ok(())
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        if let Some((key, _)) = self.current.take() {
+            self.current = self
+                .memtable
+                .seek_for_prev(Bound::Excluded(key.as_slice()), self.sequence);
+        }
+        Ok(())
+    }
+
+    fn key(&self) -> Option<&[u8]> {
+        self.current.as_ref().map(|(k, _)| k.as_slice())
+    }
+
+    fn value(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.current.as_ref().and_then(|(_, v)| v.clone()))
+    }
+
+    fn covering_range_tombstone(&self, key: &[u8]) -> Option<&RangeTombstone> {
+        range_tombstone::covering(&self.range_tombstones, key)
+    }
+}
+
+/// Walks an SSTable one decoded block at a time, so only the block
+/// under the cursor is ever held in memory
+struct SSTableCursor {
+    table: Arc<SSTable>,
+    blobs: Arc<BlobStore>,
+    block_idx: usize,
+    // decoded up front, since keys can't be read backwards out of a
+    // prefix compressed block
+    entries: Vec<(Vec<u8>, StoredValue)>,
+    pos: Option<usize>,
+}
+
+impl SSTableCursor {
+    fn load_block(&mut self, block_idx: usize) -> Result<()> {
+        if block_idx != self.block_idx || self.entries.is_empty() {
+            self.entries = self.table.read_block(block_idx)?.decode()?;
+            self.block_idx = block_idx;
+        }
+        Ok(())
+    }
+}
+
+impl SourceCursor for SSTableCursor {
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        // the table's properties often rule it out without touching the file
+        let properties = self.table.properties();
+        if properties.entries == 0 || key > properties.largest_key.as_slice() {
+            self.pos = None;
+            return Ok(());
+        }
+
+        let block_idx = self.table.find_block(key)?;
+        if block_idx == self.table.block_count()? {
+            self.pos = None;
+            return Ok(());
+        }
+
+        // the block's last key is `>= key`, so there is always a match here
+        self.load_block(block_idx)?;
+        self.pos = Some(self.entries.partition_point(|(k, _)| k.as_slice() < key));
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        let properties = self.table.properties();
+        if properties.entries == 0 || key < properties.smallest_key.as_slice() {
+            self.pos = None;
+            return Ok(());
+        }
+
+        let block_idx = self.table.find_block(key)?;
+        if block_idx == self.table.block_count()? {
+            return self.seek_to_last();
+        }
+
+        self.load_block(block_idx)?;
+        match self.entries.partition_point(|(k, _)| k.as_slice() <= key) {
+            // everything in this block is past `key`, so step back a block
+            0 => {
+                self.pos = Some(0);
+                self.prev()
+            }
+            n => {
+                self.pos = Some(n - 1);
+                Ok(())
+            }
+        }
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        if self.table.block_count()? == 0 {
+            self.pos = None;
+            return Ok(());
+        }
+        self.load_block(0)?;
+        self.pos = Some(0);
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        match self.table.block_count()? {
+            0 => self.pos = None,
+            n => {
+                self.load_block(n - 1)?;
+                self.pos = self.entries.len().checked_sub(1);
+            }
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<()> {
+        let Some(pos) = self.pos else {
+            return Ok(());
+        };
+
+        if pos + 1 < self.entries.len() {
+            self.pos = Some(pos + 1);
+        } else if self.block_idx + 1 < self.table.block_count()? {
+            self.load_block(self.block_idx + 1)?;
+            self.pos = Some(0);
+        } else {
+            self.pos = None;
+        }
+        Ok(())
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        let Some(pos) = self.pos else {
+            return Ok(());
+        };
+
+        if pos > 0 {
+            self.pos = Some(pos - 1);
+        } else if self.block_idx > 0 {
+            self.load_block(self.block_idx - 1)?;
+            self.pos = self.entries.len().checked_sub(1);
+        } else {
+            self.pos = None;
+        }
+        Ok(())
+    }
+
+    fn key(&self) -> Option<&[u8]> {
+        self.pos
+            .and_then(|pos| self.entries.get(pos))
+            .map(|(k, _)| k.as_slice())
+    }
+
+    fn value(&self) -> Result<Option<Vec<u8>>> {
+        // blob values are only read for the entry the cursor lands on
+        match self.pos.and_then(|pos| self.entries.get(pos)) {
+            Some((_, value)) => value.clone().resolve(&self.blobs),
+            None => Ok(None),
+        }
+    }
+
+    fn covering_range_tombstone(&self, key: &[u8]) -> Option<&RangeTombstone> {
+        self.table.covering_range_tombstone(key)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Backward,
+}
+
+/// A bidirectional cursor over the whole tree (memtables plus every SSTable).
+///
+/// It reads a snapshot of the tree as of `LsmTree::cursor`: later writes
+/// aren't visible, and tables it uses stay on disk until it's dropped.
+///
+/// Each key is surfaced once with its newest value: sources are kept
+/// newest first, so when several of them sit on the same key the first
+/// one wins and the rest are skipped past together. Keys whose newest
+/// value is a tombstone are skipped entirely, and so are keys hidden by a
+/// newer source's range tombstone (jumping the older sources over the
+/// whole range at once).
+///
+/// While moving forward every source sits on its first key `>= key()`,
+/// and while moving backward on its last key `<= key()`. Changing
+/// direction re-seeks the sources to restore that.
+pub struct Cursor {
+    sources: Vec<Box<dyn SourceCursor + Send>>,
+    current: Option<(Vec<u8>, Vec<u8>)>,
+    direction: Direction,
+    // keys from here on are out of sight
+    upper_bound: Option<Vec<u8>>,
+}
+
+impl Cursor {
+    /// `view` lists its sources newest first, which is the order kept here
+    pub(crate) fn new(view: ReadView, blobs: Arc<BlobStore>) -> Self {
+        let mut sources: Vec<Box<dyn SourceCursor + Send>> = Vec::new();
+        for memtable in view.memtables {
+            sources.push(Box::new(MemtableCursor {
+                range_tombstones: memtable.range_tombstones(view.sequence),
+                memtable,
+                sequence: view.sequence,
+                current: None,
+            }));
+        }
+        for table in view.sstables {
+            sources.push(Box::new(SSTableCursor {
+                table,
+                blobs: blobs.clone(),
+                block_idx: 0,
+                entries: Vec::new(),
+                pos: None,
+            }));
+        }
+
+        Self {
+            sources,
+            current: None,
+            direction: Direction::Forward,
+            upper_bound: None,
+        }
+    }
+
+    /// Hide every key `>= upper_bound`
+    pub(crate) fn with_upper_bound(mut self, upper_bound: Vec<u8>) -> Self {
+        self.upper_bound = Some(upper_bound);
+        self
+    }
+
+    /// Whether the cursor is positioned on an entry
+    pub fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    pub fn key(&self) -> Option<&[u8]> {
+        self.current.as_ref().map(|(k, _)| k.as_slice())
+    }
+
+    pub fn value(&self) -> Option<&[u8]> {
+        self.current.as_ref().map(|(_, v)| v.as_slice())
+    }
+
+    /// Position at the first key `>= key`
+    pub fn seek(&mut self, key: &[u8]) -> Result<()> {
+        for source in &mut self.sources {
+            source.seek(key)?;
+        }
+        self.direction = Direction::Forward;
+        self.pick_smallest()
+    }
+
+    /// Position at the last key `<= key`
+    pub fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        let past_bound = self
+            .upper_bound
+            .clone()
+            .filter(|bound| key >= bound.as_slice());
+        if let Some(upper_bound) = past_bound {
+            return self.seek_before(&upper_bound);
+        }
+        for source in &mut self.sources {
+            source.seek_for_prev(key)?;
+        }
+        self.direction = Direction::Backward;
+        self.pick_largest()
+    }
+
+    pub fn seek_to_first(&mut self) -> Result<()> {
+        for source in &mut self.sources {
+            source.seek_to_first()?;
+        }
+        self.direction = Direction::Forward;
+        self.pick_smallest()
+    }
+
+    pub fn seek_to_last(&mut self) -> Result<()> {
+        if let Some(upper_bound) = self.upper_bound.clone() {
+            return self.seek_before(&upper_bound);
+        }
+        for source in &mut self.sources {
+            source.seek_to_last()?;
+        }
+        self.direction = Direction::Backward;
+        self.pick_largest()
+    }
+
+    /// Position at the last key `< key`
+    fn seek_before(&mut self, key: &[u8]) -> Result<()> {
+        for source in &mut self.sources {
+            source.seek_for_prev(key)?;
+            if source.key() == Some(key) {
+                source.prev()?;
+            }
+        }
+        self.direction = Direction::Backward;
+        self.pick_largest()
+    }
+
+    /// Move to the next key. No-op if the cursor isn't valid.
+    // not an `Iterator`: it's fallible and can also walk backwards
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<()> {
+        let Some((key, _)) = self.current.take() else {
+            return Ok(());
+        };
+
+        if self.direction == Direction::Backward {
+            for source in &mut self.sources {
+                source.seek(&key)?;
+            }
+            self.direction = Direction::Forward;
+        }
+
+        // step every source sitting on the current key (including shadowed ones)
+        for source in &mut self.sources {
+            if source.key() == Some(key.as_slice()) {
+                source.next()?;
+            }
+        }
+
+        self.pick_smallest()
+    }
+
+    /// Move to the previous key. No-op if the cursor isn't valid.
+    pub fn prev(&mut self) -> Result<()> {
+        let Some((key, _)) = self.current.take() else {
+            return Ok(());
+        };
+
+        if self.direction == Direction::Forward {
+            for source in &mut self.sources {
+                source.seek_for_prev(&key)?;
+            }
+            self.direction = Direction::Backward;
+        }
+
+        for source in &mut self.sources {
+            if source.key() == Some(key.as_slice()) {
+                source.prev()?;
+            }
+        }
+
+        self.pick_largest()
+    }
+
+    fn pick_smallest(&mut self) -> Result<()> {
+        loop {
+            // `min_by` keeps the first of equal keys, which is the newest source
+            let winner = self
+                .sources
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, source)| source.key().map(|key| (key, idx)))
+                .min_by(|(a, _), (b, _)| a.cmp(b));
+            let Some((key, winner_idx)) = winner.filter(|(key, _)| {
+                self.upper_bound
+                    .as_ref()
+                    .is_none_or(|bound| *key < bound.as_slice())
+            }) else {
+                self.current = None;
+                return Ok(());
+            };
+            let key = key.to_vec();
+
+            if let Some((tombstone_idx, tombstone)) = self.newer_range_tombstone(&key, winner_idx) {
+                // everything older than the tombstone is hidden up to its end
+                for source in &mut self.sources[tombstone_idx + 1..] {
+                    if source.key().is_some_and(|k| k < tombstone.end.as_slice()) {
+                        source.seek(&tombstone.end)?;
+                    }
+                }
+                continue;
+            }
+
+            if let Some(value) = self.sources[winner_idx].value()? {
+                self.current = Some((key, value));
+                return Ok(());
+            }
+
+            // deleted, so step everything past it and look again
+            for source in &mut self.sources {
+                if source.key() == Some(key.as_slice()) {
+                    source.next()?;
+                }
+            }
+        }
+    }
+
+    fn pick_largest(&mut self) -> Result<()> {
+        loop {
+            // `max_by` would keep the last (oldest) of equal keys, so take the
+            // min of the reversed order to still prefer the newest source
+            let winner = self
+                .sources
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, source)| source.key().map(|key| (key, idx)))
+                .min_by(|(a, _), (b, _)| b.cmp(a));
+            let Some((key, winner_idx)) = winner else {
+                self.current = None;
+                return Ok(());
+            };
+            let key = key.to_vec();
+
+            if let Some((tombstone_idx, tombstone)) = self.newer_range_tombstone(&key, winner_idx) {
+                // everything older than the tombstone is hidden back to its start
+                for source in &mut self.sources[tombstone_idx + 1..] {
+                    if source
+                        .key()
+                        .is_some_and(|k| k >= tombstone.start.as_slice())
+                    {
+                        source.seek_for_prev(&tombstone.start)?;
+                        if source.key() == Some(tombstone.start.as_slice()) {
+                            source.prev()?;
+                        }
+                    }
+                }
+                continue;
+            }
+
+            if let Some(value) = self.sources[winner_idx].value()? {
+                self.current = Some((key, value));
+                return Ok(());
+            }
+
+            for source in &mut self.sources {
+                if source.key() == Some(key.as_slice()) {
+                    source.prev()?;
+                }
+            }
+        }
+    }
+
+    /// A range tombstone hiding `key`, from a source newer than the one at
+    /// `source_idx`, along with that source's index
+    fn newer_range_tombstone(
+        &self,
+        key: &[u8],
+        source_idx: usize,
+    ) -> Option<(usize, RangeTombstone)> {
+        self.sources[..source_idx]
+            .iter()
+            .enumerate()
+            .find_map(|(idx, source)| {
+                source
+                    .covering_range_tombstone(key)
+                    .map(|tombstone| (idx, tombstone.clone()))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::LsmTree;
+
+    fn collect_forward(tree: &LsmTree) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut cursor = tree.cursor();
+        cursor.seek_to_first().unwrap();
+        let mut entries = Vec::new();
+        while let (Some(key), Some(value)) = (cursor.key(), cursor.value()) {
+            entries.push((key.to_vec(), value.to_vec()));
+            cursor.next().unwrap();
+        }
+        entries
+    }
+
+    fn collect_backward(tree: &LsmTree) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut cursor = tree.cursor();
+        cursor.seek_to_last().unwrap();
+        let mut entries = Vec::new();
+        while let (Some(key), Some(value)) = (cursor.key(), cursor.value()) {
+            entries.push((key.to_vec(), value.to_vec()));
+            cursor.prev().unwrap();
+        }
+        entries
+    }
+
+    fn pairs(entries: &[(&str, &str)]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        entries
+            .iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn newer_sources_shadow_older_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        for key in ["a", "b", "c", "e"] {
+            tree.put(key.as_bytes().to_vec(), b"1".to_vec()).unwrap();
+        }
+        tree.flush().unwrap();
+        tree.put(b"a".to_vec(), b"2".to_vec()).unwrap();
+        tree.flush().unwrap();
+        // and the newest of all, still in the memtable
+        tree.put(b"b".to_vec(), b"3".to_vec()).unwrap();
+        tree.delete(b"c".to_vec()).unwrap();
+        tree.put(b"d".to_vec(), b"3".to_vec()).unwrap();
+
+        let expected = pairs(&[("a", "2"), ("b", "3"), ("d", "3"), ("e", "1")]);
+        assert_eq!(collect_forward(&tree), expected);
+        let mut reversed = expected.clone();
+        reversed.reverse();
+        assert_eq!(collect_backward(&tree), reversed);
+
+        let mut cursor = tree.cursor();
+        cursor.seek(b"c").unwrap();
+        assert_eq!(cursor.key(), Some(&b"d"[..]));
+        cursor.seek_for_prev(b"c").unwrap();
+        assert_eq!(cursor.key(), Some(&b"b"[..]));
+        // changing direction on the way
+        cursor.prev().unwrap();
+        assert_eq!(cursor.value(), Some(&b"2"[..]));
+        cursor.next().unwrap();
+        cursor.next().unwrap();
+        assert_eq!(cursor.key(), Some(&b"d"[..]));
+        cursor.seek(b"f").unwrap();
+        assert!(!cursor.valid());
+        cursor.seek_for_prev(b"0").unwrap();
+        assert!(!cursor.valid());
+    }
+
+    #[test]
+    fn matches_a_sorted_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        let mut model = BTreeMap::new();
+        let mut random = crate::xorshift(0x2545_f491_4f6c_dd1d_u64);
+
+        for i in 0..4000 {
+            let key = format!("key{:04}", random() % 1000).into_bytes();
+            if random().is_multiple_of(5) {
+                tree.delete(key.clone()).unwrap();
+                model.remove(&key);
+            } else {
+                let value = format!("value{}", i).into_bytes();
+                tree.put(key.clone(), value.clone()).unwrap();
+                model.insert(key, value);
+            }
+            if i == 1500 {
+                tree.flush().unwrap();
+            }
+            if i == 3000 {
+                tree.compact_all().unwrap();
+            }
+        }
+
+        let expected: Vec<_> = model.clone().into_iter().collect();
+        assert_eq!(collect_forward(&tree), expected);
+        assert_eq!(
+            collect_backward(&tree),
+            expected.iter().rev().cloned().collect::<Vec<_>>()
+        );
+
+        // random walks from random seeks
+        let keys: Vec<_> = model.keys().cloned().collect();
+        let mut cursor = tree.cursor();
+        for _ in 0..200 {
+            let target = format!("key{:04}", random() % 1100).into_bytes();
+            let mut position = keys.partition_point(|key| *key < target) as isize;
+            cursor.seek(&target).unwrap();
+            for _ in 0..20 {
+                let expected = usize::try_from(position).ok().and_then(|i| keys.get(i));
+                assert_eq!(cursor.key(), expected.map(|key| key.as_slice()));
+                if expected.is_none() {
+                    break;
+                }
+                if random().is_multiple_of(2) {
+                    cursor.next().unwrap();
+                    position += 1;
+                } else {
+                    cursor.prev().unwrap();
+                    position -= 1;
+                }
+            }
+
+            cursor.seek_for_prev(&target).unwrap();
+            let position = keys.partition_point(|key| *key <= target);
+            let expected = position.checked_sub(1).map(|i| keys[i].as_slice());
+            assert_eq!(cursor.key(), expected);
+        }
+    }
+}
@@ -0,0 +1,3169 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt,
+    fs::{File, OpenOptions, TryLockError},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::{RangeBounds, RangeInclusive},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, RwLock,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+#[cfg(feature = "async")]
+mod async_tree;
+mod background;
+mod blob;
+mod block;
+mod cache;
+mod compaction;
+mod compaction_filter;
+mod compression;
+mod cursor;
+mod lock_manager;
+mod memtable;
+mod range_tombstone;
+mod rate_limiter;
+mod recovery;
+mod replication;
+mod secondary_index;
+mod skiplist;
+mod subscription;
+mod table_properties;
+mod transaction;
+mod typed;
+mod write_batch;
+mod write_log;
+
+#[cfg(feature = "async")]
+pub use async_tree::{AsyncLsmTree, Scan};
+pub use blob::{BlobPointer, BlobStore};
+pub use block::Block;
+pub use cache::{BlockCache, CacheStats, Caches, TableCache};
+pub use compaction::CompactionProgress;
+pub use compaction_filter::{CompactionFilter, CompactionFilterContext, FilterDecision};
+pub use compression::Compression;
+pub use cursor::Cursor;
+pub use memtable::{Memtable, MemtableEntry, MemtableKind, MemtableRep};
+pub use range_tombstone::RangeTombstone;
+pub use rate_limiter::{IoPriority, RateLimiter};
+pub use recovery::{restore_point_in_time, RecoveryReport, RecoveryStop, RecoveryTarget};
+pub use replication::{Follower, ReplicationServer};
+pub use secondary_index::IndexExtractor;
+pub use subscription::{ChangeEvent, Subscription};
+pub use table_properties::TableProperties;
+pub use transaction::{PessimisticTransaction, Transaction};
+#[cfg(feature = "serde")]
+pub use typed::Json;
+pub use typed::{Codec, KeyEncoding, OrderedKey, Raw, TypedRange, TypedTree};
+pub use write_batch::{BatchOp, WriteBatch};
+
+use background::Job;
+use block::BlockBuilder;
+use compaction::{CompactionInputs, MergedEntries};
+use lock_manager::LockManager;
+use write_log::WriteLog;
+
+/// Errors callers may want to match on (via `anyhow::Error::downcast_ref`)
+#[derive(Debug)]
+pub enum LsmError {
+    /// Another `LsmTree` (in this or another process) holds the directory lock
+    DirectoryInUse(PathBuf),
+    /// A write was attempted through a tree opened with `open_read_only`
+    ReadOnly,
+    /// A transaction read this key, and it was written by someone else
+    /// before the transaction committed. Retrying may succeed.
+    TransactionConflict(Vec<u8>),
+    /// A pessimistic transaction waited `Options::lock_timeout` for this key
+    LockTimeout(Vec<u8>),
+    /// Waiting for this key's lock would have deadlocked
+    Deadlock(Vec<u8>),
+    /// The write with this sequence has been flushed out of the WAL (and
+    /// the in-memory write log and WAL archive), so it can't be replayed any more
+    SequenceUnavailable(u64),
+    /// A write's key is longer than the tree takes
+    KeyTooLarge(usize),
+    /// A write's value is longer than `Options::max_value_size`
+    ValueTooLarge(usize),
+    /// A write the tree won't take as it is, such as a range deletion
+    /// ending before it starts
+    InvalidWrite(String),
+}
+
+impl fmt::Display for LsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LsmError::DirectoryInUse(path) => write!(
+                f,
+                "LSM tree directory {} is already in use by another writer",
+                path.display()
+            ),
+            LsmError::ReadOnly => write!(f, "LSM tree was opened read-only"),
+            LsmError::TransactionConflict(key) => write!(
+                f,
+                "Transaction conflict: key {:?} was changed by another writer",
+                String::from_utf8_lossy(key)
+            ),
+            LsmError::LockTimeout(key) => write!(
+                f,
+                "Timed out waiting for the lock on key {:?}",
+                String::from_utf8_lossy(key)
+            ),
+            LsmError::Deadlock(key) => write!(
+                f,
+                "Deadlock detected waiting for the lock on key {:?}",
+                String::from_utf8_lossy(key)
+            ),
+            LsmError::SequenceUnavailable(sequence) => write!(
+                f,
+                "Write {} is no longer in the WAL, it has been flushed to SSTables",
+                sequence
+            ),
+            LsmError::KeyTooLarge(length) => {
+                write!(f, "Key is too large (key length={})", length)
+            }
+            LsmError::ValueTooLarge(length) => {
+                write!(f, "Value is too large (value length={})", length)
+            }
+            LsmError::InvalidWrite(reason) => write!(f, "Invalid write: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for LsmError {}
+
+/// Tuning knobs for an `LsmTree`, see `LsmTree::open_with_options`
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Values at least this many bytes are moved into blob files on flush,
+    /// leaving only a pointer in the SSTable. `None` keeps every value inline.
+    /// Enabling it also lifts the value size limit, see `max_value_size`.
+    pub blob_threshold: Option<usize>,
+    /// Fraction (above 0, up to 1) of a blob file that has to be garbage before
+    /// compaction copies its live values out and deletes it. Files with no
+    /// live values left are always deleted.
+    pub blob_gc_ratio: f64,
+    /// Data block codec per level: flushed SSTables are level 0 and
+    /// compaction output is level 1. Levels past the end of the list use
+    /// its last entry (and an empty list means no compression).
+    pub compression_per_level: Vec<Compression>,
+    /// Keys in a data block are stored as the suffix they don't share with
+    /// the key before them, except every this many entries, where the full
+    /// key lets lookups binary search the block. 1 turns prefix compression off.
+    pub block_restart_interval: usize,
+    /// Cache for decoded data blocks. Pass the same `Arc` to several trees to
+    /// share it, `None` gives this tree its own `DEFAULT_BLOCK_CACHE_SIZE` cache.
+    pub block_cache: Option<Arc<BlockCache>>,
+    /// Cache for open SSTables, shareable the same way. `None` gives this tree
+    /// its own cache of up to `DEFAULT_MAX_OPEN_TABLES` tables.
+    pub table_cache: Option<Arc<TableCache>>,
+    /// Limits how fast flushes and compactions write SSTables. Can be shared
+    /// between trees, and adjusted while running through the `Arc`.
+    /// `None` writes as fast as the disk allows.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Number of level 0 SSTables at which the compaction worker compacts
+    /// them, along with the level 1 tables sharing keys with them
+    pub l0_compaction_trigger: usize,
+    /// Size compactions split their output into (before compression), so
+    /// that later ones only rewrite the level 1 tables their keys are in
+    pub target_file_size: u64,
+    /// Number of level 0 SSTables at which writes start being delayed
+    pub l0_slowdown_writes_trigger: usize,
+    /// Number of level 0 SSTables at which writes stop until compaction catches up
+    pub l0_stop_writes_trigger: usize,
+    /// Bytes waiting to be compacted out of level 0 at which writes are delayed
+    pub pending_compaction_bytes_slowdown: u64,
+    /// Bytes waiting to be compacted out of level 0 at which writes stop
+    pub pending_compaction_bytes_stop: u64,
+    /// Full memtables allowed to queue up for flushing before writes stop
+    pub max_immutable_memtables: usize,
+    /// How long each write sleeps while writes are delayed
+    pub write_slowdown_delay: Duration,
+    /// How long a pessimistic transaction waits for a key lock before giving up
+    pub lock_timeout: Duration,
+    /// Called on every entry compaction writes, to keep, drop or rewrite it
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    /// Data structure new memtables are built on
+    pub memtable: MemtableKind,
+    /// Bytes of recent write batches kept in memory for followers (see
+    /// `ReplicationServer`) and subscribers (see `LsmTree::subscribe`) to
+    /// catch up from. Older writes are read back from the WAL for as long
+    /// as they haven't been flushed (or archived, see `wal_archive_ttl`).
+    pub write_log_size: usize,
+    /// Size at which the WAL moves on to a new segment file, even if the
+    /// memtable isn't full yet
+    pub wal_segment_size: u64,
+    /// Once their memtable is flushed, WAL segments are kept in
+    /// `<path>/archive/` for this long rather than deleted right away
+    pub wal_archive_ttl: Option<Duration>,
+    /// Secondary indexes, by name, kept up to date with every write (see
+    /// `LsmTree::query_index`). An index added to an existing tree is built
+    /// from its records by `open`, and the entries of one that's been taken
+    /// away are deleted. The entries are kept in the tree itself, under keys
+    /// from `b"\xff\xff\xff\xffidx"` on, which are then off limits to
+    /// writes, compaction filters, cursors and subscriptions (so a tree with
+    /// keys there can't have indexes added). Range deletions aren't
+    /// supported while there are indexes. Followers replicate the entries,
+    /// but need the same indexes to query them. Records a compaction filter
+    /// removes or changes keep their old entries, which queries check
+    /// against the record and skip.
+    pub secondary_indexes: BTreeMap<String, Arc<dyn IndexExtractor>>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            blob_threshold: None,
+            blob_gc_ratio: 0.5,
+            compression_per_level: vec![Compression::None],
+            block_restart_interval: 16,
+            block_cache: None,
+            table_cache: None,
+            rate_limiter: None,
+            l0_compaction_trigger: 4,
+            target_file_size: 2 * 1024 * 1024, // 2 MB
+            l0_slowdown_writes_trigger: 8,
+            l0_stop_writes_trigger: 12,
+            pending_compaction_bytes_slowdown: 64 * 1024 * 1024, // 64 MB
+            pending_compaction_bytes_stop: 256 * 1024 * 1024,    // 256 MB
+            max_immutable_memtables: 2,
+            write_slowdown_delay: Duration::from_millis(1),
+            lock_timeout: Duration::from_secs(1),
+            compaction_filter: None,
+            memtable: MemtableKind::default(),
+            write_log_size: 4 * 1024 * 1024,    // 4 MB
+            wal_segment_size: 64 * 1024 * 1024, // 64 MB
+            wal_archive_ttl: None,
+            secondary_indexes: BTreeMap::new(),
+        }
+    }
+}
+
+impl Options {
+    pub const DEFAULT_BLOCK_CACHE_SIZE: usize = 8 * 1024 * 1024; // 8 MB
+    pub const DEFAULT_MAX_OPEN_TABLES: usize = 64;
+
+    /// Largest value a tree opened with these options takes: 64 KB, the
+    /// same as for keys, or 64 MB once `blob_threshold` is set
+    pub fn max_value_size(&self) -> usize {
+        match self.blob_threshold {
+            Some(_) => LsmTree::MAX_BLOB_VALUE_SIZE,
+            None => LsmTree::MAX_ENTRY_SIZE,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self
+            .blob_threshold
+            .is_some_and(|threshold| threshold > LsmTree::MAX_ENTRY_SIZE)
+        {
+            bail!(
+                "blob_threshold must be at most {} bytes so smaller values fit inline",
+                LsmTree::MAX_ENTRY_SIZE
+            );
+        }
+        // 0 would rewrite every blob file with any garbage at all, and NaN
+        // or more than 1 would never collect one
+        if !(self.blob_gc_ratio > 0.0 && self.blob_gc_ratio <= 1.0) {
+            bail!("blob_gc_ratio must be above 0 and at most 1");
+        }
+        if self.block_restart_interval == 0 {
+            bail!("block_restart_interval must be at least 1");
+        }
+        if self.l0_compaction_trigger == 0
+            || self.l0_compaction_trigger > self.l0_slowdown_writes_trigger
+            || self.l0_slowdown_writes_trigger > self.l0_stop_writes_trigger
+        {
+            bail!(
+                "Level 0 triggers must satisfy 0 < compaction ({}) <= slowdown ({}) <= stop ({})",
+                self.l0_compaction_trigger,
+                self.l0_slowdown_writes_trigger,
+                self.l0_stop_writes_trigger
+            );
+        }
+        if self.target_file_size == 0 {
+            bail!("target_file_size must be at least 1 byte");
+        }
+        if self.pending_compaction_bytes_slowdown > self.pending_compaction_bytes_stop {
+            bail!("Pending compaction bytes slowdown threshold is above the stop threshold");
+        }
+        if self.max_immutable_memtables == 0 {
+            bail!("At least one immutable memtable must be allowed");
+        }
+        if self.wal_segment_size == 0 {
+            bail!("wal_segment_size must be at least 1 byte");
+        }
+        Ok(())
+    }
+
+    fn caches(&self) -> Caches {
+        Caches {
+            block: self
+                .block_cache
+                .clone()
+                .unwrap_or_else(|| Arc::new(BlockCache::new(Self::DEFAULT_BLOCK_CACHE_SIZE))),
+            table: self
+                .table_cache
+                .clone()
+                .unwrap_or_else(|| Arc::new(TableCache::new(Self::DEFAULT_MAX_OPEN_TABLES))),
+        }
+    }
+
+    fn compression_for_level(&self, level: usize) -> Compression {
+        self.compression_per_level
+            .get(level)
+            .or(self.compression_per_level.last())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn table_options(&self, level: u8, priority: IoPriority) -> TableOptions<'_> {
+        TableOptions {
+            level,
+            compression: self.compression_for_level(level as usize),
+            restart_interval: self.block_restart_interval,
+            rate_limiter: self
+                .rate_limiter
+                .as_deref()
+                .map(|rate_limiter| (rate_limiter, priority)),
+        }
+    }
+}
+
+/// Point-in-time numbers about a tree, see `LsmTree::stats`
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub sstables: usize,
+    /// SSTables flushed from memtables and not yet compacted
+    pub l0_sstables: usize,
+    /// Full memtables waiting on the background flush
+    pub immutable_memtables: usize,
+    /// Bytes of level 0 SSTables waiting to be compacted
+    pub pending_compaction_bytes: u64,
+    pub write_stall: WriteStall,
+    /// Total time writes have spent delayed or stopped
+    pub stall_time: Duration,
+    pub delayed_writes: u64,
+    pub stopped_writes: u64,
+    pub data_blocks: usize,
+    /// Size of all data blocks before compression
+    pub uncompressed_bytes: u64,
+    /// Size of all data blocks as stored on disk (including block headers)
+    pub compressed_bytes: u64,
+    /// Counters of the (possibly shared) block cache
+    pub block_cache: CacheStats,
+    /// Counters of the (possibly shared) table cache
+    pub table_cache: CacheStats,
+    /// The compaction running right now, if any
+    pub compaction: Option<CompactionProgress>,
+}
+
+impl Stats {
+    /// `uncompressed / compressed`, so higher is better and 1.0 means no savings
+    pub fn compression_ratio(&self) -> f64 {
+        if self.compressed_bytes == 0 {
+            return 1.0;
+        }
+        self.uncompressed_bytes as f64 / self.compressed_bytes as f64
+    }
+}
+
+/// Whether writes are currently being throttled, see the stall thresholds in `Options`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteStall {
+    #[default]
+    None,
+    /// Every write sleeps for `Options::write_slowdown_delay` first
+    Delayed,
+    /// Writes block until background work brings things back under the stop thresholds
+    Stopped,
+}
+
+/// A full memtable waiting on the background flush, along with the WAL
+/// segments that cover it (retired once the memtable is in an SSTable)
+#[derive(Debug)]
+struct ImmutableMemtable {
+    memtable: Arc<Memtable>,
+    // oldest first
+    wal_segments: Vec<PathBuf>,
+}
+
+/// Everything that changes as data moves from memtables into SSTables.
+/// Guarded by a single `RwLock` so readers always see a consistent set.
+#[derive(Debug)]
+struct TreeState {
+    memtable: Arc<Memtable>,
+    // oldest first
+    immutables: VecDeque<ImmutableMemtable>,
+    // segments of the active memtable, oldest first: the last one is being appended to
+    wal_segments: Vec<PathBuf>,
+    // oldest first
+    sstables: Vec<Arc<SSTable>>,
+    next_sstable_id: u32,
+    next_wal_id: u32,
+}
+
+impl TreeState {
+    fn l0_tables(&self) -> impl Iterator<Item = &Arc<SSTable>> {
+        self.sstables.iter().filter(|table| table.level() == 0)
+    }
+
+    /// Bytes the next compaction has to merge down out of L0
+    fn pending_compaction_bytes(&self) -> u64 {
+        self.l0_tables().map(|table| table.file_size).sum()
+    }
+
+    /// Whether `key` may have been written after `sequence`.
+    /// Only memtables remember sequences, so once writes newer than
+    /// `sequence` have been flushed we can't tell and assume it was.
+    fn changed_since(&self, key: &[u8], sequence: u64) -> bool {
+        let memtables = std::iter::once(&self.memtable).chain(
+            self.immutables
+                .iter()
+                .rev()
+                .map(|immutable| &immutable.memtable),
+        );
+        for memtable in memtables {
+            if let Some(key_sequence) = memtable.sequence_of(key) {
+                return key_sequence > sequence;
+            }
+            // every write after `sequence` is in this memtable or a newer one
+            if memtable.first_sequence() <= sequence + 1 {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The memtables and SSTables a read should look at, newest first.
+/// Taken under the state lock, then used without it: the `Arc`s keep
+/// everything alive (including files a compaction has since replaced).
+#[derive(Debug, Clone)]
+pub(crate) struct ReadView {
+    // memtable writes after this sequence aren't visible
+    sequence: u64,
+    memtables: Vec<Arc<Memtable>>,
+    sstables: Vec<Arc<SSTable>>,
+}
+
+#[derive(Debug, Default)]
+struct BackgroundState {
+    shutdown: bool,
+    // first background failure, after which writes are refused
+    error: Option<String>,
+}
+
+/// State shared between the `LsmTree` handle and its background workers
+#[derive(Debug)]
+struct TreeInner {
+    path: PathBuf,
+    options: Options,
+    caches: Caches,
+    blobs: Arc<BlobStore>,
+    read_only: bool,
+    // a `Follower`'s tree, which only takes writes replicated from its primary
+    replica: bool,
+    // also serializes writers; `None` when opened read-only
+    wal: Mutex<Option<Wal>>,
+    // sequence of the newest write batch visible to readers
+    last_sequence: AtomicU64,
+    write_log: WriteLog,
+    lock_manager: LockManager,
+    state: RwLock<TreeState>,
+    // one compaction at a time, manual or background
+    compaction_lock: Mutex<()>,
+    compaction_progress: Mutex<Option<CompactionProgress>>,
+    // wakes the workers when there is work, and stalled writers when it's done
+    background: Mutex<BackgroundState>,
+    background_cv: Condvar,
+    stall_micros: AtomicU64,
+    delayed_writes: AtomicU64,
+    stopped_writes: AtomicU64,
+    // exclusive `flock` on `<path>/LOCK`, released when the tree is dropped
+    _lock_file: Option<File>,
+}
+
+#[derive(Debug)]
+pub struct LsmTree {
+    inner: Arc<TreeInner>,
+    // flush and compaction threads (see `background::run`), none when read-only
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl LsmTree {
+    const MAX_ENTRY_SIZE: usize = 64 * 1024; // 64 KB
+    const MAX_BLOB_VALUE_SIZE: usize = 64 * 1024 * 1024; // 64 MB
+    const MAX_MEMTABLE_SIZE: usize = 128 * 1024; // 128 KB
+    const LOCK_FILE_NAME: &'static str = "LOCK";
+    // the single WAL of trees from before it was split into segments
+    const LEGACY_WAL_FILE_NAME: &'static str = "wal.log";
+    const WAL_SEGMENT_PREFIX: &'static str = "wal_";
+    const WAL_SEGMENT_EXT: &'static str = ".log";
+    const WAL_ARCHIVE_DIR: &'static str = "archive";
+
+    /// Open (or create) an LSM tree given the directory.
+    /// If the structure exists already, we will:
+    ///   - take an exclusive lock on the directory
+    ///   - replay the WAL segments (those of full memtables become immutable
+    ///     memtables again) and carry on appending to the newest one
+    ///   - load any existing SSTables
+    ///   - start the background flush and compaction workers
+    ///
+    /// Fails with `LsmError::DirectoryInUse` if another writer has it open.
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::open_with_options(path, Options::default())
+    }
+
+    /// Same as `open`, but with non-default `Options`
+    pub fn open_with_options(path: &Path, options: Options) -> Result<Self> {
+        Self::open_writable(path, options, false)
+    }
+
+    /// `open_with_options`, for a follower's tree if `replica` is set
+    fn open_writable(path: &Path, options: Options, replica: bool) -> Result<Self> {
+        options.validate()?;
+
+        let path_buf = path.to_path_buf();
+        std::fs::create_dir_all(&path_buf)?;
+
+        let lock_file = Self::lock_directory(&path_buf)?;
+
+        let caches = options.caches();
+        let sstables = Self::load_sstables(&path_buf, &caches)?;
+        let next_sstable_id = sstables
+            .iter()
+            .map(|table| table.id() + 1)
+            .max()
+            .unwrap_or(0);
+
+        let flushed_sequence = sstables
+            .iter()
+            .map(|table| table.properties().largest_sequence)
+            .max()
+            .unwrap_or(0);
+        let mut last_sequence = flushed_sequence;
+
+        let mut segments = Self::list_wal_segments(&path_buf)?;
+        let archived = Self::list_wal_segments(&path_buf.join(Self::WAL_ARCHIVE_DIR))?;
+        let mut next_wal_id = segments
+            .iter()
+            .chain(&archived)
+            .map(|(id, _)| id + 1)
+            .max()
+            .unwrap_or(0);
+        // an old single-file WAL is newer than any segment: its puts are
+        // written to a new segment under the sequences that follow. A crash
+        // before it's removed means migrating it again, which only repeats
+        // the same puts.
+        let legacy_wal_path = path_buf.join(Self::LEGACY_WAL_FILE_NAME);
+        if legacy_wal_path.exists() {
+            let mut first_sequence = flushed_sequence + 1;
+            for (_, segment_path) in &segments {
+                if let Some((sequence, _)) = Wal::read_records(segment_path)?.last() {
+                    first_sequence = first_sequence.max(sequence + 1);
+                }
+            }
+            let segment_path = Self::wal_segment_path(&path_buf, next_wal_id);
+            let mut segment = Wal::open(&segment_path)?;
+            for (sequence, batch) in Wal::read_legacy_records(&legacy_wal_path, first_sequence)? {
+                segment.append(sequence, &batch)?;
+            }
+            drop(segment);
+            sync_dir(&path_buf)?;
+            std::fs::remove_file(&legacy_wal_path).context("Failed to remove the migrated WAL")?;
+            segments.push((next_wal_id, segment_path));
+            next_wal_id += 1;
+        }
+        if segments.is_empty() {
+            segments.push((next_wal_id, Self::wal_segment_path(&path_buf, next_wal_id)));
+            next_wal_id += 1;
+        }
+
+        // appends carry on in the newest segment, minus any record torn by a crash
+        let (_, live_segment_path) = segments.pop().unwrap();
+        let mut wal = Wal::open(&live_segment_path)?;
+        let mut segment_records = Vec::new();
+        for (_, segment_path) in segments {
+            let records = Wal::read_records(&segment_path)?;
+            segment_records.push((segment_path, records));
+        }
+        segment_records.push((live_segment_path, wal.replay()?));
+
+        // regroup the segments into memtables: those that were full but not
+        // yet flushed when we last stopped, and then the active one. Segments
+        // that only hold flushed writes (after a crash before they were
+        // retired) are retired now.
+        let mut immutables = VecDeque::new();
+        let mut memtable = Memtable::new(options.memtable.new_rep(), last_sequence + 1);
+        let mut wal_segments = Vec::new();
+        let mut retired_segments = Vec::new();
+        let segment_count = segment_records.len();
+        for (i, (segment_path, records)) in segment_records.into_iter().enumerate() {
+            let is_live = i + 1 == segment_count;
+            let mut unflushed = false;
+            for (sequence, batch) in records {
+                if sequence > flushed_sequence {
+                    memtable.apply(sequence, &batch);
+                    last_sequence = last_sequence.max(sequence);
+                    unflushed = true;
+                }
+            }
+            if !unflushed && !is_live {
+                retired_segments.push(segment_path);
+                continue;
+            }
+            wal_segments.push(segment_path);
+            if !is_live && memtable.total_bytes() > Self::MAX_MEMTABLE_SIZE {
+                let full = std::mem::replace(
+                    &mut memtable,
+                    Memtable::new(options.memtable.new_rep(), last_sequence + 1),
+                );
+                immutables.push_back(ImmutableMemtable {
+                    memtable: Arc::new(full),
+                    wal_segments: std::mem::take(&mut wal_segments),
+                });
+            }
+        }
+
+        let inner = Arc::new(TreeInner {
+            blobs: Arc::new(BlobStore::open(&path_buf)?),
+            path: path_buf,
+            write_log: WriteLog::new(options.write_log_size, last_sequence),
+            options,
+            caches,
+            read_only: false,
+            replica,
+            wal: Mutex::new(Some(wal)),
+            last_sequence: AtomicU64::new(last_sequence),
+            lock_manager: LockManager::default(),
+            state: RwLock::new(TreeState {
+                memtable: Arc::new(memtable),
+                immutables,
+                wal_segments,
+                sstables: sstables.into_iter().map(Arc::new).collect(),
+                next_sstable_id,
+                next_wal_id,
+            }),
+            compaction_lock: Mutex::new(()),
+            compaction_progress: Mutex::new(None),
+            background: Mutex::new(BackgroundState::default()),
+            background_cv: Condvar::new(),
+            stall_micros: AtomicU64::new(0),
+            delayed_writes: AtomicU64::new(0),
+            stopped_writes: AtomicU64::new(0),
+            _lock_file: Some(lock_file),
+        });
+        inner.retire_wal_segments(&retired_segments)?;
+
+        let mut tree = Self {
+            inner,
+            workers: Vec::new(),
+        };
+        for job in Job::ALL {
+            let worker_inner = tree.inner.clone();
+            let worker = std::thread::Builder::new()
+                .name(job.thread_name().to_string())
+                .spawn(move || background::run(worker_inner, job))
+                .context("Failed to start background worker")?;
+            tree.workers.push(worker);
+        }
+        // a follower gets its entries from the primary
+        if !replica {
+            secondary_index::build_indexes(&tree.inner)?;
+        }
+
+        Ok(tree)
+    }
+
+    /// Open an existing LSM tree without taking the writer lock, so it can
+    /// be shared with (at most) one writer that has it open via `open`.
+    ///
+    /// The handle is a snapshot of the directory at open time: it won't see
+    /// later writes, and a compaction by the writer may remove SSTables out
+    /// from under it, in which case reads will error and it should be reopened.
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        Self::open_read_only_with_options(path, Options::default())
+    }
+
+    /// Same as `open_read_only`, but with non-default `Options` (such as
+    /// the `secondary_indexes` to query, which a writer has to have built)
+    pub fn open_read_only_with_options(path: &Path, options: Options) -> Result<Self> {
+        options.validate()?;
+
+        let path_buf = path.to_path_buf();
+        if !path_buf.is_dir() {
+            bail!("LSM tree directory {} does not exist", path_buf.display());
+        }
+        secondary_index::check_indexes_built(&path_buf, &options)?;
+
+        let caches = options.caches();
+        let sstables = Self::load_sstables(&path_buf, &caches)?;
+        let next_sstable_id = sstables
+            .iter()
+            .map(|table| table.id() + 1)
+            .max()
+            .unwrap_or(0);
+
+        let flushed_sequence = sstables
+            .iter()
+            .map(|table| table.properties().largest_sequence)
+            .max()
+            .unwrap_or(0);
+
+        // oldest segment first, then a WAL the writer hasn't migrated to segments yet
+        let mut records = Vec::new();
+        for (_, wal_path) in Self::list_wal_segments(&path_buf)? {
+            records.extend(Wal::read_records(&wal_path)?);
+        }
+        let first_legacy_sequence = records.last().map_or(flushed_sequence, |(sequence, _)| {
+            flushed_sequence.max(*sequence)
+        }) + 1;
+        records.extend(Wal::read_legacy_records(
+            &path_buf.join(Self::LEGACY_WAL_FILE_NAME),
+            first_legacy_sequence,
+        )?);
+        let memtable = Memtable::from_records(options.memtable, records, flushed_sequence + 1);
+        let last_sequence = flushed_sequence.max(memtable.last_sequence());
+
+        let inner = Arc::new(TreeInner {
+            blobs: Arc::new(BlobStore::open(&path_buf)?),
+            path: path_buf,
+            options,
+            caches,
+            read_only: true,
+            replica: false,
+            wal: Mutex::new(None),
+            last_sequence: AtomicU64::new(last_sequence),
+            write_log: WriteLog::new(0, last_sequence),
+            lock_manager: LockManager::default(),
+            state: RwLock::new(TreeState {
+                memtable: Arc::new(memtable),
+                immutables: VecDeque::new(),
+                wal_segments: Vec::new(),
+                sstables: sstables.into_iter().map(Arc::new).collect(),
+                next_sstable_id,
+                next_wal_id: 0,
+            }),
+            compaction_lock: Mutex::new(()),
+            compaction_progress: Mutex::new(None),
+            background: Mutex::new(BackgroundState::default()),
+            background_cv: Condvar::new(),
+            stall_micros: AtomicU64::new(0),
+            delayed_writes: AtomicU64::new(0),
+            stopped_writes: AtomicU64::new(0),
+            _lock_file: None,
+        });
+
+        Ok(Self {
+            inner,
+            workers: Vec::new(),
+        })
+    }
+
+    /// Take an advisory exclusive `flock` on `<path>/LOCK` (never blocks)
+    fn lock_directory(path: &Path) -> Result<File> {
+        let lock_file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.join(Self::LOCK_FILE_NAME))
+            .context("Failed to open LOCK file")?;
+
+        match lock_file.try_lock() {
+            Ok(()) => Ok(lock_file),
+            Err(TryLockError::WouldBlock) => {
+                Err(LsmError::DirectoryInUse(path.to_path_buf()).into())
+            }
+            Err(TryLockError::Error(e)) => Err(e).context("Failed to lock LSM tree directory"),
+        }
+    }
+
+    fn wal_segment_path(path: &Path, wal_id: u32) -> PathBuf {
+        path.join(format!(
+            "{}{}{}",
+            Self::WAL_SEGMENT_PREFIX,
+            wal_id,
+            Self::WAL_SEGMENT_EXT
+        ))
+    }
+
+    /// Find the WAL segments `<path>/wal_{id}.log`, oldest first. A missing
+    /// directory (such as an archive that was never needed) has none.
+    fn list_wal_segments(path: &Path) -> Result<Vec<(u32, PathBuf)>> {
+        let dir_entries = match std::fs::read_dir(path) {
+            Ok(dir_entries) => dir_entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut wals = Vec::new();
+        for dir_entry_result in dir_entries {
+            let dir_entry_path = dir_entry_result?.path();
+            let wal_id_opt = dir_entry_path
+                .file_name()
+                .and_then(|x| x.to_str())
+                .and_then(|s| s.strip_prefix(Self::WAL_SEGMENT_PREFIX))
+                .and_then(|s| s.strip_suffix(Self::WAL_SEGMENT_EXT))
+                .and_then(|s| s.parse::<u32>().ok());
+
+            if let Some(wal_id) = wal_id_opt {
+                wals.push((wal_id, dir_entry_path));
+            }
+        }
+
+        wals.sort_by_key(|(id, _)| *id);
+
+        Ok(wals)
+    }
+
+    /// Load any existing SSTables `<path>/sstable_{id}.sst`, oldest first.
+    /// Age goes by the newest write in each table: ids are taken when a
+    /// flush or compaction starts, so a compaction's output can have a
+    /// larger id than a table flushed while it ran.
+    fn load_sstables(path: &Path, caches: &Caches) -> Result<Vec<SSTable>> {
+        let mut sstables = Vec::new();
+        for dir_entry_result in std::fs::read_dir(path)? {
+            let dir_entry = dir_entry_result?;
+            let dir_entry_path = dir_entry.path();
+
+            if !dir_entry_path.is_file() {
+                continue;
+            }
+
+            let is_sstable = dir_entry_path
+                .file_name()
+                .and_then(|x| x.to_str())
+                .and_then(SSTable::parse_id)
+                .is_some();
+
+            if is_sstable {
+                sstables.push(SSTable::open(&dir_entry_path, caches)?);
+            }
+        }
+
+        sstables.sort_by_key(|table| (table.properties().largest_sequence, table.id()));
+
+        Ok(sstables)
+    }
+
+    /// Put a key/value pair onto the WAL and memtable.
+    /// May be delayed or block for a while if background work has fallen
+    /// behind (see `WriteStall`).
+    pub fn put(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        self.write(batch)
+    }
+
+    /// Delete a key, by writing a tombstone that hides any older value
+    pub fn delete(&self, key: Vec<u8>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write(batch)
+    }
+
+    /// Delete every key in `[start, end)` with a single range tombstone.
+    /// The covered data is only reclaimed by the next compaction.
+    ///
+    /// Not supported with `Options::secondary_indexes`, since finding the
+    /// index entries to remove would mean reading every record in the range.
+    pub fn delete_range(&self, start: Vec<u8>, end: Vec<u8>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete_range(start, end);
+        self.write(batch)
+    }
+
+    /// Apply a batch of puts and deletes atomically
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.inner.write(batch, |_| Ok(()))?;
+        Ok(())
+    }
+
+    /// Search for a key first against the memtables, then against
+    /// SSTable from newest to oldest
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key)
+    }
+
+    /// `get` for many keys at once, with the results in the same order as
+    /// `keys`. The keys are sorted first, so the memtables are probed once
+    /// and each SSTable is walked a single time for whatever keys are left,
+    /// skipping tables whose key range (or range tombstones) can't hold
+    /// them and reading every block at most once.
+    pub fn multi_get<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.inner.multi_get(keys)
+    }
+
+    /// Start an optimistic transaction, see `Transaction`
+    pub fn begin_transaction(&self) -> Transaction {
+        Transaction::new(self.inner.clone())
+    }
+
+    /// Start a pessimistic (locking) transaction, see `PessimisticTransaction`
+    pub fn begin_pessimistic_transaction(&self) -> PessimisticTransaction {
+        PessimisticTransaction::new(self.inner.clone())
+    }
+
+    /// The options the tree was opened with
+    pub fn options(&self) -> &Options {
+        &self.inner.options
+    }
+
+    /// Sequence of the newest write. Every write batch gets the next one,
+    /// and a `Follower` resumes replication right after its own.
+    pub fn last_sequence(&self) -> u64 {
+        self.inner.last_sequence()
+    }
+
+    /// Follow every write from the one with sequence `from_sequence` on, as
+    /// a stream of `ChangeEvent`s in the order they were made. Pass
+    /// `last_sequence() + 1` for only the writes from now on.
+    ///
+    /// Recent writes come from memory, and a subscriber that has fallen
+    /// behind catches up from the WAL, including its archive (see
+    /// `Options::wal_archive_ttl`). Writes whose segments have been deleted
+    /// are gone, and the stream fails with `LsmError::SequenceUnavailable`.
+    ///
+    /// Every event is a put, a delete or a range deletion: the tree has no
+    /// merge operator, so there are no merge events.
+    pub fn subscribe(&self, from_sequence: u64) -> Subscription {
+        Subscription::new(self.inner.clone(), from_sequence)
+    }
+
+    /// Every record whose key in the secondary index `name` (see
+    /// `Options::secondary_indexes`) is in `range`, as `(key, value)`
+    /// pairs ordered by index key and then key
+    pub fn query_index(
+        &self,
+        name: &str,
+        range: impl RangeBounds<Vec<u8>>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        secondary_index::query(&self.inner, name, range)
+    }
+
+    /// Open an unpositioned cursor over the whole tree, as of now.
+    /// Call one of the `seek*` methods before reading from it.
+    pub fn cursor(&self) -> Cursor {
+        let cursor = Cursor::new(self.inner.read_view(), self.inner.blobs.clone());
+        if self.inner.options.secondary_indexes.is_empty() {
+            return cursor;
+        }
+        // index entries aren't records
+        cursor.with_upper_bound(secondary_index::INDEX_KEY_PREFIX.to_vec())
+    }
+
+    /// Current write throttling state
+    pub fn write_stall(&self) -> WriteStall {
+        self.inner.write_stall(&self.inner.state.read().unwrap())
+    }
+
+    pub fn stats(&self) -> Result<Stats> {
+        let (sstables, immutable_memtables, write_stall, pending_compaction_bytes) = {
+            let state = self.inner.state.read().unwrap();
+            (
+                state.sstables.clone(),
+                state.immutables.len(),
+                self.inner.write_stall(&state),
+                state.pending_compaction_bytes(),
+            )
+        };
+
+        let mut stats = Stats {
+            sstables: sstables.len(),
+            l0_sstables: sstables.iter().filter(|table| table.level() == 0).count(),
+            immutable_memtables,
+            pending_compaction_bytes,
+            write_stall,
+            stall_time: Duration::from_micros(self.inner.stall_micros.load(Ordering::Relaxed)),
+            delayed_writes: self.inner.delayed_writes.load(Ordering::Relaxed),
+            stopped_writes: self.inner.stopped_writes.load(Ordering::Relaxed),
+            block_cache: self.inner.caches.block.stats(),
+            table_cache: self.inner.caches.table.stats(),
+            compaction: self.inner.compaction_progress.lock().unwrap().clone(),
+            ..Stats::default()
+        };
+        for table in &sstables {
+            for handle in &table.reader()?.index {
+                stats.data_blocks += 1;
+                stats.uncompressed_bytes += handle.uncompressed_length;
+                stats.compressed_bytes += handle.length;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Freeze the current memtable and wait for the flush worker to
+    /// write it (and any other full memtables) out to SSTables
+    pub fn flush(&self) -> Result<()> {
+        if self.inner.read_only {
+            bail!(LsmError::ReadOnly);
+        }
+
+        {
+            let mut wal_guard = self.inner.wal.lock().unwrap();
+            let wal = wal_guard.as_mut().ok_or(LsmError::ReadOnly)?;
+            let mut state = self.inner.state.write().unwrap();
+            if !state.memtable.is_empty() {
+                self.inner.freeze_memtable(&mut state, wal)?;
+            }
+        }
+        self.inner.wake_background();
+
+        let mut background = self.inner.background.lock().unwrap();
+        loop {
+            if let Some(error) = &background.error {
+                bail!("Background flush failed: {}", error);
+            }
+            if self.inner.state.read().unwrap().immutables.is_empty() {
+                return Ok(());
+            }
+            background = self.inner.background_cv.wait(background).unwrap();
+        }
+    }
+
+    /// Make a consistent copy of the tree in a new directory at `path`,
+    /// which can be opened as a tree of its own or be the starting point
+    /// of `restore_point_in_time`. SSTables and blob files are hard linked
+    /// where possible, and the WAL segments not yet flushed are copied.
+    /// Writes wait while it's taken.
+    ///
+    /// The copy is made next to `path` and only renamed to it once it's
+    /// complete and synced, so `path` never holds half a checkpoint.
+    pub fn checkpoint(&self, path: &Path) -> Result<()> {
+        let wal_guard = self.inner.wal.lock().unwrap();
+        if wal_guard.is_none() {
+            bail!(LsmError::ReadOnly);
+        }
+        if path.exists() {
+            bail!("Checkpoint {} already exists", path.display());
+        }
+        let file_name = path
+            .file_name()
+            .context("Checkpoint path has no file name")?;
+        let mut partial_name = file_name.to_os_string();
+        partial_name.push(".partial");
+        let partial_path = path.with_file_name(partial_name);
+        // left behind by a checkpoint that failed or crashed
+        if partial_path.exists() {
+            std::fs::remove_dir_all(&partial_path)?;
+        }
+        std::fs::create_dir(&partial_path)
+            .with_context(|| format!("Failed to create checkpoint {}", path.display()))?;
+
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let result = self.write_checkpoint(&partial_path).and_then(|()| {
+            std::fs::rename(&partial_path, path)?;
+            sync_dir(parent)
+        });
+        if result.is_err() {
+            let _ = std::fs::remove_dir_all(&partial_path);
+        }
+        result
+    }
+
+    /// The files of a checkpoint, see `checkpoint`, synced along with `path`
+    fn write_checkpoint(&self, path: &Path) -> Result<()> {
+        // holding the state lock keeps every file it points to around
+        let state = self.inner.state.read().unwrap();
+        let mut table_paths: Vec<PathBuf> = state
+            .sstables
+            .iter()
+            .map(|table| self.inner.sstable_path(table.id()))
+            .collect();
+        table_paths.extend(
+            self.inner
+                .blobs
+                .files()
+                .keys()
+                .map(|file_id| self.inner.blobs.file_path(*file_id)),
+        );
+        for table_path in &table_paths {
+            let file_name = table_path.file_name().context("Table file has no name")?;
+            let checkpoint_path = path.join(file_name);
+            if std::fs::hard_link(table_path, &checkpoint_path).is_err() {
+                std::fs::copy(table_path, &checkpoint_path)?;
+                File::open(&checkpoint_path)?.sync_all()?;
+            }
+        }
+
+        let segments = state
+            .immutables
+            .iter()
+            .flat_map(|immutable| &immutable.wal_segments)
+            .chain(&state.wal_segments);
+        for segment_path in segments {
+            let file_name = segment_path
+                .file_name()
+                .context("WAL segment has no name")?;
+            let checkpoint_path = path.join(file_name);
+            std::fs::copy(segment_path, &checkpoint_path)?;
+            File::open(&checkpoint_path)?.sync_all()?;
+        }
+
+        let indexes_path = self.inner.path.join(secondary_index::INDEXES_FILE_NAME);
+        if indexes_path.exists() {
+            let checkpoint_path = path.join(secondary_index::INDEXES_FILE_NAME);
+            std::fs::copy(&indexes_path, &checkpoint_path)?;
+            File::open(&checkpoint_path)?.sync_all()?;
+        }
+
+        // the links and copies themselves
+        sync_dir(path)
+    }
+
+    /// Merge every SSTable into level 1 tables (of `target_file_size`), and
+    /// then drop the old tables. The compaction worker only merges level 0
+    /// into the level 1 tables it shares keys with, once
+    /// `l0_compaction_trigger` is hit.
+    ///
+    /// Separated values are carried over as pointers without being rewritten.
+    /// This is also where blob garbage is collected, since the merged tables
+    /// are exactly the set of live pointers: blob files nothing points to are
+    /// deleted, and files that are at least `blob_gc_ratio` garbage have their
+    /// live values copied into a fresh blob file first. Compactions of
+    /// fewer than all tables leave blob files alone.
+    pub fn compact_all(&self) -> Result<()> {
+        self.compact(CompactionInputs::All)
+    }
+
+    /// Compact only the SSTables that may hold keys in `[start, end)`, e.g.
+    /// to reclaim the space of a bulk delete in one part of the keyspace.
+    /// Newer tables sharing keys with them are merged along (see
+    /// `compact_files`).
+    ///
+    /// Like every compaction, reads and writes carry on while it runs, and
+    /// `Stats::compaction` tracks how far along it is.
+    pub fn compact_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        self.compact(CompactionInputs::Range(start, end))
+    }
+
+    /// Compact the SSTables with the given ids (see `sstables`).
+    ///
+    /// Which table wins on a key depends on its age, so any newer table
+    /// sharing keys with the chosen ones is merged along with them.
+    /// Tombstones are only dropped when no older table is left out that
+    /// they could still be hiding something in, and blob files are only
+    /// garbage collected by `compact_all`.
+    pub fn compact_files(&self, ids: &[u32]) -> Result<()> {
+        self.compact(CompactionInputs::Files(ids))
+    }
+
+    fn compact(&self, inputs: CompactionInputs) -> Result<()> {
+        if self.inner.read_only {
+            bail!(LsmError::ReadOnly);
+        }
+        self.inner.compact(inputs)?;
+        self.inner.wake_background();
+        Ok(())
+    }
+
+    /// Every SSTable in the tree, oldest first
+    pub fn sstables(&self) -> Vec<SSTableInfo> {
+        let state = self.inner.state.read().unwrap();
+        state
+            .sstables
+            .iter()
+            .map(|table| SSTableInfo {
+                id: table.id(),
+                level: table.level(),
+                file_size: table.file_size,
+                properties: table.properties().clone(),
+            })
+            .collect()
+    }
+}
+
+/// Describes one SSTable, see `LsmTree::sstables`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SSTableInfo {
+    /// Used to pick tables for `LsmTree::compact_files`
+    pub id: u32,
+    pub level: u8,
+    pub file_size: u64,
+    pub properties: TableProperties,
+}
+
+impl Drop for LsmTree {
+    fn drop(&mut self) {
+        // ends subscriptions, which can't outlive the tree
+        self.inner.write_log.close();
+        self.inner.background.lock().unwrap().shutdown = true;
+        self.inner.background_cv.notify_all();
+        // unflushed memtables are safe in their WALs, so no need to wait on more than
+        // the jobs in progress
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl TreeInner {
+    fn last_sequence(&self) -> u64 {
+        self.last_sequence.load(Ordering::Acquire)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let view = self.read_view();
+
+        for memtable in &view.memtables {
+            if let Some(value) = memtable.get(key, view.sequence) {
+                return Ok(value);
+            }
+        }
+
+        for table in &view.sstables {
+            let table_read_opt = table.get(key)?;
+            if let Some(value) = table_read_opt {
+                return value.resolve(&self.blobs);
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn multi_get<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>> {
+        let view = self.read_view();
+
+        // every distinct key once and in order, so each table is walked
+        // front to back a single time
+        let mut sorted: Vec<&[u8]> = keys.iter().map(|key| key.as_ref()).collect();
+        sorted.sort_unstable();
+        sorted.dedup();
+
+        let mut values = vec![None; sorted.len()];
+        // positions in `sorted` of the keys no source has answered yet
+        let mut pending: Vec<usize> = (0..sorted.len()).collect();
+
+        for memtable in &view.memtables {
+            pending.retain(|&idx| match memtable.get(sorted[idx], view.sequence) {
+                Some(value) => {
+                    values[idx] = value;
+                    false
+                }
+                None => true,
+            });
+        }
+
+        for table in &view.sstables {
+            if pending.is_empty() {
+                break;
+            }
+            let pending_keys: Vec<&[u8]> = pending.iter().map(|&idx| sorted[idx]).collect();
+            let mut still_pending = Vec::new();
+            for (idx, found) in pending.into_iter().zip(table.multi_get(&pending_keys)?) {
+                match found {
+                    Some(value) => values[idx] = value.resolve(&self.blobs)?,
+                    None => still_pending.push(idx),
+                }
+            }
+            pending = still_pending;
+        }
+
+        Ok(keys
+            .iter()
+            .map(|key| {
+                sorted
+                    .binary_search(&key.as_ref())
+                    .ok()
+                    .and_then(|idx| values[idx].clone())
+            })
+            .collect())
+    }
+
+    /// Write a batch through the WAL into the memtable, returning its sequence.
+    /// `precondition` runs once no other write can get in between it and
+    /// this one, and aborts the write if it fails.
+    fn write(
+        &self,
+        batch: WriteBatch,
+        precondition: impl FnOnce(&TreeState) -> Result<()>,
+    ) -> Result<u64> {
+        if self.replica {
+            bail!(LsmError::ReadOnly);
+        }
+        self.write_sequenced(batch, None, true, precondition)
+    }
+
+    /// Write index entries as they are, for an index being added or
+    /// removed (`write` won't take keys in the index keyspace)
+    fn write_index_entries(&self, batch: WriteBatch) -> Result<()> {
+        self.write_sequenced(batch, None, false, |_| Ok(()))?;
+        Ok(())
+    }
+
+    /// Apply a batch a follower received from its primary, with the
+    /// sequence the primary gave it (which has to be the next one here)
+    fn write_replicated(&self, sequence: u64, batch: WriteBatch) -> Result<()> {
+        // a follower's writes already come with the primary's index entries
+        self.write_sequenced(batch, Some(sequence), false, |_| Ok(()))?;
+        Ok(())
+    }
+
+    /// `write`, taking the next sequence unless `sequence` is given, and
+    /// adding the batch's index entries if `update_indexes` is set
+    fn write_sequenced(
+        &self,
+        batch: WriteBatch,
+        sequence: Option<u64>,
+        update_indexes: bool,
+        precondition: impl FnOnce(&TreeState) -> Result<()>,
+    ) -> Result<u64> {
+        batch.validate(self.options.max_value_size())?;
+        if self.read_only {
+            bail!(LsmError::ReadOnly);
+        }
+
+        self.throttle_write()?;
+
+        let mut wal_guard = self.wal.lock().unwrap();
+        let wal = wal_guard.as_mut().ok_or(LsmError::ReadOnly)?;
+
+        precondition(&self.state.read().unwrap())?;
+        if batch.is_empty() {
+            return Ok(self.last_sequence());
+        }
+
+        let batch = match update_indexes {
+            true => secondary_index::with_index_updates(self, batch)?,
+            false => batch,
+        };
+
+        let next_sequence = self.last_sequence() + 1;
+        let sequence = sequence.unwrap_or(next_sequence);
+        if sequence != next_sequence {
+            bail!(
+                "Write {} doesn't follow on from the last one ({})",
+                sequence,
+                next_sequence - 1
+            );
+        }
+        wal.append(sequence, &batch)?;
+        let batch = Arc::new(batch);
+        self.write_log.push(sequence, batch.clone());
+
+        // the memtable can't be swapped out while we hold the WAL, and
+        // readers carry on meanwhile: they won't look at this batch until
+        // its sequence is published
+        let memtable = self.state.read().unwrap().memtable.clone();
+        memtable.apply(sequence, &batch);
+        self.last_sequence.store(sequence, Ordering::Release);
+
+        if memtable.total_bytes() > LsmTree::MAX_MEMTABLE_SIZE {
+            let mut state = self.state.write().unwrap();
+            self.freeze_memtable(&mut state, wal)?;
+            drop(state);
+            drop(wal_guard);
+            self.wake_background();
+        } else if wal.size() >= self.options.wal_segment_size {
+            self.rotate_wal(&mut self.state.write().unwrap(), wal)?;
+        }
+
+        Ok(sequence)
+    }
+
+    fn read_view(&self) -> ReadView {
+        let state = self.state.read().unwrap();
+        // loaded under the lock, so it covers every write already flushed
+        // out of the memtables
+        let sequence = self.last_sequence();
+        let mut memtables = vec![state.memtable.clone()];
+        memtables.extend(
+            state
+                .immutables
+                .iter()
+                .rev()
+                .map(|immutable| immutable.memtable.clone()),
+        );
+
+        ReadView {
+            sequence,
+            memtables,
+            sstables: state.sstables.iter().rev().cloned().collect(),
+        }
+    }
+
+    fn write_stall(&self, state: &TreeState) -> WriteStall {
+        let l0_tables = state.l0_tables().count();
+        let pending_compaction_bytes = state.pending_compaction_bytes();
+
+        if state.immutables.len() >= self.options.max_immutable_memtables
+            || l0_tables >= self.options.l0_stop_writes_trigger
+            || pending_compaction_bytes >= self.options.pending_compaction_bytes_stop
+        {
+            WriteStall::Stopped
+        } else if l0_tables >= self.options.l0_slowdown_writes_trigger
+            || pending_compaction_bytes >= self.options.pending_compaction_bytes_slowdown
+        {
+            WriteStall::Delayed
+        } else {
+            WriteStall::None
+        }
+    }
+
+    /// Apply backpressure before a write: block while writes are stopped,
+    /// then sleep a little if they're only delayed
+    fn throttle_write(&self) -> Result<()> {
+        let mut stopped_at = None;
+        let mut background = self.background.lock().unwrap();
+        let stall = loop {
+            if let Some(error) = &background.error {
+                bail!("Writes are disabled after a background error: {}", error);
+            }
+
+            let stall = self.write_stall(&self.state.read().unwrap());
+            if stall != WriteStall::Stopped {
+                break stall;
+            }
+            if stopped_at.is_none() {
+                stopped_at = Some(Instant::now());
+                self.stopped_writes.fetch_add(1, Ordering::Relaxed);
+            }
+            background = self.background_cv.wait(background).unwrap();
+        };
+        drop(background);
+
+        let mut stalled_for = stopped_at.map_or(Duration::ZERO, |at| at.elapsed());
+        if stall == WriteStall::Delayed {
+            self.delayed_writes.fetch_add(1, Ordering::Relaxed);
+            std::thread::sleep(self.options.write_slowdown_delay);
+            stalled_for += self.options.write_slowdown_delay;
+        }
+        self.stall_micros
+            .fetch_add(stalled_for.as_micros() as u64, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    fn wake_background(&self) {
+        let _background = self.background.lock().unwrap();
+        self.background_cv.notify_all();
+    }
+
+    /// Turn the active memtable into an immutable one, along with its WAL
+    /// segments, and start a new segment for the next one
+    fn freeze_memtable(&self, state: &mut TreeState, wal: &mut Wal) -> Result<()> {
+        let wal_segments = std::mem::take(&mut state.wal_segments);
+        self.rotate_wal(state, wal)?;
+
+        let memtable = std::mem::replace(
+            &mut state.memtable,
+            Arc::new(Memtable::new(
+                self.options.memtable.new_rep(),
+                self.last_sequence() + 1,
+            )),
+        );
+        state.immutables.push_back(ImmutableMemtable {
+            memtable,
+            wal_segments,
+        });
+
+        Ok(())
+    }
+
+    /// Carry on appending to a new WAL segment
+    fn rotate_wal(&self, state: &mut TreeState, wal: &mut Wal) -> Result<()> {
+        let segment_path = LsmTree::wal_segment_path(&self.path, state.next_wal_id);
+        *wal = Wal::open(&segment_path)?;
+        state.next_wal_id += 1;
+        state.wal_segments.push(segment_path);
+        Ok(())
+    }
+
+    /// Delete WAL segments whose writes are all in SSTables, or move them to
+    /// the archive if `Options::wal_archive_ttl` is set (dropping any that
+    /// have been there longer than that)
+    fn retire_wal_segments(&self, segments: &[PathBuf]) -> Result<()> {
+        let Some(ttl) = self.options.wal_archive_ttl else {
+            for segment_path in segments {
+                std::fs::remove_file(segment_path)?;
+            }
+            return Ok(());
+        };
+
+        let archive_path = self.path.join(LsmTree::WAL_ARCHIVE_DIR);
+        std::fs::create_dir_all(&archive_path)?;
+        for segment_path in segments {
+            let file_name = segment_path
+                .file_name()
+                .context("WAL segment has no name")?;
+            std::fs::rename(segment_path, archive_path.join(file_name))
+                .context("Failed to archive WAL segment")?;
+        }
+
+        // oldest first, so stop at the first one still within the TTL
+        for (_, archived_path) in LsmTree::list_wal_segments(&archive_path)? {
+            let modified = std::fs::metadata(&archived_path)?.modified()?;
+            if modified.elapsed().unwrap_or_default() < ttl {
+                break;
+            }
+            std::fs::remove_file(&archived_path)?;
+        }
+
+        Ok(())
+    }
+
+    fn sstable_path(&self, sstable_id: u32) -> PathBuf {
+        self.path.join(format!(
+            "{}{}{}",
+            SSTable::FILE_NAME_PREFIX,
+            sstable_id,
+            SSTable::FILE_EXT
+        ))
+    }
+
+    /// Write the oldest immutable memtable to a level 0 SSTable, and then
+    /// drop it and retire its WAL segments. Only ever called by the
+    /// flush worker.
+    fn flush_oldest_immutable(&self) -> Result<()> {
+        let (memtable, sstable_id) = {
+            let mut state = self.state.write().unwrap();
+            let Some(immutable) = state.immutables.front() else {
+                return Ok(());
+            };
+            let memtable = immutable.memtable.clone();
+            let sstable_id = state.next_sstable_id;
+            state.next_sstable_id += 1;
+            (memtable, sstable_id)
+        };
+
+        // separate out large values first, so the blob file is durable
+        // before any SSTable points into it
+        let mut blob_writer = match self.options.blob_threshold {
+            Some(_) => Some(self.blobs.create_writer()?),
+            None => None,
+        };
+        let mut entries = Vec::with_capacity(memtable.size());
+        for (key, value) in memtable.iter() {
+            let stored = match (&mut blob_writer, self.options.blob_threshold, value) {
+                (Some(blob_writer), Some(threshold), Some(value)) if value.len() >= threshold => {
+                    StoredValue::Blob(blob_writer.append(&key, &value)?)
+                }
+                (_, _, Some(value)) => StoredValue::Inline(value),
+                (_, _, None) => StoredValue::Tombstone,
+            };
+            entries.push((key, stored));
+        }
+        let blob_file = match blob_writer {
+            Some(blob_writer) => self.blobs.finish_writer(blob_writer)?,
+            None => None,
+        };
+
+        let sstable = SSTable::from_entries(
+            &self.sstable_path(sstable_id),
+            entries,
+            memtable.range_tombstones(u64::MAX),
+            memtable.first_sequence()..=memtable.last_sequence(),
+            self.options.table_options(0, IoPriority::High),
+            &self.caches,
+        )?;
+
+        {
+            // the blob file only becomes visible to compaction's GC together
+            // with the table pointing into it
+            let mut state = self.state.write().unwrap();
+            if let Some((file_id, file_size)) = blob_file {
+                self.blobs.register(file_id, file_size);
+            }
+            state.sstables.push(Arc::new(sstable));
+            let flushed = state.immutables.pop_front().unwrap();
+            // still under the lock, so readers of the WAL (see
+            // `write_log::records_since`) find each segment either here or in
+            // the archive
+            self.retire_wal_segments(&flushed.wal_segments)?;
+        }
+
+        Ok(())
+    }
+
+    fn compact(&self, inputs: CompactionInputs) -> Result<()> {
+        let _compaction = self.compaction_lock.lock().unwrap();
+        self.compact_locked(inputs)
+    }
+
+    /// See `LsmTree::compact_all` and friends, called with `compaction_lock`
+    /// held. Inputs are picked from the SSTables at the time it starts,
+    /// tables flushed in the meantime are left alone.
+    fn compact_locked(&self, inputs: CompactionInputs) -> Result<()> {
+        let result = self.compact_inputs(inputs);
+        *self.compaction_progress.lock().unwrap() = None;
+        result
+    }
+
+    fn compact_inputs(&self, inputs: CompactionInputs) -> Result<()> {
+        let (snapshot, blob_files) = {
+            let state = self.state.read().unwrap();
+            if state.sstables.is_empty() {
+                return Ok(());
+            }
+            (state.sstables.clone(), self.blobs.files())
+        };
+
+        let Some(picked) = compaction::pick_inputs(&snapshot, inputs)? else {
+            return Ok(());
+        };
+        let inputs: Vec<Arc<SSTable>> = snapshot
+            .iter()
+            .zip(&picked.selected)
+            .filter(|(_, &selected)| selected)
+            .map(|(table, _)| table.clone())
+            .collect();
+        let is_full_compaction = inputs.len() == snapshot.len();
+
+        *self.compaction_progress.lock().unwrap() = Some(CompactionProgress {
+            input_tables: inputs.len(),
+            input_bytes: inputs.iter().map(|table| table.file_size).sum(),
+            ..CompactionProgress::default()
+        });
+
+        // other tables may point into any blob file, so only a full
+        // compaction knows which values are garbage. It takes a pass of its
+        // own to find out, which counts values the compaction filter goes
+        // on to drop as live: their files are left for the next one.
+        let mut relocated_files = HashSet::new();
+        let mut obsolete_files = Vec::new();
+        if is_full_compaction && !blob_files.is_empty() {
+            // records are `<8 byte header><key><value>`, same as the file
+            // sizes we track
+            let mut live_blob_bytes: HashMap<u32, u64> = HashMap::new();
+            for read_result in MergedEntries::new(&inputs)? {
+                if let (key, StoredValue::Blob(pointer)) = read_result? {
+                    *live_blob_bytes.entry(pointer.file_id).or_default() +=
+                        8 + key.len() as u64 + pointer.length as u64;
+                }
+            }
+            for (&file_id, &file_size) in &blob_files {
+                match live_blob_bytes.get(&file_id) {
+                    None => obsolete_files.push(file_id),
+                    Some(&live_bytes) => {
+                        let garbage_ratio = 1.0 - live_bytes as f64 / file_size.max(1) as f64;
+                        if garbage_ratio >= self.options.blob_gc_ratio {
+                            relocated_files.insert(file_id);
+                            obsolete_files.push(file_id);
+                        }
+                    }
+                }
+            }
+        }
+
+        // holds filter rewrites too big to inline and relocated blob values
+        let mut blob_writer = None;
+        let filter_context = CompactionFilterContext {
+            output_level: 1,
+            is_full_compaction,
+        };
+        let mut merged = MergedEntries::new(&inputs)?;
+        let mut merged_tables = 0;
+        let mut next_entry = || -> Result<Option<(Vec<u8>, StoredValue)>> {
+            while let Some(read_result) = merged.next() {
+                let (key, mut value) = read_result?;
+
+                let (finished_tables, finished_bytes) = merged
+                    .finished_tables()
+                    .fold((0, 0), |(tables, bytes), table| {
+                        (tables + 1, bytes + table.file_size)
+                    });
+                if finished_tables > merged_tables {
+                    merged_tables = finished_tables;
+                    if let Some(progress) = self.compaction_progress.lock().unwrap().as_mut() {
+                        progress.merged_tables = finished_tables;
+                        progress.merged_bytes = finished_bytes;
+                    }
+                }
+
+                // with nothing older left for tombstones to hide, they can go
+                // (range tombstones included, by not carrying them over)
+                if value == StoredValue::Tombstone {
+                    if picked.bottommost {
+                        continue;
+                    }
+                    return Ok(Some((key, value)));
+                }
+
+                // index entries aren't records
+                let filter = self
+                    .options
+                    .compaction_filter
+                    .as_ref()
+                    .filter(|_| !secondary_index::is_reserved(self, &key));
+                if let Some(filter) = filter {
+                    let decision = match &value {
+                        StoredValue::Inline(bytes) => filter.filter(&filter_context, &key, bytes),
+                        StoredValue::Blob(pointer) => {
+                            filter.filter(&filter_context, &key, &self.blobs.read(pointer)?)
+                        }
+                        StoredValue::Tombstone => unreachable!("handled above"),
+                    };
+                    match decision {
+                        FilterDecision::Keep => {}
+                        // dropping it is enough, nothing older is left below
+                        FilterDecision::Remove if picked.bottommost => continue,
+                        FilterDecision::Remove => return Ok(Some((key, StoredValue::Tombstone))),
+                        FilterDecision::ChangeValue(new_value) => {
+                            value = match self.options.blob_threshold {
+                                Some(threshold) if new_value.len() >= threshold => {
+                                    let writer = match &mut blob_writer {
+                                        Some(writer) => writer,
+                                        None => blob_writer.insert(self.blobs.create_writer()?),
+                                    };
+                                    StoredValue::Blob(writer.append(&key, &new_value)?)
+                                }
+                                _ if new_value.len() > LsmTree::MAX_ENTRY_SIZE => bail!(
+                                    "Compaction filter value too large (length={})",
+                                    new_value.len()
+                                ),
+                                _ => StoredValue::Inline(new_value),
+                            };
+                        }
+                    }
+                }
+
+                if let StoredValue::Blob(pointer) = &mut value {
+                    if relocated_files.contains(&pointer.file_id) {
+                        let bytes = self.blobs.read(pointer)?;
+                        let writer = match &mut blob_writer {
+                            Some(writer) => writer,
+                            None => blob_writer.insert(self.blobs.create_writer()?),
+                        };
+                        *pointer = writer.append(&key, &bytes)?;
+                    }
+                }
+                return Ok(Some((key, value)));
+            }
+            Ok(None)
+        };
+
+        // streamed from the inputs into tables of about `target_file_size`,
+        // so a later compaction only has to rewrite those its keys are in.
+        // Range tombstones aren't split up with them though, so a
+        // compaction that keeps some writes a single table.
+        let smallest_sequence = inputs
+            .iter()
+            .map(|table| table.properties().smallest_sequence)
+            .min()
+            .unwrap_or_default();
+        let largest_sequence = inputs
+            .iter()
+            .map(|table| table.properties().largest_sequence)
+            .max()
+            .unwrap_or_default();
+        let mut range_tombstones = Vec::new();
+        if !picked.bottommost {
+            for table in &inputs {
+                range_tombstones.extend_from_slice(table.range_tombstones());
+            }
+        }
+        let split = range_tombstones.is_empty();
+        let mut read_error = None;
+        let mut entries = std::iter::from_fn(|| {
+            next_entry().unwrap_or_else(|e| {
+                read_error = Some(e);
+                None
+            })
+        })
+        .peekable();
+        let mut outputs = Vec::new();
+        let mut output_paths = Vec::new();
+        let write_result = loop {
+            let output_path = {
+                // nothing flushed in the meantime can end up with the same id
+                let mut state = self.state.write().unwrap();
+                let output_id = state.next_sstable_id;
+                state.next_sstable_id += 1;
+                self.sstable_path(output_id)
+            };
+            output_paths.push(output_path.clone());
+
+            let mut output_bytes = 0;
+            let output_entries = std::iter::from_fn(|| {
+                if split && output_bytes >= self.options.target_file_size {
+                    return None;
+                }
+                let (key, value) = entries.next()?;
+                output_bytes += (key.len() + value.encoded_len()) as u64;
+                Some((key, value))
+            });
+            match SSTable::from_entries(
+                &output_path,
+                output_entries,
+                std::mem::take(&mut range_tombstones),
+                smallest_sequence..=largest_sequence,
+                self.options.table_options(1, IoPriority::Low),
+                &self.caches,
+            ) {
+                Ok(output) => outputs.push(Arc::new(output)),
+                Err(e) => break Err(e),
+            }
+            if entries.peek().is_none() {
+                break Ok(());
+            }
+        };
+        drop(entries);
+        let relocated_blob_file = match (write_result, read_error) {
+            (Ok(()), None) => match blob_writer {
+                Some(writer) => self.blobs.finish_writer(writer)?,
+                None => None,
+            },
+            (Err(e), _) | (_, Some(e)) => {
+                // never installed, so never read
+                for output_path in &output_paths {
+                    self.caches.table.evict(output_path);
+                    let _ = std::fs::remove_file(output_path);
+                }
+                return Err(e);
+            }
+        };
+
+        // old tables and the blob files only they pointed into are deleted
+        // once the last reader holding on to any of them is done
+        let mut obsolete_paths: Vec<PathBuf> =
+            inputs.iter().map(|table| table.path.clone()).collect();
+        obsolete_paths.extend(
+            obsolete_files
+                .iter()
+                .map(|file_id| self.blobs.file_path(*file_id)),
+        );
+        let obsolete = Arc::new(ObsoleteFiles {
+            paths: obsolete_paths,
+            table_cache: self.caches.table.clone(),
+        });
+
+        {
+            let mut state = self.state.write().unwrap();
+            if let Some((file_id, file_size)) = relocated_blob_file {
+                self.blobs.register(file_id, file_size);
+            }
+            for file_id in &obsolete_files {
+                self.blobs.forget(*file_id);
+            }
+            state
+                .sstables
+                .retain(|table| !inputs.iter().any(|input| Arc::ptr_eq(input, table)));
+            // right after the newest table we started from, ahead of any
+            // flushed in the meantime (tables are ordered by sequence on open)
+            let position = state
+                .sstables
+                .iter()
+                .rposition(|table| snapshot.iter().any(|other| Arc::ptr_eq(other, table)))
+                .map_or(0, |i| i + 1);
+            state.sstables.splice(position..position, outputs);
+        }
+
+        for input in &inputs {
+            *input.obsolete.lock().unwrap() = Some(obsolete.clone());
+        }
+
+        Ok(())
+    }
+}
+
+/// Files replaced by a compaction, deleted when the last `SSTable` that
+/// was an input to it is dropped (so in-flight reads never lose a file)
+#[derive(Debug)]
+struct ObsoleteFiles {
+    paths: Vec<PathBuf>,
+    table_cache: Arc<TableCache>,
+}
+
+impl Drop for ObsoleteFiles {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            self.table_cache.evict(path);
+            // nothing useful to do on failure, it's just leftover garbage on disk
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// fsync a directory, making the files created in (or renamed into) it durable
+fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path)?
+        .sync_all()
+        .with_context(|| format!("Failed to sync {}", path.display()))
+}
+
+/// Helper function to read out key/value pairs from a file given
+/// our binary format: `<u32 key length><u32 value length><key bytes><val bytes>`
+/// Values longer than `max_value_length` are treated as corruption.
+fn read_entry_from_header<R: Read>(
+    reader: &mut R,
+    max_value_length: usize,
+) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let mut header = [0u8; 8];
+
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e).context("Failed to read entry header"),
+    }
+
+    let key_length = u32::from_le_bytes(
+        header
+            .get(0..4)
+            .ok_or_else(|| anyhow!("Invalid header: missing key length"))?
+            .try_into()
+            .context("Invalid key length slice")?,
+    ) as usize;
+
+    let value_length = u32::from_le_bytes(
+        header
+            .get(4..8)
+            .ok_or_else(|| anyhow!("Invalid header: missing value length"))?
+            .try_into()
+            .context("Invalid value length slice")?,
+    ) as usize;
+
+    // defensive check to avoid any OOM even though we also check on write
+    if key_length > LsmTree::MAX_ENTRY_SIZE || value_length > max_value_length {
+        bail!(
+            "Corrupt entry: header saying key or value is too large (key length={} value length={})",
+            key_length,
+            value_length
+        )
+    }
+
+    let mut key = vec![0u8; key_length];
+    reader.read_exact(&mut key)?;
+
+    let mut value = vec![0u8; value_length];
+    reader.read_exact(&mut value)?;
+
+    Ok(Some((key, value)))
+}
+
+/// A write read back out of the WAL
+#[derive(Debug)]
+pub struct WalRecord {
+    pub sequence: u64,
+    /// When it was appended
+    pub timestamp: SystemTime,
+    pub batch: WriteBatch,
+}
+
+/// One segment of the write-ahead log
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+}
+
+impl Wal {
+    // segments start with `<magic><u16 format version>`
+    const MAGIC: &'static [u8; 6] = b"LSMWAL";
+    const HEADER_SIZE: usize = 6 + 2;
+    const FORMAT_VERSION: u16 = 2;
+    // <u32 batch length><u64 sequence><u64 timestamp>
+    const RECORD_HEADER_SIZE: usize = 4 + 8 + 8;
+    const MAX_BATCH_SIZE: usize = u32::MAX as usize;
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let path = path.to_path_buf();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)?;
+        let mut size = file.metadata()?.len();
+
+        // too short to hold a record, so at most a header torn by a crash
+        if size < Self::HEADER_SIZE as u64 {
+            file.set_len(0)?;
+            let mut header = Self::MAGIC.to_vec();
+            header.extend_from_slice(&Self::FORMAT_VERSION.to_le_bytes());
+            (&file).write_all(&header)?;
+            file.sync_all()?;
+            size = Self::HEADER_SIZE as u64;
+        } else {
+            Self::read_header(&mut BufReader::new(&file))
+                .with_context(|| format!("Failed to open WAL {}", path.display()))?;
+        }
+
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+            size,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Bytes in the segment so far
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Append a write batch as a single record and fsync, using the
+    /// following log format:
+    /// `<u32 batch length><u64 sequence><u64 timestamp><batch>`
+    /// where the timestamp is in microseconds since the Unix epoch, and
+    /// the batch is a run of `<u32 key length><u32 value length><key bytes><val bytes>`
+    /// entries, with values tagged as in SSTables (value or tombstone).
+    pub fn append(&mut self, sequence: u64, batch: &WriteBatch) -> Result<()> {
+        let mut encoded_batch = Vec::new();
+        batch.encode(&mut encoded_batch);
+        if encoded_batch.len() > Self::MAX_BATCH_SIZE {
+            bail!("Write batch too large ({} bytes)", encoded_batch.len());
+        }
+
+        self.writer
+            .write_all(&(encoded_batch.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&sequence.to_le_bytes())?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_micros() as u64);
+        self.writer.write_all(&timestamp.to_le_bytes())?;
+        self.writer.write_all(&encoded_batch)?;
+
+        // flush buffer and sync the file for durability
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        self.size += (Self::RECORD_HEADER_SIZE + encoded_batch.len()) as u64;
+
+        Ok(())
+    }
+
+    /// Replay all records in the WAL (returns sequenced batches to rebuild
+    /// the memtable). A record torn by a crash mid-append is cut off, so
+    /// new records don't land after garbage.
+    pub fn replay(&mut self) -> Result<Vec<(u64, WriteBatch)>> {
+        // flush out any buffered writes
+        self.writer.flush()?;
+
+        let (records, valid_length) = Self::read_records_with_length(&self.path)?;
+        if valid_length < self.writer.get_ref().metadata()?.len() {
+            self.writer.get_ref().set_len(valid_length)?;
+            self.writer.get_ref().sync_all()?;
+        }
+        self.size = valid_length;
+
+        Ok(records
+            .into_iter()
+            .map(|record| (record.sequence, record.batch))
+            .collect())
+    }
+
+    /// Read all records of the WAL at `path` without opening it for writes.
+    /// A missing log is treated as empty.
+    pub fn read_records(path: &Path) -> Result<Vec<(u64, WriteBatch)>> {
+        Ok(Self::read_timed_records(path)?
+            .into_iter()
+            .map(|record| (record.sequence, record.batch))
+            .collect())
+    }
+
+    /// `read_records`, along with when each write was made
+    pub fn read_timed_records(path: &Path) -> Result<Vec<WalRecord>> {
+        Ok(Self::read_records_with_length(path)?.0)
+    }
+
+    /// Sequence of the first record in the WAL at `path`, if it has one
+    pub fn first_sequence(path: &Path) -> Result<Option<u64>> {
+        let file = match OpenOptions::new().read(true).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("Failed to open WAL"),
+        };
+        // at most a header torn by a crash
+        if file.metadata()?.len() < Self::HEADER_SIZE as u64 {
+            return Ok(None);
+        }
+        let mut reader = BufReader::new(file);
+        Self::read_header(&mut reader)
+            .with_context(|| format!("Failed to read WAL {}", path.display()))?;
+        let mut header = [0u8; Self::RECORD_HEADER_SIZE];
+        match reader.read_exact(&mut header) {
+            Ok(()) => Ok(Some(u64::from_le_bytes(header[4..12].try_into()?))),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e).context("Failed to read WAL record"),
+        }
+    }
+
+    /// Read the puts of a single-file WAL from before writes were
+    /// sequenced, in the tree's original log format
+    /// `<u32 key length><u32 value length><key bytes><val bytes>`,
+    /// numbering them from `first_sequence`. A missing log is treated as empty.
+    pub fn read_legacy_records(path: &Path, first_sequence: u64) -> Result<Vec<(u64, WriteBatch)>> {
+        let file = match OpenOptions::new().read(true).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context("Failed to open WAL"),
+        };
+        let mut reader = BufReader::new(file);
+
+        let mut records = Vec::new();
+        while let Some((key, value)) = read_entry_from_header(&mut reader, LsmTree::MAX_ENTRY_SIZE)
+            .with_context(|| format!("Failed to read WAL record from {}", path.display()))?
+        {
+            let mut batch = WriteBatch::new();
+            batch.put(key, value);
+            records.push((first_sequence + records.len() as u64, batch));
+        }
+        Ok(records)
+    }
+
+    /// Check the segment header at the start of a WAL, leaving `reader`
+    /// at the first record
+    fn read_header<R: Read>(reader: &mut R) -> Result<()> {
+        let mut header = [0u8; Self::HEADER_SIZE];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                bail!("WAL segment header is missing")
+            }
+            Err(e) => return Err(e).context("Failed to read WAL header"),
+        }
+        if header[..Self::MAGIC.len()] != Self::MAGIC[..] {
+            bail!("Not a WAL segment (bad magic)");
+        }
+
+        let version = u16::from_le_bytes(header[Self::MAGIC.len()..].try_into()?);
+        if version != Self::FORMAT_VERSION {
+            bail!("Unsupported WAL format version {}", version);
+        }
+        Ok(())
+    }
+
+    /// Also returns the length of the log up to the end of the last complete record
+    fn read_records_with_length(path: &Path) -> Result<(Vec<WalRecord>, u64)> {
+        let file = match OpenOptions::new().read(true).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+            Err(e) => return Err(e).context("Failed to open WAL"),
+        };
+        let file_length = file.metadata()?.len();
+        // a header torn by a crash is rewritten when the segment is opened
+        if file_length < Self::HEADER_SIZE as u64 {
+            return Ok((Vec::new(), 0));
+        }
+        let mut reader = BufReader::new(file);
+        Self::read_header(&mut reader)
+            .with_context(|| format!("Failed to read WAL {}", path.display()))?;
+        let mut valid_length = Self::HEADER_SIZE as u64;
+
+        let mut records = Vec::new();
+        loop {
+            let mut header = [0u8; Self::RECORD_HEADER_SIZE];
+            match reader.read_exact(&mut header) {
+                Ok(()) => {}
+                // EOF, or a header torn by a crash
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e).context("Failed to read WAL record"),
+            }
+            let batch_length = u32::from_le_bytes(header[0..4].try_into()?) as u64;
+            let sequence = u64::from_le_bytes(header[4..12].try_into()?);
+            let timestamp =
+                UNIX_EPOCH + Duration::from_micros(u64::from_le_bytes(header[12..20].try_into()?));
+
+            let record_end = valid_length + header.len() as u64 + batch_length;
+            if record_end > file_length {
+                break; // torn batch
+            }
+
+            let mut encoded_batch = vec![0u8; batch_length as usize];
+            reader.read_exact(&mut encoded_batch)?;
+            let batch = WriteBatch::decode(&encoded_batch).context("Failed to read WAL record")?;
+
+            records.push(WalRecord {
+                sequence,
+                timestamp,
+                batch,
+            });
+            valid_length = record_end;
+        }
+
+        Ok((records, valid_length))
+    }
+}
+
+/// A value as it's stored in an SSTable: the bytes themselves, a
+/// pointer into a blob file for values separated out on flush, or a
+/// tombstone marking the key as deleted.
+/// Encoded as a `u8` tag followed by the value bytes or the pointer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoredValue {
+    Inline(Vec<u8>),
+    Blob(BlobPointer),
+    Tombstone,
+}
+
+impl StoredValue {
+    const INLINE_TAG: u8 = 0;
+    const BLOB_TAG: u8 = 1;
+    const TOMBSTONE_TAG: u8 = 2;
+    const MAX_ENCODED_SIZE: usize = 1 + LsmTree::MAX_ENTRY_SIZE;
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            StoredValue::Inline(value) => Self::encode_inline(value, buf),
+            StoredValue::Blob(pointer) => {
+                buf.push(Self::BLOB_TAG);
+                pointer.encode(buf);
+            }
+            StoredValue::Tombstone => buf.push(Self::TOMBSTONE_TAG),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        1 + match self {
+            StoredValue::Inline(value) => value.len(),
+            StoredValue::Blob(_) => BlobPointer::ENCODED_SIZE,
+            StoredValue::Tombstone => 0,
+        }
+    }
+
+    /// Same as encoding `StoredValue::Inline(value)`, without the copy
+    fn encode_inline(value: &[u8], buf: &mut Vec<u8>) {
+        buf.push(Self::INLINE_TAG);
+        buf.extend_from_slice(value);
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        match bytes.split_first() {
+            Some((&Self::INLINE_TAG, value)) => Ok(StoredValue::Inline(value.to_vec())),
+            Some((&Self::BLOB_TAG, pointer)) => {
+                Ok(StoredValue::Blob(BlobPointer::decode(pointer)?))
+            }
+            Some((&Self::TOMBSTONE_TAG, [])) => Ok(StoredValue::Tombstone),
+            Some((tag, _)) => bail!("Unknown stored value tag {}", tag),
+            None => bail!("Empty stored value"),
+        }
+    }
+
+    /// The actual value bytes, reading them from the blob file if separated
+    /// (`None` for a tombstone)
+    pub fn resolve(self, blobs: &BlobStore) -> Result<Option<Vec<u8>>> {
+        match self {
+            StoredValue::Inline(value) => Ok(Some(value)),
+            StoredValue::Blob(pointer) => Ok(Some(blobs.read(&pointer)?)),
+            StoredValue::Tombstone => Ok(None),
+        }
+    }
+}
+
+/// Location of a data block inside an SSTable file, along with the
+/// last key it holds so the index can be binary searched
+#[derive(Debug, Clone)]
+pub struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u64,
+    // on-disk length, including the compression header
+    length: u64,
+    uncompressed_length: u64,
+}
+
+impl BlockHandle {
+    const ENCODED_SIZE: usize = 8 + 8 + 8;
+}
+
+/// Helper function to append a key/value pair to a buffer in the same
+/// `<u32 key length><u32 value length><key bytes><val bytes>` format
+fn encode_entry(buf: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+}
+
+/// Helper function to decode every entry in an in-memory block
+fn decode_entries(mut block: &[u8], max_value_length: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut entries = Vec::new();
+    while let Some(entry) = read_entry_from_header(&mut block, max_value_length)? {
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// How `SSTable::from_entries` should write a table
+#[derive(Debug, Clone, Copy)]
+pub struct TableOptions<'a> {
+    pub level: u8,
+    pub compression: Compression,
+    /// Entries between full keys in a data block, see `Block`
+    pub restart_interval: usize,
+    /// Every write first asks the limiter for its bytes at the given priority
+    pub rate_limiter: Option<(&'a RateLimiter, IoPriority)>,
+}
+
+/// Everything about a table besides its data and index, loaded once when
+/// it's opened
+#[derive(Debug, Clone, Default)]
+pub struct TableMeta {
+    /// 0 for tables flushed from a memtable, 1 for compaction output
+    pub level: u8,
+    pub range_tombstones: Vec<RangeTombstone>,
+    pub properties: TableProperties,
+}
+
+/// An on-disk sorted table laid out as:
+/// `[data block]...[data block][meta block]...[index block][metaindex block][footer]`
+///
+/// Data blocks hold prefix compressed entries (see `Block`) and are cut at
+/// roughly `BLOCK_SIZE` bytes (before compression, see `Compression` for the
+/// block header). The index block holds one record per data block
+/// (`last key -> <u64 offset><u64 length><u64 uncompressed length>`).
+/// Meta blocks (the table's properties, plus range tombstones if it has any)
+/// are found by name through the metaindex block
+/// (`name -> <u64 offset><u64 length>`), and the fixed size footer is
+/// `<u64 metaindex offset><u64 metaindex length><u64 index offset><u64 index length><u8 level><u32 magic>`.
+///
+/// The struct itself is only a handle, reads go through the tree's
+/// `TableCache` (open file + parsed index) and `BlockCache` (decoded blocks).
+#[derive(Debug)]
+pub struct SSTable {
+    // from the `sstable_{id}.sst` file name, newer tables have larger ids
+    id: u32,
+    path: PathBuf,
+    meta: TableMeta,
+    file_size: u64,
+    caches: Caches,
+    // process-unique, so tables of different trees never collide in a shared
+    // block cache, and kept for as long as the table so that its blocks are
+    // still found after the table cache has closed and reopened it
+    cache_id: u64,
+    // set once a compaction has replaced this table
+    obsolete: Mutex<Option<Arc<ObsoleteFiles>>>,
+}
+
+impl SSTable {
+    const FILE_NAME_PREFIX: &'static str = "sstable_";
+    const FILE_EXT: &'static str = ".sst";
+    const BLOCK_SIZE: usize = 4 * 1024; // 4 KB
+    const FOOTER_SIZE: u64 = 8 + 8 + 8 + 8 + 1 + 4;
+    const MAGIC: u32 = 0x4c53_4d36; // "LSM6"
+    const RANGE_TOMBSTONES_BLOCK: &'static str = "range_tombstones";
+    const PROPERTIES_BLOCK: &'static str = "properties";
+
+    /// Creates an SSTable file from pre-sorted entries (e.g. a memtable),
+    /// along with range tombstones hiding keys in older tables, written by
+    /// the batches numbered `sequences`.
+    /// Entries keep the record format, but are grouped into indexed blocks
+    /// that are compressed with `options.compression`.
+    pub fn from_entries<K: AsRef<[u8]>>(
+        path: &Path,
+        entries: impl IntoIterator<Item = (K, StoredValue)>,
+        range_tombstones: Vec<RangeTombstone>,
+        sequences: RangeInclusive<u64>,
+        options: TableOptions,
+        caches: &Caches,
+    ) -> Result<Self> {
+        let throttle = |bytes: usize| {
+            if let Some((rate_limiter, priority)) = options.rate_limiter {
+                rate_limiter.request(bytes, priority);
+            }
+        };
+
+        let id = Self::id_from_path(path)?;
+        let path_buf = path.to_path_buf();
+        let mut file = BufWriter::new(File::create(path)?);
+
+        let mut index = Vec::new();
+        let mut offset = 0u64;
+        let mut block = BlockBuilder::new(options.restart_interval);
+        let mut compressed_block = Vec::new();
+        let mut encoded_value = Vec::new();
+        let mut properties = TableProperties {
+            range_tombstones: range_tombstones.len() as u64,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+            smallest_sequence: *sequences.start(),
+            largest_sequence: *sequences.end(),
+            ..TableProperties::default()
+        };
+
+        let mut write_block = |block: &mut BlockBuilder| -> Result<BlockHandle> {
+            let last_key = block.last_key().to_vec();
+            let block = block.finish();
+            compressed_block.clear();
+            options
+                .compression
+                .compress_block(block, &mut compressed_block)?;
+            throttle(compressed_block.len());
+            file.write_all(&compressed_block)?;
+
+            let handle = BlockHandle {
+                last_key,
+                offset,
+                length: compressed_block.len() as u64,
+                uncompressed_length: block.len() as u64,
+            };
+            offset += handle.length;
+            Ok(handle)
+        };
+        let mut cut_block = |block: &mut BlockBuilder| -> Result<()> {
+            index.push(write_block(block)?);
+            block.reset();
+            Ok(())
+        };
+
+        // write all of the pre-sorted data, cutting a block when it fills up
+        for (key, value) in entries {
+            encoded_value.clear();
+            value.encode(&mut encoded_value);
+            block.add(key.as_ref(), &encoded_value);
+
+            if properties.entries == 0 {
+                properties.smallest_key.extend_from_slice(key.as_ref());
+            }
+            properties.entries += 1;
+            if value == StoredValue::Tombstone {
+                properties.tombstones += 1;
+            }
+            properties.raw_bytes += (key.as_ref().len() + encoded_value.len()) as u64;
+
+            if block.size() >= Self::BLOCK_SIZE {
+                cut_block(&mut block)?;
+            }
+        }
+
+        if !block.is_empty() {
+            cut_block(&mut block)?;
+        }
+
+        let mut offset = index
+            .last()
+            .map_or(0, |handle| handle.offset + handle.length);
+        properties.encoded_bytes = offset;
+        if let Some(last_block) = index.last() {
+            properties.largest_key.clone_from(&last_block.last_key);
+        }
+
+        // meta blocks are small and read once on open, so left uncompressed
+        let mut metaindex_block = Vec::new();
+        let mut write_meta_block = |name: &str, meta_block: &[u8]| -> Result<()> {
+            throttle(meta_block.len());
+            file.write_all(meta_block)?;
+            let mut location = Vec::with_capacity(8 + 8);
+            location.extend_from_slice(&offset.to_le_bytes());
+            location.extend_from_slice(&(meta_block.len() as u64).to_le_bytes());
+            encode_entry(&mut metaindex_block, name.as_bytes(), &location);
+            offset += meta_block.len() as u64;
+            Ok(())
+        };
+        if !range_tombstones.is_empty() {
+            let mut tombstone_block = Vec::new();
+            RangeTombstone::encode_block(&range_tombstones, &mut tombstone_block);
+            write_meta_block(Self::RANGE_TOMBSTONES_BLOCK, &tombstone_block)?;
+        }
+        let mut properties_block = Vec::new();
+        properties.encode_block(&mut properties_block);
+        write_meta_block(Self::PROPERTIES_BLOCK, &properties_block)?;
+
+        let index_offset = offset;
+        let mut index_block = Vec::new();
+        for handle in &index {
+            let mut location = Vec::with_capacity(BlockHandle::ENCODED_SIZE);
+            location.extend_from_slice(&handle.offset.to_le_bytes());
+            location.extend_from_slice(&handle.length.to_le_bytes());
+            location.extend_from_slice(&handle.uncompressed_length.to_le_bytes());
+            encode_entry(&mut index_block, &handle.last_key, &location);
+        }
+        let metaindex_offset = index_offset + index_block.len() as u64;
+        throttle(index_block.len() + metaindex_block.len() + Self::FOOTER_SIZE as usize);
+        file.write_all(&index_block)?;
+        file.write_all(&metaindex_block)?;
+
+        file.write_all(&metaindex_offset.to_le_bytes())?;
+        file.write_all(&(metaindex_block.len() as u64).to_le_bytes())?;
+        file.write_all(&index_offset.to_le_bytes())?;
+        file.write_all(&(index_block.len() as u64).to_le_bytes())?;
+        file.write_all(&[options.level])?;
+        file.write_all(&Self::MAGIC.to_le_bytes())?;
+
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        let file_size = metaindex_offset + metaindex_block.len() as u64 + Self::FOOTER_SIZE;
+
+        let meta = TableMeta {
+            level: options.level,
+            range_tombstones,
+            properties,
+        };
+
+        // the index is already in hand, so seed the table cache with it
+        let cache_id = Self::next_cache_id();
+        caches.table.insert(
+            path,
+            TableReader::new(File::open(path)?, cache_id, index, meta.clone()),
+        );
+
+        Ok(Self {
+            id,
+            path: path_buf,
+            meta,
+            file_size,
+            caches: caches.clone(),
+            cache_id,
+            obsolete: Mutex::new(None),
+        })
+    }
+
+    /// Open an existing SSTable, validating its footer and index on the way
+    pub fn open(path: &Path, caches: &Caches) -> Result<Self> {
+        let cache_id = Self::next_cache_id();
+        let reader = caches.table.get_or_open(path, cache_id)?;
+
+        Ok(Self {
+            id: Self::id_from_path(path)?,
+            path: path.to_path_buf(),
+            meta: reader.meta.clone(),
+            file_size: std::fs::metadata(path)?.len(),
+            caches: caches.clone(),
+            cache_id,
+            obsolete: Mutex::new(None),
+        })
+    }
+
+    /// Id from a file name of the form `sstable_{id}.sst`
+    fn parse_id(file_name: &str) -> Option<u32> {
+        file_name
+            .strip_prefix(Self::FILE_NAME_PREFIX)
+            .and_then(|s| s.strip_suffix(Self::FILE_EXT))
+            .and_then(|s| s.parse::<u32>().ok())
+    }
+
+    fn id_from_path(path: &Path) -> Result<u32> {
+        path.file_name()
+            .and_then(|x| x.to_str())
+            .and_then(Self::parse_id)
+            .ok_or_else(|| anyhow!("Invalid SSTable file name {}", path.display()))
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// 0 for tables flushed from a memtable, 1 for compaction output
+    pub fn level(&self) -> u8 {
+        self.meta.level
+    }
+
+    pub fn properties(&self) -> &TableProperties {
+        &self.meta.properties
+    }
+
+    /// Smallest and largest key the table holds, widened to cover its range
+    /// tombstones (whose exclusive ends count as included). `None` if the
+    /// table is empty.
+    fn key_span(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let properties = &self.meta.properties;
+        let mut span = (properties.entries > 0).then(|| {
+            (
+                properties.smallest_key.clone(),
+                properties.largest_key.clone(),
+            )
+        });
+        for tombstone in self.range_tombstones() {
+            let (start, end) =
+                span.get_or_insert_with(|| (tombstone.start.clone(), tombstone.end.clone()));
+            if tombstone.start < *start {
+                start.clone_from(&tombstone.start);
+            }
+            if tombstone.end > *end {
+                end.clone_from(&tombstone.end);
+            }
+        }
+        span
+    }
+
+    /// Whether the table may have anything to say about `key`, without
+    /// touching the file
+    fn may_contain(&self, key: &[u8]) -> bool {
+        self.meta.properties.overlaps(key, key) || self.covering_range_tombstone(key).is_some()
+    }
+
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.meta.range_tombstones
+    }
+
+    /// A range tombstone of this table hiding `key` in older tables
+    pub fn covering_range_tombstone(&self, key: &[u8]) -> Option<&RangeTombstone> {
+        range_tombstone::covering(&self.meta.range_tombstones, key)
+    }
+
+    fn next_cache_id() -> u64 {
+        static NEXT_CACHE_ID: AtomicU64 = AtomicU64::new(0);
+        NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn reader(&self) -> Result<Arc<TableReader>> {
+        self.caches
+            .table
+            .get_or_open(&self.path, self.cache_id)
+            .with_context(|| format!("Failed to open SSTable {}", self.path.display()))
+    }
+
+    /// Number of data blocks in the table
+    pub fn block_count(&self) -> Result<usize> {
+        Ok(self.reader()?.index.len())
+    }
+
+    /// Index of the first block that could contain `key`
+    /// (equal to `block_count()` if `key` is past the end of the table)
+    fn find_block(&self, key: &[u8]) -> Result<usize> {
+        Ok(self.reader()?.find_block(key))
+    }
+
+    /// Read and decode a single data block (through the block cache)
+    fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
+        self.reader()?.read_block(block_idx, &self.caches.block)
+    }
+
+    /// Find a single key on-disk.
+    /// Uses the index to find the only block that could hold the key,
+    /// then binary searches that block's restart points.
+    /// Keys only hidden by one of this table's range tombstones come back
+    /// as `StoredValue::Tombstone`.
+    pub fn get(&self, target_key: &[u8]) -> Result<Option<StoredValue>> {
+        Ok(self.multi_get(&[target_key])?.pop().flatten())
+    }
+
+    /// `get` for several keys at once, which must be sorted. Neighbouring
+    /// keys in the same block share a single read of it, and the file isn't
+    /// opened at all unless one of the keys is in the table's range.
+    pub fn multi_get(&self, target_keys: &[&[u8]]) -> Result<Vec<Option<StoredValue>>> {
+        let mut found = vec![None; target_keys.len()];
+        let mut reader = None;
+        let mut current_block: Option<(usize, Arc<Block>)> = None;
+
+        for (target_key, found) in target_keys.iter().zip(&mut found) {
+            if !self.may_contain(target_key) {
+                continue;
+            }
+
+            let reader = match &reader {
+                Some(reader) => reader,
+                None => reader.insert(self.reader()?),
+            };
+            let block_idx = reader.find_block(target_key);
+            if block_idx < reader.index.len() {
+                if current_block.as_ref().map(|(idx, _)| *idx) != Some(block_idx) {
+                    let block = reader.read_block(block_idx, &self.caches.block)?;
+                    current_block = Some((block_idx, block));
+                }
+                if let Some((_, block)) = &current_block {
+                    *found = block.get(target_key)?;
+                }
+            }
+
+            if found.is_none() && self.covering_range_tombstone(target_key).is_some() {
+                *found = Some(StoredValue::Tombstone);
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Simple iterator for convenience to go over all key/values
+    /// in an SSTable (primarily for compaction)
+    pub fn iter(&self) -> Result<impl Iterator<Item = Result<(Vec<u8>, StoredValue)>> + '_> {
+        let reader = self.reader()?;
+        let mut block_idx = 0;
+        let mut entries = Vec::new().into_iter();
+        Ok(std::iter::from_fn(move || loop {
+            if let Some(entry) = entries.next() {
+                return Some(Ok(entry));
+            }
+            if block_idx == reader.index.len() {
+                return None; // EOF
+            }
+            match reader
+                .read_block(block_idx, &self.caches.block)
+                .and_then(|block| block.decode())
+            {
+                Ok(block_entries) => entries = block_entries.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+            block_idx += 1;
+        }))
+    }
+}
+
+/// An SSTable opened for reads: its file handle plus the parsed index.
+/// This is what the `TableCache` keeps around between reads.
+#[derive(Debug)]
+pub struct TableReader {
+    // its `SSTable`'s, which blocks are cached under
+    cache_id: u64,
+    file: Mutex<File>,
+    index: Vec<BlockHandle>,
+    meta: TableMeta,
+}
+
+impl TableReader {
+    fn new(file: File, cache_id: u64, index: Vec<BlockHandle>, meta: TableMeta) -> Self {
+        Self {
+            cache_id,
+            file: Mutex::new(file),
+            index,
+            meta,
+        }
+    }
+
+    /// Open an SSTable file, loading its block index into memory
+    fn open(path: &Path, cache_id: u64) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let file_length = file.metadata()?.len();
+        if file_length < SSTable::FOOTER_SIZE {
+            bail!("Corrupt SSTable {}: missing footer", path.display());
+        }
+
+        let mut footer = [0u8; SSTable::FOOTER_SIZE as usize];
+        file.seek(SeekFrom::Start(file_length - SSTable::FOOTER_SIZE))?;
+        file.read_exact(&mut footer)?;
+
+        let metaindex_offset = u64::from_le_bytes(footer[0..8].try_into()?);
+        let metaindex_length = u64::from_le_bytes(footer[8..16].try_into()?);
+        let index_offset = u64::from_le_bytes(footer[16..24].try_into()?);
+        let index_length = u64::from_le_bytes(footer[24..32].try_into()?);
+        let level = footer[32];
+        let magic = u32::from_le_bytes(footer[33..37].try_into()?);
+        // checked, a corrupt footer may hold anything
+        let index_end = index_offset.checked_add(index_length);
+        let metaindex_end = metaindex_offset
+            .checked_add(metaindex_length)
+            .and_then(|end| end.checked_add(SSTable::FOOTER_SIZE));
+        if magic != SSTable::MAGIC
+            || index_end != Some(metaindex_offset)
+            || metaindex_end != Some(file_length)
+        {
+            bail!("Corrupt SSTable {}: bad footer", path.display());
+        }
+
+        let mut index_block = vec![0u8; (index_length + metaindex_length) as usize];
+        file.seek(SeekFrom::Start(index_offset))?;
+        file.read_exact(&mut index_block)?;
+        let metaindex_block = index_block.split_off(index_length as usize);
+
+        let mut meta = TableMeta {
+            level,
+            ..TableMeta::default()
+        };
+        let mut properties = None;
+        for (name, location) in
+            decode_entries(&metaindex_block, 8 + 8).context("Failed to read SSTable metaindex")?
+        {
+            if location.len() != 8 + 8 {
+                bail!("Invalid meta block handle");
+            }
+            let offset = u64::from_le_bytes(location[0..8].try_into()?);
+            let length = u64::from_le_bytes(location[8..16].try_into()?);
+            if offset
+                .checked_add(length)
+                .is_none_or(|end| end > index_offset)
+            {
+                bail!("Corrupt SSTable {}: bad meta block handle", path.display());
+            }
+
+            let mut meta_block = vec![0u8; length as usize];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut meta_block)?;
+
+            // unknown meta blocks are skipped, they may come from a newer version
+            if name == SSTable::RANGE_TOMBSTONES_BLOCK.as_bytes() {
+                meta.range_tombstones = RangeTombstone::decode_block(&meta_block)
+                    .context("Failed to read SSTable range tombstones")?;
+            } else if name == SSTable::PROPERTIES_BLOCK.as_bytes() {
+                properties = Some(
+                    TableProperties::decode_block(&meta_block)
+                        .context("Failed to read SSTable properties")?,
+                );
+            }
+        }
+        meta.properties = properties
+            .ok_or_else(|| anyhow!("Corrupt SSTable {}: missing properties", path.display()))?;
+
+        let index = decode_entries(&index_block, BlockHandle::ENCODED_SIZE)
+            .context("Failed to read SSTable index")?
+            .into_iter()
+            .map(|(last_key, location)| {
+                if location.len() != BlockHandle::ENCODED_SIZE {
+                    bail!("Invalid block handle");
+                }
+                Ok(BlockHandle {
+                    last_key,
+                    offset: u64::from_le_bytes(location[0..8].try_into()?),
+                    length: u64::from_le_bytes(location[8..16].try_into()?),
+                    uncompressed_length: u64::from_le_bytes(location[16..24].try_into()?),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self::new(file, cache_id, index, meta))
+    }
+
+    fn find_block(&self, key: &[u8]) -> usize {
+        self.index
+            .partition_point(|handle| handle.last_key.as_slice() < key)
+    }
+
+    fn read_block(&self, block_idx: usize, block_cache: &BlockCache) -> Result<Arc<Block>> {
+        let handle = self
+            .index
+            .get(block_idx)
+            .ok_or_else(|| anyhow!("Block {} out of range", block_idx))?;
+
+        block_cache.get_or_load(self.cache_id, handle.offset, || {
+            let mut block = vec![0u8; handle.length as usize];
+            {
+                let mut file = self.file.lock().unwrap();
+                file.seek(SeekFrom::Start(handle.offset))?;
+                file.read_exact(&mut block)?;
+            }
+            let block = Block::new(Compression::decompress_block(&block)?)
+                .context("Failed to read SSTable block")?;
+            let charge = block.charge();
+
+            Ok((block, charge))
+        })
+    }
+}
+
+/// A deterministic xorshift random number generator for tests, from a
+/// non-zero seed
+#[cfg(test)]
+pub(crate) fn xorshift(mut state: u64) -> impl FnMut() -> u64 {
+    move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directory_lock_keeps_out_a_second_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        tree.put(b"key".to_vec(), b"value".to_vec()).unwrap();
+
+        let err = LsmTree::open(dir.path()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(LsmError::DirectoryInUse(_))
+        ));
+
+        // readers don't need the lock, but can't write
+        let reader = LsmTree::open_read_only(dir.path()).unwrap();
+        assert_eq!(reader.get(b"key").unwrap(), Some(b"value".to_vec()));
+        let err = reader.put(b"key".to_vec(), b"other".to_vec()).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(LsmError::ReadOnly)));
+
+        drop(tree);
+        let tree = LsmTree::open(dir.path()).unwrap();
+        assert_eq!(tree.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn corrupt_footers_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        tree.put(b"key".to_vec(), b"value".to_vec()).unwrap();
+        tree.flush().unwrap();
+        let path = tree.inner.sstable_path(tree.sstables()[0].id);
+        drop(tree);
+
+        let table = std::fs::read(&path).unwrap();
+        let footer = table.len() - SSTable::FOOTER_SIZE as usize;
+        let field = |i: usize| u64::from_le_bytes(table[footer + 8 * i..][..8].try_into().unwrap());
+        let metaindex_offset = field(0);
+        // ends past u64::MAX, the index one lining up with the metaindex once wrapped
+        for fields in [
+            vec![(2, u64::MAX), (3, metaindex_offset + 1)],
+            vec![(1, u64::MAX)],
+        ] {
+            let mut corrupt = table.clone();
+            for (i, value) in fields {
+                corrupt[footer + 8 * i..][..8].copy_from_slice(&value.to_le_bytes());
+            }
+            std::fs::write(&path, &corrupt).unwrap();
+            let err = LsmTree::open(dir.path()).unwrap_err();
+            assert!(format!("{:#}", err).contains("bad footer"), "{:#}", err);
+        }
+        std::fs::write(&path, &table[..footer]).unwrap();
+        assert!(LsmTree::open(dir.path()).is_err());
+
+        std::fs::write(&path, &table).unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        assert_eq!(tree.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn multi_get_matches_get() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            blob_threshold: Some(100),
+            ..Options::default()
+        };
+        let tree = LsmTree::open_with_options(dir.path(), options).unwrap();
+        let mut random = crate::xorshift(7u64);
+        let key = |i: u64| format!("key{:05}", i).into_bytes();
+        for i in 0..10_000 {
+            let first = key(random() % 5000);
+            match random() % 20 {
+                0 => tree.delete(first).unwrap(),
+                1 => {
+                    let end = key(random() % 5000);
+                    if first < end {
+                        tree.delete_range(first, end).unwrap();
+                    }
+                }
+                _ => {
+                    let value = format!("{}{}", i, "v".repeat((random() % 150) as usize));
+                    tree.put(first, value.into_bytes()).unwrap();
+                }
+            }
+            // tables, an immutable memtable or two and the memtable all have some
+            if i % 3000 == 2999 {
+                tree.flush().unwrap();
+            }
+        }
+
+        for _ in 0..20 {
+            // unsorted, with duplicates and keys past the end
+            let keys: Vec<_> = (0..500).map(|_| key(random() % 5200)).collect();
+            let expected: Vec<_> = keys.iter().map(|key| tree.get(key).unwrap()).collect();
+            assert_eq!(tree.multi_get(&keys).unwrap(), expected);
+        }
+        assert!(tree.multi_get::<&[u8]>(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn multi_get_reads_each_block_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        let keys: Vec<_> = (0..2000)
+            .map(|i| format!("key{:05}", i).into_bytes())
+            .collect();
+        for key in &keys {
+            tree.put(key.clone(), vec![b'v'; 100]).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+
+        let tree = LsmTree::open(dir.path()).unwrap();
+        // outside the table's range, so no block is read
+        tree.multi_get(&[b"a", b"z"]).unwrap();
+        assert_eq!(tree.stats().unwrap().block_cache, CacheStats::default());
+
+        let reversed: Vec<_> = keys.iter().rev().chain(&keys).collect();
+        let values = tree.multi_get(&reversed).unwrap();
+        assert!(values
+            .iter()
+            .all(|value| value.as_deref() == Some(&[b'v'; 100][..])));
+        let stats = tree.stats().unwrap();
+        assert!(stats.data_blocks > 1);
+        assert_eq!(stats.block_cache.hits, 0);
+        assert_eq!(stats.block_cache.misses, stats.data_blocks as u64);
+    }
+
+    #[test]
+    fn stall_triggers_are_validated() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            l0_slowdown_writes_trigger: 20,
+            l0_stop_writes_trigger: 10,
+            ..Options::default()
+        };
+        assert!(LsmTree::open_with_options(dir.path(), options).is_err());
+        let options = Options {
+            max_immutable_memtables: 0,
+            ..Options::default()
+        };
+        assert!(LsmTree::open_with_options(dir.path(), options).is_err());
+    }
+
+    #[test]
+    fn writers_stall_until_the_worker_catches_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            l0_compaction_trigger: 2,
+            l0_slowdown_writes_trigger: 3,
+            l0_stop_writes_trigger: 4,
+            max_immutable_memtables: 1,
+            ..Options::default()
+        };
+        let tree = Arc::new(LsmTree::open_with_options(dir.path(), options.clone()).unwrap());
+        let key = |writer: u8, i: usize| format!("{}-{:05}", writer, i).into_bytes();
+
+        let writers: Vec<_> = (0..4u8)
+            .map(|writer| {
+                let tree = tree.clone();
+                std::thread::spawn(move || {
+                    for i in 0..2000 {
+                        tree.put(key(writer, i), vec![writer; 200]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let stats = tree.stats().unwrap();
+        assert!(stats.stopped_writes + stats.delayed_writes > 0);
+        drop(tree);
+        let tree = LsmTree::open_with_options(dir.path(), options).unwrap();
+        for writer in 0..4u8 {
+            for i in (0..2000).step_by(13) {
+                assert_eq!(tree.get(&key(writer, i)).unwrap(), Some(vec![writer; 200]));
+            }
+        }
+    }
+
+    fn wal_segment_ids(path: &Path) -> Vec<u32> {
+        LsmTree::list_wal_segments(path)
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key{:05}", i).into_bytes()
+    }
+
+    #[test]
+    fn wal_segments_roll_over_and_are_archived_once_flushed() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join(LsmTree::WAL_ARCHIVE_DIR);
+        let options = || Options {
+            wal_segment_size: 4096,
+            wal_archive_ttl: Some(Duration::from_secs(3600)),
+            write_log_size: 0,
+            ..Options::default()
+        };
+        let tree = LsmTree::open_with_options(dir.path(), options()).unwrap();
+        for i in 0..200 {
+            tree.put(key(i), vec![1; 100]).unwrap();
+        }
+        let segments = wal_segment_ids(dir.path());
+        assert!(segments.len() >= 5, "{:?}", segments);
+        assert_eq!(segments, (0..segments.len() as u32).collect::<Vec<_>>());
+        drop(tree);
+
+        // every segment is replayed
+        let tree = LsmTree::open_with_options(dir.path(), options()).unwrap();
+        assert_eq!(tree.last_sequence(), 200);
+        for i in 0..200 {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(vec![1; 100]));
+        }
+        for i in 200..2200 {
+            tree.put(key(i), vec![2; 100]).unwrap();
+        }
+        drop(tree);
+        let tree = LsmTree::open_with_options(dir.path(), options()).unwrap();
+        assert_eq!(tree.last_sequence(), 2200);
+        assert_eq!(tree.get(&key(2199)).unwrap(), Some(vec![2; 100]));
+
+        // flushed segments move to the archive, where catching up finds them
+        tree.flush().unwrap();
+        assert_eq!(wal_segment_ids(dir.path()).len(), 1);
+        assert!(wal_segment_ids(&archive).len() >= 50);
+        let caught_up = tree
+            .subscribe(1)
+            .take(2200)
+            .map(|event| event.unwrap().sequence);
+        assert!(caught_up.eq(1..=2200));
+        drop(tree);
+
+        // until they've been there for longer than the TTL
+        let options = Options {
+            wal_archive_ttl: Some(Duration::ZERO),
+            ..options()
+        };
+        let tree = LsmTree::open_with_options(dir.path(), options).unwrap();
+        assert_eq!(tree.last_sequence(), 2200);
+        tree.put(key(0), vec![3; 100]).unwrap();
+        tree.flush().unwrap();
+        assert!(wal_segment_ids(&archive).is_empty());
+        let err = tree.subscribe(1).next().unwrap().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(LsmError::SequenceUnavailable(1))
+        ));
+    }
+
+    #[test]
+    fn flushed_segments_left_behind_are_retired_at_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        tree.put(b"key".to_vec(), b"value".to_vec()).unwrap();
+        drop(tree);
+        let stale = LsmTree::wal_segment_path(dir.path(), 0);
+        let copy = dir.path().join("copy");
+        std::fs::copy(&stale, &copy).unwrap();
+
+        let tree = LsmTree::open(dir.path()).unwrap();
+        tree.delete(b"key".to_vec()).unwrap();
+        tree.flush().unwrap();
+        drop(tree);
+
+        // as if we'd crashed between flushing a segment and removing it:
+        // replaying it would bring the deleted key back
+        std::fs::rename(&copy, &stale).unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        assert_eq!(tree.get(b"key").unwrap(), None);
+        assert!(!stale.exists());
+    }
+
+    #[test]
+    fn a_single_file_wal_becomes_the_newest_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        tree.put(b"old".to_vec(), b"1".to_vec()).unwrap();
+        drop(tree);
+        // puts logged by the original tree, which had neither segments nor
+        // sequences: `<u32 key length><u32 value length><key><value>`
+        let mut legacy = Vec::new();
+        for (key, value) in [(&b"old"[..], &b"2"[..]), (b"other", b"value")] {
+            legacy.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            legacy.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            legacy.extend_from_slice(key);
+            legacy.extend_from_slice(value);
+        }
+        std::fs::write(dir.path().join(LsmTree::LEGACY_WAL_FILE_NAME), &legacy).unwrap();
+
+        let reader = LsmTree::open_read_only(dir.path()).unwrap();
+        assert_eq!(reader.get(b"old").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(reader.get(b"other").unwrap(), Some(b"value".to_vec()));
+        let tree = LsmTree::open(dir.path()).unwrap();
+        assert_eq!(tree.get(b"old").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(tree.last_sequence(), 3);
+        assert!(!dir.path().join(LsmTree::LEGACY_WAL_FILE_NAME).exists());
+        tree.put(b"new".to_vec(), b"3".to_vec()).unwrap();
+        drop(tree);
+
+        assert_eq!(wal_segment_ids(dir.path()), [0, 1]);
+        let tree = LsmTree::open(dir.path()).unwrap();
+        assert_eq!(tree.get(b"old").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(tree.get(b"other").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(tree.get(b"new").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(tree.last_sequence(), 4);
+    }
+
+    #[test]
+    fn segments_without_a_header_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        drop(LsmTree::open(dir.path()).unwrap());
+        let segment = LsmTree::wal_segment_path(dir.path(), 0);
+        std::fs::write(&segment, [0u8; 32]).unwrap();
+
+        let error = LsmTree::open(dir.path()).unwrap_err();
+        assert!(format!("{:#}", error).contains("bad magic"), "{:#}", error);
+    }
+}
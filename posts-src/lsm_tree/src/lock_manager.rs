@@ -0,0 +1,182 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use crate::LsmError;
+
+#[derive(Debug, Default)]
+struct LockTable {
+    // key -> transaction holding it
+    owners: HashMap<Vec<u8>, u64>,
+    // wait-for graph: blocked transaction -> transaction holding what it wants.
+    // A transaction only ever waits on one key, so one edge each.
+    waiting_for: HashMap<u64, u64>,
+}
+
+impl LockTable {
+    /// Whether `waiter` waiting on `owner` would close a cycle
+    fn would_deadlock(&self, waiter: u64, owner: u64) -> bool {
+        let mut current = owner;
+        // every step follows a distinct edge, so a cycle not involving
+        // `waiter` can't keep us here forever
+        for _ in 0..=self.waiting_for.len() {
+            if current == waiter {
+                return true;
+            }
+            match self.waiting_for.get(&current) {
+                Some(&next) => current = next,
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+/// Exclusive per-key locks for pessimistic transactions.
+///
+/// Waiting is bounded by a timeout, and a request that would complete a
+/// cycle in the wait-for graph fails straight away with
+/// `LsmError::Deadlock` instead of waiting for it to time out.
+#[derive(Debug, Default)]
+pub(crate) struct LockManager {
+    table: Mutex<LockTable>,
+    released: Condvar,
+    next_transaction_id: AtomicU64,
+}
+
+impl LockManager {
+    pub(crate) fn new_transaction_id(&self) -> u64 {
+        self.next_transaction_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Lock `key` for `transaction_id`, waiting up to `timeout` for the
+    /// current holder. Returns whether the lock is new (`false` if the
+    /// transaction already held it).
+    pub(crate) fn lock(&self, transaction_id: u64, key: &[u8], timeout: Duration) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        let mut table = self.table.lock().unwrap();
+        loop {
+            let owner = match table.owners.get(key) {
+                None => {
+                    table.waiting_for.remove(&transaction_id);
+                    table.owners.insert(key.to_vec(), transaction_id);
+                    return Ok(true);
+                }
+                Some(&owner) if owner == transaction_id => return Ok(false),
+                Some(&owner) => owner,
+            };
+
+            if table.would_deadlock(transaction_id, owner) {
+                table.waiting_for.remove(&transaction_id);
+                return Err(LsmError::Deadlock(key.to_vec()).into());
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                table.waiting_for.remove(&transaction_id);
+                return Err(LsmError::LockTimeout(key.to_vec()).into());
+            }
+
+            table.waiting_for.insert(transaction_id, owner);
+            table = self.released.wait_timeout(table, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Release every lock in `keys` held by `transaction_id`
+    pub(crate) fn unlock_all<'a>(
+        &self,
+        transaction_id: u64,
+        keys: impl IntoIterator<Item = &'a [u8]>,
+    ) {
+        let mut table = self.table.lock().unwrap();
+        for key in keys {
+            if table.owners.get(key) == Some(&transaction_id) {
+                table.owners.remove(key);
+            }
+        }
+        self.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn error(result: Result<bool>) -> LsmError {
+        match result.unwrap_err().downcast() {
+            Ok(e) => e,
+            Err(e) => panic!("unexpected error {:#}", e),
+        }
+    }
+
+    #[test]
+    fn locks_are_exclusive_and_reentrant() {
+        let locks = LockManager::default();
+        let (first, second) = (locks.new_transaction_id(), locks.new_transaction_id());
+        assert!(locks.lock(first, b"key", TIMEOUT).unwrap());
+        assert!(!locks.lock(first, b"key", TIMEOUT).unwrap());
+        assert!(matches!(
+            error(locks.lock(second, b"key", Duration::from_millis(10))),
+            LsmError::LockTimeout(_)
+        ));
+
+        // someone else's unlock doesn't release it
+        locks.unlock_all(second, [&b"key"[..]]);
+        assert!(locks.lock(second, b"key", Duration::ZERO).is_err());
+        locks.unlock_all(first, [&b"key"[..]]);
+        assert!(locks.lock(second, b"key", Duration::ZERO).unwrap());
+    }
+
+    #[test]
+    fn waiters_get_the_lock_once_released() {
+        let locks = LockManager::default();
+        let (first, second) = (locks.new_transaction_id(), locks.new_transaction_id());
+        locks.lock(first, b"key", TIMEOUT).unwrap();
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| locks.lock(second, b"key", TIMEOUT));
+            std::thread::sleep(Duration::from_millis(20));
+            locks.unlock_all(first, [&b"key"[..]]);
+            assert!(waiter.join().unwrap().unwrap());
+        });
+    }
+
+    #[test]
+    fn cycles_fail_fast_as_deadlocks() {
+        let locks = LockManager::default();
+        let (first, second) = (locks.new_transaction_id(), locks.new_transaction_id());
+        locks.lock(first, b"a", TIMEOUT).unwrap();
+        locks.lock(second, b"b", TIMEOUT).unwrap();
+
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| locks.lock(second, b"a", TIMEOUT));
+            while !locks
+                .table
+                .lock()
+                .unwrap()
+                .waiting_for
+                .contains_key(&second)
+            {
+                std::thread::yield_now();
+            }
+            let started = Instant::now();
+            assert!(matches!(
+                error(locks.lock(first, b"b", TIMEOUT)),
+                LsmError::Deadlock(_)
+            ));
+            assert!(started.elapsed() < TIMEOUT);
+
+            // the first transaction backing off lets the second one through
+            locks.unlock_all(first, [&b"a"[..]]);
+            assert!(waiter.join().unwrap().unwrap());
+        });
+    }
+}
@@ -0,0 +1,495 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        RwLock,
+    },
+};
+
+use crate::{range_tombstone, skiplist::SkipListRep, BatchOp, RangeTombstone, WriteBatch};
+
+/// One version of a key in a memtable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemtableEntry {
+    pub key: Vec<u8>,
+    /// Sequence of the batch that wrote it
+    pub sequence: u64,
+    /// `None` for a delete
+    pub value: Option<Vec<u8>>,
+}
+
+/// How a memtable stores its point entries.
+///
+/// A write adds a new version of its key rather than replacing the old
+/// one, so readers can ignore everything newer than their snapshot
+/// sequence (including a batch that's only partly inserted). Versions only
+/// go away with the whole memtable, once it's been flushed.
+pub trait MemtableRep: Send + Sync + fmt::Debug {
+    /// Add a version of `key`. Calls are serialized by the caller, but may
+    /// run concurrently with reads, and never repeat a `(key, sequence)`.
+    fn insert(&self, key: &[u8], sequence: u64, value: Option<&[u8]>);
+
+    /// Sequence and value of the newest version of `key` at or before `snapshot`
+    fn get(&self, key: &[u8], snapshot: u64) -> Option<(u64, Option<Vec<u8>>)>;
+
+    /// Newest version at or before `snapshot` of the first key past `start`
+    /// that has one
+    fn seek(&self, start: Bound<&[u8]>, snapshot: u64) -> Option<MemtableEntry>;
+
+    /// Same as `seek`, but for the last key before `end`
+    fn seek_for_prev(&self, end: Bound<&[u8]>, snapshot: u64) -> Option<MemtableEntry>;
+}
+
+/// Which `MemtableRep` an `LsmTree` builds its memtables with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemtableKind {
+    /// Arena-backed skiplist: reads never wait on the writer
+    #[default]
+    SkipList,
+    /// `BTreeMap` behind a `RwLock`: reads wait while an insert is in progress
+    BTreeMap,
+}
+
+impl MemtableKind {
+    pub fn new_rep(self) -> Box<dyn MemtableRep> {
+        match self {
+            MemtableKind::SkipList => Box::new(SkipListRep::new()),
+            MemtableKind::BTreeMap => Box::<BTreeMapRep>::default(),
+        }
+    }
+}
+
+// sequence and value (`None` for a delete) of one version of a key
+type Version = (u64, Option<Vec<u8>>);
+
+/// Every version of each key, oldest first
+#[derive(Debug, Default)]
+struct BTreeMapRep {
+    map: RwLock<BTreeMap<Vec<u8>, Vec<Version>>>,
+}
+
+impl BTreeMapRep {
+    fn newest_visible(key: &[u8], versions: &[Version], snapshot: u64) -> Option<MemtableEntry> {
+        versions
+            .iter()
+            .rev()
+            .find(|(sequence, _)| *sequence <= snapshot)
+            .map(|(sequence, value)| MemtableEntry {
+                key: key.to_vec(),
+                sequence: *sequence,
+                value: value.clone(),
+            })
+    }
+}
+
+impl MemtableRep for BTreeMapRep {
+    fn insert(&self, key: &[u8], sequence: u64, value: Option<&[u8]>) {
+        self.map
+            .write()
+            .unwrap()
+            .entry(key.to_vec())
+            .or_default()
+            .push((sequence, value.map(|value| value.to_vec())));
+    }
+
+    fn get(&self, key: &[u8], snapshot: u64) -> Option<(u64, Option<Vec<u8>>)> {
+        let map = self.map.read().unwrap();
+        Self::newest_visible(key, map.get(key)?, snapshot)
+            .map(|entry| (entry.sequence, entry.value))
+    }
+
+    fn seek(&self, start: Bound<&[u8]>, snapshot: u64) -> Option<MemtableEntry> {
+        self.map
+            .read()
+            .unwrap()
+            .range::<[u8], _>((start, Bound::Unbounded))
+            .find_map(|(key, versions)| Self::newest_visible(key, versions, snapshot))
+    }
+
+    fn seek_for_prev(&self, end: Bound<&[u8]>, snapshot: u64) -> Option<MemtableEntry> {
+        self.map
+            .read()
+            .unwrap()
+            .range::<[u8], _>((Bound::Unbounded, end))
+            .rev()
+            .find_map(|(key, versions)| Self::newest_visible(key, versions, snapshot))
+    }
+}
+
+/// Recent writes, sorted by key. Each key remembers the sequence of the
+/// batch that last wrote it, which is what transactions validate against.
+///
+/// Reads take the snapshot sequence to read as of: batches are inserted
+/// while readers carry on, and only become visible once `LsmTree` has
+/// published their sequence.
+#[derive(Debug)]
+pub struct Memtable {
+    rep: Box<dyn MemtableRep>,
+    // with the sequence that wrote them, see `RangeTombstone`
+    range_tombstones: RwLock<Vec<(u64, RangeTombstone)>>,
+    // every write with a sequence at least this is in this memtable or a newer one
+    first_sequence: u64,
+    last_sequence: AtomicU64,
+    // versions inserted, and their bytes (range tombstones included), kept
+    // up to date on insert rather than added up on demand
+    entries: AtomicUsize,
+    total_bytes: AtomicUsize,
+}
+
+impl Default for Memtable {
+    fn default() -> Self {
+        Self::new(MemtableKind::default().new_rep(), 1)
+    }
+}
+
+impl Memtable {
+    /// An empty memtable taking over writes from `first_sequence` on
+    pub fn new(rep: Box<dyn MemtableRep>, first_sequence: u64) -> Self {
+        Self {
+            rep,
+            range_tombstones: RwLock::new(Vec::new()),
+            first_sequence,
+            last_sequence: AtomicU64::new(first_sequence.saturating_sub(1)),
+            entries: AtomicUsize::new(0),
+            total_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Rebuild a memtable from replayed WAL records. `first_sequence` is
+    /// used if there are none.
+    pub(crate) fn from_records(
+        kind: MemtableKind,
+        records: Vec<(u64, WriteBatch)>,
+        first_sequence: u64,
+    ) -> Self {
+        let first_sequence = records
+            .first()
+            .map_or(first_sequence, |(sequence, _)| *sequence);
+        let memtable = Self::new(kind.new_rep(), first_sequence);
+        for (sequence, batch) in records {
+            memtable.apply(sequence, &batch);
+        }
+        memtable
+    }
+
+    /// Insert a batch. Only one batch may be applied at a time, each with a
+    /// larger sequence than the last.
+    pub fn apply(&self, sequence: u64, batch: &WriteBatch) {
+        // the batch gets a single version per key, so later ops win: skip
+        // any put or delete that's overwritten or range deleted further on
+        let ops: Vec<&BatchOp> = batch.iter().collect();
+        let mut later_keys = HashSet::new();
+        let mut range_tombstones = Vec::new();
+        let mut entries = 0;
+        let mut bytes = 0;
+        for op in ops.into_iter().rev() {
+            let (key, value) = match op {
+                BatchOp::Put(key, value) => (key, Some(value.as_slice())),
+                BatchOp::Delete(key) => (key, None),
+                BatchOp::DeleteRange(start, end) => {
+                    if start < end {
+                        bytes += start.len() + end.len();
+                        range_tombstones.push(RangeTombstone {
+                            start: start.clone(),
+                            end: end.clone(),
+                        });
+                    }
+                    continue;
+                }
+            };
+            if !later_keys.insert(key.as_slice())
+                || range_tombstone::covering(&range_tombstones, key).is_some()
+            {
+                continue;
+            }
+            self.rep.insert(key, sequence, value);
+            entries += 1;
+            bytes += key.len() + value.map_or(0, |value| value.len());
+        }
+        if !range_tombstones.is_empty() {
+            self.range_tombstones.write().unwrap().extend(
+                range_tombstones
+                    .into_iter()
+                    .map(|tombstone| (sequence, tombstone)),
+            );
+        }
+
+        self.entries.fetch_add(entries, Ordering::Relaxed);
+        self.total_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.last_sequence.fetch_max(sequence, Ordering::Relaxed);
+    }
+
+    /// `Some(None)` if the key was deleted, by a tombstone or a range tombstone
+    pub fn get(&self, key: &[u8], snapshot: u64) -> Option<Option<Vec<u8>>> {
+        match self.rep.get(key, snapshot) {
+            Some((sequence, value)) if !self.range_deleted(key, sequence, snapshot) => Some(value),
+            _ => self.newest_range_tombstone(key, snapshot).map(|_| None),
+        }
+    }
+
+    /// First key past `start` as of `snapshot`, with `None` for a deleted value.
+    /// Keys deleted by one of this memtable's range tombstones show up as deleted.
+    pub fn seek(&self, start: Bound<&[u8]>, snapshot: u64) -> Option<(Vec<u8>, Option<Vec<u8>>)> {
+        self.rep
+            .seek(start, snapshot)
+            .map(|entry| self.resolve(entry, snapshot))
+    }
+
+    /// Same as `seek`, but for the last key before `end`
+    pub fn seek_for_prev(
+        &self,
+        end: Bound<&[u8]>,
+        snapshot: u64,
+    ) -> Option<(Vec<u8>, Option<Vec<u8>>)> {
+        self.rep
+            .seek_for_prev(end, snapshot)
+            .map(|entry| self.resolve(entry, snapshot))
+    }
+
+    fn resolve(&self, entry: MemtableEntry, snapshot: u64) -> (Vec<u8>, Option<Vec<u8>>) {
+        if self.range_deleted(&entry.key, entry.sequence, snapshot) {
+            (entry.key, None)
+        } else {
+            (entry.key, entry.value)
+        }
+    }
+
+    /// Sequence of the last write to `key`, if it's in this memtable
+    pub fn sequence_of(&self, key: &[u8]) -> Option<u64> {
+        let written = self.rep.get(key, u64::MAX).map(|(sequence, _)| sequence);
+        written.max(self.newest_range_tombstone(key, u64::MAX))
+    }
+
+    /// Whether a version of `key` written by `sequence` is hidden by a newer
+    /// range tombstone of this memtable
+    fn range_deleted(&self, key: &[u8], sequence: u64, snapshot: u64) -> bool {
+        self.newest_range_tombstone(key, snapshot)
+            .is_some_and(|tombstone_sequence| tombstone_sequence > sequence)
+    }
+
+    /// Sequence of the newest range tombstone covering `key` as of `snapshot`
+    fn newest_range_tombstone(&self, key: &[u8], snapshot: u64) -> Option<u64> {
+        self.range_tombstones
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(sequence, tombstone)| *sequence <= snapshot && tombstone.covers(key))
+            .map(|(sequence, _)| *sequence)
+            .max()
+    }
+
+    /// Range tombstones written at or before `snapshot`
+    pub fn range_tombstones(&self, snapshot: u64) -> Vec<RangeTombstone> {
+        self.range_tombstones
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(sequence, _)| *sequence <= snapshot)
+            .map(|(_, tombstone)| tombstone.clone())
+            .collect()
+    }
+
+    pub fn first_sequence(&self) -> u64 {
+        self.first_sequence
+    }
+
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence.load(Ordering::Relaxed)
+    }
+
+    /// The newest version of every key, leaving out the ones this
+    /// memtable's range tombstones delete (as a flush writes them)
+    pub fn iter(&self) -> impl Iterator<Item = (Vec<u8>, Option<Vec<u8>>)> + '_ {
+        let mut last_key: Option<Vec<u8>> = None;
+        std::iter::from_fn(move || loop {
+            let start = match &last_key {
+                Some(key) => Bound::Excluded(key.as_slice()),
+                None => Bound::Unbounded,
+            };
+            let entry = self.rep.seek(start, u64::MAX)?;
+            last_key = Some(entry.key.clone());
+            if !self.range_deleted(&entry.key, entry.sequence, u64::MAX) {
+                return Some((entry.key, entry.value));
+            }
+        })
+    }
+
+    /// Versions held, which may be more than there are keys
+    pub fn size(&self) -> usize {
+        self.entries.load(Ordering::Relaxed)
+    }
+
+    /// Key and value bytes of every version, plus those of the range tombstones
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size() == 0 && self.range_tombstones.read().unwrap().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{LsmTree, Options};
+
+    const KINDS: [MemtableKind; 2] = [MemtableKind::SkipList, MemtableKind::BTreeMap];
+
+    /// First live key `seek` finds from `from`, skipping deletes the way a cursor does
+    fn first_live(
+        mut seek: impl FnMut(Bound<&[u8]>) -> Option<(Vec<u8>, Option<Vec<u8>>)>,
+        from: &[u8],
+    ) -> Option<(Vec<u8>, Vec<u8>)> {
+        let mut bound = Bound::Included(from.to_vec());
+        loop {
+            match seek(bound.as_ref().map(|key| key.as_slice()))? {
+                (key, Some(value)) => return Some((key, value)),
+                (key, None) => bound = Bound::Excluded(key),
+            }
+        }
+    }
+
+    #[test]
+    fn reads_as_of_any_snapshot() {
+        for kind in KINDS {
+            let memtable = Memtable::new(kind.new_rep(), 1);
+            // the state after each batch, `None` for deleted keys
+            let mut states = vec![BTreeMap::new()];
+            let mut random = crate::xorshift(99u64);
+            let key = |i: u64| format!("k{:03}", i).into_bytes();
+
+            for sequence in 1..=1000 {
+                let mut batch = WriteBatch::new();
+                let mut keys = states.last().unwrap().clone();
+                for _ in 0..random() % 4 + 1 {
+                    let first = key(random() % 300);
+                    match random() % 10 {
+                        0 => {
+                            batch.delete(first.clone());
+                            keys.insert(first, None);
+                        }
+                        1 => {
+                            let second = key(random() % 300);
+                            let (start, end) =
+                                (first.clone().min(second.clone()), first.max(second));
+                            for value in keys.range_mut(start.clone()..end.clone()) {
+                                *value.1 = None;
+                            }
+                            batch.delete_range(start, end);
+                        }
+                        _ => {
+                            let value = random().to_le_bytes().to_vec();
+                            batch.put(first.clone(), value.clone());
+                            keys.insert(first, Some(value));
+                        }
+                    }
+                }
+                memtable.apply(sequence, &batch);
+                states.push(keys);
+            }
+
+            for _ in 0..1000 {
+                let snapshot = random() % 1001;
+                let keys = &states[snapshot as usize];
+                let target = key(random() % 310);
+                assert_eq!(
+                    memtable.get(&target, snapshot).flatten(),
+                    keys.get(&target).cloned().flatten(),
+                    "{:?}",
+                    kind
+                );
+
+                let live_keys = || {
+                    keys.iter()
+                        .filter_map(|(key, value)| Some((key.clone(), value.clone()?)))
+                };
+                let next = first_live(|bound| memtable.seek(bound, snapshot), &target);
+                assert_eq!(
+                    next,
+                    live_keys().find(|(key, _)| *key >= target),
+                    "{:?}",
+                    kind
+                );
+                let previous = first_live(|bound| memtable.seek_for_prev(bound, snapshot), &target);
+                assert_eq!(
+                    previous,
+                    live_keys().rev().find(|(key, _)| *key <= target),
+                    "{:?}",
+                    kind
+                );
+            }
+
+            let flushed: Vec<_> = memtable
+                .iter()
+                .filter_map(|(key, value)| Some((key, value?)))
+                .collect();
+            let expected: Vec<_> = states
+                .last()
+                .unwrap()
+                .iter()
+                .filter_map(|(key, value)| Some((key.clone(), value.clone()?)))
+                .collect();
+            assert_eq!(flushed, expected, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn cursors_never_see_half_a_batch() {
+        for memtable in KINDS {
+            let dir = tempfile::tempdir().unwrap();
+            let options = Options {
+                memtable,
+                ..Options::default()
+            };
+            let tree = Arc::new(LsmTree::open_with_options(dir.path(), options).unwrap());
+            let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+            let readers: Vec<_> = (0..4)
+                .map(|_| {
+                    let tree = tree.clone();
+                    let done = done.clone();
+                    std::thread::spawn(move || {
+                        while !done.load(Ordering::Relaxed) {
+                            // every batch writes the same value to all three keys
+                            let mut cursor = tree.cursor();
+                            cursor.seek_to_first().unwrap();
+                            let mut values = Vec::new();
+                            while let Some(value) = cursor.value() {
+                                values.push(value.to_vec());
+                                cursor.next().unwrap();
+                            }
+                            assert!(
+                                values.is_empty()
+                                    || (values.len() == 3
+                                        && values.iter().all(|v| *v == values[0])),
+                                "{:?}",
+                                values
+                            );
+                        }
+                    })
+                })
+                .collect();
+
+            for i in 0..3_000u64 {
+                let mut batch = WriteBatch::new();
+                for key in [b"a", b"b", b"c"] {
+                    batch.put(key.to_vec(), i.to_le_bytes().to_vec());
+                }
+                tree.write(batch).unwrap();
+            }
+            done.store(true, Ordering::Relaxed);
+            for reader in readers {
+                reader.join().unwrap();
+            }
+            assert_eq!(
+                tree.get(b"a").unwrap(),
+                Some(2_999u64.to_le_bytes().to_vec())
+            );
+        }
+    }
+}
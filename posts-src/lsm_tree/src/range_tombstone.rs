@@ -0,0 +1,193 @@
+use anyhow::Result;
+
+use crate::{decode_entries, encode_entry, LsmTree};
+
+/// Deletes every key in `[start, end)` that is older than the tombstone.
+///
+/// Memtables and SSTables keep their range tombstones next to their point
+/// entries, and a source's tombstones only ever hide keys in older sources:
+/// a memtable hides its own older versions of covered keys (and leaves them
+/// out when it's flushed), so anything left alongside a tombstone in an
+/// SSTable was written afterwards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+}
+
+impl RangeTombstone {
+    pub fn covers(&self, key: &[u8]) -> bool {
+        self.start.as_slice() <= key && key < self.end.as_slice()
+    }
+
+    /// Encode tombstones for an SSTable's meta block, one `start -> end` record each
+    pub(crate) fn encode_block(tombstones: &[RangeTombstone], buf: &mut Vec<u8>) {
+        for tombstone in tombstones {
+            encode_entry(buf, &tombstone.start, &tombstone.end);
+        }
+    }
+
+    pub(crate) fn decode_block(block: &[u8]) -> Result<Vec<RangeTombstone>> {
+        Ok(decode_entries(block, LsmTree::MAX_ENTRY_SIZE)?
+            .into_iter()
+            .map(|(start, end)| RangeTombstone { start, end })
+            .collect())
+    }
+}
+
+/// First of `tombstones` covering `key`, if any
+pub(crate) fn covering<'a>(
+    tombstones: impl IntoIterator<Item = &'a RangeTombstone>,
+    key: &[u8],
+) -> Option<&'a RangeTombstone> {
+    tombstones
+        .into_iter()
+        .find(|tombstone| tombstone.covers(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::WriteBatch;
+
+    fn tombstone(start: &str, end: &str) -> RangeTombstone {
+        RangeTombstone {
+            start: start.as_bytes().to_vec(),
+            end: end.as_bytes().to_vec(),
+        }
+    }
+
+    fn contents(tree: &LsmTree) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut cursor = tree.cursor();
+        cursor.seek_to_first().unwrap();
+        let mut entries = Vec::new();
+        while let (Some(key), Some(value)) = (cursor.key(), cursor.value()) {
+            entries.push((key.to_vec(), value.to_vec()));
+            cursor.next().unwrap();
+        }
+        entries
+    }
+
+    #[test]
+    fn covers_start_but_not_end() {
+        let tombstone = tombstone("b", "d");
+        assert!(!tombstone.covers(b"a"));
+        assert!(tombstone.covers(b"b"));
+        assert!(tombstone.covers(b"c\xff"));
+        assert!(!tombstone.covers(b"d"));
+
+        let tombstones = vec![tombstone, self::tombstone("a", "z")];
+        let mut block = Vec::new();
+        RangeTombstone::encode_block(&tombstones, &mut block);
+        let decoded = RangeTombstone::decode_block(&block).unwrap();
+        assert_eq!(decoded, tombstones);
+        assert_eq!(covering(&decoded, b"c"), Some(&tombstones[0]));
+        assert_eq!(covering(&decoded, b"e"), Some(&tombstones[1]));
+        assert_eq!(covering(&decoded, b"z"), None);
+    }
+
+    #[test]
+    fn hide_older_writes_through_flush_and_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        for key in ["a", "b", "c", "d"] {
+            tree.put(key.as_bytes().to_vec(), b"old".to_vec()).unwrap();
+        }
+        tree.flush().unwrap();
+        tree.put(b"c2".to_vec(), b"old".to_vec()).unwrap();
+
+        // a put after the range deletion in the same batch survives it
+        let mut batch = WriteBatch::new();
+        batch.delete_range(b"b".to_vec(), b"d".to_vec());
+        batch.put(b"c".to_vec(), b"new".to_vec());
+        tree.write(batch).unwrap();
+        let expected = vec![
+            (b"a".to_vec(), b"old".to_vec()),
+            (b"c".to_vec(), b"new".to_vec()),
+            (b"d".to_vec(), b"old".to_vec()),
+        ];
+        assert_eq!(contents(&tree), expected);
+        assert_eq!(tree.get(b"b").unwrap(), None);
+        assert_eq!(tree.get(b"c2").unwrap(), None);
+
+        tree.flush().unwrap();
+        assert_eq!(contents(&tree), expected);
+        tree.put(b"b".to_vec(), b"newer".to_vec()).unwrap();
+        tree.flush().unwrap();
+        tree.compact_all().unwrap();
+        assert_eq!(tree.get(b"b").unwrap(), Some(b"newer".to_vec()));
+        assert_eq!(tree.get(b"c2").unwrap(), None);
+        // nothing older is left for the tombstone to hide
+        assert!(tree
+            .sstables()
+            .iter()
+            .all(|table| table.properties.range_tombstones == 0));
+
+        drop(tree);
+        let tree = LsmTree::open(dir.path()).unwrap();
+        assert_eq!(tree.get(b"b").unwrap(), Some(b"newer".to_vec()));
+        assert_eq!(tree.get(b"c").unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn tombstones_survive_a_restart_unflushed() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        tree.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        tree.flush().unwrap();
+        tree.delete_range(b"a".to_vec(), b"b".to_vec()).unwrap();
+        drop(tree);
+
+        let tree = LsmTree::open(dir.path()).unwrap();
+        assert_eq!(tree.get(b"a").unwrap(), None);
+        assert!(contents(&tree).is_empty());
+    }
+
+    #[test]
+    fn match_a_sorted_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        let mut model = BTreeMap::new();
+        let mut random = crate::xorshift(4242u64);
+        let key = |i: u64| format!("key{:04}", i).into_bytes();
+
+        for i in 0..6000 {
+            match random() % 100 {
+                0 => {
+                    let start = random() % 1000;
+                    let (start, end) = (key(start), key(start + random() % 200));
+                    tree.delete_range(start.clone(), end.clone()).unwrap();
+                    model.retain(|key: &Vec<u8>, _| !(start <= *key && *key < end));
+                }
+                1..=10 => {
+                    let key = key(random() % 1000);
+                    tree.delete(key.clone()).unwrap();
+                    model.remove(&key);
+                }
+                _ => {
+                    let (key, value) = (key(random() % 1000), format!("{}", i).into_bytes());
+                    tree.put(key.clone(), value.clone()).unwrap();
+                    model.insert(key, value);
+                }
+            }
+            if i % 2000 == 1000 {
+                tree.flush().unwrap();
+            }
+            if i == 4500 {
+                tree.compact_all().unwrap();
+            }
+        }
+
+        let expected: Vec<_> = model.into_iter().collect();
+        assert_eq!(contents(&tree), expected);
+        for i in 0..1000 {
+            let value = expected
+                .binary_search_by(|(other, _)| other.cmp(&key(i)))
+                .ok()
+                .map(|position| expected[position].1.clone());
+            assert_eq!(tree.get(&key(i)).unwrap(), value);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Who a write is for. Flushes are `High` since writers stall when they
+/// fall behind, compactions are `Low` and yield to any waiting flush.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    High,
+    Low,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // 0 means unlimited
+    bytes_per_second: u64,
+    // may go negative: a request larger than what's available is let
+    // through once the bucket isn't in debt, and later requests pay it back
+    available: f64,
+    last_refill: Instant,
+    high_priority_waiters: usize,
+}
+
+impl Bucket {
+    // at most this much unused rate is saved up for a burst
+    const MAX_BURST: Duration = Duration::from_millis(100);
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+
+        let rate = self.bytes_per_second as f64;
+        self.available =
+            (self.available + elapsed * rate).min(rate * Self::MAX_BURST.as_secs_f64());
+    }
+}
+
+/// Token bucket limiting the bytes per second that flushes and compactions
+/// write to SSTables, so background work leaves disk bandwidth for reads.
+///
+/// Pass the same `Arc` to several trees through `Options::rate_limiter` to
+/// give them one shared budget, and change it at any time with
+/// `set_bytes_per_second`.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    refilled: Condvar,
+    total_bytes: AtomicU64,
+    total_wait_micros: AtomicU64,
+}
+
+impl RateLimiter {
+    /// `bytes_per_second` of 0 disables limiting
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                bytes_per_second,
+                available: 0.0,
+                last_refill: Instant::now(),
+                high_priority_waiters: 0,
+            }),
+            refilled: Condvar::new(),
+            total_bytes: AtomicU64::new(0),
+            total_wait_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bucket.lock().unwrap().bytes_per_second
+    }
+
+    /// Change the rate, taking effect for requests already waiting too
+    pub fn set_bytes_per_second(&self, bytes_per_second: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        bucket.bytes_per_second = bytes_per_second;
+        // don't make everyone pay back debt run up at the old rate
+        bucket.available = bucket.available.max(0.0);
+        self.refilled.notify_all();
+    }
+
+    /// Block until `bytes` may be written
+    pub fn request(&self, bytes: usize, priority: IoPriority) {
+        let started_at = Instant::now();
+        let mut bucket = self.bucket.lock().unwrap();
+        if priority == IoPriority::High {
+            bucket.high_priority_waiters += 1;
+        }
+
+        loop {
+            bucket.refill();
+            if bucket.bytes_per_second == 0 {
+                break;
+            }
+
+            let yield_to_flush = priority == IoPriority::Low && bucket.high_priority_waiters > 0;
+            if !yield_to_flush && bucket.available >= 0.0 {
+                bucket.available -= bytes as f64;
+                break;
+            }
+
+            // sleep until the debt is paid off, or a bit while flushes go first
+            let wait = if bucket.available < 0.0 {
+                Duration::from_secs_f64(-bucket.available / bucket.bytes_per_second as f64)
+            } else {
+                Duration::from_millis(1)
+            };
+            bucket = self.refilled.wait_timeout(bucket, wait).unwrap().0;
+        }
+
+        if priority == IoPriority::High {
+            bucket.high_priority_waiters -= 1;
+            self.refilled.notify_all();
+        }
+        drop(bucket);
+
+        self.total_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.total_wait_micros
+            .fetch_add(started_at.elapsed().as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Bytes that have gone through the limiter so far
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Total time requests have spent waiting on the limiter
+    pub fn total_wait(&self) -> Duration {
+        Duration::from_micros(self.total_wait_micros.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn limits_bytes_per_second() {
+        let limiter = RateLimiter::new(1024 * 1024);
+        let started = Instant::now();
+        for _ in 0..10 {
+            limiter.request(30 * 1024, IoPriority::Low);
+        }
+        // ~0.3 seconds, less the little the first request gets away with
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(limiter.total_bytes(), 300 * 1024);
+        assert!(limiter.total_wait() > Duration::ZERO);
+    }
+
+    #[test]
+    fn lifting_the_limit_wakes_waiters() {
+        let limiter = Arc::new(RateLimiter::new(1024));
+        // runs up ~10 seconds of debt
+        limiter.request(10 * 1024, IoPriority::High);
+        let waiter = {
+            let limiter = limiter.clone();
+            std::thread::spawn(move || limiter.request(1, IoPriority::Low))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        let started = Instant::now();
+        limiter.set_bytes_per_second(0);
+        waiter.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(limiter.bytes_per_second(), 0);
+    }
+}
@@ -0,0 +1,384 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{LsmTree, Options, Wal};
+
+/// Where `restore_point_in_time` stops replaying writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryTarget {
+    /// Up to and including the write with this sequence
+    Sequence(u64),
+    /// Every write made up to and including this time. Writes from WAL
+    /// segments that predate timestamps are taken to be older than any
+    /// target time.
+    Time(SystemTime),
+}
+
+/// Why `restore_point_in_time` stopped replaying writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryStop {
+    /// The next write is past the target
+    ReachedTarget,
+    /// The WAL segments given have no more writes
+    EndOfWal,
+    /// The write with this sequence isn't in any of the WAL segments given,
+    /// so nothing after it could be replayed either
+    MissingWrite(u64),
+}
+
+/// Exactly where `restore_point_in_time` got to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// The newest write in the checkpoint's SSTables, replay went on from there
+    pub checkpoint_sequence: u64,
+    /// The last write replayed, or `checkpoint_sequence` if there were none
+    pub last_sequence: u64,
+    /// When the last write replayed was made, if there was one
+    pub last_timestamp: Option<SystemTime>,
+    pub replayed_writes: u64,
+    pub stop: RecoveryStop,
+}
+
+impl fmt::Display for RecoveryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Replayed {} writes on top of the checkpoint's SSTables (up to write {}), through write {}",
+            self.replayed_writes, self.checkpoint_sequence, self.last_sequence
+        )?;
+        if let Some(timestamp) = self.last_timestamp {
+            let elapsed = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+            write!(
+                f,
+                " made at {}.{:06} (Unix time)",
+                elapsed.as_secs(),
+                elapsed.subsec_micros()
+            )?;
+        }
+        match self.stop {
+            RecoveryStop::ReachedTarget => write!(f, ", stopped at the target"),
+            RecoveryStop::EndOfWal => write!(f, ", stopped at the end of the WAL"),
+            RecoveryStop::MissingWrite(sequence) => {
+                write!(f, ", stopped since write {} is missing", sequence)
+            }
+        }
+    }
+}
+
+/// Rebuild a tree as it was at `target` in a new directory at `path`,
+/// starting from a `checkpoint` (see `LsmTree::checkpoint`) and replaying
+/// the writes after it from the checkpoint's own WAL segments and
+/// `wal_segments`: segment files, or directories of them such as a tree's
+/// WAL archive (see `Options::wal_archive_ttl`).
+///
+/// The checkpoint's SSTables are copied as they are, so it has to be from
+/// before the target. Writes keep their sequences, and `options` should
+/// allow for the values they hold (e.g. `blob_threshold`).
+pub fn restore_point_in_time(
+    checkpoint: &Path,
+    wal_segments: &[PathBuf],
+    path: &Path,
+    target: RecoveryTarget,
+    options: Options,
+) -> Result<RecoveryReport> {
+    if !checkpoint.is_dir() {
+        bail!("Checkpoint {} does not exist", checkpoint.display());
+    }
+    if path.exists() && path.read_dir()?.next().is_some() {
+        bail!("{} already exists and isn't empty", path.display());
+    }
+    std::fs::create_dir_all(path)?;
+
+    // everything but the WAL and the directory lock
+    let mut segments = Vec::new();
+    for dir_entry_result in std::fs::read_dir(checkpoint)? {
+        let dir_entry = dir_entry_result?;
+        let file_name = dir_entry.file_name();
+        let is_wal = file_name.to_str().is_some_and(|name| {
+            name == LsmTree::LEGACY_WAL_FILE_NAME
+                || (name.starts_with(LsmTree::WAL_SEGMENT_PREFIX)
+                    && name.ends_with(LsmTree::WAL_SEGMENT_EXT))
+        });
+        if is_wal {
+            segments.push(dir_entry.path());
+        } else if dir_entry.file_type()?.is_file() && file_name != LsmTree::LOCK_FILE_NAME {
+            std::fs::copy(dir_entry.path(), path.join(&file_name))
+                .with_context(|| format!("Failed to copy {:?} from the checkpoint", file_name))?;
+        }
+    }
+    for segment_path in wal_segments {
+        if segment_path.is_dir() {
+            let listed = LsmTree::list_wal_segments(segment_path)?;
+            segments.extend(listed.into_iter().map(|(_, segment_path)| segment_path));
+        } else {
+            segments.push(segment_path.clone());
+        }
+    }
+
+    // segments given more than once, or overlapping the checkpoint's, are
+    // fine: writes already replayed are skipped
+    let mut ordered_segments = Vec::new();
+    for segment_path in segments {
+        if let Some(first_sequence) = Wal::first_sequence(&segment_path)? {
+            ordered_segments.push((first_sequence, segment_path));
+        }
+    }
+    ordered_segments.sort();
+
+    let tree = LsmTree::open_with_options(path, options)?;
+    let checkpoint_sequence = tree.last_sequence();
+    if let RecoveryTarget::Sequence(target_sequence) = target {
+        if target_sequence < checkpoint_sequence {
+            bail!(
+                "The checkpoint is already past the target (it has writes up to {})",
+                checkpoint_sequence
+            );
+        }
+    }
+
+    let mut report = RecoveryReport {
+        checkpoint_sequence,
+        last_sequence: checkpoint_sequence,
+        last_timestamp: None,
+        replayed_writes: 0,
+        stop: RecoveryStop::EndOfWal,
+    };
+    'segments: for (_, segment_path) in &ordered_segments {
+        for record in Wal::read_timed_records(segment_path)? {
+            if record.sequence <= report.last_sequence {
+                continue;
+            }
+            if record.sequence != report.last_sequence + 1 {
+                report.stop = RecoveryStop::MissingWrite(report.last_sequence + 1);
+                break 'segments;
+            }
+            let past_target = match target {
+                RecoveryTarget::Sequence(target_sequence) => record.sequence > target_sequence,
+                RecoveryTarget::Time(target_time) => record.timestamp > target_time,
+            };
+            if past_target {
+                report.stop = RecoveryStop::ReachedTarget;
+                break 'segments;
+            }
+
+            tree.inner.write_replicated(record.sequence, record.batch)?;
+            report.last_sequence = record.sequence;
+            report.last_timestamp = Some(record.timestamp);
+            report.replayed_writes += 1;
+        }
+    }
+    if target == RecoveryTarget::Sequence(report.last_sequence) {
+        report.stop = RecoveryStop::ReachedTarget;
+    }
+
+    tree.flush()?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key{:04}", i).into_bytes()
+    }
+
+    /// Keys below `good` hold "good", the rest "v0"
+    fn assert_restored(path: &Path, good: u32) {
+        let tree = LsmTree::open(path).unwrap();
+        for i in 0..1500 {
+            let expected: &[u8] = if i < good { b"good" } else { b"v0" };
+            assert_eq!(
+                tree.get(&key(i)).unwrap().as_deref(),
+                Some(expected),
+                "{}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn restores_up_to_a_sequence_or_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree_path = dir.path().join("tree");
+        let archive = tree_path.join(LsmTree::WAL_ARCHIVE_DIR);
+        let options = Options {
+            wal_segment_size: 4096,
+            wal_archive_ttl: Some(Duration::from_secs(3600)),
+            ..Options::default()
+        };
+        let tree = LsmTree::open_with_options(&tree_path, options).unwrap();
+        for i in 0..1500 {
+            tree.put(key(i), b"v0".to_vec()).unwrap();
+        }
+        let checkpoint = dir.path().join("checkpoint");
+        tree.checkpoint(&checkpoint).unwrap();
+        assert!(tree.checkpoint(&checkpoint).is_err());
+
+        for i in 0..300 {
+            tree.put(key(i), b"good".to_vec()).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(10));
+        let before_the_mistake = SystemTime::now();
+        std::thread::sleep(Duration::from_millis(10));
+        for i in 0..1500 {
+            tree.put(key(i), b"bad".to_vec()).unwrap();
+        }
+        tree.flush().unwrap();
+
+        // a checkpoint opens as a tree of its own, with nothing flushed yet
+        {
+            let checkpoint_tree = LsmTree::open(&checkpoint).unwrap();
+            assert_eq!(checkpoint_tree.last_sequence(), 1500);
+            assert_eq!(checkpoint_tree.get(&key(0)).unwrap(), Some(b"v0".to_vec()));
+        }
+
+        let restore = |name: &str, target| {
+            let path = dir.path().join(name);
+            let segments = [archive.clone(), tree_path.clone()];
+            let report =
+                restore_point_in_time(&checkpoint, &segments, &path, target, Options::default())
+                    .unwrap();
+            (path, report)
+        };
+        let (path, report) = restore("sequence", RecoveryTarget::Sequence(1800));
+        assert_eq!(
+            report,
+            RecoveryReport {
+                checkpoint_sequence: 0,
+                last_sequence: 1800,
+                replayed_writes: 1800,
+                stop: RecoveryStop::ReachedTarget,
+                ..report
+            }
+        );
+        assert_restored(&path, 300);
+        let (path, report) = restore("earlier", RecoveryTarget::Sequence(1750));
+        assert_eq!(report.last_sequence, 1750);
+        assert_restored(&path, 250);
+
+        let (path, report) = restore("time", RecoveryTarget::Time(before_the_mistake));
+        assert_eq!(report.last_sequence, 1800);
+        assert_eq!(report.stop, RecoveryStop::ReachedTarget);
+        assert!(report.last_timestamp.unwrap() <= before_the_mistake);
+        assert!(report.to_string().contains("through write 1800 made at"));
+        assert_restored(&path, 300);
+
+        // into an empty directory only
+        let target = RecoveryTarget::Sequence(1800);
+        assert!(
+            restore_point_in_time(&checkpoint, &[], &path, target, Options::default()).is_err()
+        );
+    }
+
+    #[test]
+    fn replay_stops_at_a_missing_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree_path = dir.path().join("tree");
+        let archive = tree_path.join(LsmTree::WAL_ARCHIVE_DIR);
+        let options = Options {
+            wal_segment_size: 4096,
+            wal_archive_ttl: Some(Duration::from_secs(3600)),
+            ..Options::default()
+        };
+        let tree = LsmTree::open_with_options(&tree_path, options).unwrap();
+        let checkpoint = dir.path().join("checkpoint");
+        tree.checkpoint(&checkpoint).unwrap();
+        for i in 0..1000 {
+            tree.put(key(i), b"v0".to_vec()).unwrap();
+        }
+        tree.flush().unwrap();
+
+        let archived = LsmTree::list_wal_segments(&archive).unwrap();
+        let (_, missing) = &archived[archived.len() / 2];
+        let first_missing = Wal::first_sequence(missing).unwrap().unwrap();
+        let segments: Vec<_> = archived
+            .iter()
+            .map(|(_, segment_path)| segment_path.clone())
+            .filter(|segment_path| segment_path != missing)
+            .collect();
+        let path = dir.path().join("restored");
+        let target = RecoveryTarget::Time(SystemTime::now());
+        let report =
+            restore_point_in_time(&checkpoint, &segments, &path, target, Options::default())
+                .unwrap();
+        assert_eq!(report.stop, RecoveryStop::MissingWrite(first_missing));
+        assert_eq!(report.last_sequence, first_missing - 1);
+        let restored = LsmTree::open(&path).unwrap();
+        assert!(restored
+            .get(&key(first_missing as u32 - 2))
+            .unwrap()
+            .is_some());
+        assert_eq!(restored.get(&key(first_missing as u32 - 1)).unwrap(), None);
+    }
+
+    #[test]
+    fn checkpoints_past_the_target_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(&dir.path().join("tree")).unwrap();
+        for i in 0..100 {
+            tree.put(key(i), b"v0".to_vec()).unwrap();
+        }
+        tree.flush().unwrap();
+        tree.put(key(0), b"good".to_vec()).unwrap();
+        let checkpoint = dir.path().join("checkpoint");
+        tree.checkpoint(&checkpoint).unwrap();
+
+        let path = dir.path().join("restored");
+        let target = RecoveryTarget::Sequence(10);
+        assert!(
+            restore_point_in_time(&checkpoint, &[], &path, target, Options::default()).is_err()
+        );
+
+        // the checkpoint's own WAL is replayed on top of its SSTables
+        let path = dir.path().join("end");
+        let target = RecoveryTarget::Sequence(1000);
+        let report =
+            restore_point_in_time(&checkpoint, &[], &path, target, Options::default()).unwrap();
+        assert_eq!(
+            (
+                report.checkpoint_sequence,
+                report.last_sequence,
+                report.stop
+            ),
+            (100, 101, RecoveryStop::EndOfWal)
+        );
+        assert_eq!(report.replayed_writes, 1);
+        let restored = LsmTree::open(&path).unwrap();
+        assert_eq!(restored.get(&key(0)).unwrap(), Some(b"good".to_vec()));
+        assert_eq!(restored.get(&key(99)).unwrap(), Some(b"v0".to_vec()));
+    }
+
+    #[test]
+    fn checkpoints_are_renamed_into_place_once_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(&dir.path().join("tree")).unwrap();
+        tree.put(key(0), b"flushed".to_vec()).unwrap();
+        tree.flush().unwrap();
+        tree.put(key(1), b"in the WAL".to_vec()).unwrap();
+
+        // as left by a checkpoint that crashed halfway through
+        let checkpoint = dir.path().join("checkpoint");
+        let partial = dir.path().join("checkpoint.partial");
+        std::fs::create_dir(&partial).unwrap();
+        std::fs::write(partial.join("sstable_99.sst"), b"torn").unwrap();
+        assert!(!checkpoint.exists());
+
+        tree.checkpoint(&checkpoint).unwrap();
+        assert!(!partial.exists());
+        assert!(!checkpoint.join("sstable_99.sst").exists());
+        let copy = LsmTree::open(&checkpoint).unwrap();
+        assert_eq!(copy.get(&key(0)).unwrap(), Some(b"flushed".to_vec()));
+        assert_eq!(copy.get(&key(1)).unwrap(), Some(b"in the WAL".to_vec()));
+
+        let err = tree.checkpoint(&checkpoint).unwrap_err();
+        assert!(err.to_string().contains("already exists"));
+    }
+}
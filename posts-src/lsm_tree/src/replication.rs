@@ -0,0 +1,560 @@
+use std::{
+    io::{BufReader, BufWriter, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::{write_log, LsmTree, Options, TreeInner, WriteBatch};
+
+// Replication protocol: the follower connects and sends
+// `<magic><u64 sequence of the first write it wants>`, and the primary
+// replies with a stream of frames, each a `u8` tag followed by:
+// - a write: `<u64 sequence><u32 batch length><batch>`, batches encoded
+//   as in the WAL
+// - a heartbeat, sent when there have been no writes for a while:
+//   `<u64 primary's last sequence>`
+// - an error, after which the primary hangs up: `<u32 length><message>`
+const MAGIC: &[u8; 8] = b"LSMREPL1";
+const WRITE_TAG: u8 = 0;
+const HEARTBEAT_TAG: u8 = 1;
+const ERROR_TAG: u8 = 2;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+// a follower gives up on a primary it hasn't heard from in this long
+const PRIMARY_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// writes read from the log per round trip to it
+const MAX_WRITES_PER_READ: usize = 1024;
+const MAX_ERROR_LENGTH: usize = 64 * 1024;
+
+/// Streams a tree's writes to `Follower`s connecting over TCP, as they're
+/// made. A follower that reconnects resumes where it left off, as long as
+/// the writes it missed are still in the primary's in-memory write log
+/// (see `Options::write_log_size`) or its WAL. Otherwise it has to be
+/// re-seeded from a copy of the primary's directory.
+///
+/// Stops serving when dropped, which should happen before the tree is.
+#[derive(Debug)]
+pub struct ReplicationServer {
+    address: SocketAddr,
+    shared: Arc<ServerShared>,
+    accept_thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug, Default)]
+struct ServerShared {
+    shutdown: AtomicBool,
+    // a thread per follower, with its stream to hang up on at shutdown
+    followers: Mutex<Vec<(JoinHandle<()>, TcpStream)>>,
+}
+
+impl ReplicationServer {
+    /// Serve `tree`'s writes to followers connecting to `listener`
+    pub fn start(tree: &LsmTree, listener: TcpListener) -> Result<Self> {
+        if tree.inner.read_only || tree.inner.replica {
+            bail!("Only a writable tree can be a replication primary");
+        }
+
+        let address = listener.local_addr()?;
+        let shared = Arc::new(ServerShared::default());
+        let inner = tree.inner.clone();
+        let accept_shared = shared.clone();
+        let accept_thread = std::thread::Builder::new()
+            .name("lsm-replication".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if accept_shared.shutdown.load(Ordering::Acquire) {
+                        return;
+                    }
+                    let Ok((stream, clone)) = stream.and_then(|stream| {
+                        let clone = stream.try_clone()?;
+                        Ok((stream, clone))
+                    }) else {
+                        continue;
+                    };
+                    let inner = inner.clone();
+                    let shared = accept_shared.clone();
+                    let thread = std::thread::spawn(move || {
+                        // a follower going away is routine, and it keeps
+                        // track of its own errors
+                        let _ = serve_follower(&inner, &shared, stream);
+                    });
+
+                    let mut followers = accept_shared.followers.lock().unwrap();
+                    followers.retain(|(thread, _)| !thread.is_finished());
+                    followers.push((thread, clone));
+                }
+            })
+            .context("Failed to start replication server")?;
+
+        Ok(Self {
+            address,
+            shared,
+            accept_thread: Some(accept_thread),
+        })
+    }
+
+    /// Address followers connect to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for ReplicationServer {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Release);
+        // wake the accept loop up so it sees the flag
+        let _ = TcpStream::connect_timeout(&self.address, CONNECT_TIMEOUT);
+        if let Some(thread) = self.accept_thread.take() {
+            let _ = thread.join();
+        }
+        let followers = std::mem::take(&mut *self.shared.followers.lock().unwrap());
+        for (thread, stream) in followers {
+            let _ = stream.shutdown(Shutdown::Both);
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve_follower(inner: &TreeInner, shared: &ServerShared, stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(PRIMARY_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    let mut handshake = [0u8; MAGIC.len() + 8];
+    reader.read_exact(&mut handshake)?;
+    if &handshake[..MAGIC.len()] != MAGIC {
+        bail!("Not a replication follower");
+    }
+    let mut next = u64::from_le_bytes(handshake[MAGIC.len()..].try_into()?);
+
+    while !shared.shutdown.load(Ordering::Acquire) {
+        let writes =
+            match write_log::records_since(inner, next, MAX_WRITES_PER_READ, HEARTBEAT_INTERVAL) {
+                Ok(writes) => writes,
+                Err(e) => {
+                    let message = format!("{:#}", e);
+                    writer.write_all(&[ERROR_TAG])?;
+                    writer.write_all(&(message.len() as u32).to_le_bytes())?;
+                    writer.write_all(message.as_bytes())?;
+                    writer.flush()?;
+                    return Err(e);
+                }
+            };
+
+        if writes.is_empty() {
+            writer.write_all(&[HEARTBEAT_TAG])?;
+            writer.write_all(&inner.last_sequence().to_le_bytes())?;
+        }
+        let mut encoded_batch = Vec::new();
+        for (sequence, batch) in writes {
+            encoded_batch.clear();
+            batch.encode(&mut encoded_batch);
+            writer.write_all(&[WRITE_TAG])?;
+            writer.write_all(&sequence.to_le_bytes())?;
+            writer.write_all(&(encoded_batch.len() as u32).to_le_bytes())?;
+            writer.write_all(&encoded_batch)?;
+            next = sequence + 1;
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// A read-only copy of a primary tree (see `ReplicationServer`), kept up
+/// to date by replaying its writes as they're streamed over.
+///
+/// Writes get the same sequences as on the primary, so the follower's
+/// `LsmTree::last_sequence` is how far it has got, and after a restart or
+/// a lost connection it picks up from there. It keeps reconnecting until
+/// dropped. Writing to its tree fails with `LsmError::ReadOnly`.
+///
+/// The follower's directory has to start out empty or as a copy of the
+/// primary's, and should use the same `blob_threshold` so the primary's
+/// values fit.
+#[derive(Debug)]
+pub struct Follower {
+    tree: LsmTree,
+    shared: Arc<FollowerShared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug, Default)]
+struct FollowerState {
+    stop: bool,
+    connected: bool,
+    // newest write the primary has told us about
+    primary_sequence: Option<u64>,
+    last_error: Option<String>,
+    // to hang up on the primary when stopping
+    stream: Option<TcpStream>,
+}
+
+#[derive(Debug, Default)]
+struct FollowerShared {
+    state: Mutex<FollowerState>,
+    // notified after every write applied
+    applied: Condvar,
+}
+
+impl Follower {
+    /// Open (or create) the follower's tree at `path` and start following
+    /// the primary at `primary`
+    pub fn open(path: &Path, options: Options, primary: impl ToSocketAddrs) -> Result<Self> {
+        let primary: Vec<SocketAddr> = primary
+            .to_socket_addrs()
+            .context("Invalid primary address")?
+            .collect();
+        if primary.is_empty() {
+            bail!("Primary address didn't resolve to anything");
+        }
+
+        let tree = LsmTree::open_writable(path, options, true)?;
+        let shared = Arc::new(FollowerShared::default());
+        let inner = tree.inner.clone();
+        let thread_shared = shared.clone();
+        let thread = std::thread::Builder::new()
+            .name("lsm-follower".to_string())
+            .spawn(move || follow(&inner, &thread_shared, &primary))
+            .context("Failed to start follower")?;
+
+        Ok(Self {
+            tree,
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// The replicated tree, for reads
+    pub fn tree(&self) -> &LsmTree {
+        &self.tree
+    }
+
+    /// Whether the follower is connected to the primary right now
+    pub fn is_connected(&self) -> bool {
+        self.shared.state.lock().unwrap().connected
+    }
+
+    /// Sequence of the newest write on the primary, as of when it last
+    /// said. Compare with `tree().last_sequence()` for the lag.
+    pub fn primary_sequence(&self) -> Option<u64> {
+        self.shared.state.lock().unwrap().primary_sequence
+    }
+
+    /// Why the last connection to the primary failed, if it did
+    pub fn last_error(&self) -> Option<String> {
+        self.shared.state.lock().unwrap().last_error.clone()
+    }
+
+    /// Wait until the write with `sequence` has been applied, for up to
+    /// `timeout`. Returns whether it was.
+    pub fn wait_for_sequence(&self, sequence: u64, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if self.tree.last_sequence() >= sequence {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self
+                .shared
+                .applied
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.stop = true;
+            if let Some(stream) = state.stream.take() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+        self.shared.applied.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Body of the follower thread: stay connected to the primary, backing
+/// off between attempts
+fn follow(inner: &TreeInner, shared: &FollowerShared, primary: &[SocketAddr]) {
+    let mut delay = Duration::from_millis(100);
+    loop {
+        let result = follow_once(inner, shared, primary, &mut delay);
+        let mut state = shared.state.lock().unwrap();
+        state.connected = false;
+        state.stream = None;
+        if state.stop {
+            return;
+        }
+        if let Err(e) = result {
+            state.last_error = Some(format!("{:#}", e));
+        }
+        // sleep, unless told to stop meanwhile
+        let state = shared.applied.wait_timeout(state, delay).unwrap().0;
+        if state.stop {
+            return;
+        }
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// One connection to the primary, until it fails
+fn follow_once(
+    inner: &TreeInner,
+    shared: &FollowerShared,
+    primary: &[SocketAddr],
+    delay: &mut Duration,
+) -> Result<()> {
+    let stream = connect(primary)?;
+    stream.set_read_timeout(Some(PRIMARY_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    {
+        let mut state = shared.state.lock().unwrap();
+        if state.stop {
+            return Ok(());
+        }
+        state.stream = Some(stream.try_clone()?);
+    }
+
+    let mut handshake = MAGIC.to_vec();
+    handshake.extend_from_slice(&(inner.last_sequence() + 1).to_le_bytes());
+    (&stream).write_all(&handshake)?;
+
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut tag = [0u8; 1];
+        reader.read_exact(&mut tag)?;
+        let primary_sequence = match tag[0] {
+            WRITE_TAG => {
+                let sequence = read_u64(&mut reader)?;
+                let length = read_u32(&mut reader)? as usize;
+                let mut encoded_batch = vec![0u8; length];
+                reader.read_exact(&mut encoded_batch)?;
+                let batch = WriteBatch::decode(&encoded_batch)
+                    .context("Failed to decode replicated write")?;
+                inner.write_replicated(sequence, batch)?;
+                sequence
+            }
+            HEARTBEAT_TAG => read_u64(&mut reader)?,
+            ERROR_TAG => {
+                let length = read_u32(&mut reader)? as usize;
+                if length > MAX_ERROR_LENGTH {
+                    bail!("Primary failed (with an error too long to read)");
+                }
+                let mut message = vec![0u8; length];
+                reader.read_exact(&mut message)?;
+                return Err(anyhow!(
+                    "Primary failed: {}",
+                    String::from_utf8_lossy(&message)
+                ));
+            }
+            tag => bail!("Unknown replication frame tag {}", tag),
+        };
+
+        {
+            let mut state = shared.state.lock().unwrap();
+            state.connected = true;
+            state.last_error = None;
+            state.primary_sequence = Some(
+                state
+                    .primary_sequence
+                    .map_or(primary_sequence, |sequence| sequence.max(primary_sequence)),
+            );
+        }
+        shared.applied.notify_all();
+        *delay = Duration::from_millis(100);
+    }
+}
+
+fn connect(addresses: &[SocketAddr]) -> Result<TcpStream> {
+    let mut last_error = None;
+    for address in addresses {
+        match TcpStream::connect_timeout(address, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error
+        .map(anyhow::Error::from)
+        .unwrap_or_else(|| anyhow!("No primary address")))
+    .context("Failed to connect to the primary")
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChangeEvent, LsmError};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn contents(tree: &LsmTree) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut cursor = tree.cursor();
+        cursor.seek_to_first().unwrap();
+        let mut entries = Vec::new();
+        while let (Some(key), Some(value)) = (cursor.key(), cursor.value()) {
+            entries.push((key.to_vec(), value.to_vec()));
+            cursor.next().unwrap();
+        }
+        entries
+    }
+
+    /// Every write `tree` has made, as it's kept in its WAL
+    fn history(tree: &LsmTree) -> Vec<ChangeEvent> {
+        let mut subscription = tree.subscribe(1);
+        // they've all happened, so there's no waiting for any
+        std::iter::from_fn(|| subscription.next_timeout(Duration::ZERO).unwrap()).collect()
+    }
+
+    fn start(primary: &LsmTree) -> ReplicationServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        ReplicationServer::start(primary, listener).unwrap()
+    }
+
+    fn put(tree: &LsmTree, prefix: &str, count: usize) {
+        for i in 0..count {
+            let key = format!("{}{:04}", prefix, i).into_bytes();
+            tree.put(key, vec![b'v'; 100]).unwrap();
+        }
+    }
+
+    #[test]
+    fn followers_catch_up() {
+        let (primary_dir, follower_dir) =
+            (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let primary = LsmTree::open(primary_dir.path()).unwrap();
+        // written before the follower ever connects
+        put(&primary, "a", 100);
+        let server = start(&primary);
+        let follower =
+            Follower::open(follower_dir.path(), Options::default(), server.local_addr()).unwrap();
+
+        put(&primary, "b", 100);
+        let mut batch = WriteBatch::new();
+        batch.delete_range(b"a0010".to_vec(), b"a0050".to_vec());
+        batch.delete(b"b0000".to_vec());
+        primary.write(batch).unwrap();
+        assert!(
+            follower.wait_for_sequence(primary.last_sequence(), TIMEOUT),
+            "{:?}",
+            follower.last_error()
+        );
+        assert_eq!(contents(follower.tree()), contents(&primary));
+        assert_eq!(follower.tree().last_sequence(), primary.last_sequence());
+        assert!(follower.is_connected());
+        assert!(follower.primary_sequence().unwrap() <= primary.last_sequence());
+
+        let err = follower
+            .tree()
+            .put(b"key".to_vec(), b"value".to_vec())
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(LsmError::ReadOnly)));
+    }
+
+    #[test]
+    fn reconnecting_followers_resume_where_they_left_off() {
+        let (primary_dir, follower_dir) =
+            (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        // small enough that the writes missed have to come from the WAL
+        let options = Options {
+            write_log_size: 1024,
+            ..Options::default()
+        };
+        let primary = LsmTree::open_with_options(primary_dir.path(), options).unwrap();
+        let server = start(&primary);
+
+        let follower =
+            Follower::open(follower_dir.path(), Options::default(), server.local_addr()).unwrap();
+        put(&primary, "a", 100);
+        assert!(follower.wait_for_sequence(primary.last_sequence(), TIMEOUT));
+        drop(follower);
+
+        put(&primary, "b", 200);
+        let follower =
+            Follower::open(follower_dir.path(), Options::default(), server.local_addr()).unwrap();
+        assert!(
+            follower.wait_for_sequence(primary.last_sequence(), TIMEOUT),
+            "{:?}",
+            follower.last_error()
+        );
+        // and on streaming what comes next
+        put(&primary, "c", 100);
+        assert!(follower.wait_for_sequence(primary.last_sequence(), TIMEOUT));
+
+        assert_eq!(contents(follower.tree()), contents(&primary));
+        // every write exactly once, with the primary's sequence
+        assert_eq!(history(follower.tree()), history(&primary));
+        assert_eq!(history(&primary).len(), 400);
+    }
+
+    #[test]
+    fn writes_no_longer_retained_are_unavailable() {
+        let (primary_dir, follower_dir) =
+            (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let options = Options {
+            write_log_size: 1024,
+            ..Options::default()
+        };
+        let primary = LsmTree::open_with_options(primary_dir.path(), options).unwrap();
+        let server = start(&primary);
+
+        let follower =
+            Follower::open(follower_dir.path(), Options::default(), server.local_addr()).unwrap();
+        put(&primary, "a", 10);
+        assert!(follower.wait_for_sequence(primary.last_sequence(), TIMEOUT));
+        let resume_from = follower.tree().last_sequence() + 1;
+        drop(follower);
+
+        // flushing deletes the WAL segments the missed writes were in
+        put(&primary, "b", 100);
+        primary.flush().unwrap();
+        put(&primary, "c", 10);
+
+        let follower =
+            Follower::open(follower_dir.path(), Options::default(), server.local_addr()).unwrap();
+        let deadline = Instant::now() + TIMEOUT;
+        let error = loop {
+            if let Some(error) = follower.last_error() {
+                break error;
+            }
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        let unavailable = LsmError::SequenceUnavailable(resume_from).to_string();
+        assert!(error.contains(&unavailable), "{}", error);
+        assert!(!follower.wait_for_sequence(primary.last_sequence(), Duration::from_millis(200)));
+        assert_eq!(follower.tree().last_sequence(), resume_from - 1);
+    }
+}
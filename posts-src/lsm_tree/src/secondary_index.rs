@@ -0,0 +1,646 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    fs::File,
+    io::Write,
+    ops::{Bound, RangeBounds},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    sync_dir, typed::encode_bytes_key, BatchOp, Cursor, KeyEncoding, LsmError, LsmTree, Options,
+    TreeInner, WriteBatch,
+};
+
+/// Finds the keys a record goes under in a secondary index, from its
+/// value. Set through `Options::secondary_indexes`.
+pub trait IndexExtractor: Send + Sync + fmt::Debug {
+    /// Index keys of a record with this value (none leaves it out of the index)
+    fn index_keys(&self, value: &[u8]) -> Vec<Vec<u8>>;
+}
+
+// Index entries live in the tree next to the records, under keys past
+// (almost) any a user would pick: `<prefix><index name><index key><primary
+// key>` with an empty value, where the name and index key are escaped and
+// terminated as in `KeyEncoding`, so entries sort by index key and split
+// back apart. Keys from the prefix on are reserved once there are indexes.
+pub(crate) const INDEX_KEY_PREFIX: &[u8] = b"\xff\xff\xff\xffidx";
+
+// the names of the indexes whose entries have been built, each as
+// `<u32 name length><name bytes>`
+pub(crate) const INDEXES_FILE_NAME: &str = "INDEXES";
+
+// entries per batch written while building an index
+const BUILD_BATCH_SIZE: usize = 1000;
+
+/// Whether the tree keeps `key` to itself for index entries
+pub(crate) fn is_reserved(inner: &TreeInner, key: &[u8]) -> bool {
+    !inner.options.secondary_indexes.is_empty() && key >= INDEX_KEY_PREFIX
+}
+
+fn index_prefix(name: &str) -> Vec<u8> {
+    let mut prefix = INDEX_KEY_PREFIX.to_vec();
+    encode_bytes_key(name.as_bytes(), &mut prefix);
+    prefix
+}
+
+fn entry_key(name: &str, index_key: &[u8], primary_key: &[u8]) -> Vec<u8> {
+    let mut entry_key = index_prefix(name);
+    encode_bytes_key(index_key, &mut entry_key);
+    entry_key.extend_from_slice(primary_key);
+    entry_key
+}
+
+/// The first key past every entry of the index
+fn index_end(name: &str) -> Vec<u8> {
+    // the prefix ends with the name's `0, 0` terminator
+    let mut end = index_prefix(name);
+    *end.last_mut().unwrap() += 1;
+    end
+}
+
+/// `batch` along with the index entries it adds and removes, so both are
+/// written together. Has to run with the WAL locked, so that the values
+/// being replaced are still the newest ones.
+pub(crate) fn with_index_updates(inner: &TreeInner, batch: WriteBatch) -> Result<WriteBatch> {
+    let indexes = &inner.options.secondary_indexes;
+    if indexes.is_empty() {
+        return Ok(batch);
+    }
+
+    // where each key the batch writes ends up, later operations winning
+    let mut written: BTreeMap<Vec<u8>, Option<Vec<u8>>> = BTreeMap::new();
+    let mut updated = WriteBatch::new();
+    for op in batch.iter() {
+        let key = match op {
+            BatchOp::Put(key, _) | BatchOp::Delete(key) | BatchOp::DeleteRange(key, _) => key,
+        };
+        if is_reserved(inner, key) {
+            bail!(LsmError::InvalidWrite(format!(
+                "keys from {:?} on are reserved for secondary index entries",
+                String::from_utf8_lossy(INDEX_KEY_PREFIX)
+            )));
+        }
+        if let BatchOp::DeleteRange(..) = op {
+            bail!(LsmError::InvalidWrite(
+                "range deletions aren't supported with secondary indexes".into()
+            ));
+        }
+
+        match op {
+            BatchOp::Put(key, value) => {
+                written.insert(key.clone(), Some(value.clone()));
+                updated.put(key.clone(), value.clone());
+            }
+            BatchOp::Delete(key) => {
+                written.insert(key.clone(), None);
+                updated.delete(key.clone());
+            }
+            BatchOp::DeleteRange(..) => unreachable!(),
+        }
+    }
+
+    let mut entries = WriteBatch::new();
+    for (key, value) in &written {
+        let old_value = inner.get(key)?;
+        if old_value == *value {
+            continue;
+        }
+        for (name, extractor) in indexes {
+            let index_keys = |value: &Option<Vec<u8>>| -> BTreeSet<Vec<u8>> {
+                value
+                    .as_ref()
+                    .map(|value| extractor.index_keys(value).into_iter().collect())
+                    .unwrap_or_default()
+            };
+            let old_keys = index_keys(&old_value);
+            let new_keys = index_keys(value);
+            for stale in old_keys.difference(&new_keys) {
+                entries.delete(entry_key(name, stale, key));
+            }
+            for added in new_keys.difference(&old_keys) {
+                entries.put(entry_key(name, added, key), Vec::new());
+            }
+        }
+    }
+    entries.validate(LsmTree::MAX_ENTRY_SIZE)?;
+
+    for op in entries.iter() {
+        match op {
+            BatchOp::Put(key, value) => updated.put(key.clone(), value.clone()),
+            BatchOp::Delete(key) => updated.delete(key.clone()),
+            BatchOp::DeleteRange(start, end) => updated.delete_range(start.clone(), end.clone()),
+        }
+    }
+    Ok(updated)
+}
+
+/// The names of the indexes built in the tree at `path`
+fn read_built_indexes(path: &Path) -> Result<BTreeSet<String>> {
+    let bytes = match std::fs::read(path.join(INDEXES_FILE_NAME)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        Err(e) => return Err(e).context("Failed to read the secondary index list"),
+    };
+    let mut names = BTreeSet::new();
+    let mut rest = bytes.as_slice();
+    while !rest.is_empty() {
+        let name = rest
+            .get(..4)
+            .map(|length| u32::from_le_bytes(length.try_into().unwrap()) as usize)
+            .and_then(|length| rest.get(4..4 + length))
+            .context("Corrupt secondary index list")?;
+        names.insert(String::from_utf8(name.to_vec()).context("Corrupt secondary index list")?);
+        rest = &rest[4 + name.len()..];
+    }
+    Ok(names)
+}
+
+/// Replace the list of built indexes, atomically
+fn write_built_indexes<'a>(path: &Path, names: impl Iterator<Item = &'a String>) -> Result<()> {
+    let mut bytes = Vec::new();
+    for name in names {
+        bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(name.as_bytes());
+    }
+    let partial_path = path.join(format!("{}.partial", INDEXES_FILE_NAME));
+    let mut file = File::create(&partial_path)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&partial_path, path.join(INDEXES_FILE_NAME))?;
+    sync_dir(path)
+}
+
+/// Bring the index entries in line with `Options::secondary_indexes` when
+/// a tree is opened for writes: indexes added since the last open are
+/// built from the records, and the entries of those taken away are deleted.
+/// An interrupted build is redone on the next open.
+pub(crate) fn build_indexes(inner: &TreeInner) -> Result<()> {
+    let indexes = &inner.options.secondary_indexes;
+    let built = read_built_indexes(&inner.path)?;
+    if indexes.keys().eq(built.iter()) {
+        return Ok(());
+    }
+
+    // keys written while the tree had no indexes would be taken for entries
+    if built.is_empty() {
+        let mut cursor = Cursor::new(inner.read_view(), inner.blobs.clone());
+        cursor.seek(INDEX_KEY_PREFIX)?;
+        if let Some(key) = cursor.key() {
+            bail!(
+                "Can't add secondary indexes: key {:?} is in the range reserved for their entries",
+                String::from_utf8_lossy(key)
+            );
+        }
+    }
+
+    for name in built.iter().filter(|name| !indexes.contains_key(*name)) {
+        let mut batch = WriteBatch::new();
+        batch.delete_range(index_prefix(name), index_end(name));
+        inner.write_index_entries(batch)?;
+    }
+
+    let added: Vec<_> = indexes
+        .iter()
+        .filter(|(name, _)| !built.contains(*name))
+        .collect();
+    if !added.is_empty() {
+        // records written from here on get their entries as usual
+        let mut cursor = Cursor::new(inner.read_view(), inner.blobs.clone());
+        cursor.seek_to_first()?;
+        let mut entries = WriteBatch::new();
+        while let (Some(key), Some(value)) = (cursor.key(), cursor.value()) {
+            if key >= INDEX_KEY_PREFIX {
+                break;
+            }
+            for (name, extractor) in &added {
+                let index_keys: BTreeSet<_> = extractor.index_keys(value).into_iter().collect();
+                for index_key in index_keys {
+                    entries.put(entry_key(name, &index_key, key), Vec::new());
+                }
+            }
+            if entries.len() >= BUILD_BATCH_SIZE {
+                inner.write_index_entries(std::mem::take(&mut entries))?;
+            }
+            cursor.next()?;
+        }
+        inner.write_index_entries(entries)?;
+    }
+
+    write_built_indexes(&inner.path, indexes.keys())
+}
+
+/// Fail unless every index in `options` has been built, for a read-only
+/// handle (which can't build them)
+pub(crate) fn check_indexes_built(path: &Path, options: &Options) -> Result<()> {
+    let built = read_built_indexes(path)?;
+    if let Some(name) = options
+        .secondary_indexes
+        .keys()
+        .find(|name| !built.contains(*name))
+    {
+        bail!(
+            "Secondary index {:?} hasn't been built, open the tree for writes with it first",
+            name
+        );
+    }
+    Ok(())
+}
+
+/// The records with an index key in `range`, see `LsmTree::query_index`
+pub(crate) fn query(
+    inner: &TreeInner,
+    name: &str,
+    range: impl RangeBounds<Vec<u8>>,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let Some(extractor) = inner.options.secondary_indexes.get(name) else {
+        bail!("No secondary index named {:?}", name);
+    };
+
+    // one view for both, so the entries and records match up
+    let view = inner.read_view();
+    let mut entries = Cursor::new(view.clone(), inner.blobs.clone());
+    let mut records = Cursor::new(view, inner.blobs.clone());
+
+    let index_prefix = index_prefix(name);
+    match range.start_bound() {
+        Bound::Included(start) | Bound::Excluded(start) => {
+            let mut seek_key = index_prefix.clone();
+            encode_bytes_key(start, &mut seek_key);
+            entries.seek(&seek_key)?;
+        }
+        Bound::Unbounded => entries.seek(&index_prefix)?,
+    }
+
+    let mut matches = Vec::new();
+    while let Some(entry_key) = entries.key() {
+        let Some(mut rest) = entry_key.strip_prefix(index_prefix.as_slice()) else {
+            break;
+        };
+        let index_key = Vec::<u8>::decode_key(&mut rest)?;
+        let past_end = match range.end_bound() {
+            Bound::Included(end) => index_key > *end,
+            Bound::Excluded(end) => index_key >= *end,
+            Bound::Unbounded => false,
+        };
+        if past_end {
+            break;
+        }
+
+        if range.contains(&index_key) {
+            let primary_key = rest.to_vec();
+            records.seek(&primary_key)?;
+            // a compaction filter may have removed or changed the record
+            // without touching its entries
+            if let (Some(key), Some(value)) = (records.key(), records.value()) {
+                if key == primary_key && extractor.index_keys(value).contains(&index_key) {
+                    matches.push((primary_key, value.to_vec()));
+                }
+            }
+        }
+        entries.next()?;
+    }
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{CompactionFilter, CompactionFilterContext, FilterDecision, Options};
+
+    /// Indexes `<color>:<rest>` values by color, and under "any" too if
+    /// they're "multi"
+    #[derive(Debug)]
+    struct ByColor;
+
+    impl IndexExtractor for ByColor {
+        fn index_keys(&self, value: &[u8]) -> Vec<Vec<u8>> {
+            let Some(colon) = value.iter().position(|&byte| byte == b':') else {
+                return Vec::new();
+            };
+            let mut index_keys = vec![value[..colon].to_vec()];
+            if value.ends_with(b"multi") {
+                index_keys.push(b"any".to_vec());
+            }
+            index_keys
+        }
+    }
+
+    fn options() -> Options {
+        Options {
+            secondary_indexes: BTreeMap::from([(
+                "color".to_string(),
+                Arc::new(ByColor) as Arc<dyn IndexExtractor>,
+            )]),
+            ..Options::default()
+        }
+    }
+
+    fn open(path: &std::path::Path) -> LsmTree {
+        LsmTree::open_with_options(path, options()).unwrap()
+    }
+
+    /// Every index entry, as `(index key, primary key)`
+    fn entries(tree: &LsmTree) -> Vec<(String, String)> {
+        let prefix = index_prefix("color");
+        let mut cursor = Cursor::new(tree.inner.read_view(), tree.inner.blobs.clone());
+        cursor.seek(&prefix).unwrap();
+        let mut entries = Vec::new();
+        while let Some(mut rest) = cursor
+            .key()
+            .and_then(|key| key.strip_prefix(prefix.as_slice()))
+        {
+            let index_key = Vec::<u8>::decode_key(&mut rest).unwrap();
+            entries.push((
+                String::from_utf8(index_key).unwrap(),
+                String::from_utf8(rest.to_vec()).unwrap(),
+            ));
+            cursor.next().unwrap();
+        }
+        entries
+    }
+
+    fn keys(found: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<String> {
+        found
+            .into_iter()
+            .map(|(key, _)| String::from_utf8(key).unwrap())
+            .collect()
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(index_key, key)| (index_key.to_string(), key.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn entries_are_written_with_their_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = open(dir.path());
+        let mut batch = WriteBatch::new();
+        batch.put(b"a".to_vec(), b"red:multi".to_vec());
+        batch.put(b"b".to_vec(), b"blue:".to_vec());
+        batch.put(b"c".to_vec(), b"uncolored".to_vec());
+        tree.write(batch).unwrap();
+        // one write, entries and all
+        assert_eq!(tree.last_sequence(), 1);
+        let expected = pairs(&[("any", "a"), ("blue", "b"), ("red", "a")]);
+        assert_eq!(entries(&tree), expected);
+
+        // a batch that fails writes neither
+        let mut batch = WriteBatch::new();
+        batch.put(b"d".to_vec(), b"red:".to_vec());
+        batch.put(INDEX_KEY_PREFIX.to_vec(), Vec::new());
+        let error = tree.write(batch).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(LsmError::InvalidWrite(_))
+        ));
+        assert_eq!(tree.get(b"d").unwrap(), None);
+        assert_eq!(entries(&tree), expected);
+        assert_eq!(tree.last_sequence(), 1);
+
+        // index entries aren't records
+        let mut cursor = tree.cursor();
+        cursor.seek_to_first().unwrap();
+        let mut records = 0;
+        while cursor.key().is_some() {
+            records += 1;
+            cursor.next().unwrap();
+        }
+        assert_eq!(records, 3);
+
+        // and come back with them from the WAL
+        drop(tree);
+        let tree = open(dir.path());
+        assert_eq!(entries(&tree), expected);
+    }
+
+    #[test]
+    fn overwrites_and_deletes_remove_stale_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = open(dir.path());
+        tree.put(b"a".to_vec(), b"red:multi".to_vec()).unwrap();
+        tree.put(b"b".to_vec(), b"red:".to_vec()).unwrap();
+        tree.flush().unwrap();
+
+        tree.put(b"a".to_vec(), b"red:".to_vec()).unwrap();
+        tree.put(b"b".to_vec(), b"green:".to_vec()).unwrap();
+        assert_eq!(entries(&tree), pairs(&[("green", "b"), ("red", "a")]));
+
+        // within a batch, the last write to a key counts
+        let mut batch = WriteBatch::new();
+        batch.put(b"c".to_vec(), b"blue:".to_vec());
+        batch.delete(b"a".to_vec());
+        batch.put(b"c".to_vec(), b"green:".to_vec());
+        batch.put(b"a".to_vec(), b"blue:".to_vec());
+        batch.delete(b"b".to_vec());
+        tree.write(batch).unwrap();
+        assert_eq!(entries(&tree), pairs(&[("blue", "a"), ("green", "c")]));
+
+        tree.delete(b"a".to_vec()).unwrap();
+        tree.delete(b"missing".to_vec()).unwrap();
+        tree.flush().unwrap();
+        tree.compact_all().unwrap();
+        assert_eq!(entries(&tree), pairs(&[("green", "c")]));
+    }
+
+    #[test]
+    fn range_deletes_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = open(dir.path());
+        tree.put(b"a".to_vec(), b"red:".to_vec()).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"b".to_vec(), b"blue:".to_vec());
+        batch.delete_range(b"a".to_vec(), b"c".to_vec());
+        let error = tree.write(batch).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(LsmError::InvalidWrite(_))
+        ));
+        assert!(tree.delete_range(b"a".to_vec(), b"c".to_vec()).is_err());
+        assert_eq!(tree.get(b"a").unwrap(), Some(b"red:".to_vec()));
+        assert_eq!(tree.get(b"b").unwrap(), None);
+        assert_eq!(entries(&tree), pairs(&[("red", "a")]));
+    }
+
+    #[test]
+    fn indexes_are_built_and_dropped_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        tree.put(b"a".to_vec(), b"red:multi".to_vec()).unwrap();
+        tree.put(b"b".to_vec(), b"blue:".to_vec()).unwrap();
+        tree.flush().unwrap();
+        tree.put(b"c".to_vec(), b"red:".to_vec()).unwrap();
+        tree.delete(b"b".to_vec()).unwrap();
+        drop(tree);
+
+        // a reader can't query an index no writer has built
+        assert!(LsmTree::open_read_only_with_options(dir.path(), options()).is_err());
+
+        let tree = open(dir.path());
+        assert_eq!(
+            entries(&tree),
+            pairs(&[("any", "a"), ("red", "a"), ("red", "c")])
+        );
+        assert_eq!(
+            keys(tree.query_index("color", ..).unwrap()),
+            ["a", "a", "c"]
+        );
+        tree.put(b"d".to_vec(), b"green:".to_vec()).unwrap();
+        drop(tree);
+        let reader = LsmTree::open_read_only_with_options(dir.path(), options()).unwrap();
+        assert_eq!(
+            keys(reader.query_index("color", ..).unwrap()),
+            ["a", "d", "a", "c"]
+        );
+        drop(reader);
+
+        // taking the index away deletes its entries
+        let tree = LsmTree::open(dir.path()).unwrap();
+        let mut cursor = Cursor::new(tree.inner.read_view(), tree.inner.blobs.clone());
+        cursor.seek(INDEX_KEY_PREFIX).unwrap();
+        assert_eq!(cursor.key(), None);
+        tree.put(b"e".to_vec(), b"red:".to_vec()).unwrap();
+        drop(tree);
+
+        // and adding it back builds them again, records written meanwhile too
+        let tree = open(dir.path());
+        assert_eq!(
+            keys(tree.query_index("color", b"red".to_vec()..).unwrap()),
+            ["a", "c", "e"]
+        );
+    }
+
+    #[test]
+    fn indexes_cant_be_added_over_reserved_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        let mut key = INDEX_KEY_PREFIX.to_vec();
+        key.push(b'!');
+        tree.put(key.clone(), b"red:".to_vec()).unwrap();
+        drop(tree);
+
+        let error = LsmTree::open_with_options(dir.path(), options()).unwrap_err();
+        assert!(format!("{:#}", error).contains("reserved"), "{:#}", error);
+
+        // the key is untouched, and once it's gone the index can be added
+        let tree = LsmTree::open(dir.path()).unwrap();
+        assert_eq!(tree.get(&key).unwrap(), Some(b"red:".to_vec()));
+        tree.delete(key).unwrap();
+        drop(tree);
+        assert!(entries(&open(dir.path())).is_empty());
+    }
+
+    #[test]
+    fn queries_take_ranges_of_index_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = open(dir.path());
+        let records = [
+            ("a", "red:multi"),
+            ("b", "blue:"),
+            ("c", "red:"),
+            ("d", "green:multi"),
+            ("e", "re:"),
+            ("f", "red\0:"),
+        ];
+        for (key, value) in records {
+            tree.put(key.as_bytes().to_vec(), value.as_bytes().to_vec())
+                .unwrap();
+        }
+
+        let query = |range: (Bound<&[u8]>, Bound<&[u8]>)| {
+            let range = (range.0.map(<[u8]>::to_vec), range.1.map(<[u8]>::to_vec));
+            keys(tree.query_index("color", range).unwrap())
+        };
+        use Bound::{Excluded, Included, Unbounded};
+        assert_eq!(
+            query((Unbounded, Unbounded)),
+            ["a", "d", "b", "d", "e", "a", "c", "f"]
+        );
+        assert_eq!(query((Included(b"red"), Included(b"red"))), ["a", "c"]);
+        assert_eq!(query((Included(b"re"), Excluded(b"red"))), ["e"]);
+        assert_eq!(query((Excluded(b"re"), Unbounded)), ["a", "c", "f"]);
+        assert_eq!(query((Excluded(b"any"), Excluded(b"re"))), ["b", "d"]);
+        assert_eq!(query((Included(b"z"), Unbounded)), Vec::<String>::new());
+
+        let (key, value) = tree
+            .query_index("color", b"blue".to_vec()..)
+            .unwrap()
+            .remove(0);
+        assert_eq!((key, value), (b"b".to_vec(), b"blue:".to_vec()));
+        assert!(tree.query_index("size", ..).is_err());
+
+        // read-only handles need the index too
+        let reader = LsmTree::open_read_only(dir.path()).unwrap();
+        assert!(reader.query_index("color", ..).is_err());
+        let reader = LsmTree::open_read_only_with_options(dir.path(), options()).unwrap();
+        assert_eq!(
+            keys(reader.query_index("color", b"red".to_vec()..).unwrap()),
+            ["a", "c", "f"]
+        );
+    }
+
+    /// Removes `gone*` and recolors `recolor*` blue, noting every key it sees
+    #[derive(Debug, Default)]
+    struct Recolor {
+        keys: Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl CompactionFilter for Recolor {
+        fn filter(
+            &self,
+            _context: &CompactionFilterContext,
+            key: &[u8],
+            _value: &[u8],
+        ) -> FilterDecision {
+            self.keys.lock().unwrap().push(key.to_vec());
+            if key.starts_with(b"gone") {
+                FilterDecision::Remove
+            } else if key.starts_with(b"recolor") {
+                FilterDecision::ChangeValue(b"blue:".to_vec())
+            } else {
+                FilterDecision::Keep
+            }
+        }
+    }
+
+    #[test]
+    fn records_changed_by_compaction_filters_are_left_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let filter = Arc::new(Recolor::default());
+        let options = Options {
+            compaction_filter: Some(filter.clone()),
+            ..options()
+        };
+        let tree = LsmTree::open_with_options(dir.path(), options).unwrap();
+        for key in ["gone", "kept", "recolor"] {
+            tree.put(key.as_bytes().to_vec(), b"red:".to_vec()).unwrap();
+        }
+        tree.flush().unwrap();
+        tree.compact_all().unwrap();
+
+        assert_eq!(tree.get(b"recolor").unwrap(), Some(b"blue:".to_vec()));
+        // the entries are still there, but don't match their records any more
+        assert_eq!(entries(&tree).len(), 3);
+        assert_eq!(keys(tree.query_index("color", ..).unwrap()), ["kept"]);
+        // and the filter only ever saw records
+        assert_eq!(
+            *filter.keys.lock().unwrap(),
+            [&b"gone"[..], b"kept", b"recolor"]
+        );
+
+        // a write puts things right
+        tree.put(b"recolor".to_vec(), b"green:".to_vec()).unwrap();
+        assert_eq!(
+            keys(tree.query_index("color", ..).unwrap()),
+            ["recolor", "kept"]
+        );
+    }
+}
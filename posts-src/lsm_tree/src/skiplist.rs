@@ -0,0 +1,526 @@
+use std::{
+    alloc::{self, Layout},
+    cmp::Ordering as CmpOrdering,
+    fmt,
+    mem::{align_of, size_of},
+    ops::Bound,
+    ptr, slice,
+    sync::{
+        atomic::{AtomicPtr, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use crate::memtable::{MemtableEntry, MemtableRep};
+
+const MAX_HEIGHT: usize = 12;
+// each level links about 1 in `BRANCHING` of the nodes on the level below
+const BRANCHING: u64 = 4;
+// `Node::value_length` of a delete
+const DELETED: u32 = u32::MAX;
+
+/// A skiplist node, allocated in the arena as this header followed by
+/// `height` links and then the key and value bytes. Nothing but the links
+/// changes once it's been linked in.
+#[repr(C)]
+struct Node {
+    sequence: u64,
+    key_length: u32,
+    value_length: u32,
+    height: u32,
+    links: [AtomicPtr<Node>; 0],
+}
+
+impl Node {
+    /// Allocate and fill in a node with unset links
+    fn create(
+        arena: &mut Arena,
+        key: &[u8],
+        sequence: u64,
+        value: Option<&[u8]>,
+        height: usize,
+    ) -> *mut Node {
+        let value_bytes = value.unwrap_or_default();
+        let size = size_of::<Node>()
+            + height * size_of::<AtomicPtr<Node>>()
+            + key.len()
+            + value_bytes.len();
+        let node = arena.allocate(size).cast::<Node>();
+        // SAFETY: the allocation is aligned for a `Node` and big enough for
+        // the header, the links and the data after them
+        unsafe {
+            node.write(Node {
+                sequence,
+                key_length: key.len() as u32,
+                value_length: value.map_or(DELETED, |value| value.len() as u32),
+                height: height as u32,
+                links: [],
+            });
+            for level in 0..height {
+                Self::link(node, level).write(AtomicPtr::new(ptr::null_mut()));
+            }
+            let data = Self::data(node);
+            ptr::copy_nonoverlapping(key.as_ptr(), data, key.len());
+            ptr::copy_nonoverlapping(value_bytes.as_ptr(), data.add(key.len()), value_bytes.len());
+        }
+        node
+    }
+
+    /// # Safety
+    /// `node` must come from `Node::create` and `level` be below its height
+    unsafe fn link(node: *const Node, level: usize) -> *mut AtomicPtr<Node> {
+        unsafe {
+            ptr::addr_of!((*node).links)
+                .cast::<AtomicPtr<Node>>()
+                .cast_mut()
+                .add(level)
+        }
+    }
+
+    /// Next node on `level`, null at the end of the list
+    ///
+    /// # Safety
+    /// As for `Node::link`
+    unsafe fn next(node: *const Node, level: usize) -> *mut Node {
+        unsafe { (*Self::link(node, level)).load(Ordering::Acquire) }
+    }
+
+    unsafe fn data(node: *const Node) -> *mut u8 {
+        unsafe { Self::link(node, (*node).height as usize).cast::<u8>() }
+    }
+
+    /// # Safety
+    /// `node` must come from `Node::create`, and the returned slice may not
+    /// outlive its arena
+    unsafe fn key<'a>(node: *const Node) -> &'a [u8] {
+        unsafe { slice::from_raw_parts(Self::data(node), (*node).key_length as usize) }
+    }
+
+    /// # Safety
+    /// As for `Node::key`
+    unsafe fn value<'a>(node: *const Node) -> Option<&'a [u8]> {
+        unsafe {
+            match (*node).value_length {
+                DELETED => None,
+                length => Some(slice::from_raw_parts(
+                    Self::data(node).add((*node).key_length as usize),
+                    length as usize,
+                )),
+            }
+        }
+    }
+
+    /// Keys ascending, then sequences descending, so the versions of a key
+    /// are newest first
+    ///
+    /// # Safety
+    /// As for `Node::key`
+    unsafe fn compare(node: *const Node, key: &[u8], sequence: u64) -> CmpOrdering {
+        unsafe {
+            Self::key(node)
+                .cmp(key)
+                .then_with(|| sequence.cmp(&(*node).sequence))
+        }
+    }
+}
+
+/// Bump allocator for the nodes: everything it hands out stays put until
+/// the whole arena is dropped, which is what lets readers follow links
+/// without any locking.
+struct Arena {
+    // free space left in the current block
+    next: *mut u8,
+    remaining: usize,
+    blocks: Vec<(*mut u8, Layout)>,
+    // total size of the blocks
+    allocated: usize,
+}
+
+impl Arena {
+    const BLOCK_SIZE: usize = 4096;
+    const ALIGN: usize = align_of::<Node>();
+
+    fn new() -> Self {
+        Self {
+            next: ptr::null_mut(),
+            remaining: 0,
+            blocks: Vec::new(),
+            allocated: 0,
+        }
+    }
+
+    fn allocate(&mut self, size: usize) -> *mut u8 {
+        // keep every allocation aligned for the next node header
+        let size = size.next_multiple_of(Self::ALIGN);
+        if size > self.remaining {
+            // large nodes get a block of their own rather than wasting the
+            // rest of the current one
+            if size > Self::BLOCK_SIZE / 4 {
+                return self.allocate_block(size);
+            }
+            self.next = self.allocate_block(Self::BLOCK_SIZE);
+            self.remaining = Self::BLOCK_SIZE;
+        }
+        let allocation = self.next;
+        // SAFETY: still within the current block
+        self.next = unsafe { self.next.add(size) };
+        self.remaining -= size;
+        allocation
+    }
+
+    fn allocate_block(&mut self, size: usize) -> *mut u8 {
+        let layout = Layout::from_size_align(size, Self::ALIGN).unwrap();
+        // SAFETY: `size` is never 0, there's always at least a node header
+        let block = unsafe { alloc::alloc(layout) };
+        if block.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        self.blocks.push((block, layout));
+        self.allocated += size;
+        block
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        for &(block, layout) in &self.blocks {
+            // SAFETY: allocated with this layout in `allocate_block`
+            unsafe { alloc::dealloc(block, layout) };
+        }
+    }
+}
+
+/// State only the (single) writer touches
+struct Writer {
+    arena: Arena,
+    // xorshift state for node heights
+    random: u64,
+}
+
+impl Writer {
+    fn random_height(&mut self) -> usize {
+        let mut height = 1;
+        while height < MAX_HEIGHT && self.next_random().is_multiple_of(BRANCHING) {
+            height += 1;
+        }
+        height
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        self.random
+    }
+}
+
+/// Arena-backed concurrent skiplist memtable.
+///
+/// Inserts take a mutex (the WAL lock already serializes writers, so it's
+/// never contended) while reads take no lock at all: a node is fully
+/// written before the release stores that link it in, and nodes are never
+/// moved or freed until the list is dropped. Readers racing an insert
+/// either see the new node or not, and filter it by sequence anyway.
+pub(crate) struct SkipListRep {
+    head: *mut Node,
+    // levels in use, only ever grows
+    height: AtomicUsize,
+    writer: Mutex<Writer>,
+}
+
+// SAFETY: the raw pointers all point into the arena owned by the list, and
+// shared access only ever goes through the atomic links
+unsafe impl Send for SkipListRep {}
+unsafe impl Sync for SkipListRep {}
+
+impl SkipListRep {
+    pub(crate) fn new() -> Self {
+        let mut arena = Arena::new();
+        let head = Node::create(&mut arena, &[], 0, None, MAX_HEIGHT);
+        Self {
+            head,
+            height: AtomicUsize::new(1),
+            writer: Mutex::new(Writer {
+                arena,
+                random: 0x2545_f491_4f6c_dd1d,
+            }),
+        }
+    }
+
+    /// First node at or after `(key, sequence)`, filling in `previous` with
+    /// the last node before it on every level
+    fn find_greater_or_equal(
+        &self,
+        key: &[u8],
+        sequence: u64,
+        mut previous: Option<&mut [*mut Node; MAX_HEIGHT]>,
+    ) -> *mut Node {
+        let mut node = self.head;
+        let mut level = self.height.load(Ordering::Relaxed) - 1;
+        loop {
+            // SAFETY: every node reachable from `head` was made by
+            // `Node::create` in our arena, and is tall enough for `level`
+            let next = unsafe { Node::next(node, level) };
+            if !next.is_null() && unsafe { Node::compare(next, key, sequence) }.is_lt() {
+                node = next;
+            } else {
+                if let Some(previous) = previous.as_deref_mut() {
+                    previous[level] = node;
+                }
+                if level == 0 {
+                    return next;
+                }
+                level -= 1;
+            }
+        }
+    }
+
+    /// Last node whose key is within `end`, or `head` if there is none
+    fn find_last_within(&self, end: Bound<&[u8]>) -> *mut Node {
+        let within = |key: &[u8]| match end {
+            Bound::Included(end) => key <= end,
+            Bound::Excluded(end) => key < end,
+            Bound::Unbounded => true,
+        };
+        let mut node = self.head;
+        let mut level = self.height.load(Ordering::Relaxed) - 1;
+        loop {
+            // SAFETY: as in `find_greater_or_equal`
+            let next = unsafe { Node::next(node, level) };
+            if !next.is_null() && within(unsafe { Node::key(next) }) {
+                node = next;
+            } else if level == 0 {
+                return node;
+            } else {
+                level -= 1;
+            }
+        }
+    }
+
+    fn entry(&self, node: *const Node) -> MemtableEntry {
+        // SAFETY: `node` is in our arena, which outlives the copies made here
+        unsafe {
+            MemtableEntry {
+                key: Node::key(node).to_vec(),
+                sequence: (*node).sequence,
+                value: Node::value(node).map(|value| value.to_vec()),
+            }
+        }
+    }
+}
+
+impl MemtableRep for SkipListRep {
+    fn insert(&self, key: &[u8], sequence: u64, value: Option<&[u8]>) {
+        let mut writer = self.writer.lock().unwrap();
+
+        let mut previous = [self.head; MAX_HEIGHT];
+        let next = self.find_greater_or_equal(key, sequence, Some(&mut previous));
+        debug_assert!(next.is_null() || unsafe { Node::compare(next, key, sequence) }.is_gt());
+
+        let height = writer.random_height();
+        // levels above the current height are still linked from `head`,
+        // and readers seeing the new height early just find them empty
+        if height > self.height.load(Ordering::Relaxed) {
+            self.height.store(height, Ordering::Relaxed);
+        }
+
+        let node = Node::create(&mut writer.arena, key, sequence, value, height);
+        for (level, &previous) in previous.iter().enumerate().take(height) {
+            // SAFETY: `node` is not reachable yet, and `previous` is tall
+            // enough for `level` (it was found on that level)
+            unsafe {
+                let next = (*Node::link(previous, level)).load(Ordering::Relaxed);
+                (*Node::link(node, level)).store(next, Ordering::Relaxed);
+                // publishes the node, with everything written above
+                (*Node::link(previous, level)).store(node, Ordering::Release);
+            }
+        }
+    }
+
+    fn get(&self, key: &[u8], snapshot: u64) -> Option<(u64, Option<Vec<u8>>)> {
+        let node = self.find_greater_or_equal(key, snapshot, None);
+        // SAFETY: as in `find_greater_or_equal`
+        if node.is_null() || unsafe { Node::key(node) } != key {
+            return None;
+        }
+        let entry = self.entry(node);
+        Some((entry.sequence, entry.value))
+    }
+
+    fn seek(&self, start: Bound<&[u8]>, snapshot: u64) -> Option<MemtableEntry> {
+        let mut node = match start {
+            Bound::Included(key) => self.find_greater_or_equal(key, u64::MAX, None),
+            // sequences start at 1, so this is past every version of `key`
+            Bound::Excluded(key) => self.find_greater_or_equal(key, 0, None),
+            // SAFETY: `head` is a full height node
+            Bound::Unbounded => unsafe { Node::next(self.head, 0) },
+        };
+        // versions are newest first, so the first one old enough is the one
+        while !node.is_null() {
+            // SAFETY: as in `find_greater_or_equal`
+            if unsafe { (*node).sequence } <= snapshot {
+                return Some(self.entry(node));
+            }
+            node = unsafe { Node::next(node, 0) };
+        }
+        None
+    }
+
+    fn seek_for_prev(&self, end: Bound<&[u8]>, snapshot: u64) -> Option<MemtableEntry> {
+        let mut end = end;
+        loop {
+            let last = self.find_last_within(end);
+            if last == self.head {
+                return None;
+            }
+            // `last` is the oldest version of its key, so look the key up
+            // again for the newest one the snapshot can see
+            // SAFETY: as in `find_greater_or_equal`, and the key lives as
+            // long as `self`
+            let key = unsafe { Node::key(last) };
+            let node = self.find_greater_or_equal(key, snapshot, None);
+            if !node.is_null() && unsafe { Node::key(node) } == key {
+                return Some(self.entry(node));
+            }
+            end = Bound::Excluded(key);
+        }
+    }
+}
+
+impl fmt::Debug for SkipListRep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SkipListRep")
+            .field("height", &self.height.load(Ordering::Relaxed))
+            .field("arena_bytes", &self.writer.lock().unwrap().arena.allocated)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicU64, Arc};
+
+    use super::*;
+    use crate::memtable::MemtableKind;
+
+    fn entry(key: &[u8], sequence: u64, value: Option<&[u8]>) -> Option<MemtableEntry> {
+        Some(MemtableEntry {
+            key: key.to_vec(),
+            sequence,
+            value: value.map(|value| value.to_vec()),
+        })
+    }
+
+    #[test]
+    fn versions_are_found_by_snapshot() {
+        let list = SkipListRep::new();
+        list.insert(b"b", 1, Some(b"b1"));
+        list.insert(b"a", 2, Some(b"a2"));
+        list.insert(b"b", 3, None);
+        list.insert(b"c", 4, Some(b"c4"));
+        list.insert(b"b", 5, Some(b""));
+
+        assert_eq!(list.get(b"b", 0), None);
+        assert_eq!(list.get(b"b", 1), Some((1, Some(b"b1".to_vec()))));
+        assert_eq!(list.get(b"b", 4), Some((3, None)));
+        assert_eq!(list.get(b"b", u64::MAX), Some((5, Some(Vec::new()))));
+        assert_eq!(list.get(b"c", 3), None);
+        assert_eq!(list.get(b"bb", u64::MAX), None);
+
+        assert_eq!(list.seek(Bound::Unbounded, 1), entry(b"b", 1, Some(b"b1")));
+        assert_eq!(list.seek(Bound::Included(b"b"), 3), entry(b"b", 3, None));
+        assert_eq!(list.seek(Bound::Excluded(b"b"), 3), None);
+        assert_eq!(
+            list.seek(Bound::Excluded(b"b"), 4),
+            entry(b"c", 4, Some(b"c4"))
+        );
+        assert_eq!(
+            list.seek(Bound::Included(b"a0"), 2),
+            entry(b"b", 1, Some(b"b1"))
+        );
+
+        assert_eq!(
+            list.seek_for_prev(Bound::Unbounded, 3),
+            entry(b"b", 3, None)
+        );
+        assert_eq!(
+            list.seek_for_prev(Bound::Included(b"b"), 2),
+            entry(b"b", 1, Some(b"b1"))
+        );
+        assert_eq!(
+            list.seek_for_prev(Bound::Excluded(b"b"), 5),
+            entry(b"a", 2, Some(b"a2"))
+        );
+        assert_eq!(list.seek_for_prev(Bound::Excluded(b"b"), 1), None);
+        assert_eq!(list.seek_for_prev(Bound::Unbounded, 0), None);
+    }
+
+    #[test]
+    fn matches_the_btree_map_rep() {
+        let list = SkipListRep::new();
+        let map = MemtableKind::BTreeMap.new_rep();
+        let mut random = crate::xorshift(77u64);
+        let key = |i: u64| format!("key{:03}", i).into_bytes();
+
+        for sequence in 1..=5000 {
+            let key = key(random() % 500);
+            let value = (!random().is_multiple_of(5)).then(|| random().to_le_bytes());
+            list.insert(&key, sequence, value.as_ref().map(|value| &value[..]));
+            map.insert(&key, sequence, value.as_ref().map(|value| &value[..]));
+        }
+
+        for _ in 0..5000 {
+            let key = key(random() % 510);
+            let snapshot = random() % 5100;
+            assert_eq!(list.get(&key, snapshot), map.get(&key, snapshot));
+            for bound in [
+                Bound::Included(key.as_slice()),
+                Bound::Excluded(key.as_slice()),
+                Bound::Unbounded,
+            ] {
+                assert_eq!(list.seek(bound, snapshot), map.seek(bound, snapshot));
+                assert_eq!(
+                    list.seek_for_prev(bound, snapshot),
+                    map.seek_for_prev(bound, snapshot)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn reads_run_alongside_inserts() {
+        let list = Arc::new(SkipListRep::new());
+        let published = Arc::new(AtomicU64::new(0));
+        let key = |i: u64| format!("{:08}", i).into_bytes();
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let list = list.clone();
+                let published = published.clone();
+                std::thread::spawn(move || loop {
+                    let snapshot = published.load(Ordering::Acquire);
+                    if snapshot == 20_000 {
+                        break;
+                    }
+                    // newer nodes may be linked in already, but are skipped
+                    let last = list.seek_for_prev(Bound::Unbounded, snapshot);
+                    assert_eq!(
+                        last.map(|entry| entry.key),
+                        (snapshot > 0).then(|| key(snapshot))
+                    );
+                    if snapshot > 0 {
+                        assert!(list.get(&key(snapshot / 2 + 1), snapshot).is_some());
+                    }
+                })
+            })
+            .collect();
+
+        for sequence in 1..=20_000 {
+            list.insert(&key(sequence), sequence, Some(b"value"));
+            published.store(sequence, Ordering::Release);
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+}
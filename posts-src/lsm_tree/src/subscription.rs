@@ -0,0 +1,279 @@
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use anyhow::Result;
+
+use crate::{secondary_index, write_log, BatchOp, TreeInner};
+
+/// One operation of a write, see `LsmTree::subscribe`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    /// Sequence of the write batch it was part of, which its other
+    /// operations share
+    pub sequence: u64,
+    pub op: BatchOp,
+}
+
+/// Stream of a tree's writes from `LsmTree::subscribe`.
+///
+/// As an `Iterator` it blocks until the next write, and ends when the tree
+/// is dropped or after an error. `next_timeout` waits for a while at most.
+#[derive(Debug)]
+pub struct Subscription {
+    inner: Arc<TreeInner>,
+    // sequence of the next write to read
+    next_sequence: u64,
+    // events of writes already read, not yet returned
+    pending: VecDeque<ChangeEvent>,
+    done: bool,
+}
+
+impl Subscription {
+    // writes read per trip to the write log
+    const MAX_WRITES_PER_READ: usize = 256;
+    // how often a blocked `next` checks whether the tree is gone
+    const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub(crate) fn new(inner: Arc<TreeInner>, from_sequence: u64) -> Self {
+        Self {
+            inner,
+            // sequences start at 1
+            next_sequence: from_sequence.max(1),
+            pending: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Sequence of the next write to be read from the tree (events of
+    /// earlier ones may still be buffered)
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+
+    /// The next event, waiting up to `timeout` for one. `None` if there
+    /// wasn't one in time or the stream has ended.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<ChangeEvent>> {
+        if self.pending.is_empty() && !self.done {
+            self.read(timeout)?;
+        }
+        Ok(self.pending.pop_front())
+    }
+
+    fn read(&mut self, timeout: Duration) -> Result<()> {
+        let writes = match write_log::records_since(
+            &self.inner,
+            self.next_sequence,
+            Self::MAX_WRITES_PER_READ,
+            timeout,
+        ) {
+            Ok(writes) => writes,
+            Err(e) => {
+                self.done = true;
+                return Err(e);
+            }
+        };
+        if writes.is_empty() && self.inner.write_log.is_closed() {
+            self.done = true;
+        }
+
+        for (sequence, batch) in writes {
+            // secondary index entries are the tree's own business, and range
+            // deletions are cut short of them (writes from before the indexes
+            // were added may reach past it)
+            let ops = batch.iter().filter_map(|op| {
+                let reserved = |key| secondary_index::is_reserved(&self.inner, key);
+                match op {
+                    BatchOp::Put(key, _) | BatchOp::Delete(key) if reserved(key) => None,
+                    BatchOp::DeleteRange(start, _) if reserved(start) => None,
+                    BatchOp::DeleteRange(start, end) if reserved(end) => {
+                        Some(BatchOp::DeleteRange(
+                            start.clone(),
+                            secondary_index::INDEX_KEY_PREFIX.to_vec(),
+                        ))
+                    }
+                    op => Some(op.clone()),
+                }
+            });
+            self.pending
+                .extend(ops.map(|op| ChangeEvent { sequence, op }));
+            self.next_sequence = sequence + 1;
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Result<ChangeEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event));
+            }
+            if self.done {
+                return None;
+            }
+            if let Err(e) = self.read(Self::POLL_INTERVAL) {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use super::*;
+    use crate::{IndexExtractor, LsmError, LsmTree, Options, WriteBatch};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn put(key: &str, value: &str) -> BatchOp {
+        BatchOp::Put(key.as_bytes().to_vec(), value.as_bytes().to_vec())
+    }
+
+    fn sequences(subscription: &mut Subscription, count: usize) -> Vec<u64> {
+        subscription
+            .take(count)
+            .map(|event| event.unwrap().sequence)
+            .collect()
+    }
+
+    #[test]
+    fn streams_writes_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        let mut live = tree.subscribe(tree.last_sequence() + 1);
+        assert!(live
+            .next_timeout(Duration::from_millis(10))
+            .unwrap()
+            .is_none());
+
+        tree.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"b".to_vec(), b"2".to_vec());
+        batch.delete(b"a".to_vec());
+        batch.delete_range(b"c".to_vec(), b"d".to_vec());
+        tree.write(batch).unwrap();
+
+        let events: Vec<_> = live.by_ref().take(4).map(|event| event.unwrap()).collect();
+        let expected = [
+            (1, put("a", "1")),
+            (2, put("b", "2")),
+            (2, BatchOp::Delete(b"a".to_vec())),
+            (2, BatchOp::DeleteRange(b"c".to_vec(), b"d".to_vec())),
+        ];
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(sequence, op)| ChangeEvent { sequence, op })
+            .collect();
+        assert_eq!(events, expected);
+        assert_eq!(live.next_sequence(), 3);
+
+        // a blocked subscriber in another thread, ended by dropping the tree
+        let waiting = std::thread::spawn(move || live.collect::<Result<Vec<_>>>().unwrap().len());
+        tree.put(b"e".to_vec(), b"5".to_vec()).unwrap();
+        drop(tree);
+        assert_eq!(waiting.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn late_subscribers_catch_up_from_the_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        // far too small for the writes below, so the oldest are only in the WAL
+        let options = Options {
+            write_log_size: 1024,
+            ..Options::default()
+        };
+        let tree = LsmTree::open_with_options(dir.path(), options).unwrap();
+        for i in 0..300 {
+            tree.put(format!("key{:03}", i).into_bytes(), vec![0; 100])
+                .unwrap();
+        }
+
+        let mut subscription = tree.subscribe(1);
+        assert_eq!(
+            sequences(&mut subscription, 300),
+            (1..=300).collect::<Vec<_>>()
+        );
+        // and then on to live writes
+        tree.put(b"live".to_vec(), Vec::new()).unwrap();
+        let event = subscription.next_timeout(TIMEOUT).unwrap().unwrap();
+        assert_eq!(
+            event,
+            ChangeEvent {
+                sequence: 301,
+                op: put("live", "")
+            }
+        );
+        let mut from_the_middle = tree.subscribe(150);
+        assert_eq!(
+            sequences(&mut from_the_middle, 152),
+            (150..=301).collect::<Vec<_>>()
+        );
+
+        // flushed away, so gone
+        tree.flush().unwrap();
+        let mut gone = tree.subscribe(1);
+        let err = gone.next().unwrap().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(LsmError::SequenceUnavailable(1))
+        ));
+        assert!(gone.next().is_none());
+    }
+
+    #[derive(Debug)]
+    struct WholeValue;
+
+    impl IndexExtractor for WholeValue {
+        fn index_keys(&self, value: &[u8]) -> Vec<Vec<u8>> {
+            vec![value.to_vec()]
+        }
+    }
+
+    #[test]
+    fn index_entries_are_left_out() {
+        let dir = tempfile::tempdir().unwrap();
+        // written before there were any indexes, reaching into their keyspace
+        let tree = LsmTree::open(dir.path()).unwrap();
+        tree.delete_range(b"a".to_vec(), b"\xff\xff\xff\xff\xff".to_vec())
+            .unwrap();
+        drop(tree);
+
+        let options = Options {
+            secondary_indexes: BTreeMap::from([(
+                "value".to_string(),
+                Arc::new(WholeValue) as Arc<dyn IndexExtractor>,
+            )]),
+            ..Options::default()
+        };
+        let tree = LsmTree::open_with_options(dir.path(), options).unwrap();
+        tree.put(b"key".to_vec(), b"indexed".to_vec()).unwrap();
+
+        let events: Vec<_> = tree
+            .subscribe(1)
+            .take(2)
+            .map(|event| event.unwrap())
+            .collect();
+        let clipped =
+            BatchOp::DeleteRange(b"a".to_vec(), secondary_index::INDEX_KEY_PREFIX.to_vec());
+        assert_eq!(
+            events[0],
+            ChangeEvent {
+                sequence: 1,
+                op: clipped
+            }
+        );
+        // the batch also held the index entry
+        assert_eq!(
+            events[1],
+            ChangeEvent {
+                sequence: 2,
+                op: put("key", "indexed")
+            }
+        );
+        let mut subscription = tree.subscribe(3);
+        assert!(subscription.next_timeout(Duration::ZERO).unwrap().is_none());
+    }
+}
@@ -0,0 +1,190 @@
+use anyhow::{bail, Result};
+
+use crate::{decode_entries, encode_entry, LsmTree};
+
+/// Summary of an SSTable's contents, written into its properties meta
+/// block and loaded with the rest of its metadata when it's opened.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableProperties {
+    /// Smallest and largest point key (both empty if there are none).
+    /// Range tombstones may reach further, see `SSTable::range_tombstones`.
+    pub smallest_key: Vec<u8>,
+    pub largest_key: Vec<u8>,
+    /// Point entries, tombstones included
+    pub entries: u64,
+    pub tombstones: u64,
+    pub range_tombstones: u64,
+    /// Keys and encoded values in the data blocks, before compression
+    pub raw_bytes: u64,
+    /// Data blocks as written to disk
+    pub encoded_bytes: u64,
+    /// When the table was written, in seconds since the Unix epoch
+    pub created_at: u64,
+    /// Sequence numbers of the writes the table was built from
+    pub smallest_sequence: u64,
+    pub largest_sequence: u64,
+}
+
+impl TableProperties {
+    const SMALLEST_KEY: &'static str = "smallest_key";
+    const LARGEST_KEY: &'static str = "largest_key";
+    const ENTRIES: &'static str = "entries";
+    const TOMBSTONES: &'static str = "tombstones";
+    const RANGE_TOMBSTONES: &'static str = "range_tombstones";
+    const RAW_BYTES: &'static str = "raw_bytes";
+    const ENCODED_BYTES: &'static str = "encoded_bytes";
+    const CREATED_AT: &'static str = "created_at";
+    const SMALLEST_SEQUENCE: &'static str = "smallest_sequence";
+    const LARGEST_SEQUENCE: &'static str = "largest_sequence";
+
+    /// Whether the table has a point entry anywhere in `[start, end]`
+    pub fn overlaps(&self, start: &[u8], end: &[u8]) -> bool {
+        self.entries > 0
+            && self.smallest_key.as_slice() <= end
+            && start <= self.largest_key.as_slice()
+    }
+
+    /// Encode as one `name -> value` record per property, with numbers as `u64`s
+    pub(crate) fn encode_block(&self, buf: &mut Vec<u8>) {
+        encode_entry(buf, Self::SMALLEST_KEY.as_bytes(), &self.smallest_key);
+        encode_entry(buf, Self::LARGEST_KEY.as_bytes(), &self.largest_key);
+        for (name, value) in [
+            (Self::ENTRIES, self.entries),
+            (Self::TOMBSTONES, self.tombstones),
+            (Self::RANGE_TOMBSTONES, self.range_tombstones),
+            (Self::RAW_BYTES, self.raw_bytes),
+            (Self::ENCODED_BYTES, self.encoded_bytes),
+            (Self::CREATED_AT, self.created_at),
+            (Self::SMALLEST_SEQUENCE, self.smallest_sequence),
+            (Self::LARGEST_SEQUENCE, self.largest_sequence),
+        ] {
+            encode_entry(buf, name.as_bytes(), &value.to_le_bytes());
+        }
+    }
+
+    /// Unknown properties are skipped, they may come from a newer version
+    pub(crate) fn decode_block(block: &[u8]) -> Result<Self> {
+        let mut properties = Self::default();
+        for (name, value) in decode_entries(block, LsmTree::MAX_ENTRY_SIZE)? {
+            let Ok(name) = std::str::from_utf8(&name) else {
+                continue;
+            };
+            let number = match name {
+                Self::SMALLEST_KEY => {
+                    properties.smallest_key = value;
+                    continue;
+                }
+                Self::LARGEST_KEY => {
+                    properties.largest_key = value;
+                    continue;
+                }
+                Self::ENTRIES => &mut properties.entries,
+                Self::TOMBSTONES => &mut properties.tombstones,
+                Self::RANGE_TOMBSTONES => &mut properties.range_tombstones,
+                Self::RAW_BYTES => &mut properties.raw_bytes,
+                Self::ENCODED_BYTES => &mut properties.encoded_bytes,
+                Self::CREATED_AT => &mut properties.created_at,
+                Self::SMALLEST_SEQUENCE => &mut properties.smallest_sequence,
+                Self::LARGEST_SEQUENCE => &mut properties.largest_sequence,
+                _ => continue,
+            };
+            let Ok(bytes) = <[u8; 8]>::try_from(value.as_slice()) else {
+                bail!("Invalid table property {}", name);
+            };
+            *number = u64::from_le_bytes(bytes);
+        }
+        Ok(properties)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Options;
+
+    #[test]
+    fn round_trips_and_skips_unknown_properties() {
+        let properties = TableProperties {
+            smallest_key: b"a".to_vec(),
+            largest_key: b"z".to_vec(),
+            entries: 10,
+            tombstones: 2,
+            range_tombstones: 1,
+            raw_bytes: 300,
+            encoded_bytes: 200,
+            created_at: 1_700_000_000,
+            smallest_sequence: 5,
+            largest_sequence: 15,
+        };
+        let mut block = Vec::new();
+        properties.encode_block(&mut block);
+        encode_entry(&mut block, b"from_a_newer_version", b"whatever");
+        assert_eq!(TableProperties::decode_block(&block).unwrap(), properties);
+
+        let mut block = Vec::new();
+        encode_entry(&mut block, TableProperties::ENTRIES.as_bytes(), b"short");
+        assert!(TableProperties::decode_block(&block).is_err());
+    }
+
+    #[test]
+    fn describe_each_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            l0_compaction_trigger: 100,
+            l0_slowdown_writes_trigger: 100,
+            l0_stop_writes_trigger: 100,
+            ..Options::default()
+        };
+        let tree = LsmTree::open_with_options(dir.path(), options.clone()).unwrap();
+        for i in 0..100 {
+            tree.put(format!("a{:03}", i).into_bytes(), vec![b'x'; 50])
+                .unwrap();
+        }
+        tree.delete(b"a050".to_vec()).unwrap();
+        tree.flush().unwrap();
+        for i in 0..100 {
+            tree.put(format!("m{:03}", i).into_bytes(), vec![b'y'; 50])
+                .unwrap();
+        }
+        tree.delete_range(b"z0".to_vec(), b"z9".to_vec()).unwrap();
+        tree.flush().unwrap();
+
+        let tables = tree.sstables();
+        let first = &tables[0].properties;
+        assert_eq!(
+            (first.smallest_key.as_slice(), first.largest_key.as_slice()),
+            (&b"a000"[..], &b"a099"[..])
+        );
+        assert_eq!(
+            (first.entries, first.tombstones, first.range_tombstones),
+            (100, 1, 0)
+        );
+        assert_eq!((first.smallest_sequence, first.largest_sequence), (1, 101));
+        assert!(first.raw_bytes > 99 * 50 && first.encoded_bytes > 0);
+        assert!(first.overlaps(b"a050", b"b") && !first.overlaps(b"b", b"c"));
+        let second = &tables[1].properties;
+        assert_eq!((second.entries, second.range_tombstones), (100, 1));
+        assert_eq!(
+            (second.smallest_sequence, second.largest_sequence),
+            (102, 202)
+        );
+
+        // compaction sums them up, and the sequence carries over restarts
+        // with every WAL flushed away
+        drop(tree);
+        let tree = LsmTree::open_with_options(dir.path(), options).unwrap();
+        tree.put(b"a001".to_vec(), b"new".to_vec()).unwrap();
+        tree.flush().unwrap();
+        assert_eq!(tree.sstables()[2].properties.smallest_sequence, 203);
+        tree.compact_all().unwrap();
+        let tables = tree.sstables();
+        assert_eq!(tables.len(), 1);
+        let compacted = &tables[0].properties;
+        assert_eq!((compacted.entries, compacted.tombstones), (199, 0));
+        assert_eq!(
+            (compacted.smallest_sequence, compacted.largest_sequence),
+            (1, 203)
+        );
+        assert_eq!(tree.get(b"a001").unwrap(), Some(b"new".to_vec()));
+    }
+}
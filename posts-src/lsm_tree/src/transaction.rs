@@ -0,0 +1,344 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Arc,
+};
+
+use anyhow::Result;
+
+use crate::{LsmError, TreeInner, WriteBatch};
+
+/// An optimistic transaction, see `LsmTree::begin_transaction`.
+///
+/// Writes are buffered privately until `commit`, and reads see them on top
+/// of the latest committed data. Nothing is locked: instead `commit` checks
+/// that no key this transaction read has been written since it began, and
+/// fails with `LsmError::TransactionConflict` otherwise. Dropping it without
+/// committing throws the writes away.
+#[derive(Debug)]
+pub struct Transaction {
+    inner: Arc<TreeInner>,
+    // writes with a newer sequence to any key in `reads` are conflicts
+    start_sequence: u64,
+    reads: HashSet<Vec<u8>>,
+    // `None` values are deletes
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(inner: Arc<TreeInner>) -> Self {
+        let start_sequence = inner.last_sequence();
+        Self {
+            inner,
+            start_sequence,
+            reads: HashSet::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Read a key, preferring this transaction's own writes
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+
+        self.reads.insert(key.to_vec());
+        self.inner.get(key)
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.writes.insert(key, None);
+    }
+
+    /// Apply every write as one atomic batch, unless a key that was read
+    /// has changed since the transaction began. On a conflict nothing is
+    /// written and the caller can retry with a new transaction.
+    pub fn commit(self) -> Result<()> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            }
+        }
+
+        let reads = self.reads;
+        let start_sequence = self.start_sequence;
+        self.inner.write(batch, |state| {
+            for key in &reads {
+                if state.changed_since(key, start_sequence) {
+                    return Err(LsmError::TransactionConflict(key.clone()).into());
+                }
+            }
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    /// Throw away the buffered writes (same as dropping the transaction)
+    pub fn rollback(self) {}
+}
+
+/// A pessimistic transaction, see `LsmTree::begin_pessimistic_transaction`.
+///
+/// Keys are locked as they are written (or read with `get_for_update`) and
+/// held until the transaction commits or rolls back, so commits never
+/// conflict. Waiting for a lock is bounded by `Options::lock_timeout`, and a
+/// wait that would deadlock fails right away with `LsmError::Deadlock`;
+/// either way the caller should roll back (drop) and retry.
+///
+/// Locks only exclude other pessimistic transactions: plain `LsmTree::put`
+/// and friends don't take them.
+#[derive(Debug)]
+pub struct PessimisticTransaction {
+    inner: Arc<TreeInner>,
+    id: u64,
+    locked: Vec<Vec<u8>>,
+    // `None` values are deletes
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl PessimisticTransaction {
+    pub(crate) fn new(inner: Arc<TreeInner>) -> Self {
+        let id = inner.lock_manager.new_transaction_id();
+        Self {
+            inner,
+            id,
+            locked: Vec::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    fn lock(&mut self, key: &[u8]) -> Result<()> {
+        let timeout = self.inner.options.lock_timeout;
+        if self.inner.lock_manager.lock(self.id, key, timeout)? {
+            self.locked.push(key.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Read a key without locking it, preferring this transaction's own writes
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        self.inner.get(key)
+    }
+
+    /// Lock a key, then read it. Other pessimistic transactions can't
+    /// change it until this one is done.
+    pub fn get_for_update(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.lock(key)?;
+        self.get(key)
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.lock(&key)?;
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) -> Result<()> {
+        self.lock(&key)?;
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// Apply every write as one atomic batch and release the locks
+    pub fn commit(mut self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in std::mem::take(&mut self.writes) {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            }
+        }
+
+        self.inner.write(batch, |_| Ok(()))?;
+
+        Ok(())
+    }
+
+    /// Throw away the buffered writes and release the locks (same as
+    /// dropping the transaction)
+    pub fn rollback(self) {}
+}
+
+impl Drop for PessimisticTransaction {
+    fn drop(&mut self) {
+        self.inner
+            .lock_manager
+            .unlock_all(self.id, self.locked.iter().map(|key| key.as_slice()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{LsmError, LsmTree, Options};
+
+    fn is_conflict(e: &anyhow::Error) -> bool {
+        matches!(e.downcast_ref(), Some(LsmError::TransactionConflict(_)))
+    }
+
+    fn read_counter(value: Option<Vec<u8>>) -> u64 {
+        u64::from_le_bytes(value.unwrap().try_into().unwrap())
+    }
+
+    #[test]
+    fn concurrent_writes_to_a_read_key_conflict() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        tree.put(b"key".to_vec(), b"0".to_vec()).unwrap();
+
+        let mut first = tree.begin_transaction();
+        let mut second = tree.begin_transaction();
+        first.get(b"key").unwrap();
+        second.get(b"key").unwrap();
+        first.put(b"key".to_vec(), b"1".to_vec());
+        second.put(b"key".to_vec(), b"2".to_vec());
+        first.commit().unwrap();
+        assert!(is_conflict(&second.commit().unwrap_err()));
+        assert_eq!(tree.get(b"key").unwrap(), Some(b"1".to_vec()));
+
+        // also when the write has been flushed since
+        let mut transaction = tree.begin_transaction();
+        transaction.get(b"key").unwrap();
+        tree.put(b"key".to_vec(), b"3".to_vec()).unwrap();
+        tree.flush().unwrap();
+        transaction.put(b"other".to_vec(), b"x".to_vec());
+        assert!(is_conflict(&transaction.commit().unwrap_err()));
+        assert_eq!(tree.get(b"other").unwrap(), None);
+    }
+
+    #[test]
+    fn writes_elsewhere_dont_conflict() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+
+        let mut transaction = tree.begin_transaction();
+        assert_eq!(transaction.get(b"read").unwrap(), None);
+        tree.put(b"unrelated".to_vec(), b"x".to_vec()).unwrap();
+        // keys only written (blind writes) aren't checked
+        tree.put(b"written".to_vec(), b"theirs".to_vec()).unwrap();
+        transaction.put(b"written".to_vec(), b"ours".to_vec());
+        transaction.commit().unwrap();
+        assert_eq!(tree.get(b"written").unwrap(), Some(b"ours".to_vec()));
+    }
+
+    #[test]
+    fn reads_see_own_writes_until_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        tree.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+
+        let mut transaction = tree.begin_transaction();
+        transaction.delete(b"a".to_vec());
+        transaction.put(b"b".to_vec(), b"2".to_vec());
+        assert_eq!(transaction.get(b"a").unwrap(), None);
+        assert_eq!(transaction.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(tree.get(b"b").unwrap(), None);
+        transaction.rollback();
+        assert_eq!(tree.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(tree.get(b"b").unwrap(), None);
+
+        let mut transaction = tree.begin_transaction();
+        transaction.delete(b"a".to_vec());
+        transaction.put(b"b".to_vec(), b"2".to_vec());
+        transaction.commit().unwrap();
+        assert_eq!(tree.get(b"a").unwrap(), None);
+        assert_eq!(tree.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn retried_increments_add_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = LsmTree::open(dir.path()).unwrap();
+        tree.put(b"counter".to_vec(), 0u64.to_le_bytes().to_vec())
+            .unwrap();
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        loop {
+                            let mut transaction = tree.begin_transaction();
+                            let counter = read_counter(transaction.get(b"counter").unwrap());
+                            let value = (counter + 1).to_le_bytes().to_vec();
+                            transaction.put(b"counter".to_vec(), value);
+                            match transaction.commit() {
+                                Ok(()) => break,
+                                Err(e) if is_conflict(&e) => {}
+                                Err(e) => panic!("{:#}", e),
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        assert_eq!(read_counter(tree.get(b"counter").unwrap()), 400);
+    }
+
+    #[test]
+    fn pessimistic_increments_never_conflict() {
+        let dir = tempfile::tempdir().unwrap();
+        // waits are short, but a loaded machine shouldn't time them out
+        let options = Options {
+            lock_timeout: Duration::from_secs(30),
+            ..Options::default()
+        };
+        let tree = LsmTree::open_with_options(dir.path(), options).unwrap();
+        tree.put(b"counter".to_vec(), 0u64.to_le_bytes().to_vec())
+            .unwrap();
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        let mut transaction = tree.begin_pessimistic_transaction();
+                        let counter = read_counter(transaction.get_for_update(b"counter").unwrap());
+                        let value = (counter + 1).to_le_bytes().to_vec();
+                        transaction.put(b"counter".to_vec(), value).unwrap();
+                        transaction.commit().unwrap();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(read_counter(tree.get(b"counter").unwrap()), 400);
+    }
+
+    #[test]
+    fn pessimistic_locks_are_held_until_done() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            lock_timeout: Duration::from_millis(20),
+            ..Options::default()
+        };
+        let tree = LsmTree::open_with_options(dir.path(), options).unwrap();
+
+        let mut holder = tree.begin_pessimistic_transaction();
+        holder.put(b"key".to_vec(), b"holder".to_vec()).unwrap();
+        let mut other = tree.begin_pessimistic_transaction();
+        let e = other.put(b"key".to_vec(), b"other".to_vec()).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(LsmError::LockTimeout(_))));
+        // plain writes don't take locks
+        tree.put(b"key".to_vec(), b"plain".to_vec()).unwrap();
+
+        holder.rollback();
+        assert_eq!(tree.get(b"key").unwrap(), Some(b"plain".to_vec()));
+        other.put(b"key".to_vec(), b"other".to_vec()).unwrap();
+        other.commit().unwrap();
+        assert_eq!(tree.get(b"key").unwrap(), Some(b"other".to_vec()));
+    }
+}
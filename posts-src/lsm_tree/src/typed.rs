@@ -0,0 +1,525 @@
+use std::{
+    fmt,
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::{Cursor, LsmTree, Options};
+
+/// Turns `T`s into bytes for a `TypedTree` and back
+pub trait Codec<T> {
+    fn encode(value: &T, buf: &mut Vec<u8>) -> Result<()>;
+    fn decode(bytes: &[u8]) -> Result<T>;
+}
+
+/// A byte encoding that sorts the same way the values do, so that range
+/// scans over a `TypedTree` with `OrderedKey`s come out in order. Each
+/// value knows where it ends, which lets tuples encode one field after
+/// the other and still sort field by field.
+pub trait KeyEncoding: Sized {
+    fn encode_key(&self, buf: &mut Vec<u8>);
+    /// Decode a value off the front of `bytes`, leaving the rest
+    fn decode_key(bytes: &mut &[u8]) -> Result<Self>;
+}
+
+/// Keys in their `KeyEncoding`, for integers, strings, byte strings and
+/// tuples of those
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OrderedKey;
+
+impl<T: KeyEncoding> Codec<T> for OrderedKey {
+    fn encode(value: &T, buf: &mut Vec<u8>) -> Result<()> {
+        value.encode_key(buf);
+        Ok(())
+    }
+
+    fn decode(mut bytes: &[u8]) -> Result<T> {
+        let value = T::decode_key(&mut bytes)?;
+        if !bytes.is_empty() {
+            bail!("Key has {} bytes left over after decoding", bytes.len());
+        }
+        Ok(value)
+    }
+}
+
+/// Byte strings as they are, and strings as UTF-8. Only order-preserving
+/// on its own, not as part of a larger key.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Raw;
+
+impl Codec<Vec<u8>> for Raw {
+    fn encode(value: &Vec<u8>, buf: &mut Vec<u8>) -> Result<()> {
+        buf.extend_from_slice(value);
+        Ok(())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl Codec<String> for Raw {
+    fn encode(value: &String, buf: &mut Vec<u8>) -> Result<()> {
+        buf.extend_from_slice(value.as_bytes());
+        Ok(())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<String> {
+        String::from_utf8(bytes.to_vec()).context("Invalid UTF-8")
+    }
+}
+
+/// Any serde type, as JSON
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> Codec<T> for Json {
+    fn encode(value: &T, buf: &mut Vec<u8>) -> Result<()> {
+        serde_json::to_writer(buf, value).context("Failed to encode value as JSON")
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T> {
+        serde_json::from_slice(bytes).context("Failed to decode JSON value")
+    }
+}
+
+fn take<'a>(bytes: &mut &'a [u8], length: usize) -> Result<&'a [u8]> {
+    if bytes.len() < length {
+        bail!("Key is truncated");
+    }
+    let (taken, rest) = bytes.split_at(length);
+    *bytes = rest;
+    Ok(taken)
+}
+
+// big endian, which sorts like the numbers
+macro_rules! unsigned_key_encoding {
+    ($($t:ty),*) => {$(
+        impl KeyEncoding for $t {
+            fn encode_key(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn decode_key(bytes: &mut &[u8]) -> Result<Self> {
+                let taken = take(bytes, std::mem::size_of::<$t>())?;
+                Ok(<$t>::from_be_bytes(taken.try_into()?))
+            }
+        }
+    )*};
+}
+
+// big endian with the sign bit flipped, so negative numbers come first
+macro_rules! signed_key_encoding {
+    ($($t:ty => $u:ty),*) => {$(
+        impl KeyEncoding for $t {
+            fn encode_key(&self, buf: &mut Vec<u8>) {
+                ((*self as $u) ^ (1 << (<$u>::BITS - 1))).encode_key(buf);
+            }
+
+            fn decode_key(bytes: &mut &[u8]) -> Result<Self> {
+                Ok((<$u>::decode_key(bytes)? ^ (1 << (<$u>::BITS - 1))) as $t)
+            }
+        }
+    )*};
+}
+
+unsigned_key_encoding!(u8, u16, u32, u64, u128);
+signed_key_encoding!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+impl KeyEncoding for bool {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+
+    fn decode_key(bytes: &mut &[u8]) -> Result<Self> {
+        match take(bytes, 1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte => bail!("Invalid bool {} in key", byte),
+        }
+    }
+}
+
+// `<bytes, with each 0x00 escaped as 0x00 0xff><0x00 0x00>`: the terminator
+// sorts below any byte a longer string could have in its place
+pub(crate) fn encode_bytes_key(bytes: &[u8], buf: &mut Vec<u8>) {
+    for &byte in bytes {
+        buf.push(byte);
+        if byte == 0 {
+            buf.push(0xff);
+        }
+    }
+    buf.extend_from_slice(&[0, 0]);
+}
+
+impl KeyEncoding for Vec<u8> {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        encode_bytes_key(self, buf);
+    }
+
+    fn decode_key(bytes: &mut &[u8]) -> Result<Self> {
+        let mut decoded = Vec::new();
+        loop {
+            match take(bytes, 1)?[0] {
+                0 => match take(bytes, 1)?[0] {
+                    0 => return Ok(decoded),
+                    0xff => decoded.push(0),
+                    byte => bail!("Invalid escape 0x00 0x{:02x} in key", byte),
+                },
+                byte => decoded.push(byte),
+            }
+        }
+    }
+}
+
+impl KeyEncoding for String {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        // UTF-8 bytes sort like the code points
+        encode_bytes_key(self.as_bytes(), buf);
+    }
+
+    fn decode_key(bytes: &mut &[u8]) -> Result<Self> {
+        String::from_utf8(Vec::<u8>::decode_key(bytes)?).context("Invalid UTF-8 in key")
+    }
+}
+
+macro_rules! tuple_key_encoding {
+    ($($name:ident),+) => {
+        impl<$($name: KeyEncoding),+> KeyEncoding for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_key(&self, buf: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_key(buf);)+
+            }
+
+            fn decode_key(bytes: &mut &[u8]) -> Result<Self> {
+                Ok(($($name::decode_key(bytes)?,)+))
+            }
+        }
+    };
+}
+
+tuple_key_encoding!(A);
+tuple_key_encoding!(A, B);
+tuple_key_encoding!(A, B, C);
+tuple_key_encoding!(A, B, C, D);
+tuple_key_encoding!(A, B, C, D, E);
+
+// the types a tree works with, without holding any, so it's `Send` and
+// `Sync` whatever they are
+type Types<K, V, KC, VC> = PhantomData<fn() -> (K, V, KC, VC)>;
+
+/// `LsmTree` with typed keys and values, encoded by the `KC` and `VC`
+/// codecs. Keys default to `OrderedKey`, so ranges of keys scan in order,
+/// and values to `Raw` (see also `Json`, with the `serde` feature).
+pub struct TypedTree<K, V, KC = OrderedKey, VC = Raw> {
+    tree: LsmTree,
+    _types: Types<K, V, KC, VC>,
+}
+
+impl<K, V, KC, VC> fmt::Debug for TypedTree<K, V, KC, VC> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypedTree")
+            .field("tree", &self.tree)
+            .finish()
+    }
+}
+
+impl<K, V, KC: Codec<K>, VC: Codec<V>> TypedTree<K, V, KC, VC> {
+    /// Open (or create) a tree, see `LsmTree::open_with_options`
+    pub fn open(path: &Path, options: Options) -> Result<Self> {
+        Ok(Self::new(LsmTree::open_with_options(path, options)?))
+    }
+
+    /// Wrap an already opened tree, whose keys and values all have to be
+    /// in this tree's encodings
+    pub fn new(tree: LsmTree) -> Self {
+        Self {
+            tree,
+            _types: PhantomData,
+        }
+    }
+
+    /// The untyped tree underneath, e.g. for `flush` and `stats`
+    pub fn tree(&self) -> &LsmTree {
+        &self.tree
+    }
+
+    pub fn into_inner(self) -> LsmTree {
+        self.tree
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.tree.get(&Self::encode_key(key)?)? {
+            Some(value) => Ok(Some(VC::decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put(&self, key: &K, value: &V) -> Result<()> {
+        let mut encoded_value = Vec::new();
+        VC::encode(value, &mut encoded_value)?;
+        self.tree.put(Self::encode_key(key)?, encoded_value)
+    }
+
+    pub fn delete(&self, key: &K) -> Result<()> {
+        self.tree.delete(Self::encode_key(key)?)
+    }
+
+    /// Delete every key in `[start, end)`
+    pub fn delete_range(&self, start: &K, end: &K) -> Result<()> {
+        self.tree
+            .delete_range(Self::encode_key(start)?, Self::encode_key(end)?)
+    }
+
+    /// Every key/value pair in `range`, in the order of the encoded keys
+    /// (which is the keys' own order with `OrderedKey`), as of this call
+    pub fn range(&self, range: impl RangeBounds<K>) -> Result<TypedRange<K, V, KC, VC>> {
+        let encode_bound = |bound: Bound<&K>| -> Result<Bound<Vec<u8>>> {
+            Ok(match bound {
+                Bound::Included(key) => Bound::Included(Self::encode_key(key)?),
+                Bound::Excluded(key) => Bound::Excluded(Self::encode_key(key)?),
+                Bound::Unbounded => Bound::Unbounded,
+            })
+        };
+
+        let mut cursor = self.tree.cursor();
+        match encode_bound(range.start_bound())? {
+            Bound::Included(start) => cursor.seek(&start)?,
+            Bound::Excluded(start) => {
+                cursor.seek(&start)?;
+                if cursor.key() == Some(start.as_slice()) {
+                    cursor.next()?;
+                }
+            }
+            Bound::Unbounded => cursor.seek_to_first()?,
+        }
+
+        Ok(TypedRange {
+            cursor,
+            end: encode_bound(range.end_bound())?,
+            done: false,
+            _types: PhantomData,
+        })
+    }
+
+    /// Every key/value pair, in key order
+    pub fn iter(&self) -> Result<TypedRange<K, V, KC, VC>> {
+        self.range(..)
+    }
+
+    fn encode_key(key: &K) -> Result<Vec<u8>> {
+        let mut encoded_key = Vec::new();
+        KC::encode(key, &mut encoded_key)?;
+        Ok(encoded_key)
+    }
+}
+
+/// Iterator returned by `TypedTree::range`. It ends after an error.
+pub struct TypedRange<K, V, KC, VC> {
+    cursor: Cursor,
+    end: Bound<Vec<u8>>,
+    done: bool,
+    _types: Types<K, V, KC, VC>,
+}
+
+impl<K, V, KC: Codec<K>, VC: Codec<V>> TypedRange<K, V, KC, VC> {
+    fn read_entry(&mut self) -> Result<Option<(K, V)>> {
+        let (Some(key), Some(value)) = (self.cursor.key(), self.cursor.value()) else {
+            return Ok(None);
+        };
+        let in_range = match &self.end {
+            Bound::Included(end) => key <= end.as_slice(),
+            Bound::Excluded(end) => key < end.as_slice(),
+            Bound::Unbounded => true,
+        };
+        if !in_range {
+            return Ok(None);
+        }
+
+        let entry = (
+            KC::decode(key).map_err(|e| anyhow!("Failed to decode key {:?}: {}", key, e))?,
+            VC::decode(value)?,
+        );
+        self.cursor.next()?;
+        Ok(Some(entry))
+    }
+}
+
+impl<K, V, KC: Codec<K>, VC: Codec<V>> Iterator for TypedRange<K, V, KC, VC> {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = self.read_entry().transpose();
+        if !matches!(entry, Some(Ok(_))) {
+            self.done = true;
+        }
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn encoded<T: KeyEncoding>(value: &T) -> Vec<u8> {
+        let mut buf = Vec::new();
+        value.encode_key(&mut buf);
+        buf
+    }
+
+    /// Encodings sort like `values` do, and decode back to them
+    fn assert_ordered<T: KeyEncoding + Ord + fmt::Debug>(mut values: Vec<T>) {
+        values.sort();
+        values.dedup();
+        for pair in values.windows(2) {
+            assert!(encoded(&pair[0]) < encoded(&pair[1]), "{:?}", pair);
+        }
+        for value in &values {
+            assert_eq!(
+                &<OrderedKey as Codec<T>>::decode(&encoded(value)).unwrap(),
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn encodings_sort_like_the_keys() {
+        let mut random = crate::xorshift(0x2545f4914f6cdd1du64);
+        // short and from a small alphabet, so prefixes and zero bytes are common
+        fn random_bytes(random: &mut impl FnMut() -> u64) -> Vec<u8> {
+            let length = random() % 4;
+            (0..length)
+                .map(|_| [0, 1, b'a', 0xff][random() as usize % 4])
+                .collect()
+        }
+
+        assert_ordered(vec![i64::MIN, -256, -1, 0, 1, 255, 256, i64::MAX]);
+        assert_ordered(vec![i8::MIN, -1, 0, 1, i8::MAX]);
+        assert_ordered(vec![0u128, 1, u128::MAX]);
+        assert_ordered(vec![false, true]);
+        assert_ordered((0..200).map(|_| random() as i32).collect());
+        assert_ordered((0..200).map(|_| random() as u16).collect());
+        assert_ordered((0..200).map(|_| random_bytes(&mut random)).collect());
+        let strings = [
+            "",
+            "\0",
+            "\0\0",
+            "\0a",
+            "a",
+            "a\0",
+            "a\0b",
+            "aa",
+            "é",
+            "\u{10000}",
+        ];
+        assert_ordered(strings.iter().map(|s| s.to_string()).collect());
+        assert_ordered(
+            (0..300)
+                .map(|_| {
+                    let first = random_bytes(&mut random);
+                    (first, random() as i8 % 3, random_bytes(&mut random))
+                })
+                .collect(),
+        );
+    }
+
+    #[test]
+    fn malformed_keys_are_rejected() {
+        // truncated, too long, and a bad escape
+        assert!(<OrderedKey as Codec<u32>>::decode(&[0, 0, 1]).is_err());
+        assert!(<OrderedKey as Codec<u32>>::decode(&[0, 0, 0, 1, 0]).is_err());
+        assert!(<OrderedKey as Codec<(String, u8)>>::decode(b"a\0").is_err());
+        assert!(<OrderedKey as Codec<String>>::decode(b"a\0\x01\0\0").is_err());
+        assert!(<OrderedKey as Codec<String>>::decode(b"\xff\0\0").is_err());
+        assert!(<OrderedKey as Codec<bool>>::decode(&[2]).is_err());
+    }
+
+    #[test]
+    fn ranges_match_a_sorted_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree: TypedTree<(String, i64), String> =
+            TypedTree::open(dir.path(), Options::default()).unwrap();
+        let mut random = crate::xorshift(7u64);
+        let mut expected = BTreeMap::new();
+        for i in 0..3000 {
+            let key = (
+                format!("user{}", random() % 7),
+                (random() % 2000) as i64 - 1000,
+            );
+            let value = format!("value{}", i);
+            tree.put(&key, &value).unwrap();
+            expected.insert(key, value);
+            if i == 1500 {
+                tree.tree().flush().unwrap();
+            }
+        }
+
+        let start = ("user3".to_string(), -10);
+        let end = ("user3".to_string(), 10);
+        let ranges = [
+            (Bound::Unbounded, Bound::Unbounded),
+            (Bound::Included(start.clone()), Bound::Excluded(end.clone())),
+            (Bound::Excluded(start.clone()), Bound::Included(end.clone())),
+            (Bound::Excluded(start), Bound::Unbounded),
+            (Bound::Unbounded, Bound::Included(end)),
+        ];
+        for range in ranges {
+            let entries: Vec<_> = tree
+                .range(range.clone())
+                .unwrap()
+                .map(Result::unwrap)
+                .collect();
+            let expected_entries: Vec<_> = expected
+                .range(range)
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            assert_eq!(entries, expected_entries);
+        }
+
+        let start = ("user1".to_string(), i64::MIN);
+        tree.delete_range(&start, &("user2".to_string(), i64::MIN))
+            .unwrap();
+        expected.retain(|(user, _), _| user != "user1");
+        let first = expected.pop_first().unwrap().0;
+        tree.delete(&first).unwrap();
+        assert_eq!(tree.get(&first).unwrap(), None);
+        let entries: Vec<_> = tree.iter().unwrap().map(Result::unwrap).collect();
+        assert!(entries.into_iter().eq(expected));
+
+        // the iterator ends at a key it can't decode
+        tree.tree().put(b"zz".to_vec(), Vec::new()).unwrap();
+        let mut entries = tree.range(("user6".to_string(), i64::MAX)..).unwrap();
+        assert!(entries.next().unwrap().is_err());
+        assert!(entries.next().is_none());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree: TypedTree<u64, serde_json::Value, OrderedKey, Json> =
+            TypedTree::open(dir.path(), Options::default()).unwrap();
+        let value = serde_json::json!({"name": "b", "tags": [1, 2]});
+        tree.put(&300, &value).unwrap();
+        tree.put(&2, &serde_json::json!(null)).unwrap();
+        assert_eq!(tree.get(&300).unwrap(), Some(value));
+        let keys: Vec<_> = tree.iter().unwrap().map(|entry| entry.unwrap().0).collect();
+        assert_eq!(keys, [2, 300]);
+
+        tree.tree()
+            .put(encoded(&5u64), b"not json".to_vec())
+            .unwrap();
+        assert!(tree.get(&5).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use anyhow::{bail, Result};
+
+use crate::{decode_entries, encode_entry, LsmError, LsmTree, StoredValue};
+
+/// A single operation in a `WriteBatch`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    /// Delete every key in `[start, end)`
+    DeleteRange(Vec<u8>, Vec<u8>),
+}
+
+/// A group of puts and deletes applied atomically by `LsmTree::write`:
+/// they share one WAL record, so after a crash either all or none of them
+/// are replayed, and readers never see part of a batch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    // follows on from `StoredValue`'s tags, which the other operations reuse
+    const DELETE_RANGE_TAG: u8 = 3;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push(BatchOp::Put(key, value));
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.ops.push(BatchOp::Delete(key));
+    }
+
+    pub fn delete_range(&mut self, start: Vec<u8>, end: Vec<u8>) {
+        self.ops.push(BatchOp::DeleteRange(start, end));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Operations in the order they were added, later ones win on the same key
+    pub fn iter(&self) -> impl Iterator<Item = &BatchOp> {
+        self.ops.iter()
+    }
+
+    /// Check every key and value against the tree's size limits. A range
+    /// deletion's end is a key too, bound by the key limit.
+    pub(crate) fn validate(&self, max_value_size: usize) -> Result<()> {
+        let check_key = |key: &[u8]| {
+            if key.len() > LsmTree::MAX_ENTRY_SIZE {
+                bail!(LsmError::KeyTooLarge(key.len()));
+            }
+            Ok(())
+        };
+        for op in &self.ops {
+            match op {
+                BatchOp::Put(key, value) => {
+                    check_key(key)?;
+                    if value.len() > max_value_size {
+                        bail!(LsmError::ValueTooLarge(value.len()));
+                    }
+                }
+                BatchOp::Delete(key) => check_key(key)?,
+                BatchOp::DeleteRange(start, end) => {
+                    if start > end {
+                        bail!(LsmError::InvalidWrite(
+                            "range deletion start is after its end".into()
+                        ));
+                    }
+                    check_key(start)?;
+                    check_key(end)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Append the operations in the usual record format, with values
+    /// tagged like in SSTables (inline value or tombstone). Range deletions
+    /// are stored as `start -> <tag><end>`.
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        let mut encoded_value = Vec::new();
+        for op in &self.ops {
+            encoded_value.clear();
+            let key = match op {
+                BatchOp::Put(key, value) => {
+                    StoredValue::encode_inline(value, &mut encoded_value);
+                    key
+                }
+                BatchOp::Delete(key) => {
+                    StoredValue::Tombstone.encode(&mut encoded_value);
+                    key
+                }
+                BatchOp::DeleteRange(start, end) => {
+                    encoded_value.push(Self::DELETE_RANGE_TAG);
+                    encoded_value.extend_from_slice(end);
+                    start
+                }
+            };
+            encode_entry(buf, key, &encoded_value);
+        }
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut ops = Vec::new();
+        for (key, value) in decode_entries(bytes, 1 + LsmTree::MAX_BLOB_VALUE_SIZE)? {
+            if let Some((&Self::DELETE_RANGE_TAG, end)) = value.split_first() {
+                ops.push(BatchOp::DeleteRange(key, end.to_vec()));
+                continue;
+            }
+            match StoredValue::decode(&value)? {
+                StoredValue::Inline(value) => ops.push(BatchOp::Put(key, value)),
+                StoredValue::Tombstone => ops.push(BatchOp::Delete(key)),
+                StoredValue::Blob(_) => bail!("Unexpected blob pointer in write batch"),
+            }
+        }
+        Ok(Self { ops })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let mut batch = WriteBatch::new();
+        batch.put(b"key".to_vec(), b"value".to_vec());
+        batch.put(b"empty".to_vec(), Vec::new());
+        batch.delete(b"gone".to_vec());
+        batch.delete_range(b"a".to_vec(), b"b".to_vec());
+        let mut encoded = Vec::new();
+        batch.encode(&mut encoded);
+        assert_eq!(WriteBatch::decode(&encoded).unwrap(), batch);
+        assert!(WriteBatch::decode(&encoded[..encoded.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn validates_keys_and_values_separately() {
+        let max_key = vec![b'k'; LsmTree::MAX_ENTRY_SIZE];
+        let too_long = vec![b'k'; LsmTree::MAX_ENTRY_SIZE + 1];
+        let validate = |op: BatchOp, max_value_size: usize| {
+            WriteBatch { ops: vec![op] }.validate(max_value_size)
+        };
+
+        assert!(validate(BatchOp::Put(max_key.clone(), vec![0; 10]), 10).is_ok());
+        assert!(validate(BatchOp::Put(max_key.clone(), vec![0; 11]), 10).is_err());
+        assert!(validate(BatchOp::Put(too_long.clone(), Vec::new()), 10).is_err());
+        assert!(validate(BatchOp::Delete(too_long.clone()), 10).is_err());
+
+        // a range's end is a key, whatever the value limit
+        assert!(validate(BatchOp::DeleteRange(b"a".to_vec(), max_key.clone()), 1).is_ok());
+        assert!(validate(BatchOp::DeleteRange(b"a".to_vec(), too_long.clone()), 1).is_err());
+        assert!(validate(BatchOp::DeleteRange(too_long, b"z".to_vec()), 1).is_err());
+        assert!(validate(BatchOp::DeleteRange(b"b".to_vec(), b"a".to_vec()), 1).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+
+use crate::{BatchOp, LsmError, LsmTree, TreeInner, Wal, WriteBatch};
+
+#[derive(Debug)]
+struct WriteLogState {
+    // oldest first, with consecutive sequences
+    records: VecDeque<(u64, Arc<WriteBatch>)>,
+    bytes: usize,
+    // sequence of the newest write pushed, kept or not
+    last_sequence: u64,
+    // the tree has been dropped, so there won't be any more
+    closed: bool,
+}
+
+/// The most recent write batches, in memory, for readers of the tree's
+/// history (see `records_since`) that are close to caught up. Bounded by
+/// bytes: the oldest batches are dropped first.
+#[derive(Debug)]
+pub(crate) struct WriteLog {
+    capacity: usize,
+    state: Mutex<WriteLogState>,
+    pushed: Condvar,
+}
+
+impl WriteLog {
+    /// A log keeping up to `capacity` bytes of batches, picking up after
+    /// the write with `last_sequence`
+    pub(crate) fn new(capacity: usize, last_sequence: u64) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(WriteLogState {
+                records: VecDeque::new(),
+                bytes: 0,
+                last_sequence,
+                closed: false,
+            }),
+            pushed: Condvar::new(),
+        }
+    }
+
+    /// Add the write that was just appended to the WAL. Called with the WAL
+    /// lock held, so in sequence order.
+    pub(crate) fn push(&self, sequence: u64, batch: Arc<WriteBatch>) {
+        let mut state = self.state.lock().unwrap();
+        state.last_sequence = sequence;
+        let size = batch_size(&batch);
+        if size <= self.capacity {
+            state.records.push_back((sequence, batch));
+            state.bytes += size;
+            while state.bytes > self.capacity {
+                let (_, dropped) = state.records.pop_front().unwrap();
+                state.bytes -= batch_size(&dropped);
+            }
+        } else {
+            // it wouldn't fit, and the log can't have a gap
+            state.records.clear();
+            state.bytes = 0;
+        }
+        drop(state);
+        self.pushed.notify_all();
+    }
+
+    /// Wake up anyone waiting for writes, since there won't be any more
+    pub(crate) fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.pushed.notify_all();
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+}
+
+/// Key and value bytes of a batch
+fn batch_size(batch: &WriteBatch) -> usize {
+    batch
+        .iter()
+        .map(|op| match op {
+            BatchOp::Put(key, value) => key.len() + value.len(),
+            BatchOp::Delete(key) => key.len(),
+            BatchOp::DeleteRange(start, end) => start.len() + end.len(),
+        })
+        .sum()
+}
+
+/// Up to `limit` consecutive writes starting with the one with sequence
+/// `next`, waiting up to `timeout` for it if it hasn't happened yet (and
+/// returning nothing if it still hasn't, or the log is closed).
+///
+/// Recent writes come from the write log, older ones are read back out of
+/// the WAL segments, archived ones included. Once a write's segment has been
+/// deleted that's no longer possible, and this fails with
+/// `LsmError::SequenceUnavailable`.
+pub(crate) fn records_since(
+    inner: &TreeInner,
+    next: u64,
+    limit: usize,
+    timeout: Duration,
+) -> Result<Vec<(u64, Arc<WriteBatch>)>> {
+    let deadline = Instant::now() + timeout;
+    let log = &inner.write_log;
+    let mut state = log.state.lock().unwrap();
+    while next > state.last_sequence {
+        if next > state.last_sequence + 1 {
+            bail!(
+                "Write {} is past the newest one ({})",
+                next,
+                state.last_sequence
+            );
+        }
+        let now = Instant::now();
+        if now >= deadline || state.closed {
+            return Ok(Vec::new());
+        }
+        state = log.pushed.wait_timeout(state, deadline - now).unwrap().0;
+    }
+
+    if let Some(&(oldest, _)) = state.records.front() {
+        if oldest <= next {
+            return Ok(state
+                .records
+                .iter()
+                .skip((next - oldest) as usize)
+                .take(limit)
+                .cloned()
+                .collect());
+        }
+    }
+    drop(state);
+
+    // holding the state lock stops segments from moving into (or out of)
+    // the archive while we go through them
+    let state = inner.state.read().unwrap();
+    let mut segments = LsmTree::list_wal_segments(&inner.path.join(LsmTree::WAL_ARCHIVE_DIR))?
+        .into_iter()
+        .map(|(_, segment_path)| segment_path)
+        .collect::<Vec<_>>();
+    for immutable in &state.immutables {
+        segments.extend(immutable.wal_segments.iter().cloned());
+    }
+    segments.extend(state.wal_segments.iter().cloned());
+
+    // skip the segments that end before `next`
+    let mut first = 0;
+    for (i, segment_path) in segments.iter().enumerate().rev() {
+        if Wal::first_sequence(segment_path)?.is_some_and(|sequence| sequence <= next) {
+            first = i;
+            break;
+        }
+    }
+
+    let mut records = Vec::new();
+    for segment_path in &segments[first..] {
+        for (sequence, batch) in Wal::read_records(segment_path)? {
+            if records.len() == limit {
+                return Ok(records);
+            }
+            if sequence < next {
+                continue;
+            }
+            if sequence != next + records.len() as u64 {
+                break;
+            }
+            records.push((sequence, Arc::new(batch)));
+        }
+    }
+    if records.is_empty() {
+        bail!(LsmError::SequenceUnavailable(next));
+    }
+    Ok(records)
+}